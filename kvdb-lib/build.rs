@@ -4,4 +4,44 @@ fn main() {
         .include("src")
         .input("src/protos/pbapi.proto")
         .run_from_script();
+
+    grpc_service();
+}
+
+// Generate the gRPC client and server for the KeyValueDb service declared in
+// src/protos/pbapi.proto.  Messages are the rust-protobuf types above, so a
+// manual service definition with a custom codec is used in place of
+// prost-based codegen.
+fn grpc_service() {
+    let methods = [
+        ("clear", "Clear", "BasicRequest", "BasicResponse"),
+        ("db_stat", "DbStat", "BasicRequest", "DbStatResponse"),
+        ("del", "Del", "KeyRequest", "BasicResponse"),
+        ("get", "Get", "GetRequest", "GetResponse"),
+        ("iter", "Iter", "IterRequest", "IterResponse"),
+        ("mutate", "Mutate", "MutationRequest", "BasicResponse"),
+        ("put", "Put", "UpdateRequest", "BasicResponse"),
+        ("scan", "Scan", "IterRequest", "ScanResponse"),
+        ("cas", "Cas", "CasRequest", "BasicResponse"),
+        ("create_ns", "CreateNs", "NsRequest", "BasicResponse"),
+        ("drop_ns", "DropNs", "NsRequest", "BasicResponse"),
+        ("list_ns", "ListNs", "BasicRequest", "NsListResponse"),
+    ];
+
+    let mut service = tonic_build::manual::Service::builder()
+        .name("KeyValueDb")
+        .package("kvdbd");
+    for (name, route_name, input_type, output_type) in methods {
+        service = service.method(
+            tonic_build::manual::Method::builder()
+                .name(name)
+                .route_name(route_name)
+                .input_type(format!("crate::pbapi::{}", input_type))
+                .output_type(format!("crate::pbapi::{}", output_type))
+                .codec_path("crate::grpc::ProtobufCodec")
+                .build(),
+        );
+    }
+
+    tonic_build::manual::Builder::new().compile(&[service.build()]);
 }
@@ -1,11 +1,360 @@
 extern crate reqwest;
 use crate::codec;
+use crate::Error;
 
-pub const API_BASEURI: &'static str = "/api";
+pub const API_BASEURI: &str = "/api";
 
-use crate::pbapi::{get_response, DbStatResponse, GetResponse, MutationRequest};
+const USER_AGENT: &str = concat!("kvdb-lib/", env!("CARGO_PKG_VERSION"));
+
+use crate::pbapi::{
+    get_response, scan_response, DbStatResponse, GetResponse, IterRequest, MutationRequest,
+    ScanResponse,
+};
 use protobuf::{EnumOrUnknown, Message};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
 use reqwest::StatusCode;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+// server certificate check of a pinned client: SHA-256 fingerprint match
+struct PinnedCert {
+    fingerprint: Vec<u8>,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let digest = ring::digest::digest(&ring::digest::SHA256, &end_entity.0);
+        if digest.as_ref() == self.fingerprint.as_slice() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(String::from(
+                "server certificate fingerprint mismatch",
+            )))
+        }
+    }
+}
+
+// server certificate check of an insecure client: none
+struct AcceptAnyCert;
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+// decode hex SHA-256 fingerprint, optionally colon-separated
+fn parse_fingerprint(s: &str) -> crate::Result<Vec<u8>> {
+    let hex: Vec<u8> = s.bytes().filter(|c| *c != b':').collect();
+    let invalid = || Error::InvalidArgument(format!("invalid SHA-256 fingerprint: {}", s));
+    if hex.len() != 64 {
+        return Err(invalid());
+    }
+
+    let mut fingerprint = Vec::with_capacity(32);
+    for pair in hex.chunks(2) {
+        let pair = std::str::from_utf8(pair).map_err(|_e| invalid())?;
+        fingerprint.push(u8::from_str_radix(pair, 16).map_err(|_e| invalid())?);
+    }
+    Ok(fingerprint)
+}
+
+// decode certificate chain and private key PEM, for client authentication
+fn parse_identity(
+    cert_pem: &[u8],
+    key_pem: &[u8],
+) -> crate::Result<(Vec<Certificate>, PrivateKey)> {
+    let certs = match rustls_pemfile::certs(&mut &cert_pem[..]) {
+        Ok(certs) if !certs.is_empty() => certs,
+        _ => {
+            return Err(Error::InvalidArgument(String::from(
+                "client cert: no certificate",
+            )))
+        }
+    };
+
+    let items = match rustls_pemfile::read_all(&mut &key_pem[..]) {
+        Ok(items) => items,
+        Err(e) => return Err(Error::InvalidArgument(format!("client key: {}", e))),
+    };
+    for item in items {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => {
+                return Ok((
+                    certs.into_iter().map(Certificate).collect(),
+                    PrivateKey(key),
+                ))
+            }
+            _ => {}
+        }
+    }
+    Err(Error::InvalidArgument(String::from(
+        "client key: no private key",
+    )))
+}
+
+/// Builder of a KvdbClient, configuring credentials, trust in the server
+/// certificate, and connection settings.
+///
+/// By default, the server certificate must chain to a system root CA, and
+/// match the endpoint host.  Trust may instead, or also, be placed in
+/// other CAs, or in a single certificate pinned by its fingerprint.
+#[derive(Clone)]
+pub struct KvdbClientBuilder {
+    endpoint: String,
+    db_id: String,
+    api_key: Option<String>,
+    client_cert: Option<(Vec<u8>, Vec<u8>)>, // cert chain, private key PEM
+    ca_certs: Vec<Vec<u8>>,                  // trusted CA certificates PEM
+    ca_files: Vec<PathBuf>,                  // files of trusted CA certificates PEM
+    system_roots: bool,
+    pin_sha256: Option<String>,
+    insecure: bool,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    user_agent: String,
+    proxy: Option<String>,
+    no_proxy: bool,
+}
+
+impl KvdbClientBuilder {
+    pub fn new(endpoint_: String, db_id_: String) -> KvdbClientBuilder {
+        KvdbClientBuilder {
+            endpoint: endpoint_,
+            db_id: db_id_,
+            api_key: None,
+            client_cert: None,
+            ca_certs: Vec::new(),
+            ca_files: Vec::new(),
+            system_roots: true,
+            pin_sha256: None,
+            insecure: false,
+            timeout: None,
+            connect_timeout: None,
+            user_agent: String::from(USER_AGENT),
+            proxy: None,
+            no_proxy: false,
+        }
+    }
+
+    /// Send `api_key` with each request, for servers with api_keys
+    /// configured.
+    pub fn api_key(mut self, api_key: &str) -> KvdbClientBuilder {
+        self.api_key = Some(api_key.to_string());
+        self
+    }
+
+    /// Present a TLS client certificate, for servers verifying clients.
+    /// `cert_pem` holds the certificate chain, `key_pem` its private key
+    /// (PKCS#8, PKCS#1 or SEC1).
+    pub fn client_cert(mut self, cert_pem: &[u8], key_pem: &[u8]) -> KvdbClientBuilder {
+        self.client_cert = Some((cert_pem.to_vec(), key_pem.to_vec()));
+        self
+    }
+
+    /// Trust CA certificates in PEM `ca_pem`, for the server certificate.
+    pub fn ca_cert(mut self, ca_pem: &[u8]) -> KvdbClientBuilder {
+        self.ca_certs.push(ca_pem.to_vec());
+        self
+    }
+
+    /// Trust CA certificates in PEM file `path`, read by build().
+    pub fn ca_file<P: Into<PathBuf>>(mut self, path: P) -> KvdbClientBuilder {
+        self.ca_files.push(path.into());
+        self
+    }
+
+    /// Trust the system root CAs (default: true).
+    pub fn system_roots(mut self, enable: bool) -> KvdbClientBuilder {
+        self.system_roots = enable;
+        self
+    }
+
+    /// Accept only the server certificate with this SHA-256 fingerprint,
+    /// in hex, optionally colon-separated.  The pinned certificate is
+    /// accepted whatever its issuer, host name and validity period.
+    pub fn pin_sha256(mut self, fingerprint: &str) -> KvdbClientBuilder {
+        self.pin_sha256 = Some(fingerprint.to_string());
+        self
+    }
+
+    /// Accept any server certificate.  Unsafe: anyone able to intercept
+    /// the connection may read and alter traffic, including credentials.
+    pub fn danger_accept_invalid_certs(mut self, insecure: bool) -> KvdbClientBuilder {
+        self.insecure = insecure;
+        self
+    }
+
+    /// Time limit of each request, from connecting until the response
+    /// body is read (default: none).
+    pub fn timeout(mut self, timeout: Duration) -> KvdbClientBuilder {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Time limit of connecting to the server (default: none).
+    pub fn connect_timeout(mut self, timeout: Duration) -> KvdbClientBuilder {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// User-Agent header value (default: "kvdb-lib/VERSION").
+    pub fn user_agent(mut self, user_agent: &str) -> KvdbClientBuilder {
+        self.user_agent = user_agent.to_string();
+        self
+    }
+
+    /// Connect through proxy `url` (http, https or socks5 scheme), instead
+    /// of proxies named by environment variables.
+    pub fn proxy(mut self, url: &str) -> KvdbClientBuilder {
+        self.proxy = Some(url.to_string());
+        self
+    }
+
+    /// Ignore proxies named by environment variables.
+    pub fn no_proxy(mut self) -> KvdbClientBuilder {
+        self.no_proxy = true;
+        self
+    }
+
+    // trusted CAs, for verifying the server certificate chain
+    fn root_store(&self) -> crate::Result<RootCertStore> {
+        let mut roots = RootCertStore::empty();
+        if self.system_roots {
+            // missing or partly unreadable system store: trust what loads
+            if let Ok(certs) = rustls_native_certs::load_native_certs() {
+                let ders: Vec<Vec<u8>> = certs.into_iter().map(|cert| cert.0).collect();
+                roots.add_parsable_certificates(&ders);
+            }
+        }
+
+        let mut ca_pems = self.ca_certs.clone();
+        for path in &self.ca_files {
+            match std::fs::read(path) {
+                Ok(pem) => ca_pems.push(pem),
+                Err(e) => {
+                    return Err(Error::InvalidArgument(format!(
+                        "CA file {}: {}",
+                        path.display(),
+                        e
+                    )))
+                }
+            }
+        }
+        for pem in ca_pems {
+            let ders = match rustls_pemfile::certs(&mut &pem[..]) {
+                Ok(ders) if !ders.is_empty() => ders,
+                _ => return Err(Error::InvalidArgument(String::from("CA: no certificate"))),
+            };
+            for der in ders {
+                if let Err(e) = roots.add(&Certificate(der)) {
+                    return Err(Error::InvalidArgument(format!("CA: {}", e)));
+                }
+            }
+        }
+        Ok(roots)
+    }
+
+    fn tls_config(&self) -> crate::Result<ClientConfig> {
+        let verifier: Arc<dyn ServerCertVerifier> = match (self.insecure, &self.pin_sha256) {
+            (true, Some(_)) => {
+                return Err(Error::InvalidArgument(String::from(
+                    "pinned certificate conflicts with accepting invalid certificates",
+                )))
+            }
+            (true, None) => Arc::new(AcceptAnyCert),
+            (false, Some(pin)) => Arc::new(PinnedCert {
+                fingerprint: parse_fingerprint(pin)?,
+            }),
+            (false, None) => Arc::new(WebPkiVerifier::new(self.root_store()?, None)),
+        };
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(verifier);
+        match &self.client_cert {
+            None => Ok(builder.with_no_client_auth()),
+            Some((cert_pem, key_pem)) => {
+                let (certs, key) = parse_identity(cert_pem, key_pem)?;
+                match builder.with_client_auth_cert(certs, key) {
+                    Ok(config) => Ok(config),
+                    Err(e) => Err(Error::InvalidArgument(format!("client cert: {}", e))),
+                }
+            }
+        }
+    }
+
+    /// Build an HTTP client with these settings, for requests KvdbClient
+    /// does not offer.
+    pub fn build_http_client(&self) -> crate::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder()
+            .use_preconfigured_tls(self.tls_config()?)
+            .user_agent(self.user_agent.as_str());
+
+        if let Some(api_key) = &self.api_key {
+            let mut auth_val = match HeaderValue::from_str(&format!("Bearer {}", api_key)) {
+                Ok(val) => val,
+                Err(e) => return Err(Error::InvalidArgument(format!("invalid API key: {}", e))),
+            };
+            auth_val.set_sensitive(true);
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, auth_val);
+            builder = builder.default_headers(headers);
+        }
+
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+
+        if self.no_proxy {
+            builder = builder.no_proxy();
+        }
+        if let Some(url) = &self.proxy {
+            match reqwest::Proxy::all(url) {
+                Ok(proxy) => builder = builder.proxy(proxy),
+                Err(e) => return Err(Error::InvalidArgument(format!("proxy: {}", e))),
+            }
+        }
+
+        match builder.build() {
+            Ok(client) => Ok(client),
+            Err(e) => Err(Error::backend(e)),
+        }
+    }
+
+    pub fn build(self) -> crate::Result<KvdbClient> {
+        let client = self.build_http_client()?;
+        Ok(KvdbClient {
+            client,
+            db_id: self.db_id,
+            endpoint: self.endpoint,
+        })
+    }
+}
 
 pub struct KvdbClient {
     client: reqwest::Client,
@@ -14,15 +363,41 @@ pub struct KvdbClient {
 }
 
 impl KvdbClient {
-    pub fn new(endpoint_: String, db_id_: String) -> KvdbClient {
-        KvdbClient {
-            client: reqwest::Client::builder()
-                .danger_accept_invalid_certs(true)
-                .build()
-                .unwrap(),
-            db_id: db_id_,
-            endpoint: endpoint_,
-        }
+    /// Client with default settings: no credentials, and a server
+    /// certificate issued by a system root CA.
+    pub fn new(endpoint_: String, db_id_: String) -> crate::Result<KvdbClient> {
+        KvdbClientBuilder::new(endpoint_, db_id_).build()
+    }
+
+    pub fn builder(endpoint_: String, db_id_: String) -> KvdbClientBuilder {
+        KvdbClientBuilder::new(endpoint_, db_id_)
+    }
+
+    /// Client sending `api_key` with each request, for servers with
+    /// api_keys configured.  Fails if `api_key` is not valid in an HTTP
+    /// header.
+    pub fn with_api_key(
+        endpoint_: String,
+        db_id_: String,
+        api_key: &str,
+    ) -> crate::Result<KvdbClient> {
+        KvdbClientBuilder::new(endpoint_, db_id_)
+            .api_key(api_key)
+            .build()
+    }
+
+    /// Client presenting a TLS client certificate, for servers verifying
+    /// clients.  `cert_pem` holds the certificate chain, `key_pem` its
+    /// private key.
+    pub fn with_client_cert(
+        endpoint_: String,
+        db_id_: String,
+        cert_pem: &[u8],
+        key_pem: &[u8],
+    ) -> crate::Result<KvdbClient> {
+        KvdbClientBuilder::new(endpoint_, db_id_)
+            .client_cert(cert_pem, key_pem)
+            .build()
     }
 
     pub async fn get1(&mut self, key: String) -> Option<Vec<u8>> {
@@ -90,6 +465,39 @@ impl KvdbClient {
         }
     }
 
+    pub async fn cas1(
+        &mut self,
+        key: String,
+        old_value: Option<String>,
+        new_value: Option<String>,
+    ) -> bool {
+        let basepath = format!("{}{}/{}/", self.endpoint, API_BASEURI, self.db_id);
+        let cas_url = format!("{}cas", basepath);
+
+        // encode compare-and-swap request
+        let out_bytes = codec::pbenc_cas_req(
+            key.as_bytes(),
+            old_value.as_ref().map(|v| v.as_bytes()),
+            new_value.as_ref().map(|v| v.as_bytes()),
+        );
+
+        // exec compare-and-swap request
+        let resp_res = self.client.post(&cas_url).body(out_bytes).send().await;
+        match resp_res {
+            Ok(resp) => {
+                if resp.status() == StatusCode::OK {
+                    match resp.text().await {
+                        Ok(_body) => true,
+                        Err(_e) => false,
+                    }
+                } else {
+                    false
+                }
+            }
+            Err(_e) => false,
+        }
+    }
+
     pub async fn put1(&mut self, key: String, value: String) -> bool {
         // encode put request
         let out_req = codec::pbenc_mutate_ins1(key.as_bytes(), value.as_bytes());
@@ -121,6 +529,69 @@ impl KvdbClient {
         }
     }
 
+    // POST namespace request; true if successful
+    async fn ns_op(&mut self, ns: &str, op: &str) -> bool {
+        let basepath = format!("{}{}/{}/", self.endpoint, API_BASEURI, self.db_id);
+        let ns_url = format!("{}{}/{}", basepath, ns, op);
+
+        // exec namespace request
+        let resp_res = self.client.post(&ns_url).send().await;
+        match resp_res {
+            Ok(resp) => {
+                if resp.status() == StatusCode::OK {
+                    match resp.text().await {
+                        Ok(_body) => true,
+                        Err(_e) => false,
+                    }
+                } else {
+                    false
+                }
+            }
+            Err(_e) => false,
+        }
+    }
+
+    pub async fn create_ns(&mut self, ns: &str) -> bool {
+        self.ns_op(ns, "create").await
+    }
+
+    pub async fn drop_ns(&mut self, ns: &str) -> bool {
+        self.ns_op(ns, "drop").await
+    }
+
+    pub async fn scan(&mut self, iter_req: &IterRequest) -> Option<ScanResponse> {
+        let basepath = format!("{}{}/{}/", self.endpoint, API_BASEURI, self.db_id);
+        let scan_url = format!("{}scan", basepath);
+
+        // encode scan request
+        let out_bytes = iter_req.write_to_bytes().unwrap();
+
+        // exec scan request
+        let resp_res = self.client.post(&scan_url).body(out_bytes).send().await;
+        if resp_res.is_err() {
+            return None;
+        }
+        let resp = resp_res.unwrap();
+        if resp.status() != StatusCode::OK {
+            return None;
+        }
+
+        // decode protobuf list-of-records response
+        match resp.bytes().await {
+            Ok(bytes) => match ScanResponse::parse_from_bytes(&bytes) {
+                Err(_e) => None,
+                Ok(in_resp) => {
+                    if in_resp.magic != EnumOrUnknown::new(scan_response::MagicNum::MAGIC) {
+                        None
+                    } else {
+                        Some(in_resp)
+                    }
+                }
+            },
+            Err(_e) => None,
+        }
+    }
+
     pub async fn stat(&mut self) -> Option<DbStatResponse> {
         let basepath = format!("{}{}/{}/", self.endpoint, API_BASEURI, self.db_id);
         let stat_url = format!("{}stat", basepath);
@@ -137,10 +608,7 @@ impl KvdbClient {
 
         // decode protobuf list-of-keys response
         match resp.bytes().await {
-            Ok(bytes) => match DbStatResponse::parse_from_bytes(&bytes) {
-                Err(_e) => None,
-                Ok(req) => Some(req),
-            },
+            Ok(bytes) => DbStatResponse::parse_from_bytes(&bytes).ok(),
             Err(_e) => None,
         }
     }
@@ -166,3 +634,55 @@ impl KvdbClient {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_formats() {
+        let hex = "00112233445566778899aabbccddeeff00112233445566778899AABBCCDDEEFF";
+        let fingerprint = parse_fingerprint(hex).unwrap();
+        assert_eq!(fingerprint.len(), 32);
+        assert_eq!(fingerprint[1], 0x11);
+        assert_eq!(fingerprint[31], 0xff);
+
+        let colons: Vec<String> = fingerprint.iter().map(|b| format!("{:02X}", b)).collect();
+        assert_eq!(parse_fingerprint(&colons.join(":")).unwrap(), fingerprint);
+
+        assert!(parse_fingerprint("").is_err());
+        assert!(parse_fingerprint(&hex[2..]).is_err());
+        assert!(parse_fingerprint(&hex.replace('a', "g")).is_err());
+    }
+
+    #[test]
+    fn build_errors() {
+        let builder =
+            || KvdbClientBuilder::new(String::from("https://127.0.0.1:8080"), String::new());
+        let pin = "00".repeat(32);
+
+        assert!(builder().build().is_ok());
+        assert!(builder().pin_sha256(&pin).build().is_ok());
+        assert!(builder()
+            .danger_accept_invalid_certs(true)
+            .pin_sha256(&pin)
+            .build()
+            .is_err());
+        assert!(builder().pin_sha256("00").build().is_err());
+        assert!(builder().ca_cert(b"not a certificate").build().is_err());
+        assert!(builder().ca_file("/nonexistent/ca.crt").build().is_err());
+        assert!(builder()
+            .client_cert(b"not a certificate", b"not a key")
+            .build()
+            .is_err());
+        assert!(builder().proxy("not a url").build().is_err());
+
+        let endpoint = || String::from("https://127.0.0.1:8080");
+        assert!(KvdbClient::new(endpoint(), String::new()).is_ok());
+        assert!(KvdbClient::with_api_key(endpoint(), String::new(), "k1").is_ok());
+        assert!(matches!(
+            KvdbClient::with_api_key(endpoint(), String::new(), "k1\n"),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+}
@@ -1,15 +1,18 @@
+use crate::db::{api, ttl};
 use crate::pbapi::{
-    db_stat_response, get_request, iter_request, iter_response, key_request, mutation_request,
-    update_request, DbStatResponse, GetOp, GetRequest, IterRequest, IterResponse, KeyRequest,
-    MutationRequest, UpdateRequest,
+    basic_response, cas_request, db_stat_response, get_request, iter_request, iter_response,
+    key_request, mutation_request, ns_list_response, ns_request, scan_response, update_request,
+    BasicResponse, CasRequest, DbStatResponse, GetOp, GetRequest, IterRequest, IterResponse,
+    KeyRequest, KeyValue, MutationRequest, NsListResponse, NsRequest, ScanResponse, UpdateRequest,
 };
+use crate::Error;
 use protobuf::{EnumOrUnknown, Message};
 
 pub fn pbenc_key_req(key: &[u8]) -> Vec<u8> {
     let mut out_msg = KeyRequest::new();
     out_msg.magic = EnumOrUnknown::new(key_request::MagicNum::MAGIC);
     out_msg.key = key.to_vec();
-    return out_msg.write_to_bytes().unwrap();
+    out_msg.write_to_bytes().unwrap()
 }
 
 pub fn pbenc_get1_req(key: &[u8], skip_val: bool) -> Vec<u8> {
@@ -21,7 +24,7 @@ pub fn pbenc_get1_req(key: &[u8], skip_val: bool) -> Vec<u8> {
     out_op.skip_val = skip_val;
     out_msg.ops.push(out_op);
 
-    return out_msg.write_to_bytes().unwrap();
+    out_msg.write_to_bytes().unwrap()
 }
 
 pub fn pbenc_mutate_ins1(key: &[u8], val: &[u8]) -> MutationRequest {
@@ -39,15 +42,72 @@ pub fn pbenc_mutate_ins1(key: &[u8], val: &[u8]) -> MutationRequest {
     out_msg
 }
 
-pub fn pbenc_db_stat_resp(n_records: u64) -> Vec<u8> {
+pub fn pbenc_scan_req(start_key: &[u8], keys_only: bool) -> IterRequest {
+    let mut out_msg = IterRequest::new();
+    out_msg.magic = EnumOrUnknown::new(iter_request::MagicNum::MAGIC);
+    out_msg.start_key = start_key.to_vec();
+    out_msg.keys_only = keys_only;
+
+    out_msg
+}
+
+pub fn pbenc_cas_req(key: &[u8], old_val: Option<&[u8]>, new_val: Option<&[u8]>) -> Vec<u8> {
+    let mut out_msg = CasRequest::new();
+    out_msg.magic = EnumOrUnknown::new(cas_request::MagicNum::MAGIC);
+    out_msg.key = key.to_vec();
+    out_msg.old_value = old_val.map(|v| v.to_vec());
+    out_msg.new_value = new_val.map(|v| v.to_vec());
+    out_msg.write_to_bytes().unwrap()
+}
+
+pub fn pbenc_ns_req(ns: &str) -> Vec<u8> {
+    let mut out_msg = NsRequest::new();
+    out_msg.magic = EnumOrUnknown::new(ns_request::MagicNum::MAGIC);
+    out_msg.ns = ns.to_string();
+    out_msg.write_to_bytes().unwrap()
+}
+
+// db op result to BasicResponse; Ok(false) means key not found
+pub fn pbenc_basic_resp(res: &crate::Result<bool>) -> BasicResponse {
+    let mut out_msg = BasicResponse::new();
+    out_msg.magic = EnumOrUnknown::new(basic_response::MagicNum::MAGIC);
+
+    match res {
+        Ok(true) => out_msg.is_ok = true,
+        Ok(false) => {
+            out_msg.err_code = basic_response::ErrCode::NOT_FOUND as i32;
+            out_msg.err_msg = String::from("not found");
+        }
+        Err(e) => {
+            out_msg.err_code = e.err_code() as i32;
+            out_msg.err_msg = e.to_string();
+        }
+    }
+
+    out_msg
+}
+
+pub fn db_stat_resp(n_records: u64) -> DbStatResponse {
     let mut out_msg = DbStatResponse::new();
     out_msg.magic = EnumOrUnknown::new(db_stat_response::MagicNum::MAGIC);
     out_msg.n_records = n_records;
 
-    return out_msg.write_to_bytes().unwrap();
+    out_msg
+}
+
+pub fn pbenc_db_stat_resp(n_records: u64) -> Vec<u8> {
+    db_stat_resp(n_records).write_to_bytes().unwrap()
+}
+
+pub fn ns_list_resp(names: &[String]) -> NsListResponse {
+    let mut out_msg = NsListResponse::new();
+    out_msg.magic = EnumOrUnknown::new(ns_list_response::MagicNum::MAGIC);
+    out_msg.names = names.to_vec();
+
+    out_msg
 }
 
-pub fn pbenc_iter_resp(key_list: &crate::db::api::KeyList) -> Vec<u8> {
+pub fn iter_resp(key_list: &api::KeyList) -> IterResponse {
     let mut out_msg = IterResponse::new();
     out_msg.magic = EnumOrUnknown::new(iter_response::MagicNum::MAGIC);
 
@@ -56,7 +116,30 @@ pub fn pbenc_iter_resp(key_list: &crate::db::api::KeyList) -> Vec<u8> {
     }
     out_msg.list_end = key_list.list_end;
 
-    return out_msg.write_to_bytes().unwrap();
+    out_msg
+}
+
+pub fn pbenc_iter_resp(key_list: &api::KeyList) -> Vec<u8> {
+    iter_resp(key_list).write_to_bytes().unwrap()
+}
+
+pub fn scan_resp(kv_list: &api::KvList) -> ScanResponse {
+    let mut out_msg = ScanResponse::new();
+    out_msg.magic = EnumOrUnknown::new(scan_response::MagicNum::MAGIC);
+
+    for kv in &kv_list.pairs {
+        let mut out_kv = KeyValue::new();
+        out_kv.key = kv.key.clone();
+        out_kv.value = kv.value.clone();
+        out_msg.pairs.push(out_kv);
+    }
+    out_msg.list_end = kv_list.list_end;
+
+    out_msg
+}
+
+pub fn pbenc_scan_resp(kv_list: &api::KvList) -> Vec<u8> {
+    scan_resp(kv_list).write_to_bytes().unwrap()
 }
 
 pub fn pbdec_iter_req(wiredata: &[u8]) -> Option<IterRequest> {
@@ -111,6 +194,32 @@ pub fn pbdec_mget_req(wiredata: &[u8]) -> Option<GetRequest> {
     }
 }
 
+pub fn pbdec_cas_req(wiredata: &[u8]) -> Option<CasRequest> {
+    match CasRequest::parse_from_bytes(wiredata) {
+        Err(_e) => None,
+        Ok(req) => {
+            if req.magic != EnumOrUnknown::new(cas_request::MagicNum::MAGIC) {
+                None
+            } else {
+                Some(req)
+            }
+        }
+    }
+}
+
+pub fn pbdec_ns_req(wiredata: &[u8]) -> Option<NsRequest> {
+    match NsRequest::parse_from_bytes(wiredata) {
+        Err(_e) => None,
+        Ok(req) => {
+            if req.magic != EnumOrUnknown::new(ns_request::MagicNum::MAGIC) {
+                None
+            } else {
+                Some(req)
+            }
+        }
+    }
+}
+
 pub fn pbdec_mutate_req(wiredata: &[u8]) -> Option<MutationRequest> {
     match MutationRequest::parse_from_bytes(wiredata) {
         Err(_e) => None,
@@ -123,3 +232,104 @@ pub fn pbdec_mutate_req(wiredata: &[u8]) -> Option<MutationRequest> {
         }
     }
 }
+
+/// Resolve the namespace of a request, named by URL path and/or message
+/// field.  Empty means the default keyspace.  Names with the reserved
+/// prefix are internal, and not addressable by clients.
+pub fn request_ns(path_ns: Option<&str>, msg_ns: &str) -> crate::Result<Option<String>> {
+    let ns = match (path_ns, msg_ns) {
+        (None, "") => return Ok(None),
+        (Some(ns), "") | (None, ns) => ns,
+        (Some(ns), msg_ns) => {
+            if ns != msg_ns {
+                return Err(Error::InvalidArgument(format!(
+                    "namespace {:?} conflicts with URL namespace {:?}",
+                    msg_ns, ns
+                )));
+            }
+            ns
+        }
+    };
+
+    if ns.starts_with(api::RESERVED_NS_PREFIX) {
+        return Err(Error::InvalidArgument(format!(
+            "namespace {:?} is reserved",
+            ns
+        )));
+    }
+    api::validate_ns_name(ns)?;
+
+    Ok(Some(ns.to_string()))
+}
+
+// IterRequest to db iteration options
+pub fn iter_opts_from_req(in_msg: &IterRequest) -> api::IterOptions {
+    let mut opts = api::IterOptions::new();
+    if !in_msg.start_key.is_empty() {
+        opts.start(&in_msg.start_key);
+    }
+    if !in_msg.end_key.is_empty() {
+        opts.end(&in_msg.end_key, in_msg.end_inclusive);
+    }
+    opts.reverse(in_msg.reverse);
+    if in_msg.limit > 0 {
+        opts.limit(in_msg.limit as usize);
+    }
+    if !in_msg.prefix.is_empty() {
+        opts.prefix(&in_msg.prefix);
+    }
+    opts.keys_only(in_msg.keys_only);
+    if in_msg.max_bytes > 0 {
+        opts.max_bytes(in_msg.max_bytes.try_into().unwrap_or(usize::MAX));
+    }
+
+    opts
+}
+
+// UpdateRequest condition fields to db precondition
+fn precondition_from_update(update: &UpdateRequest) -> crate::Result<Option<api::Precondition>> {
+    match update.condition.enum_value() {
+        Ok(update_request::Condition::NONE) => Ok(None),
+        Ok(update_request::Condition::NOT_EXISTS) => Ok(Some(api::Precondition::NotExists)),
+        Ok(update_request::Condition::VALUE_EQUALS) => Ok(Some(api::Precondition::ValueEquals(
+            update.expected_value.clone(),
+        ))),
+        Ok(update_request::Condition::VERSION_EQUALS) => Ok(Some(
+            api::Precondition::VersionEquals(update.expected_version),
+        )),
+        Err(n) => Err(Error::InvalidArgument(format!("unknown condition {}", n))),
+    }
+}
+
+// UpdateRequest lifetime to expiry time, if any
+pub fn expiry_from_update(update: &UpdateRequest) -> Option<u64> {
+    match update.ttl_ms {
+        0 => None,
+        ttl_ms => Some(ttl::expiry_after(ttl_ms)),
+    }
+}
+
+// MutationRequest to db batch.  UpdateRequest magic is checked by caller.
+// Updates without a namespace apply to default_ns.
+pub fn batch_from_mutate_req(
+    in_msg: &MutationRequest,
+    default_ns: Option<&str>,
+) -> crate::Result<api::Batch> {
+    let mut batch = api::Batch::default();
+    for update in &in_msg.reqs {
+        let ns = request_ns(default_ns, &update.ns)?;
+        batch.set_ns(ns.as_deref());
+        if update.is_insert {
+            batch.insert(&update.key, &update.value);
+        } else {
+            batch.remove(&update.key);
+        }
+        let op = batch.ops.last_mut().unwrap();
+        op.condition = precondition_from_update(update)?;
+        if update.is_insert {
+            op.expires = expiry_from_update(update);
+        }
+    }
+
+    Ok(batch)
+}
@@ -1,43 +1,251 @@
+use std::collections::HashMap;
+use std::ops::Bound;
+use std::str::FromStr;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+#[derive(Clone)]
 pub enum MutationOp {
     Insert,
     Remove,
 }
 
+/// Condition on a key's current value or version, checked before a
+/// mutation is applied.  A failed check aborts the whole batch.
+#[derive(Clone)]
+pub enum Precondition {
+    NotExists,            // key must not exist
+    ValueEquals(Vec<u8>), // key must exist, with this value
+    VersionEquals(u64),   // key must exist, with this Db::version_ns()
+}
+
+impl Precondition {
+    /// Condition for a compare-and-swap, given the expected current value
+    pub fn from_expected(old_val: Option<&[u8]>) -> Precondition {
+        match old_val {
+            None => Precondition::NotExists,
+            Some(val) => Precondition::ValueEquals(val.to_vec()),
+        }
+    }
+
+    /// Test against the key's current value.  op_idx is the position of
+    /// the mutation within its batch, reported on failure.  Versions are
+    /// not known to drivers: VersionEquals is checked by the version
+    /// layer, with check_version(), and fails here.
+    pub fn check(&self, op_idx: usize, key: &[u8], cur_val: Option<&[u8]>) -> Result<()> {
+        let failed = match (self, cur_val) {
+            (Precondition::NotExists, None) => None,
+            (Precondition::NotExists, Some(_)) => Some("key exists"),
+            (_, None) => Some("key not found"),
+            (Precondition::ValueEquals(val), Some(cur)) => match val[..] == cur[..] {
+                true => None,
+                false => Some("value mismatch"),
+            },
+            (Precondition::VersionEquals(_), Some(_)) => Some("versions not supported"),
+        };
+
+        match failed {
+            None => Ok(()),
+            Some(reason) => Err(condition_failed(op_idx, key, reason)),
+        }
+    }
+
+    /// Test against the key's current version, None if the key does not
+    /// exist.  Conditions other than VersionEquals are tested by check().
+    pub fn check_version(&self, op_idx: usize, key: &[u8], cur_version: Option<u64>) -> Result<()> {
+        let failed = match (self, cur_version) {
+            (Precondition::VersionEquals(_), None) => Some("key not found"),
+            (Precondition::VersionEquals(version), Some(cur)) => match *version == cur {
+                true => None,
+                false => Some("version mismatch"),
+            },
+            _ => None,
+        };
+
+        match failed {
+            None => Ok(()),
+            Some(reason) => Err(condition_failed(op_idx, key, reason)),
+        }
+    }
+}
+
+// failed precondition of a batch's op op_idx, on key
+fn condition_failed(op_idx: usize, key: &[u8], reason: &str) -> Error {
+    Error::ConditionFailed(format!(
+        "op {}, key {:?}: {}",
+        op_idx,
+        String::from_utf8_lossy(key),
+        reason
+    ))
+}
+
+#[derive(Clone)]
 pub struct Mutation {
     pub op: MutationOp,
+    pub ns: Option<String>, // namespace; None for the default keyspace
     pub key: Vec<u8>,
     pub value: Option<Vec<u8>>,
+    pub condition: Option<Precondition>,
+    pub expires: Option<u64>, // insert: expiry time, in ms since Unix epoch
 }
 
+#[derive(Default)]
 pub struct Batch {
     pub ops: Vec<Mutation>,
+    cur_ns: Option<String>, // namespace for mutations added next
 }
 
 impl Batch {
-    pub fn default() -> Batch {
-        Batch { ops: Vec::new() }
+    /// Select the namespace of mutations added after this call.  A batch
+    /// may span namespaces, and is still applied atomically.
+    pub fn set_ns(&mut self, ns: Option<&str>) {
+        self.cur_ns = ns.map(|s| s.to_string());
     }
 
     pub fn insert(&mut self, key_in: &[u8], value_in: &[u8]) {
         self.ops.push(Mutation {
             op: MutationOp::Insert,
+            ns: self.cur_ns.clone(),
             key: key_in.to_vec(),
             value: Some(value_in.to_vec()),
+            condition: None,
+            expires: None,
         });
     }
 
     pub fn remove(&mut self, key_in: &[u8]) {
         self.ops.push(Mutation {
             op: MutationOp::Remove,
+            ns: self.cur_ns.clone(),
             key: key_in.to_vec(),
             value: None,
+            condition: None,
+            expires: None,
         });
     }
+
+    pub fn insert_if(&mut self, key_in: &[u8], value_in: &[u8], cond: Precondition) {
+        self.insert(key_in, value_in);
+        self.ops.last_mut().unwrap().condition = Some(cond);
+    }
+
+    /// Insert a record that expires at the given time, in milliseconds
+    /// since the Unix epoch.  Inserts without expiry clear any earlier one.
+    pub fn insert_expiring(&mut self, key_in: &[u8], value_in: &[u8], expires: u64) {
+        self.insert(key_in, value_in);
+        self.ops.last_mut().unwrap().expires = Some(expires);
+    }
+
+    pub fn remove_if(&mut self, key_in: &[u8], cond: Precondition) {
+        self.remove(key_in);
+        self.ops.last_mut().unwrap().condition = Some(cond);
+    }
+
+    pub fn has_conditions(&self) -> bool {
+        self.ops.iter().any(|m| m.condition.is_some())
+    }
+
+    /// Distinct namespaces used by this batch, in order of first use
+    pub fn namespaces(&self) -> Vec<Option<&str>> {
+        let mut nss: Vec<Option<&str>> = Vec::new();
+        for m in &self.ops {
+            let ns = m.ns.as_deref();
+            if !nss.contains(&ns) {
+                nss.push(ns);
+            }
+        }
+
+        nss
+    }
+}
+
+/// Namespaces beginning with this prefix are reserved for kvdb internal use
+pub const RESERVED_NS_PREFIX: &str = "__";
+
+pub const MAX_NS_NAME_LEN: usize = 128;
+
+/// Check namespace name: non-empty, URI-compatible characters
+pub fn validate_ns_name(ns: &str) -> Result<()> {
+    if ns.is_empty() || ns.len() > MAX_NS_NAME_LEN {
+        return Err(Error::InvalidArgument(String::from(
+            "namespace name length",
+        )));
+    }
+    if !ns
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-' || b == b'.')
+    {
+        return Err(Error::InvalidArgument(format!("namespace name {:?}", ns)));
+    }
+
+    Ok(())
 }
 
+/// When committed writes reach disk.  In configuration files, one of
+/// "default", "sync", "nosync", or {"periodic": N}.
+#[derive(Clone, Copy, Debug, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// Storage engine default: sled flushes every 500 ms, LMDB syncs each
+    /// commit.
+    #[default]
+    Default,
+    /// Sync each commit before it returns.
+    Sync,
+    /// Sync every N milliseconds; a crash loses writes since the last.
+    Periodic(u64),
+    /// Sync only on flush and close, for scratch data.
+    NoSync,
+}
+
+/// Driver-specific tuning options, by name.  Values are parsed, and
+/// unknown names rejected, by each driver.
+pub type Options = HashMap<String, String>;
+
 pub struct Config {
     pub path: String,
     pub read_only: bool,
+    pub durability: Durability, // ignored, if read-only
+    pub options: Options,
+}
+
+/// Check that options name only `known` keys.
+pub fn check_option_names(options: &Options, known: &[&str]) -> Result<()> {
+    let mut unknown: Vec<&str> = options
+        .keys()
+        .map(|key| key.as_str())
+        .filter(|key| !known.contains(key))
+        .collect();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+
+    unknown.sort_unstable();
+    let known = match known.is_empty() {
+        true => String::from("none"),
+        false => known.join(", "),
+    };
+    Err(Error::InvalidArgument(format!(
+        "unknown driver option {} (supported: {})",
+        unknown.join(", "),
+        known
+    )))
+}
+
+/// Value of option `key`, parsed; None if unset.
+pub fn parse_option<T: FromStr>(options: &Options, key: &str) -> Result<Option<T>> {
+    match options.get(key) {
+        None => Ok(None),
+        Some(val) => match val.parse() {
+            Ok(val) => Ok(Some(val)),
+            Err(_e) => Err(Error::InvalidArgument(format!(
+                "driver option {}: invalid value {}",
+                key, val
+            ))),
+        },
+    }
 }
 
 pub struct KeyList {
@@ -45,55 +253,442 @@ pub struct KeyList {
     pub list_end: bool,
 }
 
+pub struct KeyValue {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>, // empty, if keys_only
+}
+
+pub struct KvList {
+    pub pairs: Vec<KeyValue>,
+    pub list_end: bool,
+}
+
+/// (lower, upper) key bounds, in ascending key order
+pub type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+#[derive(Clone)]
 pub struct IterOptions {
-    pub start_key: Option<Vec<u8>>,
+    pub ns: Option<String>,         // namespace; None for the default keyspace
+    pub start_key: Option<Vec<u8>>, // exclusive; last key of previous page
+    pub end_key: Option<Vec<u8>>,   // stop iteration at this key
+    pub end_inclusive: bool,        // include end_key itself, if present
+    pub reverse: bool,              // iterate in descending key order
+    pub limit: usize,               // max records per page
     pub prefix: Option<Vec<u8>>,
+    pub keys_only: bool,  // omit values from scan results
+    pub max_bytes: usize, // scan page size limit, in key+value bytes
 }
 
 impl IterOptions {
     pub fn new() -> IterOptions {
         IterOptions {
+            ns: None,
             start_key: None,
+            end_key: None,
+            end_inclusive: false,
+            reverse: false,
+            limit: MAX_ITER_KEYS,
             prefix: None,
+            keys_only: false,
+            max_bytes: MAX_SCAN_BYTES,
         }
     }
 
+    pub fn ns(&mut self, ns: &str) -> &mut IterOptions {
+        self.ns = Some(ns.to_string());
+
+        self
+    }
+
     pub fn start(&mut self, key: &[u8]) -> &mut IterOptions {
         self.start_key = Some(key.to_vec());
 
         self
     }
 
+    pub fn end(&mut self, key: &[u8], inclusive: bool) -> &mut IterOptions {
+        self.end_key = Some(key.to_vec());
+        self.end_inclusive = inclusive;
+
+        self
+    }
+
+    pub fn reverse(&mut self, val: bool) -> &mut IterOptions {
+        self.reverse = val;
+
+        self
+    }
+
+    pub fn limit(&mut self, n_records: usize) -> &mut IterOptions {
+        self.limit = n_records.clamp(1, MAX_ITER_KEYS);
+
+        self
+    }
+
     pub fn prefix(&mut self, prefix: &[u8]) -> &mut IterOptions {
         self.prefix = Some(prefix.to_vec());
 
         self
     }
+
+    pub fn keys_only(&mut self, val: bool) -> &mut IterOptions {
+        self.keys_only = val;
+
+        self
+    }
+
+    pub fn max_bytes(&mut self, n_bytes: usize) -> &mut IterOptions {
+        self.max_bytes = n_bytes.clamp(1, MAX_SCAN_BYTES);
+
+        self
+    }
+
+    /// Key range remaining to be visited, as (lower, upper) bounds in
+    /// ascending key order, or None if the range is empty.  Iteration
+    /// runs from start_key toward end_key; when reverse, start_key is
+    /// the upper bound.  The range is narrowed to prefix, if any, so
+    /// drivers may seek directly to it.
+    pub fn key_range(&self) -> Option<KeyRange> {
+        let start = match &self.start_key {
+            None => Bound::Unbounded,
+            Some(key) => Bound::Excluded(key.clone()),
+        };
+        let end = match &self.end_key {
+            None => Bound::Unbounded,
+            Some(key) => match self.end_inclusive {
+                true => Bound::Included(key.clone()),
+                false => Bound::Excluded(key.clone()),
+            },
+        };
+
+        let (mut lower, mut upper) = match self.reverse {
+            false => (start, end),
+            true => (end, start),
+        };
+
+        // narrow range to keys matching prefix
+        if let Some(prefix) = &self.prefix {
+            lower = max_lower(lower, Bound::Included(prefix.clone()));
+            upper = match prefix_successor(prefix) {
+                None => upper,
+                Some(succ) => min_upper(upper, Bound::Excluded(succ)),
+            };
+        }
+
+        let is_empty = match (&lower, &upper) {
+            (Bound::Included(lo), Bound::Included(hi)) => lo > hi,
+            (
+                Bound::Included(lo) | Bound::Excluded(lo),
+                Bound::Included(hi) | Bound::Excluded(hi),
+            ) => lo >= hi,
+            _ => false,
+        };
+
+        match is_empty {
+            true => None,
+            false => Some((lower, upper)),
+        }
+    }
+}
+
+impl Default for IterOptions {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 pub struct DbStat {
     pub n_records: u64,
 }
 
+/// Storage engine figures, for monitoring.  None where the backend
+/// cannot tell.
+#[derive(Default)]
+pub struct StorageStat {
+    pub disk_bytes: Option<u64>, // space used by database files
+    pub map_bytes: Option<u64>,  // size limit, for fixed-size maps (LMDB)
+}
+
+/// Smallest key greater than every key beginning with prefix, or None
+/// if there is no such key (prefix is empty or all 0xff bytes).
+pub fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut succ = prefix.to_vec();
+    while let Some(last) = succ.pop() {
+        if last < 0xff {
+            succ.push(last + 1);
+            return Some(succ);
+        }
+    }
+
+    None
+}
+
+// the more restrictive of two lower bounds
+fn max_lower(a: Bound<Vec<u8>>, b: Bound<Vec<u8>>) -> Bound<Vec<u8>> {
+    match (&a, &b) {
+        (Bound::Unbounded, _) => b,
+        (_, Bound::Unbounded) => a,
+        (Bound::Included(ka) | Bound::Excluded(ka), Bound::Included(kb) | Bound::Excluded(kb)) => {
+            if ka > kb || (ka == kb && matches!(a, Bound::Excluded(_))) {
+                a
+            } else {
+                b
+            }
+        }
+    }
+}
+
+// the more restrictive of two upper bounds
+fn min_upper(a: Bound<Vec<u8>>, b: Bound<Vec<u8>>) -> Bound<Vec<u8>> {
+    match (&a, &b) {
+        (Bound::Unbounded, _) => b,
+        (_, Bound::Unbounded) => a,
+        (Bound::Included(ka) | Bound::Excluded(ka), Bound::Included(kb) | Bound::Excluded(kb)) => {
+            if ka < kb || (ka == kb && matches!(a, Bound::Excluded(_))) {
+                a
+            } else {
+                b
+            }
+        }
+    }
+}
+
 pub const MAX_ITER_KEYS: usize = 1000;
+pub const MAX_SCAN_BYTES: usize = 4 * 1024 * 1024;
+
+/// Accumulates one page of scan results, enforcing the record count and
+/// byte size limits.  Drivers feed records in key order until `push`
+/// returns false.
+pub struct ScanPage {
+    list: KvList,
+    keys_only: bool,
+    limit: usize,
+    max_bytes: usize,
+    n_bytes: usize,
+}
+
+impl ScanPage {
+    pub fn new(opts: &IterOptions) -> ScanPage {
+        ScanPage {
+            list: KvList {
+                pairs: Vec::new(),
+                list_end: true,
+            },
+            keys_only: opts.keys_only,
+            limit: opts.limit,
+            max_bytes: opts.max_bytes,
+            n_bytes: 0,
+        }
+    }
+
+    /// Add a record to the page.  Returns false when the page is full, and
+    /// iteration should stop.  A record that would overflow the byte limit
+    /// is not added, unless it is the first on the page.
+    pub fn push(&mut self, key: &[u8], value: &[u8]) -> bool {
+        let rec_bytes = match self.keys_only {
+            true => key.len(),
+            false => key.len() + value.len(),
+        };
+        if !self.list.pairs.is_empty() && self.n_bytes + rec_bytes > self.max_bytes {
+            self.list.list_end = false;
+            return false;
+        }
+
+        self.n_bytes += rec_bytes;
+        self.list.pairs.push(KeyValue {
+            key: key.to_vec(),
+            value: match self.keys_only {
+                true => Vec::new(),
+                false => value.to_vec(),
+            },
+        });
+
+        if self.list.pairs.len() >= self.limit {
+            self.list.list_end = false;
+            return false;
+        }
+
+        true
+    }
+
+    pub fn finish(self) -> KvList {
+        self.list
+    }
+}
+
+/// A single open database.  Read methods take a shared reference, and
+/// implementations must be safe to call from many threads at once;
+/// callers serialize the `&mut self` write methods per database.
+pub trait Db: Send + Sync {
+    /// Apply all mutations atomically, across namespaces.  Preconditions
+    /// are checked in order, each seeing the effect of earlier mutations
+    /// in the batch; if any fails, nothing is applied and
+    /// Error::ConditionFailed is returned.
+    fn apply_batch(&mut self, batch: &Batch) -> Result<bool>;
+    fn clear_ns(&mut self, ns: Option<&str>) -> Result<bool>;
+    fn del_ns(&mut self, ns: Option<&str>, key: &[u8]) -> Result<bool>;
+    fn get_ns(&self, ns: Option<&str>, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn put_ns(&mut self, ns: Option<&str>, key: &[u8], val: &[u8]) -> Result<bool>;
+    fn scan(&self, opts: IterOptions) -> Result<KvList>;
+    fn stat_ns(&self, ns: Option<&str>) -> Result<DbStat>;
+
+    /// Open namespace, creating it if needed.  Operations on a namespace
+    /// that was never opened fail with Error::NotFound.
+    fn open_ns(&mut self, ns: &str) -> Result<bool>;
+
+    /// Remove namespace and all its records.  Ok(false) if not found.
+    fn drop_ns(&mut self, ns: &str) -> Result<bool>;
+
+    /// Names of all namespaces, excluding the default keyspace
+    fn list_ns(&self) -> Result<Vec<String>>;
+
+    fn clear(&mut self) -> Result<bool> {
+        self.clear_ns(None)
+    }
+
+    fn del(&mut self, key: &[u8]) -> Result<bool> {
+        self.del_ns(None, key)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_ns(None, key)
+    }
+
+    fn put(&mut self, key: &[u8], val: &[u8]) -> Result<bool> {
+        self.put_ns(None, key, val)
+    }
+
+    fn version(&self, key: &[u8]) -> Result<Option<u64>> {
+        self.version_ns(None, key)
+    }
+
+    fn stat(&self) -> Result<DbStat> {
+        self.stat_ns(None)
+    }
+
+    /// Store key, expiring at the given time in milliseconds since the
+    /// Unix epoch, if any.
+    fn put_expiring_ns(
+        &mut self,
+        ns: Option<&str>,
+        key: &[u8],
+        val: &[u8],
+        expires: Option<u64>,
+    ) -> Result<bool> {
+        match expires {
+            None => self.put_ns(ns, key, val),
+            Some(expires) => {
+                let mut batch = Batch::default();
+                batch.set_ns(ns);
+                batch.insert_expiring(key, val, expires);
+
+                self.apply_batch(&batch)
+            }
+        }
+    }
+
+    /// Version of a record: a number changed by every write of the key,
+    /// and never reused, for VersionEquals preconditions.  None if the key
+    /// does not exist.  Databases without versions fail with
+    /// Error::InvalidArgument.
+    fn version_ns(&self, _ns: Option<&str>, _key: &[u8]) -> Result<Option<u64>> {
+        Err(Error::InvalidArgument(String::from(
+            "versions not supported by driver",
+        )))
+    }
+
+    /// Remove up to limit expired records from storage.  Returns the
+    /// number of expiry entries examined; fewer than limit means none
+    /// remain.  Databases without expiry support do nothing.
+    fn purge_expired(&mut self, _limit: usize) -> Result<usize> {
+        Ok(0)
+    }
+
+    /// Atomically replace key's value with new_val, if the current value
+    /// is old_val.  None means "key does not exist", for either value,
+    /// so a None new_val removes the key.
+    fn compare_and_swap_ns(
+        &mut self,
+        ns: Option<&str>,
+        key: &[u8],
+        old_val: Option<&[u8]>,
+        new_val: Option<&[u8]>,
+    ) -> Result<bool> {
+        let cond = Precondition::from_expected(old_val);
+
+        let mut batch = Batch::default();
+        batch.set_ns(ns);
+        match new_val {
+            None => batch.remove_if(key, cond),
+            Some(val) => batch.insert_if(key, val, cond),
+        }
+
+        self.apply_batch(&batch)
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: &[u8],
+        old_val: Option<&[u8]>,
+        new_val: Option<&[u8]>,
+    ) -> Result<bool> {
+        self.compare_and_swap_ns(None, key, old_val, new_val)
+    }
+
+    /// Storage engine figures, for monitoring.
+    fn storage_stat(&self) -> Result<StorageStat> {
+        Ok(StorageStat::default())
+    }
+
+    /// Write changes buffered by the storage engine durably to disk.
+    /// Databases without buffering do nothing.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Rewrite database files to reclaim space left by removed records.
+    /// Blocks writers until done.  Databases without compaction fail with
+    /// Error::InvalidArgument.
+    fn compact(&mut self) -> Result<()> {
+        Err(Error::InvalidArgument(String::from(
+            "compaction not supported by driver",
+        )))
+    }
+
+    /// Flush, then release storage held outside the handle, such as
+    /// temporary files.  The database must not be used afterwards; the
+    /// handle is dropped to close it.
+    fn close(&mut self) -> Result<()> {
+        self.flush()
+    }
 
-pub trait Db {
-    fn apply_batch(&mut self, batch: &Batch) -> Result<bool, &'static str>;
-    fn clear(&mut self) -> Result<bool, &'static str>;
-    fn del(&mut self, key: &[u8]) -> Result<bool, &'static str>;
-    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, &'static str>;
-    fn put(&mut self, key: &[u8], val: &[u8]) -> Result<bool, &'static str>;
-    fn iter_keys(&self, opts: IterOptions) -> Result<KeyList, &'static str>;
-    fn stat(&self) -> Result<DbStat, &'static str>;
+    /// Key-only iteration, as a keys_only scan.
+    fn iter_keys(&self, mut opts: IterOptions) -> Result<KeyList> {
+        opts.keys_only = true;
+        let kv_list = self.scan(opts)?;
+
+        Ok(KeyList {
+            keys: kv_list.pairs.into_iter().map(|kv| kv.key).collect(),
+            list_end: kv_list.list_end,
+        })
+    }
 }
 
-pub trait Driver {
-    fn start_db(&self, cfg: Config) -> Result<Box<dyn Db + Send>, &'static str>;
+pub trait Driver: Send + Sync {
+    /// Check driver-specific options, without opening a database.
+    /// Drivers without options accept none.
+    fn check_options(&self, options: &Options) -> Result<()> {
+        check_option_names(options, &[])
+    }
+
+    fn start_db(&self, cfg: Config) -> Result<Box<dyn Db>>;
 }
 
 pub struct ConfigBuilder {
     pub path: Option<String>,
     pub read_only: Option<bool>,
+    pub durability: Option<Durability>,
+    pub options: Options,
 }
 
 impl ConfigBuilder {
@@ -101,6 +696,8 @@ impl ConfigBuilder {
         ConfigBuilder {
             path: None,
             read_only: None,
+            durability: None,
+            options: Options::new(),
         }
     }
 
@@ -114,6 +711,21 @@ impl ConfigBuilder {
         self
     }
 
+    pub fn durability(&mut self, val_in: Durability) -> &mut ConfigBuilder {
+        self.durability = Some(val_in);
+        self
+    }
+
+    pub fn option(&mut self, key: &str, val: &str) -> &mut ConfigBuilder {
+        self.options.insert(key.to_string(), val.to_string());
+        self
+    }
+
+    pub fn options(&mut self, options: Options) -> &mut ConfigBuilder {
+        self.options = options;
+        self
+    }
+
     pub fn build(&self) -> Config {
         Config {
             path: match &self.path {
@@ -124,130 +736,25 @@ impl ConfigBuilder {
                 None => false,
                 Some(v) => *v,
             },
+            durability: self.durability.unwrap_or_default(),
+            options: self.options.clone(),
         }
     }
 }
 
-#[cfg(test)]
-use std::collections::HashMap;
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
 
-    pub struct MemDb {
-        db: HashMap<Vec<u8>, Vec<u8>>,
-    }
-
-    impl Db for MemDb {
-        fn clear(&mut self) -> Result<bool, &'static str> {
-            self.db.clear();
-            Ok(true)
-        }
-
-        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, &'static str> {
-            match self.db.get(key) {
-                None => Ok(None),
-                Some(val) => Ok(Some(val.to_vec())),
-            }
-        }
-
-        fn stat(&self) -> Result<DbStat, &'static str> {
-            Ok(DbStat {
-                n_records: self.db.len() as u64,
-            })
-        }
-
-        fn iter_keys(&self, opts: IterOptions) -> Result<KeyList, &'static str> {
-            let mut key_list = KeyList {
-                keys: Vec::new(),
-                list_end: true,
-            };
-
-            let prefix: Vec<u8> = match opts.prefix {
-                None => Vec::new(),
-                Some(value) => value,
-            };
-            let pfx_len = prefix.len();
-
-            let start_key: Vec<u8> = match opts.start_key {
-                None => Vec::new(),
-                Some(value) => value,
-            };
-            let have_start_key: bool = start_key.len() > 0;
-
-            let mut capture = !have_start_key;
-            for key in self.db.keys() {
-                // handle prefix-only iteration; skip if no match
-                if pfx_len > 0 {
-                    if key.len() < pfx_len || prefix != &key[0..pfx_len] {
-                        continue;
-                    }
-                }
-
-                // initialize iteration
-                if !capture {
-                    if start_key == &key[0..] {
-                        capture = true;
-                        // don't push this key; caller is passing
-                        // last key seen in their previous iter()
-                    }
-
-                // continue iteration
-                } else {
-                    key_list.keys.push(key.clone());
-                }
-
-                if key_list.keys.len() >= MAX_ITER_KEYS {
-                    key_list.list_end = false;
-                    break;
-                }
-            }
-
-            Ok(key_list)
-        }
-
-        fn put(&mut self, key: &[u8], val: &[u8]) -> Result<bool, &'static str> {
-            self.db.insert(key.to_vec(), val.to_vec());
-            Ok(true)
-        }
-
-        fn del(&mut self, key: &[u8]) -> Result<bool, &'static str> {
-            match self.db.remove(key) {
-                None => Ok(false),
-                Some(_v) => Ok(true),
-            }
-        }
-
-        fn apply_batch(&mut self, batch: &Batch) -> Result<bool, &'static str> {
-            for dbm in &batch.ops {
-                match dbm.op {
-                    MutationOp::Insert => {
-                        let val: Vec<u8> = dbm.value.clone().unwrap();
-                        self.db.insert(dbm.key.to_vec(), val);
-                    }
-                    MutationOp::Remove => {
-                        self.db.remove(&dbm.key);
-                    }
-                }
-            }
-
-            Ok(true)
-        }
-    }
-
-    pub struct MemDriver {}
-
-    impl Driver for MemDriver {
-        fn start_db(&self, _cfg: Config) -> Result<Box<dyn Db + Send>, &'static str> {
-            Ok(Box::new(MemDb { db: HashMap::new() }) as Box<dyn Db + Send>)
-        }
-    }
-
-    pub fn new_driver() -> Box<dyn Driver> {
-        Box::new(MemDriver {})
-    }
+    // trait defaults and helpers, exercised through the memory driver
+    use super::super::memory::new_driver;
 
     #[test]
     fn test_get_put() {
@@ -260,12 +767,12 @@ mod tests {
 
         let mut db = driver.start_db(db_config).unwrap();
 
-        assert_eq!(db.get(b"name"), Ok(None));
-        assert_eq!(db.put(b"name", b"alan"), Ok(true));
-        assert_eq!(db.get(b"name"), Ok(Some(Vec::from("alan"))));
-        assert_eq!(db.del(b"name"), Ok(true));
-        assert_eq!(db.get(b"name"), Ok(None));
-        assert_eq!(db.get(b"never_existed"), Ok(None));
+        assert_eq!(db.get(b"name").unwrap(), None);
+        assert!(db.put(b"name", b"alan").unwrap());
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+        assert!(db.del(b"name").unwrap());
+        assert_eq!(db.get(b"name").unwrap(), None);
+        assert_eq!(db.get(b"never_existed").unwrap(), None);
     }
 
     #[test]
@@ -279,9 +786,9 @@ mod tests {
 
         let mut db = driver.start_db(db_config).unwrap();
 
-        assert_eq!(db.put(b"name", b"alan"), Ok(true));
-        assert_eq!(db.del(b"name"), Ok(true));
-        assert_eq!(db.del(b"name"), Ok(false));
+        assert!(db.put(b"name", b"alan").unwrap());
+        assert!(db.del(b"name").unwrap());
+        assert!(!db.del(b"name").unwrap());
     }
 
     #[test]
@@ -295,17 +802,17 @@ mod tests {
 
         let mut db = driver.start_db(db_config).unwrap();
 
-        assert_eq!(db.put(b"name", b"alan"), Ok(true));
+        assert!(db.put(b"name", b"alan").unwrap());
 
         let mut batch = Batch::default();
         batch.insert(b"age", b"25");
         batch.insert(b"city", b"anytown");
         batch.remove(b"name");
-        assert_eq!(db.apply_batch(&batch), Ok(true));
+        assert!(db.apply_batch(&batch).unwrap());
 
-        assert_eq!(db.get(b"name"), Ok(None));
-        assert_eq!(db.get(b"age"), Ok(Some(Vec::from("25"))));
-        assert_eq!(db.get(b"city"), Ok(Some(Vec::from("anytown"))));
+        assert_eq!(db.get(b"name").unwrap(), None);
+        assert_eq!(db.get(b"age").unwrap(), Some(Vec::from("25")));
+        assert_eq!(db.get(b"city").unwrap(), Some(Vec::from("anytown")));
     }
 
     #[test]
@@ -319,12 +826,12 @@ mod tests {
 
         let mut db = driver.start_db(db_config).unwrap();
 
-        assert_eq!(db.put(b"name", b"alan"), Ok(true));
-        assert_eq!(db.put(b"age", b"25"), Ok(true));
-        assert_eq!(db.get(b"name"), Ok(Some(Vec::from("alan"))));
-        assert_eq!(db.clear(), Ok(true));
-        assert_eq!(db.get(b"name"), Ok(None));
-        assert_eq!(db.get(b"age"), Ok(None));
+        assert!(db.put(b"name", b"alan").unwrap());
+        assert!(db.put(b"age", b"25").unwrap());
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+        assert!(db.clear().unwrap());
+        assert_eq!(db.get(b"name").unwrap(), None);
+        assert_eq!(db.get(b"age").unwrap(), None);
     }
 
     #[test]
@@ -338,11 +845,11 @@ mod tests {
 
         let mut db = driver.start_db(db_config).unwrap();
 
-        assert_eq!(db.put(b"name1", b"alan"), Ok(true));
-        assert_eq!(db.put(b"age1", b"25"), Ok(true));
-        assert_eq!(db.put(b"name", b"alan"), Ok(true));
-        assert_eq!(db.del(b"name"), Ok(true));
-        assert_eq!(db.del(b"name"), Ok(false));
+        assert!(db.put(b"name1", b"alan").unwrap());
+        assert!(db.put(b"age1", b"25").unwrap());
+        assert!(db.put(b"name", b"alan").unwrap());
+        assert!(db.del(b"name").unwrap());
+        assert!(!db.del(b"name").unwrap());
 
         let st = db.stat().unwrap();
         assert_eq!(st.n_records, 2);
@@ -361,23 +868,23 @@ mod tests {
 
         // iterate empty list
         let key_list_res = db.iter_keys(IterOptions::new());
-        assert_eq!(key_list_res.is_err(), false);
+        assert!(key_list_res.is_ok());
 
         let mut key_list = key_list_res.unwrap();
-        assert_eq!(key_list.list_end, true);
+        assert!(key_list.list_end);
 
         key_list.keys.sort();
         assert_eq!(key_list.keys.len(), 0);
 
         // iterate small list
-        assert_eq!(db.put(b"name", b"alan"), Ok(true));
-        assert_eq!(db.put(b"age", b"25"), Ok(true));
+        assert!(db.put(b"name", b"alan").unwrap());
+        assert!(db.put(b"age", b"25").unwrap());
 
         let key_list_res = db.iter_keys(IterOptions::new());
-        assert_eq!(key_list_res.is_err(), false);
+        assert!(key_list_res.is_ok());
 
         let mut key_list = key_list_res.unwrap();
-        assert_eq!(key_list.list_end, true);
+        assert!(key_list.list_end);
 
         key_list.keys.sort();
         assert_eq!(key_list.keys.len(), 2);
@@ -397,19 +904,19 @@ mod tests {
         let mut db = driver.start_db(db_config).unwrap();
 
         // iterate small list
-        assert_eq!(db.put(b"2018/name", b"alan"), Ok(true));
-        assert_eq!(db.put(b"2018/bame", b"alan"), Ok(true));
-        assert_eq!(db.put(b"2019/fame", b"alan"), Ok(true));
-        assert_eq!(db.put(b"2019/lame", b"alan"), Ok(true));
-        assert_eq!(db.put(b"2019/game", b"alan"), Ok(true));
-        assert_eq!(db.put(b"2020/tame", b"alan"), Ok(true));
-        assert_eq!(db.put(b"age", b"25"), Ok(true));
+        assert!(db.put(b"2018/name", b"alan").unwrap());
+        assert!(db.put(b"2018/bame", b"alan").unwrap());
+        assert!(db.put(b"2019/fame", b"alan").unwrap());
+        assert!(db.put(b"2019/lame", b"alan").unwrap());
+        assert!(db.put(b"2019/game", b"alan").unwrap());
+        assert!(db.put(b"2020/tame", b"alan").unwrap());
+        assert!(db.put(b"age", b"25").unwrap());
 
         let key_list_res = db.iter_keys(IterOptions::new());
-        assert_eq!(key_list_res.is_err(), false);
+        assert!(key_list_res.is_ok());
 
         let key_list = key_list_res.unwrap();
-        assert_eq!(key_list.list_end, true);
+        assert!(key_list.list_end);
         assert_eq!(key_list.keys.len(), 7);
 
         // iterate with prefix matching
@@ -417,10 +924,10 @@ mod tests {
         opts.prefix(b"2019/");
 
         let key_list_res = db.iter_keys(opts);
-        assert_eq!(key_list_res.is_err(), false);
+        assert!(key_list_res.is_ok());
 
         let mut key_list = key_list_res.unwrap();
-        assert_eq!(key_list.list_end, true);
+        assert!(key_list.list_end);
         assert_eq!(key_list.keys.len(), 3);
 
         key_list.keys.sort();
@@ -437,4 +944,112 @@ mod tests {
             String::from("2019/lame")
         );
     }
+
+    #[test]
+    fn test_scan() {
+        let db_config = ConfigBuilder::new()
+            .path("/dev/null".to_string())
+            .read_only(false)
+            .build();
+
+        let driver = new_driver();
+
+        let mut db = driver.start_db(db_config).unwrap();
+
+        assert!(db.put(b"name", b"alan").unwrap());
+        assert!(db.put(b"age", b"25").unwrap());
+
+        let mut kv_list = db.scan(IterOptions::new()).unwrap();
+        assert!(kv_list.list_end);
+        assert_eq!(kv_list.pairs.len(), 2);
+
+        kv_list.pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(kv_list.pairs[0].key, b"age");
+        assert_eq!(kv_list.pairs[0].value, b"25");
+        assert_eq!(kv_list.pairs[1].key, b"name");
+        assert_eq!(kv_list.pairs[1].value, b"alan");
+
+        // a single record larger than the page is still returned
+        let mut opts = IterOptions::new();
+        opts.max_bytes(1).keys_only(true);
+        let kv_list = db.scan(opts).unwrap();
+        assert!(!kv_list.list_end);
+        assert_eq!(kv_list.pairs.len(), 1);
+        assert!(kv_list.pairs[0].value.is_empty());
+    }
+
+    #[test]
+    fn test_prefix_successor() {
+        assert_eq!(prefix_successor(b"abc"), Some(b"abd".to_vec()));
+        assert_eq!(prefix_successor(b"a\xff\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_successor(b"\xff"), None);
+        assert_eq!(prefix_successor(b""), None);
+    }
+
+    #[test]
+    fn test_cas() {
+        let db_config = ConfigBuilder::new()
+            .path("/dev/null".to_string())
+            .read_only(false)
+            .build();
+
+        let driver = new_driver();
+
+        let mut db = driver.start_db(db_config).unwrap();
+
+        // default implementation, via conditional batch
+        assert!(db.compare_and_swap(b"name", None, Some(b"alan")).unwrap());
+        assert!(matches!(
+            db.compare_and_swap(b"name", None, Some(b"bob")),
+            Err(Error::ConditionFailed(_))
+        ));
+        assert!(db
+            .compare_and_swap(b"name", Some(b"alan"), Some(b"bob"))
+            .unwrap());
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("bob")));
+        assert!(db.compare_and_swap(b"name", Some(b"bob"), None).unwrap());
+        assert_eq!(db.get(b"name").unwrap(), None);
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let db_config = ConfigBuilder::new()
+            .path("/dev/null".to_string())
+            .read_only(false)
+            .build();
+
+        let driver = new_driver();
+
+        let mut db = driver.start_db(db_config).unwrap();
+
+        assert!(matches!(
+            db.get_ns(Some("users"), b"name"),
+            Err(Error::NotFound)
+        ));
+        assert!(db.open_ns("users").unwrap());
+        assert!(db.open_ns("users").unwrap());
+        assert!(matches!(
+            db.open_ns("bad/name"),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert_eq!(db.list_ns().unwrap(), vec![String::from("users")]);
+
+        // keyspaces are separate; batches span them
+        let mut batch = Batch::default();
+        batch.insert(b"name", b"default");
+        batch.set_ns(Some("users"));
+        batch.insert(b"name", b"alan");
+        assert_eq!(batch.namespaces(), vec![None, Some("users")]);
+        assert!(db.apply_batch(&batch).unwrap());
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("default")));
+        assert_eq!(
+            db.get_ns(Some("users"), b"name").unwrap(),
+            Some(Vec::from("alan"))
+        );
+
+        assert!(db.drop_ns("users").unwrap());
+        assert!(!db.drop_ns("users").unwrap());
+        assert!(db.list_ns().unwrap().is_empty());
+        assert_eq!(db.stat().unwrap().n_records, 1);
+    }
 }
@@ -0,0 +1,1463 @@
+/*
+ * bitcask: log-structured driver, after Bitcask.
+ *
+ * Records are appended to segment files in the database directory.  An
+ * in-memory key directory, one BTreeMap per keyspace, maps each live key
+ * to the location of its latest value: a read is one positioned read, and
+ * scans walk the key directory in key order.  On start, the key
+ * directory is rebuilt by replaying all segments, oldest first.
+ *
+ * Segment file NNNNNNNNNN.seg, numbered in write order:
+ *
+ *   "KVDBLOG1"
+ *   u8 flags      SEG_MERGED: all lower-numbered segments are obsolete
+ *   frames...
+ *
+ * Frame, integers little-endian:
+ *
+ *   u32 crc       CRC-32 of len and entries
+ *   u32 len       bytes of entries
+ *   entries...
+ *
+ * Entry:  u8 op, u8 ns_len (0: default keyspace), ns, then
+ *
+ *   OP_PUT:       u32 key_len, key, u32 val_len, val
+ *   OP_DEL:       u32 key_len, key
+ *   OP_CLEAR, OP_NS_CREATE, OP_NS_DROP:  nothing more
+ *
+ * Each write, or whole batch, is one frame, and replay applies whole
+ * frames only.  A torn or corrupt frame at the end of the newest segment,
+ * from a crash mid-write, is discarded; anywhere else it is corruption.
+ *
+ * Only the newest (active) segment is written.  Once it reaches
+ * segment_size, a new one is started.  Merging rewrites the live records
+ * of all older (sealed) segments into one merged segment, numbered as the
+ * newest of them, then deletes the others.  It runs in the background
+ * once merge_ratio of the sealed bytes are garbage, and on compaction.
+ */
+
+use super::crc::crc32;
+use super::{api, ttl, version};
+use crate::{Error, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+const SEGMENT_EXT: &str = "seg";
+
+// merge output, renamed over the newest merged segment when complete
+const MERGE_EXT: &str = "merge";
+
+// held locked while the database is open for writing
+const LOCK_FILE: &str = "LOCK";
+
+const SEGMENT_MAGIC: &[u8] = b"KVDBLOG1";
+const SEG_MERGED: u8 = 0x01;
+const HEADER_LEN: u64 = 9;
+const FRAME_HEADER_LEN: usize = 8;
+
+// entry ops
+const OP_PUT: u8 = 1;
+const OP_DEL: u8 = 2;
+const OP_CLEAR: u8 = 3;
+const OP_NS_CREATE: u8 = 4;
+const OP_NS_DROP: u8 = 5;
+
+// driver options
+const BITCASK_OPTIONS: &[&str] = &["segment_size", "merge_ratio"];
+const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+const DEFAULT_MERGE_RATIO: f64 = 0.5;
+
+// sync interval, for Durability::Default
+const DEFAULT_FLUSH_MS: u64 = 1000;
+
+// how often the background merge checks for garbage
+const MERGE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+// merge output frame size, before its last record
+const MERGE_FRAME_BYTES: usize = 1024 * 1024;
+
+fn corrupt(msg: &str) -> Error {
+    Error::Corruption(format!("bitcask: {}", msg))
+}
+
+fn segment_path(dir: &Path, id: u32) -> PathBuf {
+    dir.join(format!("{:010}.{}", id, SEGMENT_EXT))
+}
+
+fn merge_path(dir: &Path, id: u32) -> PathBuf {
+    dir.join(format!("{:010}.{}", id, MERGE_EXT))
+}
+
+// persist directory entries: new, renamed and removed files
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+// on-disk size of a PUT entry
+fn put_size(ns: Option<&str>, key_len: usize, val_len: u32) -> u64 {
+    (10 + ns.map_or(0, str::len) + key_len) as u64 + val_len as u64
+}
+
+fn len_u32(bytes: &[u8]) -> Result<u32> {
+    match u32::try_from(bytes.len()) {
+        Ok(len) => Ok(len),
+        Err(_e) => Err(Error::TooLarge(String::from("bitcask key or value"))),
+    }
+}
+
+// one frame, built in memory; the header is filled in by finish()
+struct Frame {
+    data: Vec<u8>,
+}
+
+impl Frame {
+    fn new() -> Frame {
+        Frame {
+            data: vec![0; FRAME_HEADER_LEN],
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.len() == FRAME_HEADER_LEN
+    }
+
+    fn op(&mut self, op: u8, ns: Option<&str>) {
+        let ns = ns.unwrap_or("");
+        self.data.push(op);
+        self.data.push(ns.len() as u8); // namespace names are at most 128 bytes
+        self.data.extend_from_slice(ns.as_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.data.extend_from_slice(&len_u32(bytes)?.to_le_bytes());
+        self.data.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    // add PUT entry; returns the position of the value in the frame
+    fn put(&mut self, ns: Option<&str>, key: &[u8], val: &[u8]) -> Result<usize> {
+        self.op(OP_PUT, ns);
+        self.bytes(key)?;
+        self.bytes(val)?;
+        Ok(self.data.len() - val.len())
+    }
+
+    fn del(&mut self, ns: Option<&str>, key: &[u8]) -> Result<()> {
+        self.op(OP_DEL, ns);
+        self.bytes(key)
+    }
+
+    fn finish(mut self) -> Result<Vec<u8>> {
+        let len = len_u32(&self.data[FRAME_HEADER_LEN..])?;
+        self.data[4..8].copy_from_slice(&len.to_le_bytes());
+        let crc = crc32(&self.data[4..]);
+        self.data[0..4].copy_from_slice(&crc.to_le_bytes());
+
+        Ok(self.data)
+    }
+}
+
+// reads entry fields from a frame, failing on truncation
+struct EntryReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> EntryReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() - self.pos < len {
+            return Err(corrupt("log entry truncated"));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+    }
+
+    fn ns(&mut self) -> Result<Option<String>> {
+        let ns_len = self.u8()? as usize;
+        match ns_len {
+            0 => Ok(None),
+            _ => match std::str::from_utf8(self.bytes(ns_len)?) {
+                Ok(ns) => Ok(Some(ns.to_string())),
+                Err(_e) => Err(corrupt("namespace name")),
+            },
+        }
+    }
+}
+
+// location of a live value
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Loc {
+    seg: u32,
+    offset: u64,
+    len: u32,
+}
+
+type KeyDir = BTreeMap<Vec<u8>, Loc>;
+
+struct Segment {
+    file: Arc<File>, // shared with a merge in progress
+    size: u64,       // bytes of valid frames, and header
+    dead: u64,       // bytes of superseded entries
+}
+
+fn add_dead(segs: &mut BTreeMap<u32, Segment>, id: u32, n_bytes: u64) {
+    if let Some(seg) = segs.get_mut(&id) {
+        seg.dead += n_bytes;
+    }
+}
+
+fn read_value(file: &File, loc: &Loc) -> Result<Vec<u8>> {
+    let mut val = vec![0; loc.len as usize];
+    file.read_exact_at(&mut val, loc.offset)?;
+
+    Ok(val)
+}
+
+// create a segment file, and write its header
+fn create_segment(path: &Path, flags: u8) -> Result<File> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    file.set_len(0)?;
+    file.write_all(SEGMENT_MAGIC)?;
+    file.write_all(&[flags])?;
+
+    Ok(file)
+}
+
+// segment flags, or None if the header is incomplete
+fn read_header(file: &File) -> Result<Option<u8>> {
+    let mut header = [0u8; HEADER_LEN as usize];
+    let mut n_read = 0;
+    while n_read < header.len() {
+        match file.read_at(&mut header[n_read..], n_read as u64)? {
+            0 => return Ok(None),
+            n => n_read += n,
+        }
+    }
+    if !header.starts_with(SEGMENT_MAGIC) {
+        return Err(corrupt("not a segment file"));
+    }
+
+    Ok(Some(header[SEGMENT_MAGIC.len()]))
+}
+
+struct State {
+    keydir: BTreeMap<Option<String>, KeyDir>, // None: default keyspace
+    segs: BTreeMap<u32, Segment>,
+    active: u32,            // segment written
+    failed: Option<String>, // why writes are refused, until restart
+}
+
+impl State {
+    fn keydir(&self, ns: Option<&str>) -> Result<&KeyDir> {
+        self.keydir
+            .get(&ns.map(String::from))
+            .ok_or(Error::NotFound)
+    }
+
+    fn has_ns(&self, ns: Option<&str>) -> bool {
+        self.keydir.contains_key(&ns.map(String::from))
+    }
+
+    fn read_value(&self, loc: &Loc) -> Result<Vec<u8>> {
+        match self.segs.get(&loc.seg) {
+            None => Err(corrupt("value in missing segment")),
+            Some(seg) => read_value(&seg.file, loc),
+        }
+    }
+
+    fn get(&self, ns: Option<&str>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.keydir(ns)?.get(key) {
+            None => Ok(None),
+            Some(loc) => Ok(Some(self.read_value(loc)?)),
+        }
+    }
+
+    // apply frame entries to the key directory; base is the file offset
+    // of the frame
+    fn apply_frame(&mut self, seg: u32, base: u64, frame: &[u8]) -> Result<()> {
+        let mut rdr = EntryReader {
+            data: frame,
+            pos: FRAME_HEADER_LEN,
+        };
+        while rdr.pos < frame.len() {
+            let start = rdr.pos;
+            let op = rdr.u8()?;
+            let ns = rdr.ns()?;
+            let ns_ref = ns.as_deref();
+
+            match op {
+                OP_PUT => {
+                    let key_len = rdr.u32()?;
+                    let key = rdr.bytes(key_len)?;
+                    let val_len = rdr.u32()?;
+                    let loc = Loc {
+                        seg,
+                        offset: base + rdr.pos as u64,
+                        len: val_len as u32,
+                    };
+                    rdr.bytes(val_len)?;
+
+                    let keydir = self.keydir.entry(ns.clone()).or_default();
+                    if let Some(old) = keydir.insert(key.to_vec(), loc) {
+                        add_dead(&mut self.segs, old.seg, put_size(ns_ref, key_len, old.len));
+                    }
+                }
+                OP_DEL => {
+                    let key_len = rdr.u32()?;
+                    let key = rdr.bytes(key_len)?;
+                    if let Some(keydir) = self.keydir.get_mut(&ns) {
+                        if let Some(old) = keydir.remove(key) {
+                            add_dead(&mut self.segs, old.seg, put_size(ns_ref, key_len, old.len));
+                        }
+                    }
+                }
+                OP_CLEAR | OP_NS_DROP => {
+                    let keydir = match op {
+                        OP_CLEAR => self.keydir.get_mut(&ns).map(std::mem::take),
+                        _ => self.keydir.remove(&ns),
+                    };
+                    for (key, old) in keydir.unwrap_or_default() {
+                        add_dead(
+                            &mut self.segs,
+                            old.seg,
+                            put_size(ns_ref, key.len(), old.len),
+                        );
+                    }
+                }
+                OP_NS_CREATE => {
+                    self.keydir.entry(ns.clone()).or_default();
+                }
+                _ => return Err(corrupt("unknown log entry")),
+            }
+
+            // entries other than PUT are garbage once merged
+            if op != OP_PUT {
+                add_dead(&mut self.segs, seg, (rdr.pos - start) as u64);
+            }
+        }
+
+        Ok(())
+    }
+
+    // seal the active segment, and start a new one
+    fn rotate(&mut self, dir: &Path) -> Result<()> {
+        let active = &self.segs[&self.active];
+        active.file.sync_data()?;
+
+        let id = self.active + 1;
+        let file = create_segment(&segment_path(dir, id), 0)?;
+        sync_dir(dir)?;
+        self.segs.insert(
+            id,
+            Segment {
+                file: Arc::new(file),
+                size: HEADER_LEN,
+                dead: 0,
+            },
+        );
+        self.active = id;
+
+        Ok(())
+    }
+
+    // fail once a partial frame is left at the end of the active
+    // segment.  Frames appended after it would be lost at replay, which
+    // stops at the partial frame; at restart, replay discards it.
+    fn check_failed(&self) -> Result<()> {
+        match &self.failed {
+            None => Ok(()),
+            Some(msg) => Err(Error::Io(io::Error::other(msg.clone()))),
+        }
+    }
+
+    // append frame to the active segment, and apply it
+    fn commit(&mut self, shared: &Shared, frame: Frame) -> Result<()> {
+        self.check_failed()?;
+        if self.segs[&self.active].size >= shared.segment_size {
+            self.rotate(&shared.dir)?;
+        }
+
+        let data = frame.finish()?;
+        let seg = self.segs.get_mut(&self.active).unwrap();
+        let base = seg.size;
+        if let Err(e) = (&*seg.file).write_all(&data) {
+            // drop the partial frame, so later frames are readable
+            if let Err(trunc_e) = seg.file.set_len(base) {
+                let msg = format!(
+                    "segment {} write failed ({}), and partial frame not removed ({}); writes refused until restart",
+                    self.active, e, trunc_e
+                );
+                self.failed = Some(msg.clone());
+                return Err(Error::Io(io::Error::new(e.kind(), msg)));
+            }
+            return Err(e.into());
+        }
+        seg.size += data.len() as u64;
+        if shared.sync_each {
+            seg.file.sync_data()?;
+        }
+
+        self.apply_frame(self.active, base, &data)
+    }
+
+    // true if merge_ratio of the sealed segments' bytes are garbage
+    fn merge_due(&self, merge_ratio: f64) -> bool {
+        let (mut size, mut dead) = (0, 0);
+        for seg in self.segs.range(..self.active).map(|(_id, seg)| seg) {
+            size += seg.size;
+            dead += seg.dead;
+        }
+
+        size > 0 && dead as f64 >= merge_ratio * size as f64
+    }
+}
+
+// read the valid frames of a segment into state.  Returns the length of
+// its valid prefix: shorter than the file, if its tail is torn.
+fn replay_segment(state: &mut State, id: u32, file: &File) -> Result<u64> {
+    let mut rdr = BufReader::new(file);
+    let mut skip = [0u8; HEADER_LEN as usize];
+    rdr.read_exact(&mut skip)?;
+
+    let mut offset = HEADER_LEN;
+    loop {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        match read_full(&mut rdr, &mut header)? {
+            0 => return Ok(offset),
+            n if n < header.len() => return Ok(offset),
+            _ => {}
+        }
+        let crc = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+
+        // a torn frame, cut short, or with its tail never written
+        let mut frame = header.to_vec();
+        frame.resize(FRAME_HEADER_LEN + len, 0);
+        if read_full(&mut rdr, &mut frame[FRAME_HEADER_LEN..])? < len || crc32(&frame[4..]) != crc {
+            return Ok(offset);
+        }
+
+        state.apply_frame(id, offset, &frame)?;
+        offset += frame.len() as u64;
+    }
+}
+
+// read until buf is full or end of file; returns bytes read
+fn read_full(rdr: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut n_read = 0;
+    while n_read < buf.len() {
+        match rdr.read(&mut buf[n_read..]) {
+            Ok(0) => break,
+            Ok(n) => n_read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(n_read)
+}
+
+// state shared with background threads
+struct Shared {
+    dir: PathBuf,
+    state: RwLock<State>,
+    merging: Mutex<()>, // held by a merge in progress
+    segment_size: u64,
+    sync_each: bool, // Durability::Sync
+}
+
+// rewrite the live records of all sealed segments into one merged segment,
+// first sealing the active segment if seal_active
+fn merge(shared: &Shared, seal_active: bool) -> Result<()> {
+    let _merging = shared.merging.lock().unwrap();
+
+    if seal_active {
+        let mut state = shared.state.write().unwrap();
+        state.check_failed()?;
+        if state.segs[&state.active].size > HEADER_LEN {
+            state.rotate(&shared.dir)?;
+        }
+    }
+
+    // sealed segments, and the live records within them; segments are
+    // not written once sealed, so are read without the lock
+    let (files, namespaces, records) = {
+        let state = shared.state.read().unwrap();
+        let files: BTreeMap<u32, Arc<File>> = state
+            .segs
+            .range(..state.active)
+            .map(|(id, seg)| (*id, Arc::clone(&seg.file)))
+            .collect();
+        let namespaces: Vec<String> = state.keydir.keys().flatten().cloned().collect();
+        let mut records = Vec::new();
+        for (ns, keydir) in &state.keydir {
+            for (key, loc) in keydir {
+                if files.contains_key(&loc.seg) {
+                    records.push((ns.clone(), key.clone(), *loc));
+                }
+            }
+        }
+        (files, namespaces, records)
+    };
+    let target = match files.keys().next_back() {
+        None => return Ok(()),
+        Some(id) => *id,
+    };
+
+    let tmp_path = merge_path(&shared.dir, target);
+    let written = write_merged(&tmp_path, target, &files, &namespaces, &records);
+    let (size, new_locs) = match written {
+        Ok(written) => written,
+        Err(e) => {
+            let _res = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    };
+
+    // records unchanged meanwhile now live in the merged segment; the
+    // rest are garbage there
+    let mut state = shared.state.write().unwrap();
+    let mut dead = 0;
+    for ((ns, key, old_loc), new_loc) in records.iter().zip(new_locs) {
+        let loc = state
+            .keydir
+            .get_mut(ns)
+            .and_then(|keydir| keydir.get_mut(key));
+        match loc {
+            Some(loc) if *loc == *old_loc => *loc = new_loc,
+            _ => dead += put_size(ns.as_deref(), key.len(), new_loc.len),
+        }
+    }
+
+    // the merged segment replaces all others, at once
+    let path = segment_path(&shared.dir, target);
+    fs::rename(&tmp_path, &path)?;
+    sync_dir(&shared.dir)?;
+    let file = File::open(&path)?;
+    for id in files.keys() {
+        state.segs.remove(id);
+        if *id != target {
+            fs::remove_file(segment_path(&shared.dir, *id))?;
+        }
+    }
+    state.segs.insert(
+        target,
+        Segment {
+            file: Arc::new(file),
+            size,
+            dead,
+        },
+    );
+
+    Ok(())
+}
+
+// write merge output file: namespaces, then records.  Returns its size,
+// and the new location of each record.
+fn write_merged(
+    path: &Path,
+    target: u32,
+    files: &BTreeMap<u32, Arc<File>>,
+    namespaces: &[String],
+    records: &[(Option<String>, Vec<u8>, Loc)],
+) -> Result<(u64, Vec<Loc>)> {
+    let mut file = create_segment(path, SEG_MERGED)?;
+    let mut offset = HEADER_LEN;
+
+    let mut frame = Frame::new();
+    for ns in namespaces {
+        frame.op(OP_NS_CREATE, Some(ns));
+    }
+
+    let mut new_locs = Vec::with_capacity(records.len());
+    for (idx, (ns, key, loc)) in records.iter().enumerate() {
+        let val = read_value(&files[&loc.seg], loc)?;
+        let pos = frame.put(ns.as_deref(), key, &val)?;
+        new_locs.push(Loc {
+            seg: target,
+            offset: offset + pos as u64,
+            len: loc.len,
+        });
+
+        if frame.data.len() >= MERGE_FRAME_BYTES && idx + 1 < records.len() {
+            let data = std::mem::replace(&mut frame, Frame::new()).finish()?;
+            file.write_all(&data)?;
+            offset += data.len() as u64;
+        }
+    }
+    if !frame.is_empty() {
+        let data = frame.finish()?;
+        file.write_all(&data)?;
+        offset += data.len() as u64;
+    }
+    file.sync_all()?;
+
+    Ok((offset, new_locs))
+}
+
+// background thread running a task every interval, for periodic sync and
+// merging; stopped and joined on drop
+struct Ticker {
+    stop: Option<mpsc::Sender<()>>, // dropped to stop the thread
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Ticker {
+    fn start<F>(name: &str, interval: Duration, mut task: F) -> Result<Ticker>
+    where
+        F: FnMut() + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    task();
+                }
+            })?;
+
+        Ok(Ticker {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Ticker {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _res = thread.join();
+        }
+    }
+}
+
+// values written earlier in a batch, by namespace and key; None if removed
+type Pending<'a> = HashMap<(Option<&'a str>, &'a [u8]), Option<&'a [u8]>>;
+
+pub struct BitcaskDb {
+    shared: Arc<Shared>,
+    read_only: bool,
+    _flusher: Option<Ticker>, // Durability::Default and Periodic
+    _merger: Option<Ticker>,  // background merge
+    _lock: Option<File>,      // LOCK_FILE, locked while writable
+}
+
+impl BitcaskDb {
+    // fail writes to a read-only database
+    fn writable(&self) -> Result<()> {
+        match self.read_only {
+            true => Err(Error::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    // state for a write
+    fn write_state(&self) -> Result<std::sync::RwLockWriteGuard<'_, State>> {
+        self.writable()?;
+        Ok(self.shared.state.write().unwrap())
+    }
+
+    // write a one-entry frame to namespace ns, if it exists
+    fn write_op(&mut self, op: u8, ns: Option<&str>) -> Result<()> {
+        let mut state = self.write_state()?;
+        if !state.has_ns(ns) {
+            return Err(Error::NotFound);
+        }
+
+        let mut frame = Frame::new();
+        frame.op(op, ns);
+        state.commit(&self.shared, frame)
+    }
+}
+
+impl api::Db for BitcaskDb {
+    fn clear_ns(&mut self, ns: Option<&str>) -> Result<bool> {
+        self.write_op(OP_CLEAR, ns)?;
+        Ok(true)
+    }
+
+    fn stat_ns(&self, ns: Option<&str>) -> Result<api::DbStat> {
+        let state = self.shared.state.read().unwrap();
+
+        Ok(api::DbStat {
+            n_records: state.keydir(ns)?.len() as u64,
+        })
+    }
+
+    fn flush(&self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        let state = self.shared.state.read().unwrap();
+        state.segs[&state.active].file.sync_data()?;
+
+        Ok(())
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        self.writable()?;
+        merge(&self.shared, true)
+    }
+
+    fn storage_stat(&self) -> Result<api::StorageStat> {
+        let state = self.shared.state.read().unwrap();
+
+        Ok(api::StorageStat {
+            disk_bytes: Some(state.segs.values().map(|seg| seg.size).sum()),
+            map_bytes: None,
+        })
+    }
+
+    fn get_ns(&self, ns: Option<&str>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.shared.state.read().unwrap().get(ns, key)
+    }
+
+    fn put_ns(&mut self, ns: Option<&str>, key: &[u8], val: &[u8]) -> Result<bool> {
+        let mut state = self.write_state()?;
+        if !state.has_ns(ns) {
+            return Err(Error::NotFound);
+        }
+
+        let mut frame = Frame::new();
+        frame.put(ns, key, val)?;
+        state.commit(&self.shared, frame)?;
+
+        Ok(true)
+    }
+
+    fn del_ns(&mut self, ns: Option<&str>, key: &[u8]) -> Result<bool> {
+        let mut state = self.write_state()?;
+        if !state.keydir(ns)?.contains_key(key) {
+            return Ok(false);
+        }
+
+        let mut frame = Frame::new();
+        frame.del(ns, key)?;
+        state.commit(&self.shared, frame)?;
+
+        Ok(true)
+    }
+
+    fn apply_batch(&mut self, batch: &api::Batch) -> Result<bool> {
+        let mut state = self.write_state()?;
+        for ns in batch.namespaces() {
+            if !state.has_ns(ns) {
+                return Err(Error::NotFound);
+            }
+        }
+
+        // one frame: replay applies all of it, or none; preconditions see
+        // values written earlier in the batch
+        let mut frame = Frame::new();
+        let mut pending = Pending::new();
+        for (idx, mutation) in batch.ops.iter().enumerate() {
+            let ns = mutation.ns.as_deref();
+            let key = &mutation.key[..];
+
+            if let Some(cond) = &mutation.condition {
+                let cur_val = match pending.get(&(ns, key)) {
+                    Some(val) => val.map(|val| val.to_vec()),
+                    None => state.get(ns, key)?,
+                };
+                cond.check(idx, key, cur_val.as_deref())?;
+            }
+
+            match mutation.op {
+                api::MutationOp::Insert => {
+                    let val = mutation.value.as_deref().unwrap();
+                    frame.put(ns, key, val)?;
+                    pending.insert((ns, key), Some(val));
+                }
+                api::MutationOp::Remove => {
+                    frame.del(ns, key)?;
+                    pending.insert((ns, key), None);
+                }
+            }
+        }
+
+        if !frame.is_empty() {
+            state.commit(&self.shared, frame)?;
+        }
+
+        Ok(true)
+    }
+
+    fn scan(&self, opts: api::IterOptions) -> Result<api::KvList> {
+        let mut page = api::ScanPage::new(&opts);
+        let state = self.shared.state.read().unwrap();
+        let keydir = state.keydir(opts.ns.as_deref())?;
+
+        // key range covers start/end keys and prefix
+        let range = match opts.key_range() {
+            None => return Ok(page.finish()),
+            Some(range) => range,
+        };
+        let iter: Box<dyn Iterator<Item = (&Vec<u8>, &Loc)>> = match opts.reverse {
+            false => Box::new(keydir.range(range)),
+            true => Box::new(keydir.range(range).rev()),
+        };
+
+        for (key, loc) in iter {
+            let value = match opts.keys_only {
+                true => Vec::new(),
+                false => state.read_value(loc)?,
+            };
+            if !page.push(key, &value) {
+                break;
+            }
+        }
+
+        Ok(page.finish())
+    }
+
+    fn open_ns(&mut self, ns: &str) -> Result<bool> {
+        api::validate_ns_name(ns)?;
+        if !self.shared.state.read().unwrap().has_ns(Some(ns)) {
+            let mut state = self.write_state()?;
+            let mut frame = Frame::new();
+            frame.op(OP_NS_CREATE, Some(ns));
+            state.commit(&self.shared, frame)?;
+        }
+
+        Ok(true)
+    }
+
+    fn drop_ns(&mut self, ns: &str) -> Result<bool> {
+        match self.write_op(OP_NS_DROP, Some(ns)) {
+            Ok(()) => Ok(true),
+            Err(Error::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn list_ns(&self) -> Result<Vec<String>> {
+        // BTreeMap: already in name order
+        let state = self.shared.state.read().unwrap();
+        Ok(state.keydir.keys().flatten().cloned().collect())
+    }
+}
+
+// segment ids in dir, ascending; removes unfinished merge output, if
+// writable
+fn list_segments(dir: &Path, read_only: bool) -> Result<Vec<u32>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let id = match path.file_stem().and_then(|stem| stem.to_str()) {
+            None => continue,
+            Some(stem) => match stem.parse::<u32>() {
+                Ok(id) => id,
+                Err(_e) => continue,
+            },
+        };
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(SEGMENT_EXT) => ids.push(id),
+            Some(MERGE_EXT) if !read_only => fs::remove_file(&path)?,
+            _ => {}
+        }
+    }
+    ids.sort_unstable();
+
+    Ok(ids)
+}
+
+// take the database lock, held until the returned file is closed
+fn lock_dir(dir: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join(LOCK_FILE))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let e = io::Error::last_os_error();
+        return Err(match e.kind() {
+            ErrorKind::WouldBlock => Error::InvalidArgument(format!("database {:?} in use", dir)),
+            _ => e.into(),
+        });
+    }
+
+    Ok(file)
+}
+
+// open database directory, replaying its segments
+fn open_shared(cfg: &api::Config, segment_size: u64, sync_each: bool) -> Result<Shared> {
+    let dir = Path::new(&cfg.path);
+    let mut ids = list_segments(dir, cfg.read_only)?;
+    let mut state = State {
+        keydir: BTreeMap::new(),
+        segs: BTreeMap::new(),
+        active: 1,
+        failed: None,
+    };
+    state.keydir.insert(None, KeyDir::new());
+
+    // open segments; a merged segment makes those before it obsolete
+    let mut files = Vec::new();
+    for id in &ids {
+        let path = segment_path(dir, *id);
+        let file = match cfg.read_only {
+            true => File::open(&path)?,
+            false => OpenOptions::new().read(true).append(true).open(&path)?,
+        };
+        let flags = read_header(&file)?;
+        if flags.unwrap_or(0) & SEG_MERGED != 0 {
+            files.clear();
+        }
+        files.push((*id, file, flags));
+    }
+    if !cfg.read_only {
+        let first = files.first().map_or(u32::MAX, |(id, _file, _flags)| *id);
+        for id in ids.iter().filter(|id| **id < first) {
+            fs::remove_file(segment_path(dir, *id))?;
+        }
+    }
+    ids = files.iter().map(|(id, _file, _flags)| *id).collect();
+
+    let last = ids.last().copied();
+    for (id, file, flags) in files {
+        let is_last = Some(id) == last;
+        let valid_len = match flags {
+            // header never completed: segment created just before a crash
+            None if is_last => {
+                if !cfg.read_only {
+                    drop(file);
+                    let file = create_segment(&segment_path(dir, id), 0)?;
+                    state.segs.insert(
+                        id,
+                        Segment {
+                            file: Arc::new(file),
+                            size: HEADER_LEN,
+                            dead: 0,
+                        },
+                    );
+                }
+                continue;
+            }
+            None => return Err(corrupt("segment header truncated")),
+            Some(_flags) => {
+                state.segs.insert(
+                    id,
+                    Segment {
+                        file: Arc::new(file),
+                        size: 0,
+                        dead: 0,
+                    },
+                );
+                let file = Arc::clone(&state.segs[&id].file);
+                replay_segment(&mut state, id, &file)?
+            }
+        };
+
+        // torn frame at the end of the newest segment is discarded
+        let file_len = state.segs[&id].file.metadata()?.len();
+        if valid_len < file_len {
+            if !is_last {
+                return Err(corrupt(&format!("segment {} damaged", id)));
+            }
+            if !cfg.read_only {
+                state.segs[&id].file.set_len(valid_len)?;
+            }
+        }
+        state.segs.get_mut(&id).unwrap().size = valid_len;
+    }
+
+    match state.segs.keys().next_back() {
+        Some(id) => state.active = *id,
+        None if cfg.read_only => return Err(Error::NotFound),
+        None => {
+            let file = create_segment(&segment_path(dir, 1), 0)?;
+            sync_dir(dir)?;
+            state.segs.insert(
+                1,
+                Segment {
+                    file: Arc::new(file),
+                    size: HEADER_LEN,
+                    dead: 0,
+                },
+            );
+        }
+    }
+
+    Ok(Shared {
+        dir: dir.to_path_buf(),
+        state: RwLock::new(state),
+        merging: Mutex::new(()),
+        segment_size,
+        sync_each,
+    })
+}
+
+// driver options: segment size, and merge ratio
+fn parse_options(options: &api::Options) -> Result<(u64, f64)> {
+    api::check_option_names(options, BITCASK_OPTIONS)?;
+
+    let segment_size = api::parse_option(options, "segment_size")?.unwrap_or(DEFAULT_SEGMENT_SIZE);
+    if segment_size == 0 {
+        return Err(Error::InvalidArgument(String::from(
+            "segment_size must be positive",
+        )));
+    }
+    let merge_ratio: f64 =
+        api::parse_option(options, "merge_ratio")?.unwrap_or(DEFAULT_MERGE_RATIO);
+    if !(0.0..=1.0).contains(&merge_ratio) {
+        return Err(Error::InvalidArgument(String::from(
+            "merge_ratio must be from 0 to 1",
+        )));
+    }
+
+    Ok((segment_size, merge_ratio))
+}
+
+fn open_db(cfg: &api::Config) -> Result<BitcaskDb> {
+    let (segment_size, merge_ratio) = parse_options(&cfg.options)?;
+
+    // sync each write, or on a schedule
+    let (sync_each, flush_interval) = match cfg.durability {
+        api::Durability::Default => (false, Some(DEFAULT_FLUSH_MS)),
+        api::Durability::Sync => (true, None),
+        api::Durability::Periodic(0) => {
+            return Err(Error::InvalidArgument(String::from(
+                "flush interval must be positive",
+            )))
+        }
+        api::Durability::Periodic(ms) => (false, Some(ms)),
+        api::Durability::NoSync => (false, None),
+    };
+
+    if cfg.read_only {
+        if !Path::new(&cfg.path).is_dir() {
+            return Err(Error::NotFound);
+        }
+        let shared = open_shared(cfg, segment_size, sync_each)?;
+        return Ok(BitcaskDb {
+            shared: Arc::new(shared),
+            read_only: true,
+            _flusher: None,
+            _merger: None,
+            _lock: None,
+        });
+    }
+
+    fs::create_dir_all(&cfg.path)?;
+    let lock = lock_dir(Path::new(&cfg.path))?;
+    let shared = Arc::new(open_shared(cfg, segment_size, sync_each)?);
+
+    // a failed sync or merge is retried next interval
+    let flusher = match flush_interval {
+        None => None,
+        Some(ms) => {
+            let shared = Arc::clone(&shared);
+            Some(Ticker::start(
+                "kvdb-bitcask-flush",
+                Duration::from_millis(ms),
+                move || {
+                    let state = shared.state.read().unwrap();
+                    let _res = state.segs[&state.active].file.sync_data();
+                },
+            )?)
+        }
+    };
+    let merger = match merge_ratio > 0.0 {
+        false => None,
+        true => {
+            let shared = Arc::clone(&shared);
+            Some(Ticker::start(
+                "kvdb-bitcask-merge",
+                MERGE_CHECK_INTERVAL,
+                move || {
+                    let due = shared.state.read().unwrap().merge_due(merge_ratio);
+                    if due {
+                        let _res = merge(&shared, false);
+                    }
+                },
+            )?)
+        }
+    };
+
+    Ok(BitcaskDb {
+        shared,
+        read_only: false,
+        _flusher: flusher,
+        _merger: merger,
+        _lock: Some(lock),
+    })
+}
+
+pub struct BitcaskDriver {}
+
+impl api::Driver for BitcaskDriver {
+    fn check_options(&self, options: &api::Options) -> Result<()> {
+        parse_options(options)?;
+        Ok(())
+    }
+
+    fn start_db(&self, cfg: api::Config) -> Result<Box<dyn api::Db>> {
+        ttl::wrap(version::wrap(Box::new(open_db(&cfg)?))?)
+    }
+}
+
+pub fn new_driver() -> Box<dyn api::Driver> {
+    Box::new(BitcaskDriver {})
+}
+
+#[cfg(test)]
+use super::api::{Batch, ConfigBuilder};
+#[cfg(test)]
+use super::conformance;
+#[cfg(test)]
+use tempdir::TempDir;
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    // database in dir, with default options
+    fn config(dir: &str) -> ConfigBuilder {
+        let mut builder = ConfigBuilder::new();
+        builder.path(dir.to_string());
+        builder
+    }
+
+    #[test]
+    fn test_conformance() {
+        conformance::run(new_driver().as_ref(), &config);
+    }
+
+    #[test]
+    fn test_scan_ordered() {
+        conformance::scan_ordered(new_driver().as_ref(), &config);
+    }
+
+    #[test]
+    fn test_durability() {
+        conformance::durability(new_driver().as_ref(), &config);
+    }
+
+    #[test]
+    fn test_options() {
+        let driver = new_driver();
+        let options = |pairs: &[(&str, &str)]| -> api::Options {
+            pairs
+                .iter()
+                .map(|(key, val)| (key.to_string(), val.to_string()))
+                .collect()
+        };
+
+        assert!(driver
+            .check_options(&options(&[
+                ("segment_size", "4096"),
+                ("merge_ratio", "0.25")
+            ]))
+            .is_ok());
+        for bad in [
+            ("cache_size", "1048576"),
+            ("segment_size", "0"),
+            ("segment_size", "-1"),
+            ("merge_ratio", "1.5"),
+        ] {
+            assert!(matches!(
+                driver.check_options(&options(&[bad])),
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+
+        let tmp_dir = TempDir::new("topt").unwrap();
+        let db_config = ConfigBuilder::new()
+            .path(tmp_dir.path().to_str().unwrap().to_string())
+            .option("segment_size", "4096")
+            .build();
+        let mut db = driver.start_db(db_config).unwrap();
+        assert!(db.put(b"name", b"alan").unwrap());
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+    }
+
+    // segment files in dir, in id order
+    fn segments(dir: &std::path::Path) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == SEGMENT_EXT))
+            .collect();
+        paths.sort();
+
+        paths
+    }
+
+    #[test]
+    fn test_recovery() {
+        let tmp_dir = TempDir::new("trec").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+        let mut builder = ConfigBuilder::new();
+        builder.path(tmp_path).read_only(false);
+
+        let driver = new_driver();
+
+        {
+            let mut db = driver.start_db(builder.build()).unwrap();
+            assert!(db.put(b"name", b"alan").unwrap());
+            assert!(db.open_ns("users").unwrap());
+            assert!(db.put_ns(Some("users"), b"alan", b"1").unwrap());
+        }
+        let seg_path = segments(tmp_dir.path()).pop().unwrap();
+        let good_len = std::fs::metadata(&seg_path).unwrap().len();
+
+        // torn frame at the tail is discarded
+        {
+            let mut file = OpenOptions::new().append(true).open(&seg_path).unwrap();
+            file.write_all(&[0x55, 0x66, 0x77, 0x88, 0x40, 0, 0, 0, 1, 2])
+                .unwrap();
+        }
+        {
+            let mut db = driver.start_db(builder.build()).unwrap();
+            assert_eq!(std::fs::metadata(&seg_path).unwrap().len(), good_len);
+            assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+            assert_eq!(
+                db.get_ns(Some("users"), b"alan").unwrap(),
+                Some(Vec::from("1"))
+            );
+
+            // batch, cut short by a crash: none of it is applied
+            let mut batch = Batch::default();
+            batch.insert(b"age", b"41");
+            batch.remove(b"name");
+            assert!(db.apply_batch(&batch).unwrap());
+        }
+        let batch_len = std::fs::metadata(&seg_path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&seg_path)
+            .unwrap()
+            .set_len(batch_len - 3)
+            .unwrap();
+        {
+            let mut db = driver.start_db(builder.build()).unwrap();
+            assert_eq!(db.get(b"age").unwrap(), None);
+            assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+            assert!(db.put(b"city", b"anytown").unwrap());
+        }
+
+        // checksum mismatch in the last frame
+        let mut data = std::fs::read(&seg_path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        std::fs::write(&seg_path, data).unwrap();
+        let db = driver.start_db(builder.build()).unwrap();
+        assert_eq!(db.get(b"city").unwrap(), None);
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+    }
+
+    #[test]
+    fn test_damaged_segment() {
+        let tmp_dir = TempDir::new("tdmg").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+        let mut builder = ConfigBuilder::new();
+        builder.path(tmp_path).option("segment_size", "512");
+
+        let driver = new_driver();
+
+        {
+            let mut db = driver.start_db(builder.build()).unwrap();
+            for i in 0..20 {
+                let key = format!("k{}", i);
+                assert!(db.put(key.as_bytes(), &[b'v'; 100]).unwrap());
+            }
+        }
+        let seg_paths = segments(tmp_dir.path());
+        assert!(seg_paths.len() > 2);
+
+        // damage before the newest segment is not a crash: fail to open
+        let mut data = std::fs::read(&seg_paths[0]).unwrap();
+        data[HEADER_LEN as usize + 20] ^= 0xff;
+        std::fs::write(&seg_paths[0], data).unwrap();
+        assert!(matches!(
+            driver.start_db(builder.build()),
+            Err(Error::Corruption(_))
+        ));
+    }
+
+    #[test]
+    fn test_failed_write() {
+        let tmp_dir = TempDir::new("tfw").unwrap();
+        let db_config = config(tmp_dir.path().to_str().unwrap()).build();
+
+        {
+            let mut db = open_db(&db_config).unwrap();
+            assert!(api::Db::put(&mut db, b"name", b"alan").unwrap());
+
+            // /dev/full fails writes, and the truncate removing the
+            // partial frame
+            let full = OpenOptions::new().append(true).open("/dev/full").unwrap();
+            let seg_file = {
+                let mut state = db.shared.state.write().unwrap();
+                let active = state.active;
+                let seg = state.segs.get_mut(&active).unwrap();
+                std::mem::replace(&mut seg.file, Arc::new(full))
+            };
+            assert!(matches!(
+                api::Db::put(&mut db, b"age", b"41"),
+                Err(Error::Io(_))
+            ));
+            {
+                let mut state = db.shared.state.write().unwrap();
+                let active = state.active;
+                state.segs.get_mut(&active).unwrap().file = seg_file;
+            }
+
+            // writes refused until restart; reads served
+            assert!(matches!(
+                api::Db::put(&mut db, b"age", b"41"),
+                Err(Error::Io(_))
+            ));
+            assert!(matches!(api::Db::compact(&mut db), Err(Error::Io(_))));
+            assert_eq!(api::Db::get(&db, b"name").unwrap(), Some(Vec::from("alan")));
+        }
+
+        let mut db = open_db(&db_config).unwrap();
+        assert!(api::Db::put(&mut db, b"age", b"41").unwrap());
+        assert_eq!(api::Db::get(&db, b"name").unwrap(), Some(Vec::from("alan")));
+    }
+
+    #[test]
+    fn test_compact() {
+        let tmp_dir = TempDir::new("tcomp").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+        let mut builder = ConfigBuilder::new();
+        builder
+            .path(tmp_path)
+            .option("segment_size", "1024")
+            .option("merge_ratio", "0");
+
+        let driver = new_driver();
+
+        let stale;
+        {
+            let mut db = driver.start_db(builder.build()).unwrap();
+            assert!(db.open_ns("users").unwrap());
+            for round in 0..10 {
+                for i in 0..10 {
+                    let key = format!("k{}", i);
+                    let val = format!("{:0100}", round * 10 + i);
+                    assert!(db.put(key.as_bytes(), val.as_bytes()).unwrap());
+                }
+            }
+            assert!(db.put_ns(Some("users"), b"alan", b"1").unwrap());
+            assert!(db.del(b"k9").unwrap());
+
+            stale = std::fs::read(&segments(tmp_dir.path())[0]).unwrap();
+            let n_segments = segments(tmp_dir.path()).len();
+            assert!(n_segments > 10);
+            let before = db.storage_stat().unwrap().disk_bytes.unwrap();
+
+            // one merged segment, and a new active one
+            db.compact().unwrap();
+            assert_eq!(segments(tmp_dir.path()).len(), 2);
+            assert!(db.storage_stat().unwrap().disk_bytes.unwrap() < before / 5);
+            assert_eq!(
+                db.get(b"k0").unwrap(),
+                Some(Vec::from(format!("{:0100}", 90)))
+            );
+            assert_eq!(db.get(b"k9").unwrap(), None);
+            assert_eq!(db.stat().unwrap().n_records, 9);
+            assert_eq!(
+                db.get_ns(Some("users"), b"alan").unwrap(),
+                Some(Vec::from("1"))
+            );
+
+            assert!(db.put(b"k1", b"new").unwrap());
+            db.compact().unwrap();
+            assert_eq!(segments(tmp_dir.path()).len(), 2);
+        }
+
+        // a segment superseded by a merge, left by a crash, is ignored
+        std::fs::write(segment_path(tmp_dir.path(), 1), stale).unwrap();
+        let db = driver.start_db(builder.build()).unwrap();
+        assert_eq!(segments(tmp_dir.path()).len(), 2);
+        assert_eq!(db.get(b"k1").unwrap(), Some(Vec::from("new")));
+        assert_eq!(
+            db.get(b"k2").unwrap(),
+            Some(Vec::from(format!("{:0100}", 92)))
+        );
+        assert_eq!(db.get(b"k9").unwrap(), None);
+        assert_eq!(db.list_ns().unwrap(), vec![String::from("users")]);
+    }
+
+    #[test]
+    fn test_merge() {
+        let tmp_dir = TempDir::new("tmrg").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+        let mut builder = ConfigBuilder::new();
+        builder.path(tmp_path).option("segment_size", "1024");
+
+        let mut db = open_db(&builder.build()).unwrap();
+        for i in 0..20 {
+            assert!(api::Db::put(&mut db, b"name", format!("{:0100}", i).as_bytes()).unwrap());
+        }
+        assert!(api::Db::put(&mut db, b"age", b"41").unwrap());
+        assert!(db
+            .shared
+            .state
+            .read()
+            .unwrap()
+            .merge_due(DEFAULT_MERGE_RATIO));
+
+        // background merge leaves the active segment alone
+        let active = db.shared.state.read().unwrap().active;
+        merge(&db.shared, false).unwrap();
+        let state = db.shared.state.read().unwrap();
+        assert_eq!(state.active, active);
+        assert_eq!(state.segs.len(), 2);
+        assert!(!state.merge_due(DEFAULT_MERGE_RATIO));
+        drop(state);
+
+        assert_eq!(
+            api::Db::get(&db, b"name").unwrap(),
+            Some(Vec::from(format!("{:0100}", 19)))
+        );
+        assert_eq!(api::Db::get(&db, b"age").unwrap(), Some(Vec::from("41")));
+    }
+
+    #[test]
+    fn test_lock() {
+        let tmp_dir = TempDir::new("tlck").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+        let mut builder = ConfigBuilder::new();
+        builder.path(tmp_path);
+
+        let driver = new_driver();
+
+        let db = driver.start_db(builder.build()).unwrap();
+        assert!(matches!(
+            driver.start_db(builder.build()),
+            Err(Error::InvalidArgument(_))
+        ));
+        drop(db);
+        assert!(driver.start_db(builder.build()).is_ok());
+    }
+}
@@ -0,0 +1,855 @@
+/*
+ * conformance: tests of the behaviour every database driver shares.
+ *
+ * A driver's test module runs the suite on its driver, given a function
+ * returning the configuration of a database in a directory:
+ *
+ *     conformance::run(new_driver().as_ref(), &|dir| {
+ *         let mut builder = ConfigBuilder::new();
+ *         builder.path(dir.to_string());
+ *         builder
+ *     });
+ *
+ * Reopening a database must find what was written before close.  Key
+ * order and durability levels vary by driver, so scan_ordered and
+ * durability are run separately, by drivers that support them.  Tests
+ * of driver-specific behaviour stay in the driver's module.
+ */
+
+use super::api::{self, Batch, ConfigBuilder, Driver, Durability};
+use crate::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempdir::TempDir;
+
+// configuration builder for a database in a directory
+pub type NewConfig<'a> = &'a dyn Fn(&str) -> ConfigBuilder;
+
+/// Run the tests every driver must pass.
+pub fn run(driver: &dyn Driver, config: NewConfig) {
+    get_put(driver, config);
+    del(driver, config);
+    batch(driver, config);
+    batch_conditions(driver, config);
+    cas(driver, config);
+    versions(driver, config);
+    clear(driver, config);
+    stat(driver, config);
+    storage_stat(driver, config);
+    flush_close(driver, config);
+    unknown_option(driver, config);
+    iter(driver, config);
+    iter_prefix(driver, config);
+    namespaces(driver, config);
+    ns_name_keys(driver, config);
+    read_only(driver, config);
+}
+
+/// Run the scan tests of a driver storing keys in order.
+pub fn scan_ordered(driver: &dyn Driver, config: NewConfig) {
+    scan(driver, config);
+    scan_range(driver, config);
+    scan_prefix(driver, config);
+}
+
+fn get_put(driver: &dyn Driver, config: NewConfig) {
+    let tmp_dir = TempDir::new("tgp").unwrap();
+    let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+    let db_config = config(&tmp_path).read_only(false).build();
+
+    let mut db = driver.start_db(db_config).unwrap();
+
+    assert_eq!(db.get(b"name").unwrap(), None);
+    assert!(db.put(b"name", b"alan").unwrap());
+    assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+    assert!(db.del(b"name").unwrap());
+    assert_eq!(db.get(b"name").unwrap(), None);
+    assert_eq!(db.get(b"never_existed").unwrap(), None);
+
+    // empty values are stored
+    assert!(db.put(b"empty", b"").unwrap());
+    assert_eq!(db.get(b"empty").unwrap(), Some(Vec::new()));
+}
+
+fn del(driver: &dyn Driver, config: NewConfig) {
+    let tmp_dir = TempDir::new("td").unwrap();
+    let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+    let db_config = config(&tmp_path).read_only(false).build();
+
+    let mut db = driver.start_db(db_config).unwrap();
+
+    assert!(db.put(b"name", b"alan").unwrap());
+    assert!(db.del(b"name").unwrap());
+    assert!(!db.del(b"name").unwrap());
+}
+
+fn batch(driver: &dyn Driver, config: NewConfig) {
+    let tmp_dir = TempDir::new("tb").unwrap();
+    let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+    let db_config = config(&tmp_path).read_only(false).build();
+
+    let mut db = driver.start_db(db_config).unwrap();
+
+    assert!(db.put(b"name", b"alan").unwrap());
+
+    let mut batch = Batch::default();
+    batch.insert(b"age", b"25");
+    batch.insert(b"city", b"anytown");
+    batch.remove(b"name");
+    assert!(db.apply_batch(&batch).unwrap());
+
+    assert_eq!(db.get(b"name").unwrap(), None);
+    assert_eq!(db.get(b"age").unwrap(), Some(Vec::from("25")));
+    assert_eq!(db.get(b"city").unwrap(), Some(Vec::from("anytown")));
+}
+
+fn clear(driver: &dyn Driver, config: NewConfig) {
+    let tmp_dir = TempDir::new("tc").unwrap();
+    let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+    let db_config = config(&tmp_path).read_only(false).build();
+
+    let mut db = driver.start_db(db_config).unwrap();
+
+    assert!(db.put(b"name", b"alan").unwrap());
+    assert!(db.put(b"age", b"25").unwrap());
+    assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+    assert!(db.clear().unwrap());
+    assert_eq!(db.get(b"name").unwrap(), None);
+    assert_eq!(db.get(b"age").unwrap(), None);
+    assert_eq!(db.stat().unwrap().n_records, 0);
+}
+
+fn stat(driver: &dyn Driver, config: NewConfig) {
+    let tmp_dir = TempDir::new("tc").unwrap();
+    let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+    let db_config = config(&tmp_path).read_only(false).build();
+
+    let mut db = driver.start_db(db_config).unwrap();
+
+    assert!(db.put(b"name1", b"alan").unwrap());
+    assert!(db.put(b"age1", b"25").unwrap());
+    assert!(db.put(b"name", b"alan").unwrap());
+    assert!(db.del(b"name").unwrap());
+    assert!(!db.del(b"name").unwrap());
+
+    let st = db.stat().unwrap();
+    assert_eq!(st.n_records, 2);
+}
+
+fn storage_stat(driver: &dyn Driver, config: NewConfig) {
+    let tmp_dir = TempDir::new("tss").unwrap();
+    let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+    let db_config = config(&tmp_path).read_only(false).build();
+
+    let mut db = driver.start_db(db_config).unwrap();
+    assert!(db.put(b"name1", b"alan").unwrap());
+    db.flush().unwrap();
+
+    // only memory-mapped drivers report a map size
+    let st = db.storage_stat().unwrap();
+    let disk_bytes = st.disk_bytes.unwrap();
+    assert!(disk_bytes > 0);
+    if let Some(map_bytes) = st.map_bytes {
+        assert!(map_bytes >= disk_bytes);
+    }
+}
+
+fn flush_close(driver: &dyn Driver, config: NewConfig) {
+    let tmp_dir = TempDir::new("tfc").unwrap();
+    let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    {
+        let db_config = config(&tmp_path).read_only(false).build();
+        let mut db = driver.start_db(db_config).unwrap();
+        assert!(db.put(b"name", b"alan").unwrap());
+        db.flush().unwrap();
+        assert!(db.put(b"age", b"41").unwrap());
+        db.close().unwrap();
+    }
+
+    // reopen: all writes are stored
+    let db_config = config(&tmp_path).read_only(false).build();
+    let db = driver.start_db(db_config).unwrap();
+    assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+    assert_eq!(db.get(b"age").unwrap(), Some(Vec::from("41")));
+}
+
+/// Test that writes at each durability level persist, and that
+/// Periodic(0) is rejected.
+pub fn durability(driver: &dyn Driver, config: NewConfig) {
+    for durability in [
+        Durability::Sync,
+        Durability::Periodic(10),
+        Durability::NoSync,
+    ] {
+        let tmp_dir = TempDir::new("tdu").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+
+        {
+            let db_config = config(&tmp_path).durability(durability).build();
+            let mut db = driver.start_db(db_config).unwrap();
+            assert!(db.put(b"name", b"alan").unwrap());
+            assert!(db.open_ns("users").unwrap());
+            assert!(db.put_ns(Some("users"), b"alan", b"1").unwrap());
+            db.close().unwrap();
+        }
+
+        let db_config = config(&tmp_path).build();
+        let db = driver.start_db(db_config).unwrap();
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+        assert_eq!(
+            db.get_ns(Some("users"), b"alan").unwrap(),
+            Some(Vec::from("1"))
+        );
+    }
+
+    let tmp_dir = TempDir::new("tdu").unwrap();
+    let db_config = config(tmp_dir.path().to_str().unwrap())
+        .durability(Durability::Periodic(0))
+        .build();
+    assert!(matches!(
+        driver.start_db(db_config),
+        Err(Error::InvalidArgument(_))
+    ));
+}
+
+fn unknown_option(driver: &dyn Driver, config: NewConfig) {
+    let tmp_dir = TempDir::new("topt").unwrap();
+    let db_config = config(tmp_dir.path().to_str().unwrap())
+        .option("no_such_option", "1")
+        .build();
+    assert!(matches!(
+        driver.start_db(db_config),
+        Err(Error::InvalidArgument(_))
+    ));
+}
+
+fn iter(driver: &dyn Driver, config: NewConfig) {
+    let tmp_dir = TempDir::new("tc").unwrap();
+    let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+    let db_config = config(&tmp_path).read_only(false).build();
+
+    let mut db = driver.start_db(db_config).unwrap();
+
+    // iterate empty list
+    let key_list_res = db.iter_keys(api::IterOptions::new());
+    assert!(key_list_res.is_ok());
+
+    let mut key_list = key_list_res.unwrap();
+    assert!(key_list.list_end);
+
+    key_list.keys.sort();
+    assert_eq!(key_list.keys.len(), 0);
+
+    // iterate small list
+    assert!(db.put(b"name", b"alan").unwrap());
+    assert!(db.put(b"age", b"25").unwrap());
+
+    let key_list_res = db.iter_keys(api::IterOptions::new());
+    assert!(key_list_res.is_ok());
+
+    let mut key_list = key_list_res.unwrap();
+    assert!(key_list.list_end);
+
+    key_list.keys.sort();
+    assert_eq!(key_list.keys.len(), 2);
+    assert_eq!(key_list.keys[0], b"age");
+    assert_eq!(key_list.keys[1], b"name");
+}
+
+fn iter_prefix(driver: &dyn Driver, config: NewConfig) {
+    let tmp_dir = TempDir::new("tc").unwrap();
+    let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+    let db_config = config(&tmp_path).read_only(false).build();
+
+    let mut db = driver.start_db(db_config).unwrap();
+
+    // iterate small list
+    assert!(db.put(b"2018/name", b"alan").unwrap());
+    assert!(db.put(b"2018/bame", b"alan").unwrap());
+    assert!(db.put(b"2019/fame", b"alan").unwrap());
+    assert!(db.put(b"2019/lame", b"alan").unwrap());
+    assert!(db.put(b"2019/game", b"alan").unwrap());
+    assert!(db.put(b"2020/tame", b"alan").unwrap());
+    assert!(db.put(b"age", b"25").unwrap());
+
+    let key_list_res = db.iter_keys(api::IterOptions::new());
+    assert!(key_list_res.is_ok());
+
+    let key_list = key_list_res.unwrap();
+    assert!(key_list.list_end);
+    assert_eq!(key_list.keys.len(), 7);
+
+    // iterate with prefix matching
+    let mut opts = api::IterOptions::new();
+    opts.prefix(b"2019/");
+
+    let key_list_res = db.iter_keys(opts);
+    assert!(key_list_res.is_ok());
+
+    let mut key_list = key_list_res.unwrap();
+    assert!(key_list.list_end);
+    assert_eq!(key_list.keys.len(), 3);
+
+    key_list.keys.sort();
+    assert_eq!(
+        String::from_utf8_lossy(&key_list.keys[0]),
+        String::from("2019/fame")
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&key_list.keys[1]),
+        String::from("2019/game")
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&key_list.keys[2]),
+        String::from("2019/lame")
+    );
+}
+
+fn scan(driver: &dyn Driver, config: NewConfig) {
+    let tmp_dir = TempDir::new("ts").unwrap();
+    let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+    let db_config = config(&tmp_path).read_only(false).build();
+
+    let mut db = driver.start_db(db_config).unwrap();
+
+    assert!(db.put(b"a", b"1").unwrap());
+    assert!(db.put(b"b", b"22").unwrap());
+    assert!(db.put(b"c", b"333").unwrap());
+
+    // scan returns keys and values, in key order
+    let kv_list = db.scan(api::IterOptions::new()).unwrap();
+    assert!(kv_list.list_end);
+    assert_eq!(kv_list.pairs.len(), 3);
+    assert_eq!(kv_list.pairs[1].key, b"b");
+    assert_eq!(kv_list.pairs[1].value, b"22");
+
+    // keys-only scan omits values
+    let mut opts = api::IterOptions::new();
+    opts.keys_only(true);
+    let kv_list = db.scan(opts).unwrap();
+    assert_eq!(kv_list.pairs.len(), 3);
+    assert_eq!(kv_list.pairs[2].key, b"c");
+    assert!(kv_list.pairs[2].value.is_empty());
+
+    // byte-limited pages, continued from the last key seen
+    let mut opts = api::IterOptions::new();
+    opts.max_bytes(4);
+    let kv_list = db.scan(opts).unwrap();
+    assert!(!kv_list.list_end);
+    assert_eq!(kv_list.pairs.len(), 1);
+    assert_eq!(kv_list.pairs[0].key, b"a");
+
+    let mut opts = api::IterOptions::new();
+    opts.max_bytes(4).start(b"a");
+    let kv_list = db.scan(opts).unwrap();
+    assert!(!kv_list.list_end);
+    assert_eq!(kv_list.pairs.len(), 1);
+    assert_eq!(kv_list.pairs[0].key, b"b");
+
+    let mut opts = api::IterOptions::new();
+    opts.max_bytes(4).start(b"b");
+    let kv_list = db.scan(opts).unwrap();
+    assert!(kv_list.list_end);
+    assert_eq!(kv_list.pairs.len(), 1);
+    assert_eq!(kv_list.pairs[0].value, b"333");
+}
+
+fn scan_range(driver: &dyn Driver, config: NewConfig) {
+    let tmp_dir = TempDir::new("tsr").unwrap();
+    let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+    let db_config = config(&tmp_path).read_only(false).build();
+
+    let mut db = driver.start_db(db_config).unwrap();
+
+    for i in 0..10 {
+        let key = format!("k{}", i);
+        assert!(db.put(key.as_bytes(), b"v").unwrap());
+    }
+
+    fn keys(kv_list: &api::KvList) -> Vec<String> {
+        kv_list
+            .pairs
+            .iter()
+            .map(|kv| String::from_utf8_lossy(&kv.key).to_string())
+            .collect()
+    }
+
+    // forward, exclusive and inclusive end
+    let mut opts = api::IterOptions::new();
+    opts.start(b"k2").end(b"k5", false);
+    let kv_list = db.scan(opts).unwrap();
+    assert!(kv_list.list_end);
+    assert_eq!(keys(&kv_list), vec!["k3", "k4"]);
+
+    let mut opts = api::IterOptions::new();
+    opts.end(b"k2", true);
+    let kv_list = db.scan(opts).unwrap();
+    assert_eq!(keys(&kv_list), vec!["k0", "k1", "k2"]);
+
+    // start key need not exist
+    let mut opts = api::IterOptions::new();
+    opts.start(b"k75");
+    let kv_list = db.scan(opts).unwrap();
+    assert_eq!(keys(&kv_list), vec!["k8", "k9"]);
+
+    // forward, limited pages
+    let mut opts = api::IterOptions::new();
+    opts.limit(4);
+    let kv_list = db.scan(opts).unwrap();
+    assert!(!kv_list.list_end);
+    assert_eq!(keys(&kv_list), vec!["k0", "k1", "k2", "k3"]);
+
+    // reverse: latest 3 before k5
+    let mut opts = api::IterOptions::new();
+    opts.reverse(true).start(b"k5").limit(3);
+    let kv_list = db.scan(opts).unwrap();
+    assert!(!kv_list.list_end);
+    assert_eq!(keys(&kv_list), vec!["k4", "k3", "k2"]);
+
+    // reverse, from end of db, stopping at inclusive end key
+    let mut opts = api::IterOptions::new();
+    opts.reverse(true).end(b"k7", true);
+    let kv_list = db.scan(opts).unwrap();
+    assert!(kv_list.list_end);
+    assert_eq!(keys(&kv_list), vec!["k9", "k8", "k7"]);
+
+    // reverse, start key past end of db
+    let mut opts = api::IterOptions::new();
+    opts.reverse(true).start(b"z").end(b"k8", false);
+    let kv_list = db.scan(opts).unwrap();
+    assert_eq!(keys(&kv_list), vec!["k9"]);
+
+    // empty ranges
+    let mut opts = api::IterOptions::new();
+    opts.start(b"k5").end(b"k5", true);
+    assert!(db.scan(opts).unwrap().pairs.is_empty());
+
+    let mut opts = api::IterOptions::new();
+    opts.start(b"k9");
+    let kv_list = db.scan(opts).unwrap();
+    assert!(kv_list.list_end);
+    assert!(kv_list.pairs.is_empty());
+
+    let mut opts = api::IterOptions::new();
+    opts.reverse(true).start(b"k0");
+    assert!(db.scan(opts).unwrap().pairs.is_empty());
+}
+
+fn scan_prefix(driver: &dyn Driver, config: NewConfig) {
+    let tmp_dir = TempDir::new("tsp").unwrap();
+    let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+    let db_config = config(&tmp_path).read_only(false).build();
+
+    let mut db = driver.start_db(db_config).unwrap();
+
+    for key in [
+        &b"a/1"[..],
+        b"b/1",
+        b"b/2",
+        b"b/3",
+        b"c/1",
+        b"\xff\xff",
+        b"\xff\xff1",
+    ] {
+        assert!(db.put(key, b"v").unwrap());
+    }
+
+    fn keys(kv_list: &api::KvList) -> Vec<Vec<u8>> {
+        kv_list.pairs.iter().map(|kv| kv.key.clone()).collect()
+    }
+
+    // prefix pages stop at end of prefix, continuing from start key
+    let mut opts = api::IterOptions::new();
+    opts.prefix(b"b/").limit(2);
+    let kv_list = db.scan(opts).unwrap();
+    assert!(!kv_list.list_end);
+    assert_eq!(keys(&kv_list), vec![b"b/1".to_vec(), b"b/2".to_vec()]);
+
+    let mut opts = api::IterOptions::new();
+    opts.prefix(b"b/").limit(2).start(b"b/2");
+    let kv_list = db.scan(opts).unwrap();
+    assert!(kv_list.list_end);
+    assert_eq!(keys(&kv_list), vec![b"b/3".to_vec()]);
+
+    // start key before prefix
+    let mut opts = api::IterOptions::new();
+    opts.prefix(b"c/").start(b"a/1");
+    let kv_list = db.scan(opts).unwrap();
+    assert_eq!(keys(&kv_list), vec![b"c/1".to_vec()]);
+
+    // reverse within prefix
+    let mut opts = api::IterOptions::new();
+    opts.prefix(b"b/").reverse(true).start(b"b/3");
+    let kv_list = db.scan(opts).unwrap();
+    assert!(kv_list.list_end);
+    assert_eq!(keys(&kv_list), vec![b"b/2".to_vec(), b"b/1".to_vec()]);
+
+    // prefix of all 0xff bytes has no upper bound
+    let mut opts = api::IterOptions::new();
+    opts.prefix(b"\xff\xff");
+    let kv_list = db.scan(opts).unwrap();
+    assert_eq!(kv_list.pairs.len(), 2);
+
+    // no matching keys
+    let mut opts = api::IterOptions::new();
+    opts.prefix(b"bb");
+    let kv_list = db.scan(opts).unwrap();
+    assert!(kv_list.list_end);
+    assert!(kv_list.pairs.is_empty());
+}
+
+fn batch_conditions(driver: &dyn Driver, config: NewConfig) {
+    let tmp_dir = TempDir::new("tbc").unwrap();
+    let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+    let db_config = config(&tmp_path).read_only(false).build();
+
+    let mut db = driver.start_db(db_config).unwrap();
+
+    assert!(db.put(b"name", b"alan").unwrap());
+
+    // all preconditions hold
+    let mut batch = Batch::default();
+    batch.insert_if(b"age", b"25", api::Precondition::NotExists);
+    batch.insert_if(
+        b"name",
+        b"bob",
+        api::Precondition::ValueEquals(b"alan".to_vec()),
+    );
+    assert!(db.apply_batch(&batch).unwrap());
+    assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("bob")));
+
+    // one failed precondition aborts the whole batch
+    let version = db.version(b"name").unwrap().unwrap();
+    let mut batch = Batch::default();
+    batch.insert(b"city", b"anytown");
+    batch.remove_if(b"name", api::Precondition::VersionEquals(version));
+    batch.insert_if(b"age", b"26", api::Precondition::NotExists);
+    match db.apply_batch(&batch) {
+        Err(Error::ConditionFailed(msg)) => assert!(msg.starts_with("op 2,")),
+        _ => panic!("expected failed precondition"),
+    }
+    assert_eq!(db.get(b"city").unwrap(), None);
+    assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("bob")));
+    assert_eq!(db.get(b"age").unwrap(), Some(Vec::from("25")));
+
+    // preconditions see earlier mutations in the batch
+    let mut batch = Batch::default();
+    batch.remove(b"age");
+    batch.insert_if(b"age", b"26", api::Precondition::NotExists);
+    assert!(db.apply_batch(&batch).unwrap());
+    assert_eq!(db.get(b"age").unwrap(), Some(Vec::from("26")));
+}
+
+fn cas(driver: &dyn Driver, config: NewConfig) {
+    let tmp_dir = TempDir::new("tcas").unwrap();
+    let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+    let db_config = config(&tmp_path).read_only(false).build();
+
+    let mut db = driver.start_db(db_config).unwrap();
+
+    assert!(db.compare_and_swap(b"name", None, Some(b"alan")).unwrap());
+    assert!(matches!(
+        db.compare_and_swap(b"name", Some(b"bob"), Some(b"carl")),
+        Err(Error::ConditionFailed(_))
+    ));
+    assert!(db
+        .compare_and_swap(b"name", Some(b"alan"), Some(b"bob"))
+        .unwrap());
+    assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("bob")));
+    assert!(db.compare_and_swap(b"name", Some(b"bob"), None).unwrap());
+    assert_eq!(db.get(b"name").unwrap(), None);
+    assert!(matches!(
+        db.compare_and_swap(b"name", Some(b"bob"), None),
+        Err(Error::ConditionFailed(_))
+    ));
+}
+
+fn versions(driver: &dyn Driver, config: NewConfig) {
+    let tmp_dir = TempDir::new("tver").unwrap();
+    let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    let mut seen = Vec::new();
+    {
+        let mut db = driver.start_db(config(&tmp_path).build()).unwrap();
+        assert_eq!(db.version(b"name").unwrap(), None);
+
+        // every write is a new version, even of the same value
+        assert!(db.put(b"name", b"alan").unwrap());
+        seen.push(db.version(b"name").unwrap().unwrap());
+        assert!(db.put(b"name", b"alan").unwrap());
+        seen.push(db.version(b"name").unwrap().unwrap());
+        assert!(db.put(b"name", b"bob").unwrap());
+        assert!(db.put(b"name", b"alan").unwrap());
+        seen.push(db.version(b"name").unwrap().unwrap());
+
+        // removed, then created again: not an earlier version
+        assert!(db.del(b"name").unwrap());
+        assert_eq!(db.version(b"name").unwrap(), None);
+        assert!(db.compare_and_swap(b"name", None, Some(b"alan")).unwrap());
+        seen.push(db.version(b"name").unwrap().unwrap());
+
+        let mut sorted = seen.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted.len(), seen.len());
+
+        // a stale version fails, naming the op; the current one holds
+        let mut batch = Batch::default();
+        batch.insert(b"city", b"anytown");
+        batch.insert_if(b"name", b"carl", api::Precondition::VersionEquals(seen[0]));
+        match db.apply_batch(&batch) {
+            Err(Error::ConditionFailed(msg)) => assert!(msg.starts_with("op 1,")),
+            _ => panic!("expected failed precondition"),
+        }
+        assert_eq!(db.get(b"city").unwrap(), None);
+
+        let version = *seen.last().unwrap();
+        let mut batch = Batch::default();
+        batch.insert_if(b"name", b"carl", api::Precondition::VersionEquals(version));
+        assert!(db.apply_batch(&batch).unwrap());
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("carl")));
+
+        // preconditions see earlier mutations in the batch
+        let mut batch = Batch::default();
+        batch.remove(b"name");
+        batch.insert_if(b"name", b"dave", api::Precondition::VersionEquals(0));
+        assert!(matches!(
+            db.apply_batch(&batch),
+            Err(Error::ConditionFailed(_))
+        ));
+
+        // keyspaces are versioned separately
+        assert!(db.open_ns("users").unwrap());
+        assert_eq!(db.version_ns(Some("users"), b"name").unwrap(), None);
+        assert!(db.put_ns(Some("users"), b"name", b"alan").unwrap());
+        assert!(db.version_ns(Some("users"), b"name").unwrap().is_some());
+        seen.push(db.version(b"name").unwrap().unwrap());
+        db.close().unwrap();
+    }
+
+    // versions persist across reopen
+    let mut db = driver.start_db(config(&tmp_path).build()).unwrap();
+    let version = *seen.last().unwrap();
+    assert_eq!(db.version(b"name").unwrap(), Some(version));
+    assert!(db.put(b"name", b"erin").unwrap());
+    assert!(db.version(b"name").unwrap().unwrap() > version);
+
+    // clear and drop leave no version behind
+    assert!(db.clear().unwrap());
+    assert_eq!(db.version(b"name").unwrap(), None);
+    assert!(db.drop_ns("users").unwrap());
+    assert_eq!(db.list_ns().unwrap(), Vec::<String>::new());
+}
+
+fn namespaces(driver: &dyn Driver, config: NewConfig) {
+    let tmp_dir = TempDir::new("tns").unwrap();
+    let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    {
+        let db_config = config(&tmp_path).read_only(false).build();
+        let mut db = driver.start_db(db_config).unwrap();
+
+        assert!(matches!(
+            db.get_ns(Some("users"), b"name"),
+            Err(Error::NotFound)
+        ));
+        assert!(db.open_ns("users").unwrap());
+        assert!(db.open_ns("groups").unwrap());
+        assert!(db.open_ns("users").unwrap());
+        assert!(matches!(
+            db.open_ns("bad/name"),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        // keyspaces are separate; batches span them
+        let mut batch = Batch::default();
+        batch.insert(b"name", b"default");
+        batch.set_ns(Some("users"));
+        batch.insert(b"name", b"alan");
+        batch.insert(b"age", b"41");
+        batch.set_ns(Some("groups"));
+        batch.insert(b"name", b"admin");
+        assert!(db.apply_batch(&batch).unwrap());
+
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("default")));
+        assert_eq!(
+            db.get_ns(Some("users"), b"name").unwrap(),
+            Some(Vec::from("alan"))
+        );
+        assert_eq!(db.stat().unwrap().n_records, 1);
+        assert_eq!(db.stat_ns(Some("users")).unwrap().n_records, 2);
+
+        let mut opts = api::IterOptions::new();
+        opts.ns("users");
+        let kv_list = db.scan(opts).unwrap();
+        assert_eq!(kv_list.pairs.len(), 2);
+        let kv_list = db.scan(api::IterOptions::new()).unwrap();
+        assert_eq!(kv_list.pairs.len(), 1);
+
+        assert!(db.clear_ns(Some("groups")).unwrap());
+        assert_eq!(db.get_ns(Some("groups"), b"name").unwrap(), None);
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("default")));
+
+        // a failed batch is undone in every namespace
+        let mut batch = Batch::default();
+        batch.insert(b"name", b"changed");
+        batch.set_ns(Some("users"));
+        batch.remove(b"age");
+        batch.set_ns(Some("groups"));
+        batch.insert_if(b"name", b"x", api::Precondition::ValueEquals(b"y".to_vec()));
+        assert!(matches!(
+            db.apply_batch(&batch),
+            Err(Error::ConditionFailed(_))
+        ));
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("default")));
+        assert_eq!(
+            db.get_ns(Some("users"), b"age").unwrap(),
+            Some(Vec::from("41"))
+        );
+        db.close().unwrap();
+    }
+
+    // namespaces persist across reopen
+    let db_config = config(&tmp_path).read_only(false).build();
+    let mut db = driver.start_db(db_config).unwrap();
+    assert_eq!(
+        db.list_ns().unwrap(),
+        vec![String::from("groups"), String::from("users")]
+    );
+    assert_eq!(
+        db.get_ns(Some("users"), b"age").unwrap(),
+        Some(Vec::from("41"))
+    );
+
+    assert!(db.drop_ns("users").unwrap());
+    assert!(!db.drop_ns("users").unwrap());
+    assert_eq!(db.list_ns().unwrap(), vec![String::from("groups")]);
+    assert!(matches!(
+        db.get_ns(Some("users"), b"age"),
+        Err(Error::NotFound)
+    ));
+
+    assert!(db.clear().unwrap());
+    assert_eq!(db.stat().unwrap().n_records, 0);
+    assert_eq!(db.list_ns().unwrap(), vec![String::from("groups")]);
+}
+
+// keys of the default keyspace may equal namespace names
+fn ns_name_keys(driver: &dyn Driver, config: NewConfig) {
+    let tmp_dir = TempDir::new("tnk").unwrap();
+    let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    {
+        let db_config = config(&tmp_path).read_only(false).build();
+        let mut db = driver.start_db(db_config).unwrap();
+
+        // key before namespace, and namespace before key
+        assert!(db.put(b"users", b"1").unwrap());
+        assert!(db.open_ns("users").unwrap());
+        assert!(db.open_ns("groups").unwrap());
+        assert!(db.put(b"groups", b"2").unwrap());
+        assert!(db.put(b"__kvdb_namespaces", b"3").unwrap());
+        assert!(db.put_ns(Some("users"), b"users", b"4").unwrap());
+
+        assert_eq!(db.get(b"users").unwrap(), Some(Vec::from("1")));
+        assert_eq!(db.get(b"groups").unwrap(), Some(Vec::from("2")));
+        assert_eq!(db.stat().unwrap().n_records, 3);
+        let kv_list = db.scan(api::IterOptions::new()).unwrap();
+        assert_eq!(kv_list.pairs.len(), 3);
+
+        assert!(db.del(b"groups").unwrap());
+        assert_eq!(db.get(b"groups").unwrap(), None);
+        db.close().unwrap();
+    }
+
+    let db_config = config(&tmp_path).read_only(false).build();
+    let mut db = driver.start_db(db_config).unwrap();
+    assert_eq!(
+        db.list_ns().unwrap(),
+        vec![String::from("groups"), String::from("users")]
+    );
+    assert_eq!(db.get(b"users").unwrap(), Some(Vec::from("1")));
+    assert_eq!(
+        db.get_ns(Some("users"), b"users").unwrap(),
+        Some(Vec::from("4"))
+    );
+
+    // clearing the default keyspace leaves namespaces
+    assert!(db.clear().unwrap());
+    assert_eq!(db.stat().unwrap().n_records, 0);
+    assert_eq!(
+        db.get_ns(Some("users"), b"users").unwrap(),
+        Some(Vec::from("4"))
+    );
+    assert!(db.put(b"users", b"5").unwrap());
+    assert!(db.drop_ns("users").unwrap());
+    assert_eq!(db.get(b"users").unwrap(), Some(Vec::from("5")));
+}
+
+// file names and contents below dir, in name order.  An LMDB lock file
+// holds the reader table, updated by every reader.
+fn dir_contents(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+    let mut contents = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.ends_with("lock.mdb") {
+            continue;
+        } else if path.is_dir() {
+            contents.extend(dir_contents(&path));
+        } else {
+            let data = fs::read(&path).unwrap();
+            contents.push((path, data));
+        }
+    }
+    contents.sort();
+
+    contents
+}
+
+fn read_only(driver: &dyn Driver, config: NewConfig) {
+    let tmp_dir = TempDir::new("tro").unwrap();
+    let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    // read-only db must exist
+    let db_config = config(&format!("{}/missing", tmp_path))
+        .read_only(true)
+        .build();
+    assert!(driver.start_db(db_config).is_err());
+
+    {
+        let db_config = config(&tmp_path).read_only(false).build();
+        let mut db = driver.start_db(db_config).unwrap();
+        assert!(db.put(b"name", b"alan").unwrap());
+        assert!(db.open_ns("users").unwrap());
+        db.close().unwrap();
+    }
+    let before = dir_contents(tmp_dir.path());
+
+    {
+        let db_config = config(&tmp_path).read_only(true).build();
+        let mut db = driver.start_db(db_config).unwrap();
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+        assert_eq!(db.list_ns().unwrap(), vec![String::from("users")]);
+
+        assert!(matches!(db.put(b"name", b"bob"), Err(Error::ReadOnly)));
+        assert!(matches!(db.del(b"name"), Err(Error::ReadOnly)));
+        assert!(matches!(db.clear(), Err(Error::ReadOnly)));
+        assert!(matches!(
+            db.compare_and_swap(b"name", Some(b"alan"), None),
+            Err(Error::ReadOnly)
+        ));
+        let mut batch = Batch::default();
+        batch.insert(b"age", b"41");
+        assert!(matches!(db.apply_batch(&batch), Err(Error::ReadOnly)));
+        assert!(matches!(db.open_ns("groups"), Err(Error::ReadOnly)));
+        assert!(matches!(db.drop_ns("users"), Err(Error::ReadOnly)));
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+
+        // nothing to flush
+        db.close().unwrap();
+    }
+
+    // files are untouched
+    assert!(before == dir_contents(tmp_dir.path()));
+}
@@ -0,0 +1,1009 @@
+/*
+ * gdbm: driver over GNU dbm hash files, via libgdbm.
+ *
+ * The database path is either an existing GDBM file, served in place, or
+ * a directory holding the file DB_FILE.  The default keyspace is that
+ * file; namespace N is the file beside it, named with suffix
+ * NS_FILE_INFIX + N.
+ *
+ * GDBM is a hash table: scans visit records in hash order, which is
+ * unrelated to key order and may change as the file is written.  A scan
+ * page continues from its start key in that order; prefix and end key
+ * filter the records visited.  Reverse scans are not supported.
+ *
+ * GDBM has no transactions.  A batch is applied under the database lock,
+ * and undone if a mutation fails, but a crash midway may leave it partly
+ * applied.
+ */
+
+use super::{api, ttl, version};
+use crate::{Error, Result};
+use libc::{c_int, c_void};
+use std::collections::hash_map::{Entry, HashMap};
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::RangeBounds;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// database file, within the database directory
+const DB_FILE: &str = "kvdb.gdbm";
+
+// namespace files: default keyspace file name + NS_FILE_INFIX + namespace
+const NS_FILE_INFIX: &str = ".ns.";
+
+// permissions of new files, before umask
+const FILE_MODE: c_int = 0o666;
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    use libc::{c_char, c_int, c_void};
+
+    pub type GDBM_FILE = *mut c_void;
+    pub type gdbm_count_t = u64;
+
+    #[repr(C)]
+    pub struct datum {
+        pub dptr: *mut c_char,
+        pub dsize: c_int,
+    }
+
+    // gdbm_open flags
+    pub const GDBM_READER: c_int = 0;
+    pub const GDBM_WRCREAT: c_int = 2;
+    pub const GDBM_SYNC: c_int = 0x20;
+
+    // gdbm_store flags
+    pub const GDBM_REPLACE: c_int = 1;
+
+    // gdbm_error codes
+    pub const GDBM_BAD_MAGIC_NUMBER: c_int = 7;
+    pub const GDBM_ITEM_NOT_FOUND: c_int = 15;
+    pub const GDBM_MALFORMED_DATA: c_int = 18;
+    pub const GDBM_BYTE_SWAPPED: c_int = 21;
+    pub const GDBM_BAD_FILE_OFFSET: c_int = 22;
+    pub const GDBM_NEED_RECOVERY: c_int = 29;
+    pub const GDBM_BAD_BUCKET: c_int = 32;
+    pub const GDBM_BAD_DIR_ENTRY: c_int = 36;
+
+    #[link(name = "gdbm")]
+    extern "C" {
+        pub fn gdbm_open(
+            name: *const c_char,
+            block_size: c_int,
+            flags: c_int,
+            mode: c_int,
+            fatal_func: Option<extern "C" fn(*const c_char)>,
+        ) -> GDBM_FILE;
+        pub fn gdbm_close(dbf: GDBM_FILE) -> c_int;
+        pub fn gdbm_store(dbf: GDBM_FILE, key: datum, content: datum, flag: c_int) -> c_int;
+        pub fn gdbm_fetch(dbf: GDBM_FILE, key: datum) -> datum;
+        pub fn gdbm_delete(dbf: GDBM_FILE, key: datum) -> c_int;
+        pub fn gdbm_exists(dbf: GDBM_FILE, key: datum) -> c_int;
+        pub fn gdbm_firstkey(dbf: GDBM_FILE) -> datum;
+        pub fn gdbm_nextkey(dbf: GDBM_FILE, key: datum) -> datum;
+        pub fn gdbm_reorganize(dbf: GDBM_FILE) -> c_int;
+        pub fn gdbm_sync(dbf: GDBM_FILE) -> c_int;
+        pub fn gdbm_count(dbf: GDBM_FILE, pcount: *mut gdbm_count_t) -> c_int;
+        pub fn gdbm_errno_location() -> *mut c_int;
+        pub fn gdbm_last_errno(dbf: GDBM_FILE) -> c_int;
+        pub fn gdbm_last_syserr(dbf: GDBM_FILE) -> c_int;
+        pub fn gdbm_check_syserr(err: c_int) -> c_int;
+        pub fn gdbm_strerror(err: c_int) -> *const c_char;
+    }
+}
+
+/// libgdbm failure, by gdbm_error code
+#[derive(Debug)]
+pub struct GdbmError {
+    code: c_int,
+}
+
+impl fmt::Display for GdbmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = unsafe { CStr::from_ptr(ffi::gdbm_strerror(self.code)) };
+        write!(f, "gdbm: {}", msg.to_string_lossy())
+    }
+}
+
+impl std::error::Error for GdbmError {}
+
+// error for gdbm_error code, with the system errno behind it, if any
+fn gdbm_error(code: c_int, syserr: Option<io::Error>) -> Error {
+    match code {
+        ffi::GDBM_BAD_MAGIC_NUMBER
+        | ffi::GDBM_MALFORMED_DATA
+        | ffi::GDBM_BYTE_SWAPPED
+        | ffi::GDBM_BAD_FILE_OFFSET
+        | ffi::GDBM_NEED_RECOVERY
+        | ffi::GDBM_BAD_BUCKET..=ffi::GDBM_BAD_DIR_ENTRY => {
+            Error::Corruption(GdbmError { code }.to_string())
+        }
+        _ => match syserr {
+            Some(e) if unsafe { ffi::gdbm_check_syserr(code) } != 0 => Error::Io(e),
+            _ => Error::backend(GdbmError { code }),
+        },
+    }
+}
+
+// datum pointing at bytes, which gdbm does not modify
+fn datum(bytes: &[u8]) -> Result<ffi::datum> {
+    match c_int::try_from(bytes.len()) {
+        Ok(dsize) => Ok(ffi::datum {
+            dptr: bytes.as_ptr() as *mut _,
+            dsize,
+        }),
+        Err(_e) => Err(Error::TooLarge(String::from("gdbm key or value"))),
+    }
+}
+
+// one open GDBM file
+struct GdbmFile {
+    dbf: ffi::GDBM_FILE,
+    path: PathBuf,
+}
+
+// handles are only used under the keyspace lock
+unsafe impl Send for GdbmFile {}
+
+impl GdbmFile {
+    fn open(path: &Path, flags: c_int) -> Result<GdbmFile> {
+        let name = match CString::new(path.as_os_str().as_bytes()) {
+            Ok(name) => name,
+            Err(_e) => return Err(Error::InvalidArgument(format!("path {:?}", path))),
+        };
+
+        let dbf = unsafe { ffi::gdbm_open(name.as_ptr(), 0, flags, FILE_MODE, None) };
+        if dbf.is_null() {
+            let syserr = io::Error::last_os_error();
+            let code = unsafe { *ffi::gdbm_errno_location() };
+            return Err(gdbm_error(code, Some(syserr)));
+        }
+
+        Ok(GdbmFile {
+            dbf,
+            path: path.to_path_buf(),
+        })
+    }
+
+    // error from the last failed operation on this file
+    fn last_error(&self) -> Error {
+        let code = unsafe { ffi::gdbm_last_errno(self.dbf) };
+        let syserr = match unsafe { ffi::gdbm_last_syserr(self.dbf) } {
+            0 => None,
+            errno => Some(io::Error::from_raw_os_error(errno)),
+        };
+
+        gdbm_error(code, syserr)
+    }
+
+    fn check(&self, rc: c_int) -> Result<()> {
+        match rc {
+            0 => Ok(()),
+            _ => Err(self.last_error()),
+        }
+    }
+
+    // copy out, and free, a datum returned by gdbm; a null datum is a
+    // missing key, or the end of iteration
+    fn take(&self, dat: ffi::datum) -> Result<Option<Vec<u8>>> {
+        if dat.dptr.is_null() {
+            return match unsafe { ffi::gdbm_last_errno(self.dbf) } {
+                ffi::GDBM_ITEM_NOT_FOUND => Ok(None),
+                _ => Err(self.last_error()),
+            };
+        }
+
+        let bytes = unsafe {
+            let bytes = std::slice::from_raw_parts(dat.dptr as *const u8, dat.dsize as usize);
+            let bytes = bytes.to_vec();
+            libc::free(dat.dptr as *mut c_void);
+            bytes
+        };
+
+        Ok(Some(bytes))
+    }
+
+    fn fetch(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let dat = unsafe { ffi::gdbm_fetch(self.dbf, datum(key)?) };
+        self.take(dat)
+    }
+
+    fn exists(&self, key: &[u8]) -> Result<bool> {
+        Ok(unsafe { ffi::gdbm_exists(self.dbf, datum(key)?) } != 0)
+    }
+
+    fn store(&self, key: &[u8], val: &[u8]) -> Result<()> {
+        let rc = unsafe { ffi::gdbm_store(self.dbf, datum(key)?, datum(val)?, ffi::GDBM_REPLACE) };
+        self.check(rc)
+    }
+
+    // remove key; false if not found
+    fn delete(&self, key: &[u8]) -> Result<bool> {
+        match unsafe { ffi::gdbm_delete(self.dbf, datum(key)?) } {
+            0 => Ok(true),
+            _ => match unsafe { ffi::gdbm_last_errno(self.dbf) } {
+                ffi::GDBM_ITEM_NOT_FOUND => Ok(false),
+                _ => Err(self.last_error()),
+            },
+        }
+    }
+
+    fn first_key(&self) -> Result<Option<Vec<u8>>> {
+        let dat = unsafe { ffi::gdbm_firstkey(self.dbf) };
+        self.take(dat)
+    }
+
+    // key after key, in hash order
+    fn next_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let dat = unsafe { ffi::gdbm_nextkey(self.dbf, datum(key)?) };
+        self.take(dat)
+    }
+
+    fn keys(&self) -> Result<Vec<Vec<u8>>> {
+        let mut keys = Vec::new();
+        let mut next = self.first_key()?;
+        while let Some(key) = next {
+            next = self.next_key(&key)?;
+            keys.push(key);
+        }
+
+        Ok(keys)
+    }
+
+    fn count(&self) -> Result<u64> {
+        let mut count: ffi::gdbm_count_t = 0;
+        let rc = unsafe { ffi::gdbm_count(self.dbf, &mut count) };
+        self.check(rc)?;
+
+        Ok(count)
+    }
+
+    fn sync(&self) -> Result<()> {
+        let rc = unsafe { ffi::gdbm_sync(self.dbf) };
+        self.check(rc)
+    }
+
+    fn reorganize(&self) -> Result<()> {
+        let rc = unsafe { ffi::gdbm_reorganize(self.dbf) };
+        self.check(rc)
+    }
+}
+
+impl Drop for GdbmFile {
+    fn drop(&mut self) {
+        // syncs a file open for writing
+        unsafe { ffi::gdbm_close(self.dbf) };
+    }
+}
+
+// open files, by namespace; None is the default keyspace
+type Keyspaces = HashMap<Option<String>, GdbmFile>;
+
+// batch mutations applied so far: namespace, key, prior value
+type UndoLog<'a> = Vec<(Option<&'a str>, &'a [u8], Option<Vec<u8>>)>;
+
+fn keyspace<'a>(files: &'a Keyspaces, ns: Option<&str>) -> Result<&'a GdbmFile> {
+    files.get(&ns.map(String::from)).ok_or(Error::NotFound)
+}
+
+fn sync_all(files: &Keyspaces) -> Result<()> {
+    for file in files.values() {
+        file.sync()?;
+    }
+
+    Ok(())
+}
+
+// apply batch mutations, recording prior values in undo
+fn apply_ops<'a>(files: &Keyspaces, batch: &'a api::Batch, undo: &mut UndoLog<'a>) -> Result<()> {
+    for (idx, mutation) in batch.ops.iter().enumerate() {
+        let ns = mutation.ns.as_deref();
+        let file = keyspace(files, ns)?;
+
+        let cur_val = file.fetch(&mutation.key)?;
+        if let Some(cond) = &mutation.condition {
+            cond.check(idx, &mutation.key, cur_val.as_deref())?;
+        }
+
+        match mutation.op {
+            api::MutationOp::Insert => {
+                file.store(&mutation.key, mutation.value.as_ref().unwrap())?
+            }
+            api::MutationOp::Remove => {
+                file.delete(&mutation.key)?;
+            }
+        }
+        undo.push((ns, &mutation.key, cur_val));
+    }
+
+    Ok(())
+}
+
+// background thread syncing a database, for Durability::Periodic; stopped
+// and joined on drop
+struct Flusher {
+    stop: Option<mpsc::Sender<()>>, // dropped to stop the thread
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Flusher {
+    fn start(files: Arc<Mutex<Keyspaces>>, interval: Duration) -> Result<Flusher> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name(String::from("kvdb-gdbm-flush"))
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    // a failed sync is retried next interval, and on close
+                    let _res = sync_all(&files.lock().unwrap());
+                }
+            })?;
+
+        Ok(Flusher {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _res = thread.join();
+        }
+    }
+}
+
+pub struct GdbmDb {
+    files: Arc<Mutex<Keyspaces>>, // shared with flusher
+    path: PathBuf,                // default keyspace file
+    read_only: bool,
+    open_flags: c_int,         // gdbm_open flags, for new namespace files
+    _flusher: Option<Flusher>, // Durability::Periodic
+}
+
+impl GdbmDb {
+    // fail writes to a read-only database
+    fn writable(&self) -> Result<()> {
+        match self.read_only {
+            true => Err(Error::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    fn ns_path(&self, ns: &str) -> PathBuf {
+        let mut name = self.path.file_name().unwrap().to_os_string();
+        name.push(NS_FILE_INFIX);
+        name.push(ns);
+
+        self.path.with_file_name(name)
+    }
+}
+
+impl api::Db for GdbmDb {
+    fn clear_ns(&mut self, ns: Option<&str>) -> Result<bool> {
+        self.writable()?;
+        let files = self.files.lock().unwrap();
+        let file = keyspace(&files, ns)?;
+
+        // keys first: deleting during iteration may skip records
+        for key in file.keys()? {
+            file.delete(&key)?;
+        }
+
+        Ok(true)
+    }
+
+    fn stat_ns(&self, ns: Option<&str>) -> Result<api::DbStat> {
+        let files = self.files.lock().unwrap();
+
+        Ok(api::DbStat {
+            n_records: keyspace(&files, ns)?.count()?,
+        })
+    }
+
+    fn flush(&self) -> Result<()> {
+        match self.read_only {
+            true => Ok(()),
+            false => sync_all(&self.files.lock().unwrap()),
+        }
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        self.writable()?;
+        for file in self.files.lock().unwrap().values() {
+            file.reorganize()?;
+        }
+
+        Ok(())
+    }
+
+    fn storage_stat(&self) -> Result<api::StorageStat> {
+        let mut disk_bytes = 0;
+        for file in self.files.lock().unwrap().values() {
+            disk_bytes += fs::metadata(&file.path)?.len();
+        }
+
+        Ok(api::StorageStat {
+            disk_bytes: Some(disk_bytes),
+            map_bytes: None,
+        })
+    }
+
+    fn get_ns(&self, ns: Option<&str>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let files = self.files.lock().unwrap();
+        keyspace(&files, ns)?.fetch(key)
+    }
+
+    fn put_ns(&mut self, ns: Option<&str>, key: &[u8], val: &[u8]) -> Result<bool> {
+        self.writable()?;
+        let files = self.files.lock().unwrap();
+        keyspace(&files, ns)?.store(key, val)?;
+
+        Ok(true)
+    }
+
+    fn del_ns(&mut self, ns: Option<&str>, key: &[u8]) -> Result<bool> {
+        self.writable()?;
+        let files = self.files.lock().unwrap();
+        keyspace(&files, ns)?.delete(key)
+    }
+
+    fn apply_batch(&mut self, batch: &api::Batch) -> Result<bool> {
+        self.writable()?;
+        let files = self.files.lock().unwrap();
+        for ns in batch.namespaces() {
+            keyspace(&files, ns)?;
+        }
+
+        let mut undo = UndoLog::new();
+        match apply_ops(&files, batch, &mut undo) {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                // restore prior values, latest first; a failed restore
+                // leaves the batch partly applied
+                for (ns, key, old_val) in undo.into_iter().rev() {
+                    let file = keyspace(&files, ns)?;
+                    let _res = match old_val {
+                        None => file.delete(key).map(|_found| ()),
+                        Some(val) => file.store(key, &val),
+                    };
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn scan(&self, opts: api::IterOptions) -> Result<api::KvList> {
+        if opts.reverse {
+            return Err(Error::InvalidArgument(String::from(
+                "gdbm scans are unordered, and cannot be reversed",
+            )));
+        }
+
+        let mut page = api::ScanPage::new(&opts);
+        let files = self.files.lock().unwrap();
+        let file = keyspace(&files, opts.ns.as_deref())?;
+
+        // start key is a position in hash order, not a bound; end key
+        // and prefix filter the keys visited
+        let mut filter_opts = opts.clone();
+        filter_opts.start_key = None;
+        let range = match filter_opts.key_range() {
+            None => return Ok(page.finish()),
+            Some(range) => range,
+        };
+
+        // gdbm_nextkey locates its position by the key, so a start key
+        // deleted since the previous page leaves nowhere to continue
+        // from; the client restarts the scan
+        let mut next = match &opts.start_key {
+            None => file.first_key()?,
+            Some(key) => {
+                if !file.exists(key)? {
+                    return Err(Error::InvalidArgument(String::from(
+                        "gdbm scan start key not found, restart scan",
+                    )));
+                }
+                file.next_key(key)?
+            }
+        };
+
+        while let Some(key) = next {
+            if range.contains(&key) {
+                let value = match opts.keys_only {
+                    true => Some(Vec::new()),
+                    false => file.fetch(&key)?,
+                };
+                if let Some(value) = value {
+                    if !page.push(&key, &value) {
+                        break;
+                    }
+                }
+            }
+            next = file.next_key(&key)?;
+        }
+
+        Ok(page.finish())
+    }
+
+    fn open_ns(&mut self, ns: &str) -> Result<bool> {
+        api::validate_ns_name(ns)?;
+        self.writable()?;
+
+        let mut files = self.files.lock().unwrap();
+        if let Entry::Vacant(entry) = files.entry(Some(ns.to_string())) {
+            entry.insert(GdbmFile::open(&self.ns_path(ns), self.open_flags)?);
+        }
+
+        Ok(true)
+    }
+
+    fn drop_ns(&mut self, ns: &str) -> Result<bool> {
+        self.writable()?;
+
+        // close, then remove the file
+        let file = match self.files.lock().unwrap().remove(&Some(ns.to_string())) {
+            None => return Ok(false),
+            Some(file) => file,
+        };
+        let path = file.path.clone();
+        drop(file);
+        fs::remove_file(path)?;
+
+        Ok(true)
+    }
+
+    fn list_ns(&self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = self
+            .files
+            .lock()
+            .unwrap()
+            .keys()
+            .filter_map(|ns| ns.clone())
+            .collect();
+        names.sort();
+
+        Ok(names)
+    }
+}
+
+// namespaces with files beside the default keyspace file
+fn find_namespaces(path: &Path) -> Result<Vec<String>> {
+    let mut prefix = path.file_name().unwrap().to_os_string();
+    prefix.push(NS_FILE_INFIX);
+    let prefix = prefix.as_bytes();
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let file_name = entry?.file_name();
+        if let Some(name) = file_name.as_bytes().strip_prefix(prefix) {
+            if let Ok(name) = std::str::from_utf8(name) {
+                if api::validate_ns_name(name).is_ok() {
+                    names.push(name.to_string());
+                }
+            }
+        }
+    }
+
+    Ok(names)
+}
+
+pub struct GdbmDriver {}
+
+impl api::Driver for GdbmDriver {
+    fn start_db(&self, cfg: api::Config) -> Result<Box<dyn api::Db>> {
+        self.check_options(&cfg.options)?;
+
+        // an existing GDBM file is served in place
+        let cfg_path = Path::new(&cfg.path);
+        let path = match cfg_path.is_file() {
+            true => cfg_path.to_path_buf(),
+            false => cfg_path.join(DB_FILE),
+        };
+
+        // sync each write, or none until the next flush
+        let (sync_flag, flush_interval) = match cfg.durability {
+            api::Durability::Default | api::Durability::NoSync => (0, None),
+            api::Durability::Sync => (ffi::GDBM_SYNC, None),
+            api::Durability::Periodic(0) => {
+                return Err(Error::InvalidArgument(String::from(
+                    "flush interval must be positive",
+                )))
+            }
+            api::Durability::Periodic(ms) => (0, Some(Duration::from_millis(ms))),
+        };
+
+        let open_flags = match cfg.read_only {
+            true => {
+                if !path.is_file() {
+                    return Err(Error::NotFound);
+                }
+                ffi::GDBM_READER
+            }
+            false => {
+                if !cfg_path.is_file() {
+                    fs::create_dir_all(cfg_path)?;
+                }
+                ffi::GDBM_WRCREAT | sync_flag
+            }
+        };
+
+        let mut gdbm_db = GdbmDb {
+            files: Arc::new(Mutex::new(Keyspaces::new())),
+            path: path.clone(),
+            read_only: cfg.read_only,
+            open_flags,
+            _flusher: None,
+        };
+
+        // open default keyspace, and all existing namespaces
+        {
+            let mut files = gdbm_db.files.lock().unwrap();
+            files.insert(None, GdbmFile::open(&path, open_flags)?);
+            for name in find_namespaces(&path)? {
+                let file = GdbmFile::open(&gdbm_db.ns_path(&name), open_flags)?;
+                files.insert(Some(name), file);
+            }
+        }
+
+        if let (false, Some(interval)) = (cfg.read_only, flush_interval) {
+            gdbm_db._flusher = Some(Flusher::start(Arc::clone(&gdbm_db.files), interval)?);
+        }
+
+        ttl::wrap(version::wrap(Box::new(gdbm_db))?)
+    }
+}
+
+pub fn new_driver() -> Box<dyn api::Driver> {
+    Box::new(GdbmDriver {})
+}
+
+#[cfg(test)]
+use super::api::ConfigBuilder;
+#[cfg(test)]
+use super::conformance;
+#[cfg(test)]
+use tempdir::TempDir;
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    // database in dir, with default options
+    fn config(dir: &str) -> ConfigBuilder {
+        let mut builder = ConfigBuilder::new();
+        builder.path(dir.to_string());
+        builder
+    }
+
+    #[test]
+    fn test_conformance() {
+        conformance::run(new_driver().as_ref(), &config);
+    }
+
+    #[test]
+    fn test_durability() {
+        conformance::durability(new_driver().as_ref(), &config);
+    }
+
+    #[test]
+    fn test_options() {
+        let driver = new_driver();
+
+        // no options
+        let mut options = api::Options::new();
+        assert!(driver.check_options(&options).is_ok());
+        options.insert(String::from("cache_size"), String::from("1048576"));
+        assert!(matches!(
+            driver.check_options(&options),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        let tmp_dir = TempDir::new("topt").unwrap();
+        let db_config = ConfigBuilder::new()
+            .path(tmp_dir.path().to_str().unwrap().to_string())
+            .options(options)
+            .build();
+        assert!(matches!(
+            driver.start_db(db_config),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_legacy_file() {
+        let tmp_dir = TempDir::new("tlf").unwrap();
+        let file_path = tmp_dir.path().join("legacy.db");
+
+        // GDBM file made by other tooling
+        {
+            let file = GdbmFile::open(&file_path, ffi::GDBM_WRCREAT).unwrap();
+            file.store(b"name", b"alan").unwrap();
+        }
+
+        let driver = new_driver();
+
+        {
+            let db_config = ConfigBuilder::new()
+                .path(file_path.to_str().unwrap().to_string())
+                .build();
+            let mut db = driver.start_db(db_config).unwrap();
+            assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+            assert!(db.put(b"age", b"41").unwrap());
+            assert!(db.open_ns("users").unwrap());
+            db.close().unwrap();
+        }
+
+        // served in place, with namespace files beside it
+        assert!(tmp_dir.path().join("legacy.db.ns.users").is_file());
+        assert!(!tmp_dir.path().join(DB_FILE).exists());
+
+        let file = GdbmFile::open(&file_path, ffi::GDBM_READER).unwrap();
+        assert_eq!(file.fetch(b"age").unwrap(), Some(Vec::from("41")));
+        assert_eq!(file.count().unwrap(), 2);
+        drop(file);
+
+        // not a GDBM file
+        let bad_path = tmp_dir.path().join("bad.db");
+        std::fs::write(&bad_path, b"not a gdbm file, just some bytes").unwrap();
+        let db_config = ConfigBuilder::new()
+            .path(bad_path.to_str().unwrap().to_string())
+            .build();
+        assert!(driver.start_db(db_config).is_err());
+    }
+
+    #[test]
+    fn test_namespace_files() {
+        let tmp_dir = TempDir::new("tnf").unwrap();
+        let db_config = config(tmp_dir.path().to_str().unwrap()).build();
+
+        let driver = new_driver();
+
+        let mut db = driver.start_db(db_config).unwrap();
+        assert!(db.open_ns("users").unwrap());
+        assert!(tmp_dir.path().join("kvdb.gdbm.ns.users").is_file());
+
+        // dropped namespace file is removed
+        assert!(db.drop_ns("users").unwrap());
+        assert!(!tmp_dir.path().join("kvdb.gdbm.ns.users").exists());
+    }
+
+    #[test]
+    fn test_compact() {
+        let tmp_dir = TempDir::new("tcp").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+        let db_config = ConfigBuilder::new().path(tmp_path).read_only(false).build();
+
+        let driver = new_driver();
+
+        let mut db = driver.start_db(db_config).unwrap();
+        assert!(db.open_ns("users").unwrap());
+
+        let value = vec![b'v'; 4096];
+        for i in 0..500 {
+            let key = format!("key{}", i);
+            assert!(db.put(key.as_bytes(), &value).unwrap());
+            assert!(db.put_ns(Some("users"), key.as_bytes(), &value).unwrap());
+        }
+        for i in 1..500 {
+            let key = format!("key{}", i);
+            assert!(db.del(key.as_bytes()).unwrap());
+        }
+        let before = db.storage_stat().unwrap().disk_bytes.unwrap();
+
+        // reorganized files are smaller, with all records kept
+        db.compact().unwrap();
+        assert!(db.storage_stat().unwrap().disk_bytes.unwrap() < before);
+        assert_eq!(db.get(b"key0").unwrap(), Some(value.clone()));
+        assert_eq!(db.stat().unwrap().n_records, 1);
+        assert_eq!(db.stat_ns(Some("users")).unwrap().n_records, 500);
+        assert!(db.put(b"key1", b"1").unwrap());
+    }
+
+    #[test]
+    fn test_compact_read_only() {
+        let tmp_dir = TempDir::new("tcro").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap();
+
+        let driver = new_driver();
+
+        {
+            let mut db = driver.start_db(config(tmp_path).build()).unwrap();
+            assert!(db.put(b"name", b"alan").unwrap());
+        }
+
+        let db_config = config(tmp_path).read_only(true).build();
+        let mut db = driver.start_db(db_config).unwrap();
+        assert!(matches!(db.compact(), Err(Error::ReadOnly)));
+    }
+
+    #[test]
+    fn test_scan() {
+        let tmp_dir = TempDir::new("ts").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+        let db_config = ConfigBuilder::new().path(tmp_path).read_only(false).build();
+
+        let driver = new_driver();
+
+        let mut db = driver.start_db(db_config).unwrap();
+
+        assert!(db.put(b"a", b"1").unwrap());
+        assert!(db.put(b"b", b"22").unwrap());
+        assert!(db.put(b"c", b"333").unwrap());
+
+        // scan returns keys and values, in hash order
+        let kv_list = db.scan(api::IterOptions::new()).unwrap();
+        assert!(kv_list.list_end);
+        assert_eq!(kv_list.pairs.len(), 3);
+        for kv in &kv_list.pairs {
+            assert_eq!(kv.value.len(), (kv.key[0] - b'a' + 1) as usize);
+        }
+
+        // keys-only scan omits values
+        let mut opts = api::IterOptions::new();
+        opts.keys_only(true);
+        let kv_list = db.scan(opts).unwrap();
+        assert_eq!(kv_list.pairs.len(), 3);
+        assert!(kv_list.pairs.iter().all(|kv| kv.value.is_empty()));
+
+        // one-record pages, continued from the last key seen, visit
+        // each record once
+        let mut keys = Vec::new();
+        let mut opts = api::IterOptions::new();
+        opts.limit(1);
+        loop {
+            let kv_list = db.scan(opts.clone()).unwrap();
+            for kv in &kv_list.pairs {
+                keys.push(kv.key.clone());
+                opts.start(&kv.key);
+            }
+            if kv_list.list_end {
+                break;
+            }
+        }
+        keys.sort();
+        assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+
+        // no order to reverse; start key must exist
+        let mut opts = api::IterOptions::new();
+        opts.reverse(true);
+        assert!(matches!(db.scan(opts), Err(Error::InvalidArgument(_))));
+
+        let mut opts = api::IterOptions::new();
+        opts.start(b"bb");
+        assert!(matches!(db.scan(opts), Err(Error::InvalidArgument(_))));
+
+        // last key of a page deleted before the next page: restart
+        let mut opts = api::IterOptions::new();
+        opts.limit(1);
+        let kv_list = db.scan(opts.clone()).unwrap();
+        let last_key = kv_list.pairs[0].key.clone();
+        assert!(db.del(&last_key).unwrap());
+        opts.start(&last_key);
+        assert!(matches!(db.scan(opts), Err(Error::InvalidArgument(_))));
+        let kv_list = db.scan(api::IterOptions::new()).unwrap();
+        assert!(kv_list.list_end);
+        assert_eq!(kv_list.pairs.len(), 2);
+    }
+
+    #[test]
+    fn test_scan_range() {
+        let tmp_dir = TempDir::new("tsr").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+        let db_config = ConfigBuilder::new().path(tmp_path).read_only(false).build();
+
+        let driver = new_driver();
+
+        let mut db = driver.start_db(db_config).unwrap();
+
+        for i in 0..10 {
+            let key = format!("k{}", i);
+            assert!(db.put(key.as_bytes(), b"v").unwrap());
+        }
+
+        fn sorted_keys(kv_list: &api::KvList) -> Vec<String> {
+            let mut keys: Vec<String> = kv_list
+                .pairs
+                .iter()
+                .map(|kv| String::from_utf8_lossy(&kv.key).to_string())
+                .collect();
+            keys.sort();
+            keys
+        }
+
+        // end key filters, exclusive and inclusive
+        let mut opts = api::IterOptions::new();
+        opts.end(b"k3", false);
+        let kv_list = db.scan(opts).unwrap();
+        assert!(kv_list.list_end);
+        assert_eq!(sorted_keys(&kv_list), vec!["k0", "k1", "k2"]);
+
+        let mut opts = api::IterOptions::new();
+        opts.end(b"k2", true);
+        let kv_list = db.scan(opts).unwrap();
+        assert_eq!(sorted_keys(&kv_list), vec!["k0", "k1", "k2"]);
+
+        // limited pages
+        let mut opts = api::IterOptions::new();
+        opts.limit(4);
+        let kv_list = db.scan(opts).unwrap();
+        assert!(!kv_list.list_end);
+        assert_eq!(kv_list.pairs.len(), 4);
+
+        // empty range
+        let mut opts = api::IterOptions::new();
+        opts.prefix(b"k").end(b"k", false);
+        let kv_list = db.scan(opts).unwrap();
+        assert!(kv_list.list_end);
+        assert!(kv_list.pairs.is_empty());
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        let tmp_dir = TempDir::new("tsp").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+        let db_config = ConfigBuilder::new().path(tmp_path).read_only(false).build();
+
+        let driver = new_driver();
+
+        let mut db = driver.start_db(db_config).unwrap();
+
+        for key in [
+            &b"a/1"[..],
+            b"b/1",
+            b"b/2",
+            b"b/3",
+            b"c/1",
+            b"\xff\xff",
+            b"\xff\xff1",
+        ] {
+            assert!(db.put(key, b"v").unwrap());
+        }
+
+        // prefix pages, continuing from start key, visit each match once
+        let mut keys = Vec::new();
+        let mut opts = api::IterOptions::new();
+        opts.prefix(b"b/").limit(2);
+        loop {
+            let kv_list = db.scan(opts.clone()).unwrap();
+            for kv in &kv_list.pairs {
+                keys.push(kv.key.clone());
+                opts.start(&kv.key);
+            }
+            if kv_list.list_end {
+                break;
+            }
+        }
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![b"b/1".to_vec(), b"b/2".to_vec(), b"b/3".to_vec()]
+        );
+
+        // prefix of all 0xff bytes has no upper bound
+        let mut opts = api::IterOptions::new();
+        opts.prefix(b"\xff\xff");
+        let kv_list = db.scan(opts).unwrap();
+        assert_eq!(kv_list.pairs.len(), 2);
+
+        // no matching keys
+        let mut opts = api::IterOptions::new();
+        opts.prefix(b"bb");
+        let kv_list = db.scan(opts).unwrap();
+        assert!(kv_list.list_end);
+        assert!(kv_list.pairs.is_empty());
+    }
+}
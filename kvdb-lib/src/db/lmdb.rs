@@ -1,49 +1,144 @@
-use super::api;
+use super::{api, ttl, version};
+use crate::{Error, Result};
+use libc::c_uint;
 use lmdb::{Cursor, Transaction};
+use lmdb_sys as ffi;
+use std::collections::HashMap;
+use std::ops::Bound;
 use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// named database listing all namespaces (named databases)
+const NS_REGISTRY: &str = "__kvdb_namespaces";
+
+// named database holding the default keyspace.  LMDB keeps named
+// database records in the main db, so it holds no keys of ours.
+const DEFAULT_DB: &str = "__kvdb_default";
+
+// named database limit for the environment, including DEFAULT_DB and
+// NS_REGISTRY; default of driver option max_dbs
+const MAX_DBS: c_uint = 128;
+
+// driver options, each an lmdb::EnvironmentBuilder setting
+const LMDB_OPTIONS: &[&str] = &["map_size", "max_dbs", "max_readers"];
+
+// background thread syncing an environment opened without per-commit
+// sync, for Durability::Periodic; stopped and joined on drop
+struct Flusher {
+    stop: Option<mpsc::Sender<()>>, // dropped to stop the thread
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Flusher {
+    fn start(env: Arc<lmdb::Environment>, interval: Duration) -> Result<Flusher> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name(String::from("kvdb-lmdb-flush"))
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    // a failed sync is retried next interval, and on close
+                    let _res = env.sync(true);
+                }
+            })?;
+
+        Ok(Flusher {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        // the thread holds an environment handle; LMDB must not see the
+        // environment reopened in this process before it is closed
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _res = thread.join();
+        }
+    }
+}
 
 pub struct LmdbWrapper {
-    env: lmdb::Environment,
-    db: lmdb::Database,
+    env: Arc<lmdb::Environment>,
+    db: lmdb::Database,                   // default keyspace: DEFAULT_DB
+    registry: Option<lmdb::Database>,     // None, until first namespace
+    nss: HashMap<String, lmdb::Database>, // open namespaces
+    read_only: bool,
+    _flusher: Option<Flusher>, // Durability::Periodic
+}
+
+impl LmdbWrapper {
+    // lookup database for namespace; None is the default keyspace
+    fn dbi(&self, ns: Option<&str>) -> Result<lmdb::Database> {
+        match ns {
+            None => Ok(self.db),
+            Some(name) => self.nss.get(name).copied().ok_or(Error::NotFound),
+        }
+    }
 }
 
 impl api::Db for LmdbWrapper {
-    fn clear(&mut self) -> Result<bool, &'static str> {
-        let res = self.env.begin_rw_txn();
-        match res {
-            Err(_e) => Err("begin-rw-txn failed"),
-            Ok(mut txn) => match txn.clear_db(self.db) {
-                Err(_e) => Err("clear_db failed"),
-                Ok(_) => match txn.commit() {
-                    Err(_e) => Err("commit failed"),
-                    Ok(_) => Ok(true),
-                },
-            },
+    fn clear_ns(&mut self, ns: Option<&str>) -> Result<bool> {
+        let db = self.dbi(ns)?;
+        let mut txn = self.env.begin_rw_txn()?;
+        txn.clear_db(db)?;
+
+        match txn.commit() {
+            Err(e) => Err(e.into()),
+            Ok(_) => Ok(true),
+        }
+    }
+
+    fn stat_ns(&self, ns: Option<&str>) -> Result<api::DbStat> {
+        let db = self.dbi(ns)?;
+        let txn = self.env.begin_ro_txn()?;
+        let n_records = db_entries(&txn, db)?;
+        txn.abort();
+
+        Ok(api::DbStat { n_records })
+    }
+
+    fn flush(&self) -> Result<()> {
+        // a read-only environment has nothing to write, and refuses sync
+        if self.read_only {
+            return Ok(());
         }
+        self.env.sync(true)?;
+        Ok(())
     }
 
-    fn stat(&self) -> Result<api::DbStat, &'static str> {
-        let res = self.env.stat();
-        if res.is_err() {
-            return Err("db stat failed");
+    fn storage_stat(&self) -> Result<api::StorageStat> {
+        let mut info = std::mem::MaybeUninit::<ffi::MDB_envinfo>::uninit();
+        let rc = unsafe { ffi::mdb_env_info(self.env.env(), info.as_mut_ptr()) };
+        if rc != 0 {
+            return Err(lmdb::Error::from_err_code(rc).into());
         }
-        let st = res.unwrap();
+        let info = unsafe { info.assume_init() };
 
-        Ok(api::DbStat {
-            n_records: st.entries() as u64,
+        // pages in use, through the last one written
+        let page_size = self.env.stat()?.page_size() as u64;
+        Ok(api::StorageStat {
+            disk_bytes: Some((info.me_last_pgno as u64 + 1) * page_size),
+            map_bytes: Some(info.me_mapsize as u64),
         })
     }
 
-    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, &'static str> {
+    fn get_ns(&self, ns: Option<&str>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let db = self.dbi(ns)?;
+
         let res = self.env.begin_ro_txn();
         match res {
-            Err(_e) => Err("begin-ro-txn failed"),
-            Ok(txn) => match txn.get(self.db, &key.to_vec()) {
+            Err(e) => Err(e.into()),
+            Ok(txn) => match txn.get(db, &key.to_vec()) {
                 Err(e) => {
                     if e == lmdb::Error::NotFound {
                         Ok(None)
                     } else {
-                        Err("get failed")
+                        Err(e.into())
                     }
                 }
                 Ok(data) => {
@@ -55,170 +150,400 @@ impl api::Db for LmdbWrapper {
         }
     }
 
-    fn put(&mut self, key: &[u8], val: &[u8]) -> Result<bool, &'static str> {
-        let res = self.env.begin_rw_txn();
-        match res {
-            Err(_e) => Err("begin-rw-txn failed"),
-            Ok(mut txn) => match txn.put(
-                self.db,
-                &key.to_vec(),
-                &val.to_vec(),
-                lmdb::WriteFlags::empty(),
-            ) {
-                Err(_e) => Err("put failed"),
-                Ok(_) => match txn.commit() {
-                    Err(_e) => Err("commit failed"),
-                    Ok(_) => Ok(true),
-                },
-            },
-        }
+    fn put_ns(&mut self, ns: Option<&str>, key: &[u8], val: &[u8]) -> Result<bool> {
+        let mut batch = api::Batch::default();
+        batch.set_ns(ns);
+        batch.insert(key, val);
+
+        self.apply_batch(&batch)
     }
 
-    fn del(&mut self, key: &[u8]) -> Result<bool, &'static str> {
+    fn del_ns(&mut self, ns: Option<&str>, key: &[u8]) -> Result<bool> {
+        let db = self.dbi(ns)?;
+
         let res = self.env.begin_rw_txn();
         match res {
-            Err(_e) => Err("begin-rw-txn failed"),
-            Ok(mut txn) => match txn.del(self.db, &key.to_vec(), None) {
+            Err(e) => Err(e.into()),
+            Ok(mut txn) => match txn.del(db, &key.to_vec(), None) {
                 Err(e) => {
                     if e == lmdb::Error::NotFound {
                         Ok(false)
                     } else {
-                        Err("del failed")
+                        Err(e.into())
                     }
                 }
                 Ok(_) => match txn.commit() {
-                    Err(_e) => Err("commit failed"),
+                    Err(e) => Err(e.into()),
                     Ok(_) => Ok(true),
                 },
             },
         }
     }
 
-    fn apply_batch(&mut self, batch: &api::Batch) -> Result<bool, &'static str> {
+    fn apply_batch(&mut self, batch: &api::Batch) -> Result<bool> {
         let res = self.env.begin_rw_txn();
         match res {
-            Err(_e) => Err("begin-rw-txn failed"),
+            Err(e) => Err(e.into()),
             Ok(mut txn) => {
-                for dbm in &batch.ops {
+                for (idx, dbm) in batch.ops.iter().enumerate() {
+                    let ns = dbm.ns.as_deref();
+                    let db = self.dbi(ns)?;
+
+                    // check precondition; returning drops (aborts) txn
+                    if let Some(cond) = &dbm.condition {
+                        let cur_val = match txn.get(db, &dbm.key) {
+                            Ok(val) => Some(val),
+                            Err(lmdb::Error::NotFound) => None,
+                            Err(e) => return Err(e.into()),
+                        };
+                        cond.check(idx, &dbm.key, cur_val)?;
+                    }
+
                     match dbm.op {
                         api::MutationOp::Insert => {
                             let value = dbm.value.clone().unwrap();
-                            let res = txn.put(self.db, &dbm.key, &value, lmdb::WriteFlags::empty());
-                            if res.is_err() {
-                                return Err("txn.put failed");
-                            }
-                        }
-                        api::MutationOp::Remove => {
-                            let res = txn.del(self.db, &dbm.key, None);
-                            if res.is_err() {
-                                return Err("txn.put failed");
-                            }
+                            txn.put(db, &dbm.key, &value, lmdb::WriteFlags::empty())?;
                         }
+                        api::MutationOp::Remove => match txn.del(db, &dbm.key, None) {
+                            Ok(_) | Err(lmdb::Error::NotFound) => {}
+                            Err(e) => return Err(e.into()),
+                        },
                     }
                 }
 
                 match txn.commit() {
-                    Err(_e) => Err("commit failed"),
+                    Err(e) => Err(e.into()),
                     Ok(_) => Ok(true),
                 }
             }
         }
     }
 
-    fn iter_keys(&self, opts: api::IterOptions) -> Result<api::KeyList, &'static str> {
-        let mut key_list = api::KeyList {
-            keys: Vec::new(),
-            list_end: true,
+    fn scan(&self, opts: api::IterOptions) -> Result<api::KvList> {
+        let mut page = api::ScanPage::new(&opts);
+
+        let (lower, upper) = match opts.key_range() {
+            None => return Ok(page.finish()),
+            Some(range) => range,
         };
 
-        /*
-         * Work around lmdb-rs bug that panics when database
-         * is empty.  https://github.com/danburkert/lmdb-rs/issues/27
-         */
-        let st = self.stat()?;
-        if st.n_records == 0 {
-            return Ok(key_list);
-        }
-
-        let res = self.env.begin_ro_txn();
-        if res.is_err() {
-            return Err("begin-ro-txn failed");
-        }
-        let txn = res.unwrap();
+        let ns = opts.ns.as_deref();
+        let db = self.dbi(ns)?;
+        let txn = self.env.begin_ro_txn()?;
 
         {
             // extra scope, for cursor lifetime
-            let res = txn.open_ro_cursor(self.db);
-            if res.is_err() {
-                return Err("open-ro-cursor failed");
-            }
-            let mut cursor = res.unwrap();
-
-            let mut it;
-            if opts.start_key.is_none() {
-                it = cursor.iter_start();
-            } else {
-                it = cursor.iter_from(opts.start_key.unwrap());
-                it.next(); // absorb queried-for prev-key
-            }
+            let cursor = txn.open_ro_cursor(db)?;
 
-            let prefix: Vec<u8> = match opts.prefix {
-                None => Vec::new(),
-                Some(value) => value,
+            // position cursor at first record in range (start/end keys and
+            // prefix), in iteration order
+            let (first, stop_bound, step_op) = match opts.reverse {
+                false => (cursor_seek_lower(&cursor, &lower)?, upper, ffi::MDB_NEXT),
+                true => (cursor_seek_upper(&cursor, &upper)?, lower, ffi::MDB_PREV),
             };
-            let pfx_len = prefix.len();
 
+            let mut rec = first;
             loop {
-                // get next record
-                let opt_val = it.next();
-                if opt_val.is_none() {
+                // stop at end of db, or end of range
+                let (key, value) = match rec {
+                    None => break,
+                    Some(rec) => rec,
+                };
+                if past_bound(key, &stop_bound, opts.reverse) {
                     break;
                 }
-                let record_tuple = opt_val.unwrap();
-                let key = record_tuple.0.to_vec();
-
-                // filter by prefix
-                let mut want_push = true;
-                if pfx_len > 0 {
-                    if key.len() < pfx_len || prefix != &key[0..pfx_len] {
-                        want_push = false;
-                    }
-                }
-
-                // add record's key to returned list
-                if want_push {
-                    key_list.keys.push(record_tuple.0.to_vec());
 
-                    if key_list.keys.len() >= api::MAX_ITER_KEYS {
-                        key_list.list_end = false;
-                        break;
-                    }
+                // add record to returned page
+                if !page.push(key, value) {
+                    break;
                 }
+
+                // get next record
+                rec = cursor_get(&cursor, None, step_op)?;
             }
         } // end cursor scope, before we abort txn
 
         txn.abort();
 
-        Ok(key_list)
+        Ok(page.finish())
+    }
+
+    fn open_ns(&mut self, ns: &str) -> Result<bool> {
+        api::validate_ns_name(ns)?;
+        if ns == DEFAULT_DB || ns == NS_REGISTRY {
+            return Err(Error::InvalidArgument(String::from(
+                "namespace name is reserved",
+            )));
+        }
+        if self.nss.contains_key(ns) {
+            return Ok(true);
+        }
+
+        let registry = match self.registry {
+            Some(registry) => registry,
+            None => self
+                .env
+                .create_db(Some(NS_REGISTRY), lmdb::DatabaseFlags::empty())?,
+        };
+        self.registry = Some(registry);
+
+        // create named database, then record it in the registry
+        let db = self.env.create_db(Some(ns), lmdb::DatabaseFlags::empty())?;
+        let mut txn = self.env.begin_rw_txn()?;
+        txn.put(registry, &ns, &[], lmdb::WriteFlags::empty())?;
+        txn.commit()?;
+
+        self.nss.insert(ns.to_string(), db);
+
+        Ok(true)
+    }
+
+    fn drop_ns(&mut self, ns: &str) -> Result<bool> {
+        let db = match self.nss.get(ns) {
+            None => return Ok(false),
+            Some(db) => *db,
+        };
+
+        let mut txn = self.env.begin_rw_txn()?;
+        // SAFETY: the handle is removed from self.nss below, and no other
+        // transaction can use it while we hold &mut self
+        unsafe {
+            txn.drop_db(db)?;
+        }
+        if let Some(registry) = self.registry {
+            match txn.del(registry, &ns, None) {
+                Ok(_) | Err(lmdb::Error::NotFound) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        txn.commit()?;
+
+        self.nss.remove(ns);
+
+        Ok(true)
+    }
+
+    fn list_ns(&self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = self.nss.keys().cloned().collect();
+        names.sort();
+
+        Ok(names)
+    }
+}
+
+// create the default keyspace database, moving into it any records of
+// the main db other than named databases, in one transaction
+fn create_default_db(
+    env: &lmdb::Environment,
+    nss: &HashMap<String, lmdb::Database>,
+) -> Result<lmdb::Database> {
+    let main_db = env.open_db(None)?;
+    let mut txn = env.begin_rw_txn()?;
+    // SAFETY: no other thread opens databases in the environment while
+    // the driver starts it
+    let db = unsafe { txn.create_db(Some(DEFAULT_DB), lmdb::DatabaseFlags::empty())? };
+
+    let mut records: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+    {
+        let cursor = txn.open_ro_cursor(main_db)?;
+        let mut rec = cursor_get(&cursor, None, ffi::MDB_FIRST)?;
+        while let Some((key, value)) = rec {
+            let is_db_name = match std::str::from_utf8(key) {
+                Err(_e) => false,
+                Ok(name) => name == NS_REGISTRY || name == DEFAULT_DB || nss.contains_key(name),
+            };
+            if !is_db_name {
+                records.push((key.to_vec(), value.to_vec()));
+            }
+            rec = cursor_get(&cursor, None, ffi::MDB_NEXT)?;
+        }
+    }
+    for (key, value) in records {
+        txn.put(db, &key, &value, lmdb::WriteFlags::empty())?;
+        txn.del(main_db, &key, None)?;
+    }
+    txn.commit()?;
+
+    Ok(db)
+}
+
+// record count of one database
+fn db_entries<T: Transaction>(txn: &T, db: lmdb::Database) -> Result<u64> {
+    let mut st = std::mem::MaybeUninit::<ffi::MDB_stat>::uninit();
+    let rc = unsafe { ffi::mdb_stat(txn.txn(), db.dbi(), st.as_mut_ptr()) };
+    if rc != 0 {
+        return Err(lmdb::Error::from_err_code(rc).into());
+    }
+
+    let st = unsafe { st.assume_init() };
+    Ok(st.ms_entries as u64)
+}
+
+type Record<'txn> = Option<(&'txn [u8], &'txn [u8])>;
+
+// cursor get, mapping end-of-db to None
+fn cursor_get<'txn>(
+    cursor: &lmdb::RoCursor<'txn>,
+    key: Option<&[u8]>,
+    op: c_uint,
+) -> Result<Record<'txn>> {
+    match cursor.get(key, None, op) {
+        Ok((Some(key), value)) => Ok(Some((key, value))),
+        Ok((None, _value)) => Err(lmdb::Error::Invalid.into()),
+        Err(lmdb::Error::NotFound) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// position cursor at the lowest key within lower bound
+fn cursor_seek_lower<'txn>(
+    cursor: &lmdb::RoCursor<'txn>,
+    lower: &Bound<Vec<u8>>,
+) -> Result<Record<'txn>> {
+    match lower {
+        Bound::Unbounded => cursor_get(cursor, None, ffi::MDB_FIRST),
+        Bound::Included(lo) => cursor_get(cursor, Some(lo), ffi::MDB_SET_RANGE),
+        Bound::Excluded(lo) => match cursor_get(cursor, Some(lo), ffi::MDB_SET_RANGE)? {
+            Some((key, _value)) if key == &lo[..] => cursor_get(cursor, None, ffi::MDB_NEXT),
+            rec => Ok(rec),
+        },
+    }
+}
+
+// position cursor at the highest key within upper bound
+fn cursor_seek_upper<'txn>(
+    cursor: &lmdb::RoCursor<'txn>,
+    upper: &Bound<Vec<u8>>,
+) -> Result<Record<'txn>> {
+    let (hi, inclusive) = match upper {
+        Bound::Unbounded => return cursor_get(cursor, None, ffi::MDB_LAST),
+        Bound::Included(hi) => (hi, true),
+        Bound::Excluded(hi) => (hi, false),
+    };
+
+    // SET_RANGE finds the first key >= hi; step back unless it is in range
+    match cursor_get(cursor, Some(hi), ffi::MDB_SET_RANGE)? {
+        None => cursor_get(cursor, None, ffi::MDB_LAST),
+        Some((key, value)) if inclusive && key == &hi[..] => Ok(Some((key, value))),
+        Some(_rec) => cursor_get(cursor, None, ffi::MDB_PREV),
     }
 }
 
+// is key beyond the bound at which iteration stops?
+fn past_bound(key: &[u8], bound: &Bound<Vec<u8>>, reverse: bool) -> bool {
+    match (bound, reverse) {
+        (Bound::Unbounded, _) => false,
+        (Bound::Included(k), false) => key > &k[..],
+        (Bound::Excluded(k), false) => key >= &k[..],
+        (Bound::Included(k), true) => key < &k[..],
+        (Bound::Excluded(k), true) => key <= &k[..],
+    }
+}
+
+// apply driver options to environment builder
+fn apply_options(cfg_builder: &mut lmdb::EnvironmentBuilder, options: &api::Options) -> Result<()> {
+    api::check_option_names(options, LMDB_OPTIONS)?;
+
+    if let Some(n_bytes) = api::parse_option(options, "map_size")? {
+        cfg_builder.set_map_size(n_bytes);
+    }
+    if let Some(n_readers) = api::parse_option(options, "max_readers")? {
+        cfg_builder.set_max_readers(n_readers);
+    }
+    if let Some(n_dbs) = api::parse_option(options, "max_dbs")? {
+        cfg_builder.set_max_dbs(n_dbs);
+    }
+
+    Ok(())
+}
+
 pub struct LmdbDriver {}
 
 impl api::Driver for LmdbDriver {
-    fn start_db(&self, cfg: api::Config) -> Result<Box<dyn api::Db + Send>, &'static str> {
+    fn check_options(&self, options: &api::Options) -> Result<()> {
+        apply_options(&mut lmdb::Environment::new(), options)
+    }
+
+    fn start_db(&self, cfg: api::Config) -> Result<Box<dyn api::Db>> {
         let mut cfg_builder = lmdb::Environment::new();
+        cfg_builder.set_max_dbs(MAX_DBS);
+        apply_options(&mut cfg_builder, &cfg.options)?;
+        let no_sync = lmdb::EnvironmentFlags::NO_SYNC | lmdb::EnvironmentFlags::NO_META_SYNC;
+        let mut flush_interval = None;
         if cfg.read_only {
             cfg_builder = *cfg_builder.set_flags(lmdb::EnvironmentFlags::READ_ONLY);
+        } else {
+            // LMDB syncs each commit by default
+            match cfg.durability {
+                api::Durability::Default | api::Durability::Sync => {}
+                api::Durability::Periodic(0) => {
+                    return Err(Error::InvalidArgument(String::from(
+                        "flush interval must be positive",
+                    )))
+                }
+                api::Durability::Periodic(ms) => {
+                    cfg_builder = *cfg_builder.set_flags(no_sync);
+                    flush_interval = Some(Duration::from_millis(ms));
+                }
+                api::Durability::NoSync => {
+                    cfg_builder = *cfg_builder.set_flags(no_sync);
+                }
+            }
         }
         let path = Path::new(&cfg.path);
 
         let db_env_res = cfg_builder.open(path);
         match db_env_res {
-            Err(_e) => Err("env-open failed"),
+            Err(e) => Err(e.into()),
             Ok(env) => {
-                let db = env.create_db(None, lmdb::DatabaseFlags::empty()).unwrap();
-                Ok(Box::new(LmdbWrapper { env, db }) as Box<dyn api::Db + Send>)
+                // open registry and namespaces, if any
+                let registry = match env.open_db(Some(NS_REGISTRY)) {
+                    Ok(registry) => Some(registry),
+                    Err(lmdb::Error::NotFound) => None,
+                    Err(e) => return Err(e.into()),
+                };
+                let mut nss = HashMap::new();
+                if let Some(registry) = registry {
+                    let mut names: Vec<String> = Vec::new();
+                    {
+                        let txn = env.begin_ro_txn()?;
+                        let cursor = txn.open_ro_cursor(registry)?;
+                        let mut rec = cursor_get(&cursor, None, ffi::MDB_FIRST)?;
+                        while let Some((key, _value)) = rec {
+                            names.push(String::from_utf8_lossy(key).into_owned());
+                            rec = cursor_get(&cursor, None, ffi::MDB_NEXT)?;
+                        }
+                    }
+                    for name in names {
+                        let ns_db = env.open_db(Some(&name))?;
+                        nss.insert(name, ns_db);
+                    }
+                }
+
+                // databases written before namespaces keep the default
+                // keyspace in the main db: move it on first writable open,
+                // and read it in place until then
+                let db = match env.open_db(Some(DEFAULT_DB)) {
+                    Ok(db) => db,
+                    Err(lmdb::Error::NotFound) if !cfg.read_only => create_default_db(&env, &nss)?,
+                    Err(lmdb::Error::NotFound) => env.open_db(None)?,
+                    Err(e) => return Err(e.into()),
+                };
+
+                let env = Arc::new(env);
+                let flusher = match flush_interval {
+                    None => None,
+                    Some(interval) => Some(Flusher::start(Arc::clone(&env), interval)?),
+                };
+
+                ttl::wrap(version::wrap(Box::new(LmdbWrapper {
+                    env,
+                    db,
+                    registry,
+                    nss,
+                    read_only: cfg.read_only,
+                    _flusher: flusher,
+                }))?)
             }
         }
     }
@@ -229,7 +554,9 @@ pub fn new_driver() -> Box<dyn api::Driver> {
 }
 
 #[cfg(test)]
-use super::api::{Batch, ConfigBuilder};
+use super::api::ConfigBuilder;
+#[cfg(test)]
+use super::conformance;
 #[cfg(test)]
 use tempdir::TempDir;
 
@@ -238,185 +565,145 @@ mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
 
-    #[test]
-    fn test_get_put() {
-        let tmp_dir = TempDir::new("tgp").unwrap();
-        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
-        let db_config = ConfigBuilder::new().path(tmp_path).read_only(false).build();
-
-        let driver = new_driver();
-
-        let mut db = driver.start_db(db_config).unwrap();
-
-        assert_eq!(db.get(b"name"), Ok(None));
-        assert_eq!(db.put(b"name", b"alan"), Ok(true));
-        assert_eq!(db.get(b"name"), Ok(Some(Vec::from("alan"))));
-        assert_eq!(db.del(b"name"), Ok(true));
-        assert_eq!(db.get(b"name"), Ok(None));
-        assert_eq!(db.get(b"never_existed"), Ok(None));
+    // database in dir, with default options
+    fn config(dir: &str) -> ConfigBuilder {
+        let mut builder = ConfigBuilder::new();
+        builder.path(dir.to_string());
+        builder
     }
 
     #[test]
-    fn test_del() {
-        let tmp_dir = TempDir::new("td").unwrap();
-        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
-        let db_config = ConfigBuilder::new().path(tmp_path).read_only(false).build();
-
-        let driver = new_driver();
-
-        let mut db = driver.start_db(db_config).unwrap();
-
-        assert_eq!(db.put(b"name", b"alan"), Ok(true));
-        assert_eq!(db.del(b"name"), Ok(true));
-        assert_eq!(db.del(b"name"), Ok(false));
+    fn test_conformance() {
+        conformance::run(new_driver().as_ref(), &config);
     }
 
     #[test]
-    fn test_batch() {
-        let tmp_dir = TempDir::new("tb").unwrap();
-        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
-        let db_config = ConfigBuilder::new().path(tmp_path).read_only(false).build();
-
-        let driver = new_driver();
-
-        let mut db = driver.start_db(db_config).unwrap();
-
-        assert_eq!(db.put(b"name", b"alan"), Ok(true));
-
-        let mut batch = Batch::default();
-        batch.insert(b"age", b"25");
-        batch.insert(b"city", b"anytown");
-        batch.remove(b"name");
-        assert_eq!(db.apply_batch(&batch), Ok(true));
-
-        assert_eq!(db.get(b"name"), Ok(None));
-        assert_eq!(db.get(b"age"), Ok(Some(Vec::from("25"))));
-        assert_eq!(db.get(b"city"), Ok(Some(Vec::from("anytown"))));
+    fn test_scan_ordered() {
+        conformance::scan_ordered(new_driver().as_ref(), &config);
     }
 
     #[test]
-    fn test_clear() {
-        let tmp_dir = TempDir::new("tc").unwrap();
-        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
-        let db_config = ConfigBuilder::new().path(tmp_path).read_only(false).build();
-
-        let driver = new_driver();
-
-        let mut db = driver.start_db(db_config).unwrap();
-
-        assert_eq!(db.put(b"name", b"alan"), Ok(true));
-        assert_eq!(db.put(b"age", b"25"), Ok(true));
-        assert_eq!(db.get(b"name"), Ok(Some(Vec::from("alan"))));
-        assert_eq!(db.clear(), Ok(true));
-        assert_eq!(db.get(b"name"), Ok(None));
-        assert_eq!(db.get(b"age"), Ok(None));
+    fn test_durability() {
+        conformance::durability(new_driver().as_ref(), &config);
     }
 
     #[test]
-    fn test_stat() {
-        let tmp_dir = TempDir::new("tc").unwrap();
+    fn test_storage_stat() {
+        let tmp_dir = TempDir::new("tss").unwrap();
         let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
         let db_config = ConfigBuilder::new().path(tmp_path).read_only(false).build();
 
         let driver = new_driver();
 
         let mut db = driver.start_db(db_config).unwrap();
+        let st = db.storage_stat().unwrap();
+        let empty_bytes = st.disk_bytes.unwrap();
+        assert!(st.map_bytes.unwrap() >= empty_bytes);
 
-        assert_eq!(db.put(b"name1", b"alan"), Ok(true));
-        assert_eq!(db.put(b"age1", b"25"), Ok(true));
-        assert_eq!(db.put(b"name", b"alan"), Ok(true));
-        assert_eq!(db.del(b"name"), Ok(true));
-        assert_eq!(db.del(b"name"), Ok(false));
-
-        let st = db.stat().unwrap();
-        assert_eq!(st.n_records, 2);
+        for i in 0..1000 {
+            assert!(db.put(format!("key{}", i).as_bytes(), &[0u8; 100]).unwrap());
+        }
+        let st = db.storage_stat().unwrap();
+        assert!(st.disk_bytes.unwrap() > empty_bytes);
     }
 
     #[test]
-    fn test_iter() {
-        let tmp_dir = TempDir::new("tc").unwrap();
-        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
-        let db_config = ConfigBuilder::new().path(tmp_path).read_only(false).build();
-
+    fn test_options() {
         let driver = new_driver();
+        let options = |pairs: &[(&str, &str)]| -> api::Options {
+            pairs
+                .iter()
+                .map(|(key, val)| (key.to_string(), val.to_string()))
+                .collect()
+        };
 
+        assert!(driver
+            .check_options(&options(&[("map_size", "1048576"), ("max_readers", "16")]))
+            .is_ok());
+        assert!(matches!(
+            driver.check_options(&options(&[("cache_capacity", "1024")])),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            driver.check_options(&options(&[("map_size", "1MB")])),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        let tmp_dir = TempDir::new("topt").unwrap();
+        let db_config = ConfigBuilder::new()
+            .path(tmp_dir.path().to_str().unwrap().to_string())
+            .option("map_size", "1048576")
+            .option("max_dbs", "4")
+            .build();
         let mut db = driver.start_db(db_config).unwrap();
-
-        // iterate empty list
-        let key_list_res = db.iter_keys(api::IterOptions::new());
-        assert_eq!(key_list_res.is_err(), false);
-
-        let mut key_list = key_list_res.unwrap();
-        assert_eq!(key_list.list_end, true);
-
-        key_list.keys.sort();
-        assert_eq!(key_list.keys.len(), 0);
-
-        // iterate small list
-        assert_eq!(db.put(b"name", b"alan"), Ok(true));
-        assert_eq!(db.put(b"age", b"25"), Ok(true));
-
-        let key_list_res = db.iter_keys(api::IterOptions::new());
-        assert_eq!(key_list_res.is_err(), false);
-
-        let mut key_list = key_list_res.unwrap();
-        assert_eq!(key_list.list_end, true);
-
-        key_list.keys.sort();
-        assert_eq!(key_list.keys.len(), 2);
-        assert_eq!(key_list.keys[0], b"age");
-        assert_eq!(key_list.keys[1], b"name");
+        assert_eq!(db.storage_stat().unwrap().map_bytes, Some(1048576));
+
+        // default keyspace, registry, versions, and one namespace
+        assert!(db.put(b"name", b"alan").unwrap());
+        assert!(db.open_ns("users").unwrap());
+        assert!(matches!(db.open_ns("groups"), Err(Error::Full(_))));
+
+        // small map fills up
+        let res = (0..1000).try_for_each(|i| {
+            db.put(format!("key{}", i).as_bytes(), &[0u8; 2000])
+                .map(|_ok| ())
+        });
+        assert!(matches!(res, Err(Error::Full(_))));
+
+        // unknown option fails open
+        let tmp_dir = TempDir::new("topt").unwrap();
+        let db_config = ConfigBuilder::new()
+            .path(tmp_dir.path().to_str().unwrap().to_string())
+            .option("mapsize", "1048576")
+            .build();
+        assert!(matches!(
+            driver.start_db(db_config),
+            Err(Error::InvalidArgument(_))
+        ));
     }
 
     #[test]
-    fn test_iter_prefix() {
-        let tmp_dir = TempDir::new("tc").unwrap();
+    fn test_main_db_records_moved() {
+        let tmp_dir = TempDir::new("tmm").unwrap();
         let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
-        let db_config = ConfigBuilder::new().path(tmp_path).read_only(false).build();
+
+        // default keyspace in the main db, as written before namespaces
+        {
+            let env = lmdb::Environment::new().open(tmp_dir.path()).unwrap();
+            let main_db = env.open_db(None).unwrap();
+            let mut txn = env.begin_rw_txn().unwrap();
+            txn.put(main_db, b"name", b"alan", lmdb::WriteFlags::empty())
+                .unwrap();
+            txn.put(main_db, b"age", b"41", lmdb::WriteFlags::empty())
+                .unwrap();
+            txn.commit().unwrap();
+        }
 
         let driver = new_driver();
 
-        let mut db = driver.start_db(db_config).unwrap();
+        // read in place while read-only
+        {
+            let db_config = ConfigBuilder::new()
+                .path(tmp_path.clone())
+                .read_only(true)
+                .build();
+            let db = driver.start_db(db_config).unwrap();
+            assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+        }
+
+        // moved on writable open, leaving the main db to named databases
+        {
+            let db_config = ConfigBuilder::new().path(tmp_path.clone()).build();
+            let mut db = driver.start_db(db_config).unwrap();
+            assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+            assert_eq!(db.stat().unwrap().n_records, 2);
+            assert!(db.open_ns("name").unwrap());
+            db.close().unwrap();
+        }
 
-        // iterate small list
-        assert_eq!(db.put(b"2018/name", b"alan"), Ok(true));
-        assert_eq!(db.put(b"2018/bame", b"alan"), Ok(true));
-        assert_eq!(db.put(b"2019/fame", b"alan"), Ok(true));
-        assert_eq!(db.put(b"2019/lame", b"alan"), Ok(true));
-        assert_eq!(db.put(b"2019/game", b"alan"), Ok(true));
-        assert_eq!(db.put(b"2020/tame", b"alan"), Ok(true));
-        assert_eq!(db.put(b"age", b"25"), Ok(true));
-
-        let key_list_res = db.iter_keys(api::IterOptions::new());
-        assert_eq!(key_list_res.is_err(), false);
-
-        let key_list = key_list_res.unwrap();
-        assert_eq!(key_list.list_end, true);
-        assert_eq!(key_list.keys.len(), 7);
-
-        // iterate with prefix matching
-        let mut opts = api::IterOptions::new();
-        opts.prefix(b"2019/");
-
-        let key_list_res = db.iter_keys(opts);
-        assert_eq!(key_list_res.is_err(), false);
-
-        let mut key_list = key_list_res.unwrap();
-        assert_eq!(key_list.list_end, true);
-        assert_eq!(key_list.keys.len(), 3);
-
-        key_list.keys.sort();
-        assert_eq!(
-            String::from_utf8_lossy(&key_list.keys[0]),
-            String::from("2019/fame")
-        );
-        assert_eq!(
-            String::from_utf8_lossy(&key_list.keys[1]),
-            String::from("2019/game")
-        );
-        assert_eq!(
-            String::from_utf8_lossy(&key_list.keys[2]),
-            String::from("2019/lame")
-        );
+        let db_config = ConfigBuilder::new().path(tmp_path).build();
+        let db = driver.start_db(db_config).unwrap();
+        assert_eq!(db.get(b"age").unwrap(), Some(Vec::from("41")));
+        assert_eq!(db.get_ns(Some("name"), b"age").unwrap(), None);
     }
 }
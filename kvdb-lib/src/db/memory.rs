@@ -0,0 +1,563 @@
+/*
+ * memory: in-memory driver, over ordered maps.
+ *
+ * Each keyspace is a BTreeMap, so scans page in key order like the
+ * on-disk drivers.  With the "snapshot" option set, the whole database
+ * is written to a snapshot file in the database directory on flush and
+ * close, and read back on start.  The snapshot is replaced atomically:
+ * written to a temporary file, synced, then renamed over the old one.
+ *
+ * Snapshot format, integers little-endian:
+ *
+ *   "KVDBMEM1"
+ *   u32 keyspaces
+ *   per keyspace:  u32 ns_len (0: default keyspace), ns, u64 records
+ *   per record:    u32 key_len, key, u32 val_len, val
+ *   u32 crc        CRC-32 of everything before it
+ */
+
+use super::crc::crc32;
+use super::{api, ttl, version};
+use crate::{Error, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// snapshot file, within the database directory
+const SNAPSHOT_FILE: &str = "kvdb-memory.snap";
+
+const SNAPSHOT_MAGIC: &[u8] = b"KVDBMEM1";
+
+// driver options
+const MEMORY_OPTIONS: &[&str] = &["snapshot"];
+
+type KeySpace = BTreeMap<Vec<u8>, Vec<u8>>;
+
+// batch mutations applied so far: namespace, key, prior value
+type UndoLog = Vec<(Option<String>, Vec<u8>, Option<Vec<u8>>)>;
+
+pub struct MemoryDb {
+    db: BTreeMap<Option<String>, KeySpace>, // None: default keyspace
+    read_only: bool,
+    snapshot: Option<PathBuf>, // snapshot file, if enabled
+    dirty: Mutex<bool>,        // changed since last snapshot; held while writing one
+}
+
+impl MemoryDb {
+    // fail writes to a read-only database
+    fn writable(&self) -> Result<()> {
+        match self.read_only {
+            true => Err(Error::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    // note a change, to be written by the next flush
+    fn mark_dirty(&mut self) {
+        *self.dirty.get_mut().unwrap() = true;
+    }
+
+    fn keyspace(&self, ns: Option<&str>) -> Result<&KeySpace> {
+        self.db.get(&ns.map(String::from)).ok_or(Error::NotFound)
+    }
+
+    // keyspace for a write, marking the database changed
+    fn keyspace_mut(&mut self, ns: Option<&str>) -> Result<&mut KeySpace> {
+        self.writable()?;
+        self.mark_dirty();
+        self.db
+            .get_mut(&ns.map(String::from))
+            .ok_or(Error::NotFound)
+    }
+
+    // apply batch mutations, recording prior values in undo
+    fn apply_ops(&mut self, batch: &api::Batch, undo: &mut UndoLog) -> Result<()> {
+        for (idx, mutation) in batch.ops.iter().enumerate() {
+            let keyspace = self.keyspace_mut(mutation.ns.as_deref())?;
+
+            if let Some(cond) = &mutation.condition {
+                cond.check(
+                    idx,
+                    &mutation.key,
+                    keyspace.get(&mutation.key).map(|v| &v[..]),
+                )?;
+            }
+
+            let old_val = match mutation.op {
+                api::MutationOp::Insert => {
+                    keyspace.insert(mutation.key.clone(), mutation.value.clone().unwrap())
+                }
+                api::MutationOp::Remove => keyspace.remove(&mutation.key),
+            };
+            undo.push((mutation.ns.clone(), mutation.key.clone(), old_val));
+        }
+
+        Ok(())
+    }
+
+    // restore prior values, latest first
+    fn rollback(&mut self, undo: UndoLog) {
+        for (ns, key, old_val) in undo.into_iter().rev() {
+            let keyspace = self.db.get_mut(&ns).unwrap();
+            match old_val {
+                None => keyspace.remove(&key),
+                Some(val) => keyspace.insert(key, val),
+            };
+        }
+    }
+}
+
+impl api::Db for MemoryDb {
+    fn clear_ns(&mut self, ns: Option<&str>) -> Result<bool> {
+        self.keyspace_mut(ns)?.clear();
+        Ok(true)
+    }
+
+    fn stat_ns(&self, ns: Option<&str>) -> Result<api::DbStat> {
+        Ok(api::DbStat {
+            n_records: self.keyspace(ns)?.len() as u64,
+        })
+    }
+
+    fn flush(&self) -> Result<()> {
+        let path = match &self.snapshot {
+            None => return Ok(()),
+            Some(path) => path,
+        };
+        // a concurrent flush returns only once the snapshot it would
+        // have written is on disk; a failed write leaves changes unsaved
+        let mut dirty = self.dirty.lock().unwrap();
+        if self.read_only || !*dirty {
+            return Ok(());
+        }
+
+        write_snapshot(path, &self.db)?;
+        *dirty = false;
+        Ok(())
+    }
+
+    fn storage_stat(&self) -> Result<api::StorageStat> {
+        let disk_bytes = match &self.snapshot {
+            None => None,
+            Some(path) => match fs::metadata(path) {
+                Ok(md) => Some(md.len()),
+                Err(e) if e.kind() == ErrorKind::NotFound => Some(0),
+                Err(e) => return Err(e.into()),
+            },
+        };
+
+        Ok(api::StorageStat {
+            disk_bytes,
+            map_bytes: None,
+        })
+    }
+
+    fn get_ns(&self, ns: Option<&str>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.keyspace(ns)?.get(key) {
+            None => Ok(None),
+            Some(val) => Ok(Some(val.to_vec())),
+        }
+    }
+
+    fn put_ns(&mut self, ns: Option<&str>, key: &[u8], val: &[u8]) -> Result<bool> {
+        self.keyspace_mut(ns)?.insert(key.to_vec(), val.to_vec());
+        Ok(true)
+    }
+
+    fn del_ns(&mut self, ns: Option<&str>, key: &[u8]) -> Result<bool> {
+        match self.keyspace_mut(ns)?.remove(key) {
+            None => Ok(false),
+            Some(_v) => Ok(true),
+        }
+    }
+
+    fn apply_batch(&mut self, batch: &api::Batch) -> Result<bool> {
+        self.writable()?;
+
+        // apply in place; undo all if any mutation fails
+        let mut undo = Vec::with_capacity(batch.ops.len());
+        match self.apply_ops(batch, &mut undo) {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                self.rollback(undo);
+                Err(e)
+            }
+        }
+    }
+
+    fn scan(&self, opts: api::IterOptions) -> Result<api::KvList> {
+        let mut page = api::ScanPage::new(&opts);
+        let keyspace = self.keyspace(opts.ns.as_deref())?;
+
+        // key range covers start/end keys and prefix
+        let range = match opts.key_range() {
+            None => return Ok(page.finish()),
+            Some(range) => range,
+        };
+        let iter: Box<dyn Iterator<Item = (&Vec<u8>, &Vec<u8>)>> = match opts.reverse {
+            false => Box::new(keyspace.range(range)),
+            true => Box::new(keyspace.range(range).rev()),
+        };
+
+        for (key, value) in iter {
+            if !page.push(key, value) {
+                break;
+            }
+        }
+
+        Ok(page.finish())
+    }
+
+    fn open_ns(&mut self, ns: &str) -> Result<bool> {
+        api::validate_ns_name(ns)?;
+        let ns = Some(ns.to_string());
+        if !self.db.contains_key(&ns) {
+            self.writable()?;
+            self.mark_dirty();
+            self.db.insert(ns, KeySpace::new());
+        }
+
+        Ok(true)
+    }
+
+    fn drop_ns(&mut self, ns: &str) -> Result<bool> {
+        self.writable()?;
+        match self.db.remove(&Some(ns.to_string())) {
+            None => Ok(false),
+            Some(_keyspace) => {
+                self.mark_dirty();
+                Ok(true)
+            }
+        }
+    }
+
+    fn list_ns(&self) -> Result<Vec<String>> {
+        // BTreeMap: already in name order
+        Ok(self.db.keys().flatten().cloned().collect())
+    }
+}
+
+// write database to snapshot file at path, replacing any earlier one
+fn write_snapshot(path: &Path, db: &BTreeMap<Option<String>, KeySpace>) -> Result<()> {
+    let mut data = SNAPSHOT_MAGIC.to_vec();
+    data.extend_from_slice(&(db.len() as u32).to_le_bytes());
+    for (ns, keyspace) in db {
+        let ns = ns.as_deref().unwrap_or("");
+        data.extend_from_slice(&(ns.len() as u32).to_le_bytes());
+        data.extend_from_slice(ns.as_bytes());
+        data.extend_from_slice(&(keyspace.len() as u64).to_le_bytes());
+        for (key, val) in keyspace {
+            data.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            data.extend_from_slice(key);
+            data.extend_from_slice(&(val.len() as u32).to_le_bytes());
+            data.extend_from_slice(val);
+        }
+    }
+    let crc = crc32(&data);
+    data.extend_from_slice(&crc.to_le_bytes());
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+// reads fields from snapshot data, failing on truncation
+struct SnapshotReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> SnapshotReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(Error::Corruption(String::from("memory snapshot truncated")));
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<usize> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let bytes = self.bytes(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+// read database from snapshot file at path
+fn read_snapshot(path: &Path) -> Result<BTreeMap<Option<String>, KeySpace>> {
+    let corrupt = |msg: &str| Error::Corruption(format!("memory snapshot: {}", msg));
+
+    let data = fs::read(path)?;
+    if data.len() < SNAPSHOT_MAGIC.len() + 4 || !data.starts_with(SNAPSHOT_MAGIC) {
+        return Err(corrupt("not a snapshot file"));
+    }
+    let (body, crc) = data.split_at(data.len() - 4);
+    if crc32(body) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return Err(corrupt("checksum mismatch"));
+    }
+
+    let mut rdr = SnapshotReader {
+        data: &body[SNAPSHOT_MAGIC.len()..],
+    };
+    let mut db = BTreeMap::new();
+    for _ in 0..rdr.u32()? {
+        let ns_len = rdr.u32()?;
+        let ns = match ns_len {
+            0 => None,
+            _ => match std::str::from_utf8(rdr.bytes(ns_len)?) {
+                Ok(ns) => Some(ns.to_string()),
+                Err(_e) => return Err(corrupt("namespace name")),
+            },
+        };
+
+        let mut keyspace = KeySpace::new();
+        for _ in 0..rdr.u64()? {
+            let key_len = rdr.u32()?;
+            let key = rdr.bytes(key_len)?.to_vec();
+            let val_len = rdr.u32()?;
+            let val = rdr.bytes(val_len)?.to_vec();
+            keyspace.insert(key, val);
+        }
+        db.insert(ns, keyspace);
+    }
+    if !rdr.data.is_empty() {
+        return Err(corrupt("trailing data"));
+    }
+
+    Ok(db)
+}
+
+// snapshot option: disabled unless "true"
+fn snapshot_enabled(options: &api::Options) -> Result<bool> {
+    api::check_option_names(options, MEMORY_OPTIONS)?;
+    Ok(api::parse_option(options, "snapshot")?.unwrap_or(false))
+}
+
+pub struct MemoryDriver {}
+
+impl api::Driver for MemoryDriver {
+    fn check_options(&self, options: &api::Options) -> Result<()> {
+        snapshot_enabled(options)?;
+        Ok(())
+    }
+
+    fn start_db(&self, cfg: api::Config) -> Result<Box<dyn api::Db>> {
+        // writes reach the snapshot only on flush and close
+        if !cfg.read_only {
+            match cfg.durability {
+                api::Durability::Default | api::Durability::NoSync => {}
+                _ => {
+                    return Err(Error::InvalidArgument(String::from(
+                        "memory driver supports only default and nosync durability",
+                    )))
+                }
+            }
+        }
+
+        let snapshot = match snapshot_enabled(&cfg.options)? {
+            false => None,
+            true => Some(Path::new(&cfg.path).join(SNAPSHOT_FILE)),
+        };
+
+        // load snapshot, if any; a read-only database must have one
+        let mut db = match &snapshot {
+            Some(path) if path.is_file() => read_snapshot(path)?,
+            Some(_path) if cfg.read_only => return Err(Error::NotFound),
+            _ => BTreeMap::new(),
+        };
+        db.entry(None).or_default();
+
+        ttl::wrap(version::wrap(Box::new(MemoryDb {
+            db,
+            read_only: cfg.read_only,
+            snapshot,
+            dirty: Mutex::new(false),
+        }))?)
+    }
+}
+
+pub fn new_driver() -> Box<dyn api::Driver> {
+    Box::new(MemoryDriver {})
+}
+
+#[cfg(test)]
+use super::api::{ConfigBuilder, Durability};
+#[cfg(test)]
+use super::conformance;
+#[cfg(test)]
+use tempdir::TempDir;
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    // database in dir, writing a snapshot file to test persistence
+    fn config(dir: &str) -> ConfigBuilder {
+        let mut builder = ConfigBuilder::new();
+        builder.path(dir.to_string()).option("snapshot", "true");
+        builder
+    }
+
+    #[test]
+    fn test_conformance() {
+        conformance::run(new_driver().as_ref(), &config);
+    }
+
+    #[test]
+    fn test_scan_ordered() {
+        conformance::scan_ordered(new_driver().as_ref(), &config);
+    }
+
+    #[test]
+    fn test_storage_stat() {
+        let tmp_dir = TempDir::new("tss").unwrap();
+        let db_config = config(tmp_dir.path().to_str().unwrap()).build();
+
+        let driver = new_driver();
+
+        let mut db = driver.start_db(db_config).unwrap();
+        assert!(db.put(b"name1", b"alan").unwrap());
+
+        // snapshot file size; zero until first flush
+        let st = db.storage_stat().unwrap();
+        assert_eq!(st.disk_bytes, Some(0));
+        assert_eq!(st.map_bytes, None);
+    }
+
+    #[test]
+    fn test_durability() {
+        let driver = new_driver();
+
+        // snapshots are written only on flush and close
+        for durability in [Durability::Default, Durability::NoSync] {
+            let tmp_dir = TempDir::new("tdu").unwrap();
+            let db_config = config(tmp_dir.path().to_str().unwrap())
+                .durability(durability)
+                .build();
+            assert!(driver.start_db(db_config).is_ok());
+        }
+        for durability in [Durability::Sync, Durability::Periodic(10)] {
+            let tmp_dir = TempDir::new("tdu").unwrap();
+            let db_config = config(tmp_dir.path().to_str().unwrap())
+                .durability(durability)
+                .build();
+            assert!(matches!(
+                driver.start_db(db_config),
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_options() {
+        let driver = new_driver();
+        let options = |pairs: &[(&str, &str)]| -> api::Options {
+            pairs
+                .iter()
+                .map(|(key, val)| (key.to_string(), val.to_string()))
+                .collect()
+        };
+
+        assert!(driver
+            .check_options(&options(&[("snapshot", "true")]))
+            .is_ok());
+        for bad in [("snapshot", "yes"), ("map_size", "1048576")] {
+            assert!(matches!(
+                driver.check_options(&options(&[bad])),
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+
+        // without snapshot, nothing is written, or read back
+        let tmp_dir = TempDir::new("topt").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+        {
+            let db_config = ConfigBuilder::new()
+                .path(tmp_path.clone())
+                .option("snapshot", "false")
+                .build();
+            let mut db = driver.start_db(db_config).unwrap();
+            assert!(db.put(b"name", b"alan").unwrap());
+            assert_eq!(db.storage_stat().unwrap().disk_bytes, None);
+            db.close().unwrap();
+        }
+        assert_eq!(std::fs::read_dir(tmp_dir.path()).unwrap().count(), 0);
+
+        let db_config = config(&tmp_path).build();
+        let db = driver.start_db(db_config).unwrap();
+        assert_eq!(db.get(b"name").unwrap(), None);
+    }
+
+    #[test]
+    fn test_flush_concurrent() {
+        let tmp_dir = TempDir::new("tfc").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+        let snap_path = tmp_dir.path().join(SNAPSHOT_FILE);
+
+        let driver = new_driver();
+
+        // every flush returns with the write on disk, whichever wrote it
+        for i in 0..20 {
+            let db_config = config(&tmp_path).build();
+            let mut db = driver.start_db(db_config).unwrap();
+            let key = format!("key{}", i);
+            assert!(db.put(key.as_bytes(), &vec![0u8; 100_000]).unwrap());
+
+            let db = &*db;
+            std::thread::scope(|scope| {
+                for _thread in 0..4 {
+                    scope.spawn(|| {
+                        db.flush().unwrap();
+                        let snap = read_snapshot(&snap_path).unwrap();
+                        assert!(snap[&None].contains_key(key.as_bytes()));
+                    });
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn test_snapshot_corrupt() {
+        let tmp_dir = TempDir::new("tsc").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+        let snap_path = tmp_dir.path().join(SNAPSHOT_FILE);
+
+        let driver = new_driver();
+
+        {
+            let db_config = config(&tmp_path).build();
+            let mut db = driver.start_db(db_config).unwrap();
+            assert!(db.put(b"name", b"alan").unwrap());
+            db.close().unwrap();
+        }
+        let data = std::fs::read(&snap_path).unwrap();
+
+        // truncated, or a flipped bit
+        let mut flipped = data.clone();
+        flipped[SNAPSHOT_MAGIC.len() + 6] ^= 1;
+        for bad in [&data[..data.len() - 1], &flipped[..]] {
+            std::fs::write(&snap_path, bad).unwrap();
+            let db_config = config(&tmp_path).build();
+            assert!(matches!(
+                driver.start_db(db_config),
+                Err(Error::Corruption(_))
+            ));
+        }
+    }
+}
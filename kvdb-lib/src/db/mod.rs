@@ -1,3 +1,14 @@
 pub mod api;
+pub mod bitcask;
+#[cfg(test)]
+mod conformance;
+mod crc;
+#[cfg(feature = "gdbm")]
+pub mod gdbm;
 pub mod lmdb;
+pub mod memory;
+pub mod redb;
 pub mod sled;
+pub mod sqlite;
+pub mod ttl;
+pub mod version;
@@ -0,0 +1,454 @@
+/*
+ * redb: driver over a redb database file.
+ *
+ * The database directory holds one redb file.  Each keyspace is a redb
+ * table: the default keyspace is table DEFAULT_TABLE, and namespace N is
+ * table NS_TABLE_PREFIX + N.
+ */
+
+use super::{api, ttl, version};
+use crate::{Error, Result};
+use redb::{ReadableDatabase, ReadableTable, ReadableTableMetadata, TableDefinition, TableHandle};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// database file, within the database directory
+const DB_FILE: &str = "kvdb.redb";
+
+// table names: default keyspace, and prefix of namespace tables
+const DEFAULT_TABLE: &str = "default";
+const NS_TABLE_PREFIX: &str = "ns.";
+
+// driver options, each a redb::Builder setting
+const REDB_OPTIONS: &[&str] = &["cache_size"];
+
+type KvTable<'a> = TableDefinition<'a, &'static [u8], &'static [u8]>;
+
+// table name for namespace; None is the default keyspace
+fn table_name(ns: Option<&str>) -> String {
+    match ns {
+        None => String::from(DEFAULT_TABLE),
+        Some(name) => format!("{}{}", NS_TABLE_PREFIX, name),
+    }
+}
+
+// commit an empty transaction durably, persisting earlier commits made
+// without durability
+fn sync_db(db: &redb::Database) -> Result<()> {
+    let mut txn = db.begin_write()?;
+    txn.set_durability(redb::Durability::Immediate)?;
+    txn.commit()?;
+
+    Ok(())
+}
+
+// background thread syncing a database written without durability, for
+// Durability::Periodic; stopped and joined on drop
+struct Flusher {
+    stop: Option<mpsc::Sender<()>>, // dropped to stop the thread
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Flusher {
+    fn start(db: Arc<redb::Database>, interval: Duration) -> Result<Flusher> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name(String::from("kvdb-redb-flush"))
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    // a failed sync is retried next interval, and on close
+                    let _res = sync_db(&db);
+                }
+            })?;
+
+        Ok(Flusher {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        // the thread holds a database handle; the file stays locked until
+        // it is closed
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _res = thread.join();
+        }
+    }
+}
+
+// database opened for writing, or read-only
+enum Handle {
+    ReadWrite(Arc<redb::Database>),
+    ReadOnly(redb::ReadOnlyDatabase),
+}
+
+pub struct RedbDb {
+    db: Handle,
+    path: PathBuf,                // database file
+    nss: HashSet<String>,         // open namespaces
+    durability: redb::Durability, // of each write transaction
+    _flusher: Option<Flusher>,    // Durability::Periodic
+}
+
+impl RedbDb {
+    fn begin_read(&self) -> Result<redb::ReadTransaction> {
+        let txn = match &self.db {
+            Handle::ReadWrite(db) => db.begin_read()?,
+            Handle::ReadOnly(db) => db.begin_read()?,
+        };
+
+        Ok(txn)
+    }
+
+    // write transaction, failing for a read-only database
+    fn begin_write(&self) -> Result<redb::WriteTransaction> {
+        match &self.db {
+            Handle::ReadOnly(_db) => Err(Error::ReadOnly),
+            Handle::ReadWrite(db) => {
+                let mut txn = db.begin_write()?;
+                txn.set_durability(self.durability)?;
+                Ok(txn)
+            }
+        }
+    }
+
+    // fail operations on a namespace never opened
+    fn check_ns(&self, ns: Option<&str>) -> Result<()> {
+        match ns {
+            Some(name) if !self.nss.contains(name) => Err(Error::NotFound),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl api::Db for RedbDb {
+    fn clear_ns(&mut self, ns: Option<&str>) -> Result<bool> {
+        let txn = self.begin_write()?;
+        self.check_ns(ns)?;
+
+        // drop and recreate the table
+        let name = table_name(ns);
+        txn.delete_table(KvTable::new(&name))?;
+        txn.open_table(KvTable::new(&name))?;
+        txn.commit()?;
+
+        Ok(true)
+    }
+
+    fn stat_ns(&self, ns: Option<&str>) -> Result<api::DbStat> {
+        self.check_ns(ns)?;
+        let txn = self.begin_read()?;
+        let table = txn.open_table(KvTable::new(&table_name(ns)))?;
+
+        Ok(api::DbStat {
+            n_records: table.len()?,
+        })
+    }
+
+    fn flush(&self) -> Result<()> {
+        match &self.db {
+            Handle::ReadOnly(_db) => Ok(()),
+            Handle::ReadWrite(db) => sync_db(db),
+        }
+    }
+
+    fn storage_stat(&self) -> Result<api::StorageStat> {
+        Ok(api::StorageStat {
+            disk_bytes: Some(fs::metadata(&self.path)?.len()),
+            map_bytes: None,
+        })
+    }
+
+    fn get_ns(&self, ns: Option<&str>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.check_ns(ns)?;
+        let txn = self.begin_read()?;
+        let table = txn.open_table(KvTable::new(&table_name(ns)))?;
+        let val = table.get(key)?;
+
+        Ok(val.map(|val| val.value().to_vec()))
+    }
+
+    fn put_ns(&mut self, ns: Option<&str>, key: &[u8], val: &[u8]) -> Result<bool> {
+        let txn = self.begin_write()?;
+        self.check_ns(ns)?;
+        {
+            let mut table = txn.open_table(KvTable::new(&table_name(ns)))?;
+            table.insert(key, val)?;
+        }
+        txn.commit()?;
+
+        Ok(true)
+    }
+
+    fn del_ns(&mut self, ns: Option<&str>, key: &[u8]) -> Result<bool> {
+        let txn = self.begin_write()?;
+        self.check_ns(ns)?;
+        let removed = {
+            let mut table = txn.open_table(KvTable::new(&table_name(ns)))?;
+            let old_val = table.remove(key)?;
+            old_val.is_some()
+        };
+        txn.commit()?;
+
+        Ok(removed)
+    }
+
+    fn apply_batch(&mut self, batch: &api::Batch) -> Result<bool> {
+        let txn = self.begin_write()?;
+
+        // one transaction over all tables touched by the batch; aborted
+        // if dropped uncommitted
+        let nss = batch.namespaces();
+        let names: Vec<String> = nss.iter().map(|ns| table_name(*ns)).collect();
+        let mut tables = Vec::new();
+        for (ns, name) in nss.iter().zip(&names) {
+            self.check_ns(*ns)?;
+            tables.push(txn.open_table(KvTable::new(name))?);
+        }
+
+        for (idx, mutation) in batch.ops.iter().enumerate() {
+            let table_idx = nss.iter().position(|ns| *ns == mutation.ns.as_deref());
+            let table = &mut tables[table_idx.unwrap()];
+
+            if let Some(cond) = &mutation.condition {
+                let cur_val = table.get(&mutation.key[..])?;
+                cond.check(idx, &mutation.key, cur_val.as_ref().map(|val| val.value()))?;
+            }
+
+            match mutation.op {
+                api::MutationOp::Insert => {
+                    table.insert(&mutation.key[..], &mutation.value.as_ref().unwrap()[..])?;
+                }
+                api::MutationOp::Remove => {
+                    table.remove(&mutation.key[..])?;
+                }
+            }
+        }
+        drop(tables);
+        txn.commit()?;
+
+        Ok(true)
+    }
+
+    fn scan(&self, opts: api::IterOptions) -> Result<api::KvList> {
+        let mut page = api::ScanPage::new(&opts);
+        self.check_ns(opts.ns.as_deref())?;
+        let txn = self.begin_read()?;
+        let table = txn.open_table(KvTable::new(&table_name(opts.ns.as_deref())))?;
+
+        // key range covers start/end keys and prefix
+        let (lower, upper) = match opts.key_range() {
+            None => return Ok(page.finish()),
+            Some(range) => range,
+        };
+        let range = (
+            lower.as_ref().map(|key| &key[..]),
+            upper.as_ref().map(|key| &key[..]),
+        );
+        let iter: Box<dyn Iterator<Item = _>> = match opts.reverse {
+            false => Box::new(table.range::<&[u8]>(range)?),
+            true => Box::new(table.range::<&[u8]>(range)?.rev()),
+        };
+
+        for rec in iter {
+            let (key, value) = rec?;
+            if !page.push(key.value(), value.value()) {
+                break;
+            }
+        }
+
+        Ok(page.finish())
+    }
+
+    fn open_ns(&mut self, ns: &str) -> Result<bool> {
+        api::validate_ns_name(ns)?;
+        if !self.nss.contains(ns) {
+            let txn = self.begin_write()?;
+            txn.open_table(KvTable::new(&table_name(Some(ns))))?;
+            txn.commit()?;
+            self.nss.insert(ns.to_string());
+        }
+
+        Ok(true)
+    }
+
+    fn drop_ns(&mut self, ns: &str) -> Result<bool> {
+        let txn = self.begin_write()?;
+        if !self.nss.contains(ns) {
+            return Ok(false);
+        }
+
+        txn.delete_table(KvTable::new(&table_name(Some(ns))))?;
+        txn.commit()?;
+        self.nss.remove(ns);
+
+        Ok(true)
+    }
+
+    fn list_ns(&self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = self.nss.iter().cloned().collect();
+        names.sort();
+
+        Ok(names)
+    }
+}
+
+// redb builder, with driver options applied
+fn builder(options: &api::Options) -> Result<redb::Builder> {
+    api::check_option_names(options, REDB_OPTIONS)?;
+
+    let mut builder = redb::Builder::new();
+    if let Some(n_bytes) = api::parse_option(options, "cache_size")? {
+        builder.set_cache_size(n_bytes);
+    }
+
+    Ok(builder)
+}
+
+pub struct RedbDriver {}
+
+impl api::Driver for RedbDriver {
+    fn check_options(&self, options: &api::Options) -> Result<()> {
+        builder(options)?;
+        Ok(())
+    }
+
+    fn start_db(&self, cfg: api::Config) -> Result<Box<dyn api::Db>> {
+        let builder = builder(&cfg.options)?;
+        let path = Path::new(&cfg.path).join(DB_FILE);
+
+        // each commit durable, or none until the next flush
+        let (durability, flush_interval) = match cfg.durability {
+            api::Durability::Default | api::Durability::Sync => (redb::Durability::Immediate, None),
+            api::Durability::Periodic(0) => {
+                return Err(Error::InvalidArgument(String::from(
+                    "flush interval must be positive",
+                )))
+            }
+            api::Durability::Periodic(ms) => {
+                (redb::Durability::None, Some(Duration::from_millis(ms)))
+            }
+            api::Durability::NoSync => (redb::Durability::None, None),
+        };
+
+        let (db, flusher) = match cfg.read_only {
+            true => {
+                if !path.is_file() {
+                    return Err(Error::NotFound);
+                }
+                (Handle::ReadOnly(builder.open_read_only(&path)?), None)
+            }
+            false => {
+                fs::create_dir_all(&cfg.path)?;
+                let db = Arc::new(builder.create(&path)?);
+
+                // default keyspace table exists from the start
+                let txn = db.begin_write()?;
+                txn.open_table(KvTable::new(DEFAULT_TABLE))?;
+                txn.commit()?;
+
+                let flusher = match flush_interval {
+                    None => None,
+                    Some(interval) => Some(Flusher::start(Arc::clone(&db), interval)?),
+                };
+                (Handle::ReadWrite(db), flusher)
+            }
+        };
+
+        let mut redb_db = RedbDb {
+            db,
+            path,
+            nss: HashSet::new(),
+            durability,
+            _flusher: flusher,
+        };
+
+        // open all existing namespaces
+        let txn = redb_db.begin_read()?;
+        for table in txn.list_tables()? {
+            if let Some(name) = table.name().strip_prefix(NS_TABLE_PREFIX) {
+                redb_db.nss.insert(name.to_string());
+            }
+        }
+        drop(txn);
+
+        ttl::wrap(version::wrap(Box::new(redb_db))?)
+    }
+}
+
+pub fn new_driver() -> Box<dyn api::Driver> {
+    Box::new(RedbDriver {})
+}
+
+#[cfg(test)]
+use super::api::ConfigBuilder;
+#[cfg(test)]
+use super::conformance;
+#[cfg(test)]
+use tempdir::TempDir;
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    // database in dir, with default options
+    fn config(dir: &str) -> ConfigBuilder {
+        let mut builder = ConfigBuilder::new();
+        builder.path(dir.to_string());
+        builder
+    }
+
+    #[test]
+    fn test_conformance() {
+        conformance::run(new_driver().as_ref(), &config);
+    }
+
+    #[test]
+    fn test_scan_ordered() {
+        conformance::scan_ordered(new_driver().as_ref(), &config);
+    }
+
+    #[test]
+    fn test_durability() {
+        conformance::durability(new_driver().as_ref(), &config);
+    }
+
+    #[test]
+    fn test_options() {
+        let driver = new_driver();
+        let options = |pairs: &[(&str, &str)]| -> api::Options {
+            pairs
+                .iter()
+                .map(|(key, val)| (key.to_string(), val.to_string()))
+                .collect()
+        };
+
+        assert!(driver
+            .check_options(&options(&[("cache_size", "1048576")]))
+            .is_ok());
+        for bad in [("map_size", "1048576"), ("cache_size", "-1")] {
+            assert!(matches!(
+                driver.check_options(&options(&[bad])),
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+
+        let tmp_dir = TempDir::new("topt").unwrap();
+        let db_config = ConfigBuilder::new()
+            .path(tmp_dir.path().to_str().unwrap().to_string())
+            .option("cache_size", "1048576")
+            .build();
+        let mut db = driver.start_db(db_config).unwrap();
+        assert!(db.put(b"name", b"alan").unwrap());
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+    }
+}
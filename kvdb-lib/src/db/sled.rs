@@ -1,51 +1,119 @@
-use super::api;
+use super::{api, ttl, version};
+use crate::{Error, Result};
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+use std::collections::HashMap;
+use std::fs::File;
+use std::mem::ManuallyDrop;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// name sled gives the default tree, listed by tree_names()
+const SLED_DEFAULT_TREE: &[u8] = b"__sled__default";
+
+// driver options, each a sled::Config setting
+const SLED_OPTIONS: &[&str] = &["cache_capacity", "mode", "use_compression"];
+
+// file in the db directory sled holds locked while open
+const SLED_LOCK_FILE: &str = "db";
+
+// longest wait on drop for sled to release its lock, and poll interval
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+const CLOSE_POLL: Duration = Duration::from_millis(5);
 
 pub struct SledDb {
-    db: sled::Db,
+    db: ManuallyDrop<sled::Db>,         // dropped first, in drop()
+    trees: HashMap<String, sled::Tree>, // open namespaces
+    lock_path: PathBuf,                 // SLED_LOCK_FILE, released on drop
+    sync_writes: bool,                  // Durability::Sync: flush each write
+}
+
+impl SledDb {
+    // with Durability::Sync, flush a completed write before returning
+    fn synced<T>(&self, res: Result<T>) -> Result<T> {
+        if self.sync_writes && res.is_ok() {
+            self.db.flush()?;
+        }
+        res
+    }
+
+    // lookup tree for namespace; None is the default tree
+    fn tree(&self, ns: Option<&str>) -> Result<&sled::Tree> {
+        match ns {
+            None => Ok(&self.db),
+            Some(name) => self.trees.get(name).ok_or(Error::NotFound),
+        }
+    }
 }
 
 impl api::Db for SledDb {
-    fn clear(&mut self) -> Result<bool, &'static str> {
-        match self.db.clear() {
+    fn clear_ns(&mut self, ns: Option<&str>) -> Result<bool> {
+        let res = match self.tree(ns)?.clear() {
             Ok(_) => Ok(true),
-            Err(_e) => Err("clear failed"),
-        }
+            Err(e) => Err(e.into()),
+        };
+        self.synced(res)
     }
 
-    fn stat(&self) -> Result<api::DbStat, &'static str> {
+    fn stat_ns(&self, ns: Option<&str>) -> Result<api::DbStat> {
         Ok(api::DbStat {
-            n_records: self.db.len() as u64,
+            n_records: self.tree(ns)?.len() as u64,
         })
     }
 
-    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, &'static str> {
-        match self.db.get(key) {
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn storage_stat(&self) -> Result<api::StorageStat> {
+        match self.db.size_on_disk() {
+            Ok(n_bytes) => Ok(api::StorageStat {
+                disk_bytes: Some(n_bytes),
+                map_bytes: None,
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn get_ns(&self, ns: Option<&str>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.tree(ns)?.get(key) {
             Ok(opt_val) => match opt_val {
                 None => Ok(None),
                 Some(val) => Ok(Some(val.to_vec())),
             },
-            Err(_e) => Err("get failed"),
+            Err(e) => Err(e.into()),
         }
     }
 
-    fn put(&mut self, key: &[u8], val: &[u8]) -> Result<bool, &'static str> {
-        match self.db.insert(key, val) {
+    fn put_ns(&mut self, ns: Option<&str>, key: &[u8], val: &[u8]) -> Result<bool> {
+        let res = match self.tree(ns)?.insert(key, val) {
             Ok(_old_val) => Ok(true),
-            Err(_e) => Err("put failed"),
-        }
+            Err(e) => Err(e.into()),
+        };
+        self.synced(res)
     }
 
-    fn del(&mut self, key: &[u8]) -> Result<bool, &'static str> {
-        match self.db.remove(key) {
+    fn del_ns(&mut self, ns: Option<&str>, key: &[u8]) -> Result<bool> {
+        let res = match self.tree(ns)?.remove(key) {
             Ok(old_val) => match old_val {
                 None => Ok(false),
                 Some(_v) => Ok(true),
             },
-            Err(_e) => Err("del failed"),
-        }
+            Err(e) => Err(e.into()),
+        };
+        self.synced(res)
     }
 
-    fn apply_batch(&mut self, batch_in: &api::Batch) -> Result<bool, &'static str> {
+    fn apply_batch(&mut self, batch_in: &api::Batch) -> Result<bool> {
+        // conditional or multi-namespace batches need a transaction
+        let nss = batch_in.namespaces();
+        if batch_in.has_conditions() || nss.len() > 1 {
+            let res = self.apply_batch_txn(batch_in, &nss);
+            return self.synced(res);
+        }
+
         let mut batch = sled::Batch::default();
         for mutation in &batch_in.ops {
             match mutation.op {
@@ -56,35 +124,46 @@ impl api::Db for SledDb {
             }
         }
 
-        match self.db.apply_batch(batch) {
+        let tree = self.tree(nss.first().copied().flatten())?;
+        let res = match tree.apply_batch(batch) {
             Ok(_optval) => Ok(true),
-            Err(_e) => Err("batch failed"),
-        }
+            Err(e) => Err(e.into()),
+        };
+        self.synced(res)
     }
 
-    fn iter_keys(&self, opts: api::IterOptions) -> Result<api::KeyList, &'static str> {
-        let mut iter;
-
-        // todo: use self.db.scan_prefix() to narrow search,
-        // when prefix is present.  The trade-off:  when using
-        // scan_prefix(), we cannot jump directly to the start key.
-        if opts.start_key.is_none() {
-            iter = self.db.iter();
-        } else {
-            iter = self.db.range(opts.start_key.unwrap()..);
-            iter.next(); // absorb queried-for prev-key
+    fn compare_and_swap_ns(
+        &mut self,
+        ns: Option<&str>,
+        key: &[u8],
+        old_val: Option<&[u8]>,
+        new_val: Option<&[u8]>,
+    ) -> Result<bool> {
+        match self.tree(ns)?.compare_and_swap(key, old_val, new_val)? {
+            Ok(()) => self.synced(Ok(true)),
+            Err(cas_err) => {
+                // describe failure, against the value sled found
+                let cond = api::Precondition::from_expected(old_val);
+                cond.check(0, key, cas_err.current.as_deref())?;
+                Err(Error::ConditionFailed(String::from("compare-and-swap")))
+            }
         }
+    }
 
-        let mut key_list = api::KeyList {
-            keys: Vec::new(),
-            list_end: true,
-        };
+    fn scan(&self, opts: api::IterOptions) -> Result<api::KvList> {
+        let mut page = api::ScanPage::new(&opts);
+        let tree = self.tree(opts.ns.as_deref())?;
 
-        let prefix: Vec<u8> = match opts.prefix {
-            None => Vec::new(),
-            Some(value) => value,
+        // key range covers start/end keys and prefix
+        let range = match opts.key_range() {
+            None => return Ok(page.finish()),
+            Some(range) => range,
         };
-        let pfx_len = prefix.len();
+        let mut iter: Box<dyn Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>>> =
+            match opts.reverse {
+                false => Box::new(tree.range(range)),
+                true => Box::new(tree.range(range).rev()),
+            };
 
         loop {
             let opt_val = iter.next();
@@ -93,45 +172,201 @@ impl api::Db for SledDb {
             }
 
             match opt_val.unwrap() {
-                Err(_e) => {
-                    return Err("iter failed");
+                Err(e) => {
+                    return Err(e.into());
                 }
-                Ok(record_tuple) => {
-                    let key = record_tuple.0.to_vec();
-
-                    // filter by prefix
-                    let mut want_push = true;
-                    if pfx_len > 0 {
-                        if key.len() < pfx_len || prefix != &key[0..pfx_len] {
-                            want_push = false;
-                        }
+                Ok((key, value)) => {
+                    if !page.push(&key, &value) {
+                        break;
                     }
+                }
+            }
+        }
+
+        Ok(page.finish())
+    }
 
-                    if want_push {
-                        key_list.keys.push(key);
+    fn open_ns(&mut self, ns: &str) -> Result<bool> {
+        api::validate_ns_name(ns)?;
+        if !self.trees.contains_key(ns) {
+            let tree = self.db.open_tree(ns)?;
+            self.trees.insert(ns.to_string(), tree);
+            return self.synced(Ok(true));
+        }
+
+        Ok(true)
+    }
+
+    fn drop_ns(&mut self, ns: &str) -> Result<bool> {
+        match self.trees.remove(ns) {
+            None => Ok(false),
+            Some(_tree) => {
+                let dropped = self.db.drop_tree(ns)?;
+                self.synced(Ok(dropped))
+            }
+        }
+    }
+
+    fn list_ns(&self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = self.trees.keys().cloned().collect();
+        names.sort();
+
+        Ok(names)
+    }
+}
+
+impl SledDb {
+    fn apply_batch_txn(&mut self, batch_in: &api::Batch, nss: &[Option<&str>]) -> Result<bool> {
+        // one transaction over all trees touched by the batch
+        let mut trees: Vec<sled::Tree> = Vec::new();
+        for ns in nss {
+            trees.push(self.tree(*ns)?.clone());
+        }
+
+        let res = trees.as_slice().transaction(|tx_trees| {
+            for (idx, mutation) in batch_in.ops.iter().enumerate() {
+                let tree_idx = nss.iter().position(|ns| *ns == mutation.ns.as_deref());
+                let tx_db = &tx_trees[tree_idx.unwrap()];
+
+                if let Some(cond) = &mutation.condition {
+                    let cur_val = tx_db.get(&mutation.key)?;
+                    if let Err(e) = cond.check(idx, &mutation.key, cur_val.as_deref()) {
+                        return Err(ConflictableTransactionError::Abort(e));
                     }
                 }
-            }
 
-            if key_list.keys.len() >= api::MAX_ITER_KEYS {
-                key_list.list_end = false;
-                break;
+                match mutation.op {
+                    api::MutationOp::Insert => {
+                        tx_db.insert(mutation.key.clone(), mutation.value.clone().unwrap())?;
+                    }
+                    api::MutationOp::Remove => {
+                        tx_db.remove(mutation.key.clone())?;
+                    }
+                }
             }
+            Ok(())
+        });
+
+        match res {
+            Ok(()) => Ok(true),
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
         }
+    }
+}
 
-        Ok(key_list)
+impl Drop for SledDb {
+    // Close deterministically, so the path may be reopened at once
+    // (detach/attach, reload).  Dropping the last Tree and Db handle joins
+    // sled's flusher thread; write tasks on sled's thread pool may still
+    // hold the file lock a moment longer, so wait for its release.
+    fn drop(&mut self) {
+        self.trees.clear();
+        unsafe { ManuallyDrop::drop(&mut self.db) };
+
+        wait_unlocked(&self.lock_path);
     }
 }
 
+// wait, up to CLOSE_TIMEOUT, until no handle holds a lock on path
+fn wait_unlocked(path: &Path) {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_e) => return,
+    };
+
+    let deadline = Instant::now() + CLOSE_TIMEOUT;
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) };
+            return;
+        }
+        if Instant::now() >= deadline {
+            return;
+        }
+        thread::sleep(CLOSE_POLL);
+    }
+}
+
+// apply driver options to sled config
+fn apply_options(mut sled_cfg: sled::Config, options: &api::Options) -> Result<sled::Config> {
+    api::check_option_names(options, SLED_OPTIONS)?;
+
+    if let Some(n_bytes) = api::parse_option(options, "cache_capacity")? {
+        sled_cfg = sled_cfg.cache_capacity(n_bytes);
+    }
+    if let Some(mode) = options.get("mode") {
+        let mode = match mode.as_str() {
+            "low_space" => sled::Mode::LowSpace,
+            "high_throughput" => sled::Mode::HighThroughput,
+            _ => {
+                return Err(Error::InvalidArgument(format!(
+                    "driver option mode: invalid value {} (low_space, high_throughput)",
+                    mode
+                )))
+            }
+        };
+        sled_cfg = sled_cfg.mode(mode);
+    }
+
+    // sled's compression feature is not built
+    if api::parse_option(options, "use_compression")? == Some(true) {
+        return Err(Error::InvalidArgument(String::from(
+            "driver option use_compression: sled compression support not built",
+        )));
+    }
+
+    Ok(sled_cfg)
+}
+
 pub struct SledDriver {}
 
 impl api::Driver for SledDriver {
-    fn start_db(&self, cfg: api::Config) -> Result<Box<dyn api::Db + Send>, &'static str> {
-        let sled_db_cfg = sled::Config::default().path(cfg.path);
+    fn check_options(&self, options: &api::Options) -> Result<()> {
+        apply_options(sled::Config::default(), options)?;
+        Ok(())
+    }
 
-        Ok(Box::new(SledDb {
-            db: sled_db_cfg.open().unwrap(),
-        }) as Box<dyn api::Db + Send>)
+    fn start_db(&self, cfg: api::Config) -> Result<Box<dyn api::Db>> {
+        // sled has no read-only mode, and rewrites its files when opened
+        if cfg.read_only {
+            return Err(Error::InvalidArgument(String::from(
+                "read-only mode is not supported by the sled driver",
+            )));
+        }
+
+        let lock_path = Path::new(&cfg.path).join(SLED_LOCK_FILE);
+        let sled_db_cfg = apply_options(sled::Config::default().path(cfg.path), &cfg.options)?;
+
+        // background flushes: sled's default, every N ms, or none
+        let sled_db_cfg = match cfg.durability {
+            api::Durability::Default => sled_db_cfg,
+            api::Durability::Periodic(0) => {
+                return Err(Error::InvalidArgument(String::from(
+                    "flush interval must be positive",
+                )))
+            }
+            api::Durability::Periodic(ms) => sled_db_cfg.flush_every_ms(Some(ms)),
+            api::Durability::Sync | api::Durability::NoSync => sled_db_cfg.flush_every_ms(None),
+        };
+        let db = sled_db_cfg.open()?;
+
+        // open all existing namespaces
+        let mut trees = HashMap::new();
+        for name in db.tree_names() {
+            if name == SLED_DEFAULT_TREE {
+                continue;
+            }
+            let tree = db.open_tree(&name)?;
+            trees.insert(String::from_utf8_lossy(&name).to_string(), tree);
+        }
+
+        ttl::wrap(version::wrap(Box::new(SledDb {
+            db: ManuallyDrop::new(db),
+            trees,
+            lock_path,
+            sync_writes: cfg.durability == api::Durability::Sync,
+        }))?)
     }
 }
 
@@ -140,7 +375,7 @@ pub fn new_driver() -> Box<dyn api::Driver> {
 }
 
 #[cfg(test)]
-use super::api::{Batch, ConfigBuilder};
+use super::api::{Batch, ConfigBuilder, Durability};
 #[cfg(test)]
 use tempdir::TempDir;
 
@@ -159,12 +394,12 @@ mod tests {
 
         let mut db = driver.start_db(db_config).unwrap();
 
-        assert_eq!(db.get(b"name"), Ok(None));
-        assert_eq!(db.put(b"name", b"alan"), Ok(true));
-        assert_eq!(db.get(b"name"), Ok(Some(Vec::from("alan"))));
-        assert_eq!(db.del(b"name"), Ok(true));
-        assert_eq!(db.get(b"name"), Ok(None));
-        assert_eq!(db.get(b"never_existed"), Ok(None));
+        assert_eq!(db.get(b"name").unwrap(), None);
+        assert!(db.put(b"name", b"alan").unwrap());
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+        assert!(db.del(b"name").unwrap());
+        assert_eq!(db.get(b"name").unwrap(), None);
+        assert_eq!(db.get(b"never_existed").unwrap(), None);
     }
 
     #[test]
@@ -177,9 +412,9 @@ mod tests {
 
         let mut db = driver.start_db(db_config).unwrap();
 
-        assert_eq!(db.put(b"name", b"alan"), Ok(true));
-        assert_eq!(db.del(b"name"), Ok(true));
-        assert_eq!(db.del(b"name"), Ok(false));
+        assert!(db.put(b"name", b"alan").unwrap());
+        assert!(db.del(b"name").unwrap());
+        assert!(!db.del(b"name").unwrap());
     }
 
     #[test]
@@ -192,17 +427,17 @@ mod tests {
 
         let mut db = driver.start_db(db_config).unwrap();
 
-        assert_eq!(db.put(b"name", b"alan"), Ok(true));
+        assert!(db.put(b"name", b"alan").unwrap());
 
         let mut batch = Batch::default();
         batch.insert(b"age", b"25");
         batch.insert(b"city", b"anytown");
         batch.remove(b"name");
-        assert_eq!(db.apply_batch(&batch), Ok(true));
+        assert!(db.apply_batch(&batch).unwrap());
 
-        assert_eq!(db.get(b"name"), Ok(None));
-        assert_eq!(db.get(b"age"), Ok(Some(Vec::from("25"))));
-        assert_eq!(db.get(b"city"), Ok(Some(Vec::from("anytown"))));
+        assert_eq!(db.get(b"name").unwrap(), None);
+        assert_eq!(db.get(b"age").unwrap(), Some(Vec::from("25")));
+        assert_eq!(db.get(b"city").unwrap(), Some(Vec::from("anytown")));
     }
 
     #[test]
@@ -215,12 +450,12 @@ mod tests {
 
         let mut db = driver.start_db(db_config).unwrap();
 
-        assert_eq!(db.put(b"name", b"alan"), Ok(true));
-        assert_eq!(db.put(b"age", b"25"), Ok(true));
-        assert_eq!(db.get(b"name"), Ok(Some(Vec::from("alan"))));
-        assert_eq!(db.clear(), Ok(true));
-        assert_eq!(db.get(b"name"), Ok(None));
-        assert_eq!(db.get(b"age"), Ok(None));
+        assert!(db.put(b"name", b"alan").unwrap());
+        assert!(db.put(b"age", b"25").unwrap());
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+        assert!(db.clear().unwrap());
+        assert_eq!(db.get(b"name").unwrap(), None);
+        assert_eq!(db.get(b"age").unwrap(), None);
     }
 
     #[test]
@@ -233,16 +468,153 @@ mod tests {
 
         let mut db = driver.start_db(db_config).unwrap();
 
-        assert_eq!(db.put(b"name1", b"alan"), Ok(true));
-        assert_eq!(db.put(b"age1", b"25"), Ok(true));
-        assert_eq!(db.put(b"name", b"alan"), Ok(true));
-        assert_eq!(db.del(b"name"), Ok(true));
-        assert_eq!(db.del(b"name"), Ok(false));
+        assert!(db.put(b"name1", b"alan").unwrap());
+        assert!(db.put(b"age1", b"25").unwrap());
+        assert!(db.put(b"name", b"alan").unwrap());
+        assert!(db.del(b"name").unwrap());
+        assert!(!db.del(b"name").unwrap());
 
         let st = db.stat().unwrap();
         assert_eq!(st.n_records, 2);
     }
 
+    #[test]
+    fn test_storage_stat() {
+        let tmp_dir = TempDir::new("tss").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+        let db_config = ConfigBuilder::new().path(tmp_path).read_only(false).build();
+
+        let driver = new_driver();
+
+        let mut db = driver.start_db(db_config).unwrap();
+        assert!(db.put(b"name1", b"alan").unwrap());
+
+        // file size; zero until sled first flushes
+        let st = db.storage_stat().unwrap();
+        assert!(st.disk_bytes.is_some());
+        assert_eq!(st.map_bytes, None);
+    }
+
+    #[test]
+    fn test_flush_close() {
+        let tmp_dir = TempDir::new("tfc").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+
+        let driver = new_driver();
+
+        {
+            let db_config = ConfigBuilder::new()
+                .path(tmp_path.clone())
+                .read_only(false)
+                .build();
+            let mut db = driver.start_db(db_config).unwrap();
+            assert!(db.put(b"name", b"alan").unwrap());
+            db.flush().unwrap();
+            assert!(db.put(b"age", b"41").unwrap());
+            db.close().unwrap();
+        }
+
+        // reopen: all writes are stored
+        let db_config = ConfigBuilder::new().path(tmp_path).read_only(false).build();
+        let db = driver.start_db(db_config).unwrap();
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+        assert_eq!(db.get(b"age").unwrap(), Some(Vec::from("41")));
+    }
+
+    #[test]
+    fn test_durability() {
+        let driver = new_driver();
+
+        for durability in [
+            Durability::Sync,
+            Durability::Periodic(10),
+            Durability::NoSync,
+        ] {
+            let tmp_dir = TempDir::new("tdu").unwrap();
+            let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+
+            {
+                let db_config = ConfigBuilder::new()
+                    .path(tmp_path.clone())
+                    .durability(durability)
+                    .build();
+                let mut db = driver.start_db(db_config).unwrap();
+                assert!(db.put(b"name", b"alan").unwrap());
+                assert!(db.open_ns("users").unwrap());
+                assert!(db.put_ns(Some("users"), b"alan", b"1").unwrap());
+                db.close().unwrap();
+            }
+
+            let db_config = ConfigBuilder::new().path(tmp_path).build();
+            let db = driver.start_db(db_config).unwrap();
+            assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+            assert_eq!(
+                db.get_ns(Some("users"), b"alan").unwrap(),
+                Some(Vec::from("1"))
+            );
+        }
+
+        let tmp_dir = TempDir::new("tdu").unwrap();
+        let db_config = ConfigBuilder::new()
+            .path(tmp_dir.path().to_str().unwrap().to_string())
+            .durability(Durability::Periodic(0))
+            .build();
+        assert!(matches!(
+            driver.start_db(db_config),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_options() {
+        let driver = new_driver();
+        let options = |pairs: &[(&str, &str)]| -> api::Options {
+            pairs
+                .iter()
+                .map(|(key, val)| (key.to_string(), val.to_string()))
+                .collect()
+        };
+
+        assert!(driver
+            .check_options(&options(&[
+                ("mode", "high_throughput"),
+                ("use_compression", "false")
+            ]))
+            .is_ok());
+        for bad in [
+            ("map_size", "1048576"),
+            ("mode", "fast"),
+            ("cache_capacity", "-1"),
+            ("use_compression", "true"),
+        ] {
+            assert!(matches!(
+                driver.check_options(&options(&[bad])),
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+
+        let tmp_dir = TempDir::new("topt").unwrap();
+        let db_config = ConfigBuilder::new()
+            .path(tmp_dir.path().to_str().unwrap().to_string())
+            .option("cache_capacity", "1048576")
+            .option("mode", "low_space")
+            .build();
+        let mut db = driver.start_db(db_config).unwrap();
+        assert!(db.put(b"name", b"alan").unwrap());
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+
+        // unknown option fails open
+        let tmp_dir = TempDir::new("topt").unwrap();
+        let db_config = ConfigBuilder::new()
+            .path(tmp_dir.path().to_str().unwrap().to_string())
+            .option("cache", "1048576")
+            .build();
+        assert!(matches!(
+            driver.start_db(db_config),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
     #[test]
     fn test_iter() {
         let tmp_dir = TempDir::new("tc").unwrap();
@@ -255,23 +627,23 @@ mod tests {
 
         // iterate empty list
         let key_list_res = db.iter_keys(api::IterOptions::new());
-        assert_eq!(key_list_res.is_err(), false);
+        assert!(key_list_res.is_ok());
 
         let mut key_list = key_list_res.unwrap();
-        assert_eq!(key_list.list_end, true);
+        assert!(key_list.list_end);
 
         key_list.keys.sort();
         assert_eq!(key_list.keys.len(), 0);
 
         // iterate small list
-        assert_eq!(db.put(b"name", b"alan"), Ok(true));
-        assert_eq!(db.put(b"age", b"25"), Ok(true));
+        assert!(db.put(b"name", b"alan").unwrap());
+        assert!(db.put(b"age", b"25").unwrap());
 
         let key_list_res = db.iter_keys(api::IterOptions::new());
-        assert_eq!(key_list_res.is_err(), false);
+        assert!(key_list_res.is_ok());
 
         let mut key_list = key_list_res.unwrap();
-        assert_eq!(key_list.list_end, true);
+        assert!(key_list.list_end);
 
         key_list.keys.sort();
         assert_eq!(key_list.keys.len(), 2);
@@ -290,19 +662,19 @@ mod tests {
         let mut db = driver.start_db(db_config).unwrap();
 
         // iterate small list
-        assert_eq!(db.put(b"2018/name", b"alan"), Ok(true));
-        assert_eq!(db.put(b"2018/bame", b"alan"), Ok(true));
-        assert_eq!(db.put(b"2019/fame", b"alan"), Ok(true));
-        assert_eq!(db.put(b"2019/lame", b"alan"), Ok(true));
-        assert_eq!(db.put(b"2019/game", b"alan"), Ok(true));
-        assert_eq!(db.put(b"2020/tame", b"alan"), Ok(true));
-        assert_eq!(db.put(b"age", b"25"), Ok(true));
+        assert!(db.put(b"2018/name", b"alan").unwrap());
+        assert!(db.put(b"2018/bame", b"alan").unwrap());
+        assert!(db.put(b"2019/fame", b"alan").unwrap());
+        assert!(db.put(b"2019/lame", b"alan").unwrap());
+        assert!(db.put(b"2019/game", b"alan").unwrap());
+        assert!(db.put(b"2020/tame", b"alan").unwrap());
+        assert!(db.put(b"age", b"25").unwrap());
 
         let key_list_res = db.iter_keys(api::IterOptions::new());
-        assert_eq!(key_list_res.is_err(), false);
+        assert!(key_list_res.is_ok());
 
         let key_list = key_list_res.unwrap();
-        assert_eq!(key_list.list_end, true);
+        assert!(key_list.list_end);
         assert_eq!(key_list.keys.len(), 7);
 
         // iterate with prefix matching
@@ -310,10 +682,10 @@ mod tests {
         opts.prefix(b"2019/");
 
         let key_list_res = db.iter_keys(opts);
-        assert_eq!(key_list_res.is_err(), false);
+        assert!(key_list_res.is_ok());
 
         let mut key_list = key_list_res.unwrap();
-        assert_eq!(key_list.list_end, true);
+        assert!(key_list.list_end);
         assert_eq!(key_list.keys.len(), 3);
 
         key_list.keys.sort();
@@ -330,4 +702,383 @@ mod tests {
             String::from("2019/lame")
         );
     }
+
+    #[test]
+    fn test_scan() {
+        let tmp_dir = TempDir::new("ts").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+        let db_config = ConfigBuilder::new().path(tmp_path).read_only(false).build();
+
+        let driver = new_driver();
+
+        let mut db = driver.start_db(db_config).unwrap();
+
+        assert!(db.put(b"a", b"1").unwrap());
+        assert!(db.put(b"b", b"22").unwrap());
+        assert!(db.put(b"c", b"333").unwrap());
+
+        // scan returns keys and values, in key order
+        let kv_list = db.scan(api::IterOptions::new()).unwrap();
+        assert!(kv_list.list_end);
+        assert_eq!(kv_list.pairs.len(), 3);
+        assert_eq!(kv_list.pairs[1].key, b"b");
+        assert_eq!(kv_list.pairs[1].value, b"22");
+
+        // keys-only scan omits values
+        let mut opts = api::IterOptions::new();
+        opts.keys_only(true);
+        let kv_list = db.scan(opts).unwrap();
+        assert_eq!(kv_list.pairs.len(), 3);
+        assert_eq!(kv_list.pairs[2].key, b"c");
+        assert!(kv_list.pairs[2].value.is_empty());
+
+        // byte-limited pages, continued from the last key seen
+        let mut opts = api::IterOptions::new();
+        opts.max_bytes(4);
+        let kv_list = db.scan(opts).unwrap();
+        assert!(!kv_list.list_end);
+        assert_eq!(kv_list.pairs.len(), 1);
+        assert_eq!(kv_list.pairs[0].key, b"a");
+
+        let mut opts = api::IterOptions::new();
+        opts.max_bytes(4).start(b"a");
+        let kv_list = db.scan(opts).unwrap();
+        assert!(!kv_list.list_end);
+        assert_eq!(kv_list.pairs.len(), 1);
+        assert_eq!(kv_list.pairs[0].key, b"b");
+
+        let mut opts = api::IterOptions::new();
+        opts.max_bytes(4).start(b"b");
+        let kv_list = db.scan(opts).unwrap();
+        assert!(kv_list.list_end);
+        assert_eq!(kv_list.pairs.len(), 1);
+        assert_eq!(kv_list.pairs[0].value, b"333");
+    }
+
+    #[test]
+    fn test_scan_range() {
+        let tmp_dir = TempDir::new("tsr").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+        let db_config = ConfigBuilder::new().path(tmp_path).read_only(false).build();
+
+        let driver = new_driver();
+
+        let mut db = driver.start_db(db_config).unwrap();
+
+        for i in 0..10 {
+            let key = format!("k{}", i);
+            assert!(db.put(key.as_bytes(), b"v").unwrap());
+        }
+
+        fn keys(kv_list: &api::KvList) -> Vec<String> {
+            kv_list
+                .pairs
+                .iter()
+                .map(|kv| String::from_utf8_lossy(&kv.key).to_string())
+                .collect()
+        }
+
+        // forward, exclusive and inclusive end
+        let mut opts = api::IterOptions::new();
+        opts.start(b"k2").end(b"k5", false);
+        let kv_list = db.scan(opts).unwrap();
+        assert!(kv_list.list_end);
+        assert_eq!(keys(&kv_list), vec!["k3", "k4"]);
+
+        let mut opts = api::IterOptions::new();
+        opts.end(b"k2", true);
+        let kv_list = db.scan(opts).unwrap();
+        assert_eq!(keys(&kv_list), vec!["k0", "k1", "k2"]);
+
+        // start key need not exist
+        let mut opts = api::IterOptions::new();
+        opts.start(b"k75");
+        let kv_list = db.scan(opts).unwrap();
+        assert_eq!(keys(&kv_list), vec!["k8", "k9"]);
+
+        // forward, limited pages
+        let mut opts = api::IterOptions::new();
+        opts.limit(4);
+        let kv_list = db.scan(opts).unwrap();
+        assert!(!kv_list.list_end);
+        assert_eq!(keys(&kv_list), vec!["k0", "k1", "k2", "k3"]);
+
+        // reverse: latest 3 before k5
+        let mut opts = api::IterOptions::new();
+        opts.reverse(true).start(b"k5").limit(3);
+        let kv_list = db.scan(opts).unwrap();
+        assert!(!kv_list.list_end);
+        assert_eq!(keys(&kv_list), vec!["k4", "k3", "k2"]);
+
+        // reverse, from end of db, stopping at inclusive end key
+        let mut opts = api::IterOptions::new();
+        opts.reverse(true).end(b"k7", true);
+        let kv_list = db.scan(opts).unwrap();
+        assert!(kv_list.list_end);
+        assert_eq!(keys(&kv_list), vec!["k9", "k8", "k7"]);
+
+        // reverse, start key past end of db
+        let mut opts = api::IterOptions::new();
+        opts.reverse(true).start(b"z").end(b"k8", false);
+        let kv_list = db.scan(opts).unwrap();
+        assert_eq!(keys(&kv_list), vec!["k9"]);
+
+        // empty ranges
+        let mut opts = api::IterOptions::new();
+        opts.start(b"k5").end(b"k5", true);
+        assert!(db.scan(opts).unwrap().pairs.is_empty());
+
+        let mut opts = api::IterOptions::new();
+        opts.start(b"k9");
+        let kv_list = db.scan(opts).unwrap();
+        assert!(kv_list.list_end);
+        assert!(kv_list.pairs.is_empty());
+
+        let mut opts = api::IterOptions::new();
+        opts.reverse(true).start(b"k0");
+        assert!(db.scan(opts).unwrap().pairs.is_empty());
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        let tmp_dir = TempDir::new("tsp").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+        let db_config = ConfigBuilder::new().path(tmp_path).read_only(false).build();
+
+        let driver = new_driver();
+
+        let mut db = driver.start_db(db_config).unwrap();
+
+        for key in [
+            &b"a/1"[..],
+            b"b/1",
+            b"b/2",
+            b"b/3",
+            b"c/1",
+            b"\xff\xff",
+            b"\xff\xff1",
+        ] {
+            assert!(db.put(key, b"v").unwrap());
+        }
+
+        fn keys(kv_list: &api::KvList) -> Vec<Vec<u8>> {
+            kv_list.pairs.iter().map(|kv| kv.key.clone()).collect()
+        }
+
+        // prefix pages stop at end of prefix, continuing from start key
+        let mut opts = api::IterOptions::new();
+        opts.prefix(b"b/").limit(2);
+        let kv_list = db.scan(opts).unwrap();
+        assert!(!kv_list.list_end);
+        assert_eq!(keys(&kv_list), vec![b"b/1".to_vec(), b"b/2".to_vec()]);
+
+        let mut opts = api::IterOptions::new();
+        opts.prefix(b"b/").limit(2).start(b"b/2");
+        let kv_list = db.scan(opts).unwrap();
+        assert!(kv_list.list_end);
+        assert_eq!(keys(&kv_list), vec![b"b/3".to_vec()]);
+
+        // start key before prefix
+        let mut opts = api::IterOptions::new();
+        opts.prefix(b"c/").start(b"a/1");
+        let kv_list = db.scan(opts).unwrap();
+        assert_eq!(keys(&kv_list), vec![b"c/1".to_vec()]);
+
+        // reverse within prefix
+        let mut opts = api::IterOptions::new();
+        opts.prefix(b"b/").reverse(true).start(b"b/3");
+        let kv_list = db.scan(opts).unwrap();
+        assert!(kv_list.list_end);
+        assert_eq!(keys(&kv_list), vec![b"b/2".to_vec(), b"b/1".to_vec()]);
+
+        // prefix of all 0xff bytes has no upper bound
+        let mut opts = api::IterOptions::new();
+        opts.prefix(b"\xff\xff");
+        let kv_list = db.scan(opts).unwrap();
+        assert_eq!(kv_list.pairs.len(), 2);
+
+        // no matching keys
+        let mut opts = api::IterOptions::new();
+        opts.prefix(b"bb");
+        let kv_list = db.scan(opts).unwrap();
+        assert!(kv_list.list_end);
+        assert!(kv_list.pairs.is_empty());
+    }
+
+    #[test]
+    fn test_batch_conditions() {
+        let tmp_dir = TempDir::new("tbc").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+        let db_config = ConfigBuilder::new().path(tmp_path).read_only(false).build();
+
+        let driver = new_driver();
+
+        let mut db = driver.start_db(db_config).unwrap();
+
+        assert!(db.put(b"name", b"alan").unwrap());
+
+        // all preconditions hold
+        let mut batch = Batch::default();
+        batch.insert_if(b"age", b"25", api::Precondition::NotExists);
+        batch.insert_if(
+            b"name",
+            b"bob",
+            api::Precondition::ValueEquals(b"alan".to_vec()),
+        );
+        assert!(db.apply_batch(&batch).unwrap());
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("bob")));
+
+        // one failed precondition aborts the whole batch
+        let version = db.version(b"name").unwrap().unwrap();
+        let mut batch = Batch::default();
+        batch.insert(b"city", b"anytown");
+        batch.remove_if(b"name", api::Precondition::VersionEquals(version));
+        batch.insert_if(b"age", b"26", api::Precondition::NotExists);
+        match db.apply_batch(&batch) {
+            Err(Error::ConditionFailed(msg)) => assert!(msg.starts_with("op 2,")),
+            _ => panic!("expected failed precondition"),
+        }
+        assert_eq!(db.get(b"city").unwrap(), None);
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("bob")));
+        assert_eq!(db.get(b"age").unwrap(), Some(Vec::from("25")));
+
+        // preconditions see earlier mutations in the batch
+        let mut batch = Batch::default();
+        batch.remove(b"age");
+        batch.insert_if(b"age", b"26", api::Precondition::NotExists);
+        assert!(db.apply_batch(&batch).unwrap());
+        assert_eq!(db.get(b"age").unwrap(), Some(Vec::from("26")));
+    }
+
+    #[test]
+    fn test_cas() {
+        let tmp_dir = TempDir::new("tcas").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+        let db_config = ConfigBuilder::new().path(tmp_path).read_only(false).build();
+
+        let driver = new_driver();
+
+        let mut db = driver.start_db(db_config).unwrap();
+
+        assert!(db.compare_and_swap(b"name", None, Some(b"alan")).unwrap());
+        assert!(matches!(
+            db.compare_and_swap(b"name", Some(b"bob"), Some(b"carl")),
+            Err(Error::ConditionFailed(_))
+        ));
+        assert!(db
+            .compare_and_swap(b"name", Some(b"alan"), Some(b"bob"))
+            .unwrap());
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("bob")));
+        assert!(db.compare_and_swap(b"name", Some(b"bob"), None).unwrap());
+        assert_eq!(db.get(b"name").unwrap(), None);
+        assert!(matches!(
+            db.compare_and_swap(b"name", Some(b"bob"), None),
+            Err(Error::ConditionFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let tmp_dir = TempDir::new("tns").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+
+        let driver = new_driver();
+
+        {
+            let db_config = ConfigBuilder::new()
+                .path(tmp_path.clone())
+                .read_only(false)
+                .build();
+            let mut db = driver.start_db(db_config).unwrap();
+
+            assert!(matches!(
+                db.get_ns(Some("users"), b"name"),
+                Err(Error::NotFound)
+            ));
+            assert!(db.open_ns("users").unwrap());
+            assert!(db.open_ns("groups").unwrap());
+            assert!(db.open_ns("users").unwrap());
+            assert!(matches!(
+                db.open_ns("bad/name"),
+                Err(Error::InvalidArgument(_))
+            ));
+
+            // keyspaces are separate; batches span them
+            let mut batch = Batch::default();
+            batch.insert(b"name", b"default");
+            batch.set_ns(Some("users"));
+            batch.insert(b"name", b"alan");
+            batch.insert(b"age", b"41");
+            batch.set_ns(Some("groups"));
+            batch.insert(b"name", b"admin");
+            assert!(db.apply_batch(&batch).unwrap());
+
+            assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("default")));
+            assert_eq!(
+                db.get_ns(Some("users"), b"name").unwrap(),
+                Some(Vec::from("alan"))
+            );
+            assert_eq!(db.stat().unwrap().n_records, 1);
+            assert_eq!(db.stat_ns(Some("users")).unwrap().n_records, 2);
+
+            let mut opts = api::IterOptions::new();
+            opts.ns("users");
+            let kv_list = db.scan(opts).unwrap();
+            assert_eq!(kv_list.pairs.len(), 2);
+            let kv_list = db.scan(api::IterOptions::new()).unwrap();
+            assert_eq!(kv_list.pairs.len(), 1);
+
+            assert!(db.clear_ns(Some("groups")).unwrap());
+            assert_eq!(db.get_ns(Some("groups"), b"name").unwrap(), None);
+            assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("default")));
+        }
+
+        // namespaces persist across reopen
+        let db_config = ConfigBuilder::new().path(tmp_path).read_only(false).build();
+        let mut db = driver.start_db(db_config).unwrap();
+        assert_eq!(
+            db.list_ns().unwrap(),
+            vec![String::from("groups"), String::from("users")]
+        );
+        assert_eq!(
+            db.get_ns(Some("users"), b"age").unwrap(),
+            Some(Vec::from("41"))
+        );
+
+        assert!(db.drop_ns("users").unwrap());
+        assert!(!db.drop_ns("users").unwrap());
+        assert_eq!(db.list_ns().unwrap(), vec![String::from("groups")]);
+        assert!(matches!(
+            db.get_ns(Some("users"), b"age"),
+            Err(Error::NotFound)
+        ));
+
+        assert!(db.clear().unwrap());
+        assert_eq!(db.stat().unwrap().n_records, 0);
+        assert_eq!(db.list_ns().unwrap(), vec![String::from("groups")]);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let tmp_dir = TempDir::new("tro").unwrap();
+        let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+
+        let driver = new_driver();
+
+        {
+            let db_config = ConfigBuilder::new()
+                .path(tmp_path.clone())
+                .read_only(false)
+                .build();
+            let mut db = driver.start_db(db_config).unwrap();
+            assert!(db.put(b"name", b"alan").unwrap());
+        }
+
+        // refused, rather than opened writable
+        let db_config = ConfigBuilder::new().path(tmp_path).read_only(true).build();
+        assert!(matches!(
+            driver.start_db(db_config),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
 }
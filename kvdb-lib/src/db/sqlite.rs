@@ -0,0 +1,593 @@
+/*
+ * sqlite: driver over a single-file SQLite database.
+ *
+ * The database directory holds one SQLite file (plus, in WAL mode, its
+ * -wal and -shm companions), with every keyspace in one table:
+ *
+ *   kv             (ns TEXT, key BLOB, value BLOB), primary key (ns, key)
+ *   kv_namespaces  (name TEXT), primary key name
+ *
+ * The default keyspace is ns ''.  SQLite compares BLOBs bytewise, so the
+ * primary key index serves scans in key order, as for the other drivers.
+ */
+
+use super::{api, ttl, version};
+use crate::{Error, Result};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OpenFlags, OptionalExtension};
+use std::collections::HashSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// database file, within the database directory
+const DB_FILE: &str = "kvdb.sqlite3";
+
+// driver options, each a SQLite pragma
+const SQLITE_OPTIONS: &[&str] = &["journal_mode", "synchronous"];
+
+const JOURNAL_MODES: &[&str] = &["delete", "truncate", "persist", "wal"];
+const SYNC_LEVELS: &[&str] = &["off", "normal", "full", "extra"];
+
+// wait for locks held by other processes, such as ops tools reading
+// the file, before failing with SQLITE_BUSY
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS kv (
+        ns TEXT NOT NULL,
+        key BLOB NOT NULL,
+        value BLOB NOT NULL,
+        PRIMARY KEY (ns, key)
+    ) WITHOUT ROWID;
+    CREATE TABLE IF NOT EXISTS kv_namespaces (
+        name TEXT NOT NULL PRIMARY KEY
+    ) WITHOUT ROWID;
+";
+
+// ns column value for namespace; None is the default keyspace
+fn ns_col(ns: Option<&str>) -> &str {
+    ns.unwrap_or("")
+}
+
+// write-ahead log of database file path, in WAL mode
+fn wal_path(path: &Path) -> PathBuf {
+    let mut wal_path = path.as_os_str().to_owned();
+    wal_path.push("-wal");
+
+    PathBuf::from(wal_path)
+}
+
+// write database files to disk.  Callers hold the connection lock, so no
+// transaction is in progress, and the files are consistent.
+fn sync_files(path: &Path) -> Result<()> {
+    fs::File::open(path)?.sync_all()?;
+    match fs::File::open(wal_path(path)) {
+        Ok(wal) => wal.sync_all()?,
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(())
+}
+
+// checkpoint the write-ahead log, in WAL mode.  With synchronous=NORMAL,
+// SQLite syncs the log before copying it into the database file, then
+// syncs the file; commits up to the checkpoint are on disk.  Passive: a
+// checkpoint blocked by readers is left for later.  Without a log, as in
+// rollback journal modes, nothing is done.
+fn checkpoint(conn: &Connection) -> Result<()> {
+    conn.query_row("PRAGMA wal_checkpoint(PASSIVE)", [], |_row| Ok(()))?;
+    Ok(())
+}
+
+// background thread checkpointing a database opened with
+// synchronous=NORMAL, for Durability::Periodic; stopped and joined on drop
+struct Flusher {
+    stop: Option<mpsc::Sender<()>>, // dropped to stop the thread
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Flusher {
+    fn start(conn: Arc<Mutex<Connection>>, interval: Duration) -> Result<Flusher> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name(String::from("kvdb-sqlite-flush"))
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    // a failed checkpoint is retried next interval, and
+                    // on close
+                    let _res = checkpoint(&conn.lock().unwrap());
+                }
+            })?;
+
+        Ok(Flusher {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _res = thread.join();
+        }
+    }
+}
+
+pub struct SqliteDb {
+    conn: Arc<Mutex<Connection>>, // shared with flusher
+    path: PathBuf,                // database file
+    nss: HashSet<String>,         // open namespaces
+    read_only: bool,
+    _flusher: Option<Flusher>, // Durability::Periodic
+}
+
+impl SqliteDb {
+    // fail writes to a read-only database
+    fn writable(&self) -> Result<()> {
+        match self.read_only {
+            true => Err(Error::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    // fail operations on a namespace never opened
+    fn check_ns(&self, ns: Option<&str>) -> Result<()> {
+        match ns {
+            Some(name) if !self.nss.contains(name) => Err(Error::NotFound),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl api::Db for SqliteDb {
+    fn clear_ns(&mut self, ns: Option<&str>) -> Result<bool> {
+        self.writable()?;
+        self.check_ns(ns)?;
+        let conn = self.conn.lock().unwrap();
+        conn.prepare_cached("DELETE FROM kv WHERE ns = ?1")?
+            .execute(params![ns_col(ns)])?;
+        Ok(true)
+    }
+
+    fn stat_ns(&self, ns: Option<&str>) -> Result<api::DbStat> {
+        self.check_ns(ns)?;
+        let conn = self.conn.lock().unwrap();
+        let n_records: i64 = conn
+            .prepare_cached("SELECT COUNT(*) FROM kv WHERE ns = ?1")?
+            .query_row(params![ns_col(ns)], |row| row.get(0))?;
+
+        Ok(api::DbStat {
+            n_records: n_records as u64,
+        })
+    }
+
+    fn flush(&self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        let _conn = self.conn.lock().unwrap();
+        sync_files(&self.path)
+    }
+
+    fn storage_stat(&self) -> Result<api::StorageStat> {
+        // database file, and its write-ahead log if any
+        let mut n_bytes = fs::metadata(&self.path)?.len();
+        match fs::metadata(wal_path(&self.path)) {
+            Ok(md) => n_bytes += md.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(api::StorageStat {
+            disk_bytes: Some(n_bytes),
+            map_bytes: None,
+        })
+    }
+
+    fn get_ns(&self, ns: Option<&str>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.check_ns(ns)?;
+        let conn = self.conn.lock().unwrap();
+        let val = conn
+            .prepare_cached("SELECT value FROM kv WHERE ns = ?1 AND key = ?2")?
+            .query_row(params![ns_col(ns), key], |row| row.get(0))
+            .optional()?;
+
+        Ok(val)
+    }
+
+    fn put_ns(&mut self, ns: Option<&str>, key: &[u8], val: &[u8]) -> Result<bool> {
+        self.writable()?;
+        self.check_ns(ns)?;
+        let conn = self.conn.lock().unwrap();
+        conn.prepare_cached("INSERT OR REPLACE INTO kv (ns, key, value) VALUES (?1, ?2, ?3)")?
+            .execute(params![ns_col(ns), key, val])?;
+        Ok(true)
+    }
+
+    fn del_ns(&mut self, ns: Option<&str>, key: &[u8]) -> Result<bool> {
+        self.writable()?;
+        self.check_ns(ns)?;
+        let conn = self.conn.lock().unwrap();
+        let n_deleted = conn
+            .prepare_cached("DELETE FROM kv WHERE ns = ?1 AND key = ?2")?
+            .execute(params![ns_col(ns), key])?;
+        Ok(n_deleted > 0)
+    }
+
+    fn apply_batch(&mut self, batch: &api::Batch) -> Result<bool> {
+        self.writable()?;
+        for ns in batch.namespaces() {
+            self.check_ns(ns)?;
+        }
+
+        // one transaction; rolled back when dropped uncommitted
+        let mut conn = self.conn.lock().unwrap();
+        let txn = conn.transaction()?;
+        for (idx, mutation) in batch.ops.iter().enumerate() {
+            let ns = ns_col(mutation.ns.as_deref());
+
+            if let Some(cond) = &mutation.condition {
+                let cur_val: Option<Vec<u8>> = txn
+                    .prepare_cached("SELECT value FROM kv WHERE ns = ?1 AND key = ?2")?
+                    .query_row(params![ns, mutation.key], |row| row.get(0))
+                    .optional()?;
+                cond.check(idx, &mutation.key, cur_val.as_deref())?;
+            }
+
+            match mutation.op {
+                api::MutationOp::Insert => {
+                    txn.prepare_cached(
+                        "INSERT OR REPLACE INTO kv (ns, key, value) VALUES (?1, ?2, ?3)",
+                    )?
+                    .execute(params![
+                        ns,
+                        mutation.key,
+                        mutation.value.as_ref().unwrap()
+                    ])?;
+                }
+                api::MutationOp::Remove => {
+                    txn.prepare_cached("DELETE FROM kv WHERE ns = ?1 AND key = ?2")?
+                        .execute(params![ns, mutation.key])?;
+                }
+            }
+        }
+        txn.commit()?;
+
+        Ok(true)
+    }
+
+    fn scan(&self, opts: api::IterOptions) -> Result<api::KvList> {
+        let mut page = api::ScanPage::new(&opts);
+        self.check_ns(opts.ns.as_deref())?;
+
+        // key range covers start/end keys and prefix
+        let (lower, upper) = match opts.key_range() {
+            None => return Ok(page.finish()),
+            Some(range) => range,
+        };
+
+        // range query over the primary key index
+        let mut sql = match opts.keys_only {
+            true => String::from("SELECT key, x'' FROM kv WHERE ns = ?1"),
+            false => String::from("SELECT key, value FROM kv WHERE ns = ?1"),
+        };
+        let mut args = vec![Value::Text(ns_col(opts.ns.as_deref()).to_string())];
+        for (bound, incl_op, excl_op) in [(lower, ">=", ">"), (upper, "<=", "<")] {
+            let (op, key) = match bound {
+                Bound::Unbounded => continue,
+                Bound::Included(key) => (incl_op, key),
+                Bound::Excluded(key) => (excl_op, key),
+            };
+            args.push(Value::Blob(key));
+            sql.push_str(&format!(" AND key {} ?{}", op, args.len()));
+        }
+        sql.push_str(match opts.reverse {
+            false => " ORDER BY key ASC",
+            true => " ORDER BY key DESC",
+        });
+        sql.push_str(&format!(" LIMIT {}", opts.limit));
+
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(&sql)?;
+        let mut rows = stmt.query(params_from_iter(args.iter()))?;
+        while let Some(row) = rows.next()? {
+            let key: Vec<u8> = row.get(0)?;
+            let value: Vec<u8> = row.get(1)?;
+            if !page.push(&key, &value) {
+                break;
+            }
+        }
+
+        Ok(page.finish())
+    }
+
+    fn open_ns(&mut self, ns: &str) -> Result<bool> {
+        api::validate_ns_name(ns)?;
+        if !self.nss.contains(ns) {
+            self.writable()?;
+            let conn = self.conn.lock().unwrap();
+            conn.prepare_cached("INSERT OR IGNORE INTO kv_namespaces (name) VALUES (?1)")?
+                .execute(params![ns])?;
+            drop(conn);
+            self.nss.insert(ns.to_string());
+        }
+
+        Ok(true)
+    }
+
+    fn drop_ns(&mut self, ns: &str) -> Result<bool> {
+        self.writable()?;
+        if !self.nss.contains(ns) {
+            return Ok(false);
+        }
+
+        let mut conn = self.conn.lock().unwrap();
+        let txn = conn.transaction()?;
+        txn.execute("DELETE FROM kv WHERE ns = ?1", params![ns])?;
+        txn.execute("DELETE FROM kv_namespaces WHERE name = ?1", params![ns])?;
+        txn.commit()?;
+        drop(conn);
+        self.nss.remove(ns);
+
+        Ok(true)
+    }
+
+    fn list_ns(&self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = self.nss.iter().cloned().collect();
+        names.sort();
+
+        Ok(names)
+    }
+}
+
+// value of option key, which must be one of allowed; None if unset
+fn choice_option<'a>(
+    options: &'a api::Options,
+    key: &str,
+    allowed: &[&str],
+) -> Result<Option<&'a str>> {
+    match options.get(key) {
+        None => Ok(None),
+        Some(val) if allowed.contains(&val.as_str()) => Ok(Some(val.as_str())),
+        Some(val) => Err(Error::InvalidArgument(format!(
+            "driver option {}: invalid value {} ({})",
+            key,
+            val,
+            allowed.join(", ")
+        ))),
+    }
+}
+
+// synchronous pragma for durability and options, or None for SQLite's
+// default (full); and the background checkpoint interval, if any.
+// Periodic and NoSync use NORMAL, not OFF: commits skip sync, but SQLite
+// still orders its writes so that a crash cannot corrupt the database.
+fn sync_level(
+    durability: api::Durability,
+    options: &api::Options,
+) -> Result<(Option<&str>, Option<Duration>)> {
+    let level = choice_option(options, "synchronous", SYNC_LEVELS)?;
+    match (durability, level) {
+        (api::Durability::Default, level) => Ok((level, None)),
+        (_, Some(_level)) => Err(Error::InvalidArgument(String::from(
+            "driver option synchronous requires default durability",
+        ))),
+        (api::Durability::Sync, None) => Ok((Some("full"), None)),
+        (api::Durability::Periodic(0), None) => Err(Error::InvalidArgument(String::from(
+            "flush interval must be positive",
+        ))),
+        (api::Durability::Periodic(ms), None) => {
+            Ok((Some("normal"), Some(Duration::from_millis(ms))))
+        }
+        (api::Durability::NoSync, None) => Ok((Some("normal"), None)),
+    }
+}
+
+// open database file read-only.  Any reader of a WAL-mode database
+// creates -wal and -shm files, which a read-only connection cannot
+// remove; so when no log remains from the last writer, the file is
+// opened immutable, and nothing is created.
+fn open_read_only(path: &Path) -> Result<Connection> {
+    if !path.is_file() {
+        return Err(Error::NotFound);
+    }
+
+    let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    if wal_path(path).exists() {
+        return Ok(Connection::open_with_flags(path, flags)?);
+    }
+
+    // URI file name: escape characters with meaning in a URI
+    let mut uri = String::from("file:");
+    for c in path.to_string_lossy().chars() {
+        match c {
+            '%' | '?' | '#' => uri.push_str(&format!("%{:02x}", c as u32)),
+            c => uri.push(c),
+        }
+    }
+    uri.push_str("?immutable=1");
+
+    Ok(Connection::open_with_flags(
+        uri,
+        flags | OpenFlags::SQLITE_OPEN_URI,
+    )?)
+}
+
+pub struct SqliteDriver {}
+
+impl api::Driver for SqliteDriver {
+    fn check_options(&self, options: &api::Options) -> Result<()> {
+        api::check_option_names(options, SQLITE_OPTIONS)?;
+        choice_option(options, "journal_mode", JOURNAL_MODES)?;
+        choice_option(options, "synchronous", SYNC_LEVELS)?;
+        Ok(())
+    }
+
+    fn start_db(&self, cfg: api::Config) -> Result<Box<dyn api::Db>> {
+        self.check_options(&cfg.options)?;
+        let path = Path::new(&cfg.path).join(DB_FILE);
+
+        let conn = match cfg.read_only {
+            true => open_read_only(&path)?,
+            false => {
+                fs::create_dir_all(&cfg.path)?;
+                Connection::open(&path)?
+            }
+        };
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+
+        let mut flush_interval = None;
+        if !cfg.read_only {
+            let journal_mode = choice_option(&cfg.options, "journal_mode", JOURNAL_MODES)?;
+            let _mode: String = conn.pragma_update_and_check(
+                None,
+                "journal_mode",
+                journal_mode.unwrap_or("wal"),
+                |row| row.get(0),
+            )?;
+
+            let (level, interval) = sync_level(cfg.durability, &cfg.options)?;
+            if let Some(level) = level {
+                conn.pragma_update(None, "synchronous", level)?;
+            }
+            flush_interval = interval;
+
+            conn.execute_batch(SCHEMA)?;
+        }
+
+        // open all existing namespaces
+        let mut nss = HashSet::new();
+        {
+            let mut stmt = conn.prepare("SELECT name FROM kv_namespaces")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                nss.insert(row.get(0)?);
+            }
+        }
+
+        let conn = Arc::new(Mutex::new(conn));
+        let flusher = match flush_interval {
+            None => None,
+            Some(interval) => Some(Flusher::start(Arc::clone(&conn), interval)?),
+        };
+
+        ttl::wrap(version::wrap(Box::new(SqliteDb {
+            conn,
+            path,
+            nss,
+            read_only: cfg.read_only,
+            _flusher: flusher,
+        }))?)
+    }
+}
+
+pub fn new_driver() -> Box<dyn api::Driver> {
+    Box::new(SqliteDriver {})
+}
+
+#[cfg(test)]
+use super::api::{ConfigBuilder, Durability};
+#[cfg(test)]
+use super::conformance;
+#[cfg(test)]
+use tempdir::TempDir;
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    // database in dir, with default options
+    fn config(dir: &str) -> ConfigBuilder {
+        let mut builder = ConfigBuilder::new();
+        builder.path(dir.to_string());
+        builder
+    }
+
+    #[test]
+    fn test_conformance() {
+        conformance::run(new_driver().as_ref(), &config);
+    }
+
+    #[test]
+    fn test_scan_ordered() {
+        conformance::scan_ordered(new_driver().as_ref(), &config);
+    }
+
+    #[test]
+    fn test_durability() {
+        let driver = new_driver();
+        conformance::durability(driver.as_ref(), &config);
+
+        // periodic and nosync commits skip sync, but never use OFF
+        let no_options = api::Options::new();
+        assert_eq!(
+            sync_level(Durability::Periodic(10), &no_options).unwrap(),
+            (Some("normal"), Some(Duration::from_millis(10)))
+        );
+        assert_eq!(
+            sync_level(Durability::NoSync, &no_options).unwrap(),
+            (Some("normal"), None)
+        );
+
+        // synchronous option only with default durability
+        let tmp_dir = TempDir::new("tdu").unwrap();
+        let db_config = config(tmp_dir.path().to_str().unwrap())
+            .durability(Durability::Sync)
+            .option("synchronous", "normal")
+            .build();
+        assert!(matches!(
+            driver.start_db(db_config),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_options() {
+        let driver = new_driver();
+        let options = |pairs: &[(&str, &str)]| -> api::Options {
+            pairs
+                .iter()
+                .map(|(key, val)| (key.to_string(), val.to_string()))
+                .collect()
+        };
+
+        assert!(driver
+            .check_options(&options(&[
+                ("journal_mode", "wal"),
+                ("synchronous", "extra")
+            ]))
+            .is_ok());
+        for bad in [
+            ("map_size", "1048576"),
+            ("journal_mode", "memory"),
+            ("synchronous", "2"),
+        ] {
+            assert!(matches!(
+                driver.check_options(&options(&[bad])),
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+
+        // rollback journal: no write-ahead log
+        let tmp_dir = TempDir::new("topt").unwrap();
+        let db_config = ConfigBuilder::new()
+            .path(tmp_dir.path().to_str().unwrap().to_string())
+            .option("journal_mode", "delete")
+            .option("synchronous", "normal")
+            .build();
+        let mut db = driver.start_db(db_config).unwrap();
+        assert!(db.put(b"name", b"alan").unwrap());
+        assert_eq!(db.get(b"name").unwrap(), Some(Vec::from("alan")));
+        assert!(!wal_path(&tmp_dir.path().join(DB_FILE)).exists());
+    }
+}
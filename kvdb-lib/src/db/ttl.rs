@@ -0,0 +1,584 @@
+/*
+ * ttl: per-key expiry, layered over any driver.
+ *
+ * Expiry times are stored in two reserved namespaces, written in the same
+ * atomic batch as the records they describe:
+ *
+ *   TTL_NS      [ns_len][ns][key]              -> expiry (u64, big-endian)
+ *   TTL_IDX_NS  [expiry][ns_len][ns][key]      -> empty
+ *
+ * TTL_NS answers "has this key expired?" on every read.  TTL_IDX_NS is
+ * ordered by expiry time, so purge_expired() finds expired records with a
+ * single range scan.  Index entries are never updated in place; stale
+ * entries are recognized by comparison with TTL_NS, and discarded.
+ */
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::api;
+use crate::{Error, Result};
+
+// key -> expiry time
+const TTL_NS: &str = "__ttl";
+
+// expiry time + key -> empty, in expiry order
+const TTL_IDX_NS: &str = "__ttl_idx";
+
+/// Current time, in milliseconds since the Unix epoch
+pub fn now_ms() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_millis() as u64,
+        Err(_e) => 0,
+    }
+}
+
+/// Expiry time for a record stored now, with a lifetime of ttl_ms
+pub fn expiry_after(ttl_ms: u64) -> u64 {
+    now_ms().saturating_add(ttl_ms)
+}
+
+// TTL_NS key for a record; also its key in the version layer's namespace
+pub(super) fn meta_key(ns: Option<&str>, key: &[u8]) -> Vec<u8> {
+    let ns = ns.unwrap_or("");
+    let mut out = Vec::with_capacity(1 + ns.len() + key.len());
+    out.push(ns.len() as u8); // namespace names are at most 128 bytes
+    out.extend_from_slice(ns.as_bytes());
+    out.extend_from_slice(key);
+
+    out
+}
+
+// TTL_IDX_NS key for a record
+fn idx_key(expires: u64, ns: Option<&str>, key: &[u8]) -> Vec<u8> {
+    let mut out = expires.to_be_bytes().to_vec();
+    out.extend_from_slice(&meta_key(ns, key));
+
+    out
+}
+
+// TTL_IDX_NS key to (expiry, namespace, key)
+fn parse_idx_key(idx_key: &[u8]) -> Result<(u64, Option<String>, Vec<u8>)> {
+    let corrupt = || Error::Corruption(String::from("ttl index key"));
+
+    if idx_key.len() < 9 {
+        return Err(corrupt());
+    }
+    let expires = u64::from_be_bytes(idx_key[0..8].try_into().unwrap());
+    let ns_len = idx_key[8] as usize;
+    let ns_end = 9 + ns_len;
+    if idx_key.len() < ns_end {
+        return Err(corrupt());
+    }
+    let ns = match ns_len {
+        0 => None,
+        _ => match std::str::from_utf8(&idx_key[9..ns_end]) {
+            Ok(ns) => Some(ns.to_string()),
+            Err(_e) => return Err(corrupt()),
+        },
+    };
+
+    Ok((expires, ns, idx_key[ns_end..].to_vec()))
+}
+
+/// Database wrapper adding per-key expiry to any driver.  Expiry
+/// namespaces are created on first use, so databases that never store an
+/// expiring record are unchanged on disk.
+pub struct TtlDb {
+    db: Box<dyn api::Db>,
+    enabled: bool, // expiry namespaces exist
+}
+
+/// Wrap an open database with expiry support
+pub fn wrap(db: Box<dyn api::Db>) -> Result<Box<dyn api::Db>> {
+    let enabled = db.list_ns()?.iter().any(|name| name == TTL_NS);
+
+    Ok(Box::new(TtlDb { db, enabled }))
+}
+
+impl TtlDb {
+    // create expiry namespaces
+    fn enable(&mut self) -> Result<()> {
+        self.db.open_ns(TTL_NS)?;
+        self.db.open_ns(TTL_IDX_NS)?;
+        self.enabled = true;
+
+        Ok(())
+    }
+
+    // expiry time of a record, if any
+    fn expires(&self, ns: Option<&str>, key: &[u8]) -> Result<Option<u64>> {
+        if !self.enabled {
+            return Ok(None);
+        }
+
+        match self.db.get_ns(Some(TTL_NS), &meta_key(ns, key))? {
+            None => Ok(None),
+            Some(val) => match <[u8; 8]>::try_from(&val[..]) {
+                Ok(bytes) => Ok(Some(u64::from_be_bytes(bytes))),
+                Err(_e) => Err(Error::Corruption(String::from("ttl value"))),
+            },
+        }
+    }
+
+    fn is_expired(&self, ns: Option<&str>, key: &[u8], now: u64) -> Result<bool> {
+        match self.expires(ns, key)? {
+            None => Ok(false),
+            Some(expires) => Ok(expires <= now),
+        }
+    }
+
+    // remove expired records touched by batch, so that preconditions
+    // see them as absent
+    fn purge_batch_keys(&mut self, batch: &api::Batch) -> Result<()> {
+        let now = now_ms();
+        let mut purge = api::Batch::default();
+        for m in &batch.ops {
+            let ns = m.ns.as_deref();
+            if self.is_expired(ns, &m.key, now)? {
+                purge.set_ns(ns);
+                purge.remove(&m.key);
+                purge.set_ns(Some(TTL_NS));
+                purge.remove(&meta_key(ns, &m.key));
+            }
+        }
+
+        if !purge.ops.is_empty() {
+            self.db.apply_batch(&purge)?;
+        }
+
+        Ok(())
+    }
+
+    // number of expired records in namespace not yet purged
+    fn count_expired(&self, ns: Option<&str>) -> Result<u64> {
+        if !self.enabled {
+            return Ok(0);
+        }
+
+        // index entries for all records expiring up to now
+        let now = now_ms();
+        let mut opts = api::IterOptions::new();
+        opts.ns(TTL_IDX_NS)
+            .keys_only(true)
+            .end(&(now + 1).to_be_bytes(), false);
+
+        let mut count = 0;
+        loop {
+            let key_list = self.db.iter_keys(opts.clone())?;
+            for idx in &key_list.keys {
+                let (expires, idx_ns, key) = parse_idx_key(idx)?;
+
+                // skip other namespaces, and stale entries
+                if idx_ns.as_deref() == ns
+                    && self.expires(ns, &key)? == Some(expires)
+                    && self.db.get_ns(ns, &key)?.is_some()
+                {
+                    count += 1;
+                }
+            }
+
+            match key_list.keys.last() {
+                Some(last) if !key_list.list_end => opts.start_key = Some(last.clone()),
+                _ => return Ok(count),
+            }
+        }
+    }
+
+    // remove expiry times of all records in namespace
+    fn clear_meta(&mut self, ns: Option<&str>) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        let prefix = meta_key(ns, b"");
+        loop {
+            let mut opts = api::IterOptions::new();
+            opts.ns(TTL_NS).prefix(&prefix).keys_only(true);
+            let key_list = self.db.iter_keys(opts)?;
+
+            let mut batch = api::Batch::default();
+            batch.set_ns(Some(TTL_NS));
+            for key in &key_list.keys {
+                batch.remove(key);
+            }
+            if !batch.ops.is_empty() {
+                self.db.apply_batch(&batch)?;
+            }
+
+            if key_list.list_end {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl api::Db for TtlDb {
+    fn apply_batch(&mut self, batch: &api::Batch) -> Result<bool> {
+        let has_expiry = batch.ops.iter().any(|m| m.expires.is_some());
+        if !self.enabled {
+            if !has_expiry {
+                return self.db.apply_batch(batch);
+            }
+            self.enable()?;
+        }
+
+        self.purge_batch_keys(batch)?;
+
+        // caller's mutations first, so precondition failures report the
+        // caller's op index; then their expiry updates, in the same order
+        let mut full = api::Batch::default();
+        full.ops = batch.ops.clone();
+        for m in &batch.ops {
+            let ns = m.ns.as_deref();
+            full.set_ns(Some(TTL_NS));
+            match (&m.op, m.expires) {
+                (api::MutationOp::Insert, Some(expires)) => {
+                    full.insert(&meta_key(ns, &m.key), &expires.to_be_bytes());
+                    full.set_ns(Some(TTL_IDX_NS));
+                    full.insert(&idx_key(expires, ns, &m.key), b"");
+                }
+                _ => full.remove(&meta_key(ns, &m.key)),
+            }
+        }
+
+        self.db.apply_batch(&full)
+    }
+
+    fn clear_ns(&mut self, ns: Option<&str>) -> Result<bool> {
+        let res = self.db.clear_ns(ns)?;
+        self.clear_meta(ns)?;
+
+        Ok(res)
+    }
+
+    fn del_ns(&mut self, ns: Option<&str>, key: &[u8]) -> Result<bool> {
+        if !self.enabled {
+            return self.db.del_ns(ns, key);
+        }
+
+        let expired = self.is_expired(ns, key, now_ms())?;
+        let found = self.db.get_ns(ns, key)?.is_some();
+
+        // record and its expiry time, atomically
+        let mut batch = api::Batch::default();
+        batch.set_ns(ns);
+        batch.remove(key);
+        batch.set_ns(Some(TTL_NS));
+        batch.remove(&meta_key(ns, key));
+        self.db.apply_batch(&batch)?;
+
+        Ok(found && !expired)
+    }
+
+    fn get_ns(&self, ns: Option<&str>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.db.get_ns(ns, key)? {
+            None => Ok(None),
+            Some(val) => match self.is_expired(ns, key, now_ms())? {
+                true => Ok(None),
+                false => Ok(Some(val)),
+            },
+        }
+    }
+
+    fn version_ns(&self, ns: Option<&str>, key: &[u8]) -> Result<Option<u64>> {
+        match self.is_expired(ns, key, now_ms())? {
+            true => Ok(None),
+            false => self.db.version_ns(ns, key),
+        }
+    }
+
+    fn put_ns(&mut self, ns: Option<&str>, key: &[u8], val: &[u8]) -> Result<bool> {
+        if !self.enabled {
+            return self.db.put_ns(ns, key, val);
+        }
+
+        let mut batch = api::Batch::default();
+        batch.set_ns(ns);
+        batch.insert(key, val);
+
+        self.apply_batch(&batch)
+    }
+
+    fn scan(&self, opts: api::IterOptions) -> Result<api::KvList> {
+        if !self.enabled {
+            return self.db.scan(opts);
+        }
+
+        let now = now_ms();
+        let ns = opts.ns.clone();
+        let mut page = api::ScanPage::new(&opts);
+        let mut inner_opts = opts;
+
+        // refill page from underlying db, skipping expired records
+        loop {
+            let kv_list = self.db.scan(inner_opts.clone())?;
+            for kv in &kv_list.pairs {
+                if self.is_expired(ns.as_deref(), &kv.key, now)? {
+                    continue;
+                }
+                if !page.push(&kv.key, &kv.value) {
+                    return Ok(page.finish());
+                }
+            }
+
+            match kv_list.pairs.last() {
+                Some(last) if !kv_list.list_end => {
+                    inner_opts.start_key = Some(last.key.clone());
+                }
+                _ => return Ok(page.finish()),
+            }
+        }
+    }
+
+    fn stat_ns(&self, ns: Option<&str>) -> Result<api::DbStat> {
+        let mut st = self.db.stat_ns(ns)?;
+        st.n_records = st.n_records.saturating_sub(self.count_expired(ns)?);
+
+        Ok(st)
+    }
+
+    fn storage_stat(&self) -> Result<api::StorageStat> {
+        self.db.storage_stat()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        self.db.compact()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.db.close()
+    }
+
+    fn open_ns(&mut self, ns: &str) -> Result<bool> {
+        self.db.open_ns(ns)
+    }
+
+    fn drop_ns(&mut self, ns: &str) -> Result<bool> {
+        let res = self.db.drop_ns(ns)?;
+        self.clear_meta(Some(ns))?;
+
+        Ok(res)
+    }
+
+    fn list_ns(&self) -> Result<Vec<String>> {
+        let names = self.db.list_ns()?;
+
+        Ok(names
+            .into_iter()
+            .filter(|name| name != TTL_NS && name != TTL_IDX_NS)
+            .collect())
+    }
+
+    fn compare_and_swap_ns(
+        &mut self,
+        ns: Option<&str>,
+        key: &[u8],
+        old_val: Option<&[u8]>,
+        new_val: Option<&[u8]>,
+    ) -> Result<bool> {
+        if !self.enabled {
+            return self.db.compare_and_swap_ns(ns, key, old_val, new_val);
+        }
+
+        let cond = api::Precondition::from_expected(old_val);
+
+        let mut batch = api::Batch::default();
+        batch.set_ns(ns);
+        match new_val {
+            None => batch.remove_if(key, cond),
+            Some(val) => batch.insert_if(key, val, cond),
+        }
+
+        self.apply_batch(&batch)
+    }
+
+    fn purge_expired(&mut self, limit: usize) -> Result<usize> {
+        if !self.enabled {
+            return Ok(0);
+        }
+
+        // index entries for all records expiring up to now
+        let now = now_ms();
+        let mut opts = api::IterOptions::new();
+        opts.ns(TTL_IDX_NS)
+            .keys_only(true)
+            .limit(limit)
+            .end(&(now + 1).to_be_bytes(), false);
+        let key_list = self.db.iter_keys(opts)?;
+
+        let nss = self.db.list_ns()?;
+        let mut batch = api::Batch::default();
+        for idx in &key_list.keys {
+            let (expires, ns, key) = parse_idx_key(idx)?;
+            batch.set_ns(Some(TTL_IDX_NS));
+            batch.remove(idx);
+
+            // skip stale entries: namespace dropped, or expiry changed
+            let ns = ns.as_deref();
+            let ns_exists = match ns {
+                None => true,
+                Some(name) => nss.iter().any(|n| n == name),
+            };
+            if ns_exists && self.expires(ns, &key)? == Some(expires) {
+                batch.set_ns(ns);
+                batch.remove(&key);
+                batch.set_ns(Some(TTL_NS));
+                batch.remove(&meta_key(ns, &key));
+            }
+        }
+
+        if !batch.ops.is_empty() {
+            self.db.apply_batch(&batch)?;
+        }
+
+        Ok(key_list.keys.len())
+    }
+}
+
+#[cfg(test)]
+use super::api::{Batch, ConfigBuilder, Db, IterOptions};
+#[cfg(test)]
+use tempdir::TempDir;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type NewDriver = fn() -> Box<dyn api::Driver>;
+    type Options = &'static [(&'static str, &'static str)];
+
+    // run test against each driver, with options keeping data across reopen
+    fn each_driver(test: fn(&dyn Fn() -> Box<dyn Db>)) {
+        #[allow(unused_mut)] // optional drivers are added below
+        let mut drivers: Vec<(NewDriver, Options)> = vec![
+            (super::super::sled::new_driver, &[]),
+            (super::super::lmdb::new_driver, &[]),
+            (super::super::memory::new_driver, &[("snapshot", "true")]),
+            (super::super::redb::new_driver, &[]),
+            (super::super::sqlite::new_driver, &[]),
+            (super::super::bitcask::new_driver, &[]),
+        ];
+        #[cfg(feature = "gdbm")]
+        drivers.push((super::super::gdbm::new_driver, &[]));
+
+        for (new_driver, options) in drivers {
+            let tmp_dir = TempDir::new("tttl").unwrap();
+            let tmp_path = tmp_dir.path().to_str().unwrap().to_string();
+            let driver = new_driver();
+
+            let start = || {
+                let mut builder = ConfigBuilder::new();
+                for (key, val) in options {
+                    builder.option(key, val);
+                }
+                let db_config = builder.path(tmp_path.clone()).read_only(false).build();
+                driver.start_db(db_config).unwrap()
+            };
+            test(&start);
+        }
+    }
+
+    #[test]
+    fn test_expiry() {
+        each_driver(|start| {
+            let mut db = start();
+            let past = now_ms() - 1;
+            let future = expiry_after(3_600_000);
+
+            let mut batch = Batch::default();
+            batch.insert_expiring(b"gone", b"v1", past);
+            batch.insert_expiring(b"later", b"v2", future);
+            batch.insert(b"keep", b"v3");
+            assert!(db.apply_batch(&batch).unwrap());
+
+            // expired records are invisible at once
+            assert_eq!(db.get(b"gone").unwrap(), None);
+            assert_eq!(db.get(b"later").unwrap(), Some(Vec::from("v2")));
+            let mut key_list = db.iter_keys(IterOptions::new()).unwrap();
+            key_list.keys.sort(); // gdbm: hash order
+            assert_eq!(key_list.keys, vec![Vec::from("keep"), Vec::from("later")]);
+            assert!(db.list_ns().unwrap().is_empty());
+
+            // expired record counts as absent for preconditions
+            assert!(db.compare_and_swap(b"gone", None, Some(b"v4")).unwrap());
+            assert_eq!(db.get(b"gone").unwrap(), Some(Vec::from("v4")));
+
+            // plain insert clears expiry
+            let mut batch = Batch::default();
+            batch.insert_expiring(b"keep", b"v3", past);
+            assert!(db.apply_batch(&batch).unwrap());
+            assert_eq!(db.get(b"keep").unwrap(), None);
+            assert!(db.put(b"keep", b"v5").unwrap());
+            assert_eq!(db.get(b"keep").unwrap(), Some(Vec::from("v5")));
+        });
+    }
+
+    #[test]
+    fn test_scan_expired_page() {
+        each_driver(|start| {
+            let mut db = start();
+            let past = now_ms() - 1;
+
+            // a full page of expired records, followed by a live one
+            let mut batch = Batch::default();
+            for i in 0..10 {
+                batch.insert_expiring(format!("a{}", i).as_bytes(), b"x", past);
+            }
+            batch.insert(b"b", b"y");
+            assert!(db.apply_batch(&batch).unwrap());
+
+            let mut opts = IterOptions::new();
+            opts.limit(3);
+            let kv_list = db.scan(opts).unwrap();
+            assert_eq!(kv_list.pairs.len(), 1);
+            assert_eq!(kv_list.pairs[0].key, b"b");
+            assert!(kv_list.list_end);
+        });
+    }
+
+    #[test]
+    fn test_purge_expired() {
+        each_driver(|start| {
+            let past = now_ms() - 1;
+            {
+                let mut db = start();
+                db.open_ns("users").unwrap();
+
+                let mut batch = Batch::default();
+                batch.insert_expiring(b"k1", b"v1", past);
+                batch.insert_expiring(b"k2", b"v2", expiry_after(3_600_000));
+                batch.set_ns(Some("users"));
+                batch.insert_expiring(b"k1", b"v1", past);
+                batch.insert_expiring(b"k3", b"v3", past);
+                assert!(db.apply_batch(&batch).unwrap());
+
+                // k3 rewritten without expiry: its index entry is stale
+                assert!(db.put_ns(Some("users"), b"k3", b"v3").unwrap());
+
+                // expired records are not counted, even before purge
+                assert_eq!(db.stat().unwrap().n_records, 1);
+                assert_eq!(db.stat_ns(Some("users")).unwrap().n_records, 1);
+                db.close().unwrap();
+            }
+
+            // expiry survives reopen
+            let mut db = start();
+            assert_eq!(db.get(b"k1").unwrap(), None);
+
+            assert_eq!(db.purge_expired(1).unwrap(), 1);
+            assert_eq!(db.purge_expired(100).unwrap(), 2);
+            assert_eq!(db.purge_expired(100).unwrap(), 0);
+            assert_eq!(db.stat().unwrap().n_records, 1);
+            assert_eq!(db.stat_ns(Some("users")).unwrap().n_records, 1);
+            assert_eq!(
+                db.get_ns(Some("users"), b"k3").unwrap(),
+                Some(Vec::from("v3"))
+            );
+            assert_eq!(db.list_ns().unwrap(), vec![String::from("users")]);
+        });
+    }
+}
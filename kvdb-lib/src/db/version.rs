@@ -0,0 +1,275 @@
+/*
+ * version: per-key version numbers, layered over any driver.
+ *
+ * Each write of a record gives it the next number of a counter kept for
+ * the whole database, so versions are never reused: not by a rewrite
+ * with the same value, nor by a key removed and created again.  Versions
+ * are stored in a reserved namespace, written in the same atomic batch
+ * as the records they describe:
+ *
+ *   VERSION_NS  [ns_len][ns][key]              -> version (u64, big-endian)
+ *   VERSION_NS  SEQ_KEY                        -> last version assigned
+ *
+ * A record written before versions were stored has version 0, until it
+ * is next written.  Reserved namespaces, such as the expiry layer's, are
+ * not versioned.
+ */
+
+use std::collections::HashMap;
+
+use super::api;
+use super::ttl::meta_key;
+use crate::{Error, Result};
+
+// key -> version, and the version counter
+const VERSION_NS: &str = "__version";
+
+// counter key; record keys begin with a namespace length, at most 128
+const SEQ_KEY: &[u8] = b"\xff";
+
+// decode a stored version
+fn parse_version(val: &[u8]) -> Result<u64> {
+    match <[u8; 8]>::try_from(val) {
+        Ok(bytes) => Ok(u64::from_be_bytes(bytes)),
+        Err(_e) => Err(Error::Corruption(String::from("version value"))),
+    }
+}
+
+fn is_reserved(ns: Option<&str>) -> bool {
+    ns.is_some_and(|name| name.starts_with(api::RESERVED_NS_PREFIX))
+}
+
+/// Database wrapper adding per-key versions to any driver.  The version
+/// namespace is created on first write, so databases never written since
+/// are unchanged on disk.
+pub struct VersionDb {
+    db: Box<dyn api::Db>,
+    enabled: bool, // version namespace exists
+}
+
+/// Wrap an open database with version support
+pub fn wrap(db: Box<dyn api::Db>) -> Result<Box<dyn api::Db>> {
+    let enabled = db.list_ns()?.iter().any(|name| name == VERSION_NS);
+
+    Ok(Box::new(VersionDb { db, enabled }))
+}
+
+impl VersionDb {
+    // create version namespace
+    fn enable(&mut self) -> Result<()> {
+        if !self.enabled {
+            self.db.open_ns(VERSION_NS)?;
+            self.enabled = true;
+        }
+
+        Ok(())
+    }
+
+    // stored version of a record, whether or not it exists
+    fn stored_version(&self, ns: Option<&str>, key: &[u8]) -> Result<u64> {
+        if !self.enabled {
+            return Ok(0);
+        }
+
+        match self.db.get_ns(Some(VERSION_NS), &meta_key(ns, key))? {
+            None => Ok(0),
+            Some(val) => parse_version(&val),
+        }
+    }
+
+    // last version assigned
+    fn last_seq(&self) -> Result<u64> {
+        match self.db.get_ns(Some(VERSION_NS), SEQ_KEY)? {
+            None => Ok(0),
+            Some(val) => parse_version(&val),
+        }
+    }
+
+    // remove versions of all records in namespace
+    fn clear_versions(&mut self, ns: Option<&str>) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        let prefix = meta_key(ns, b"");
+        loop {
+            let mut opts = api::IterOptions::new();
+            opts.ns(VERSION_NS).prefix(&prefix).keys_only(true);
+            let key_list = self.db.iter_keys(opts)?;
+
+            let mut batch = api::Batch::default();
+            batch.set_ns(Some(VERSION_NS));
+            for key in &key_list.keys {
+                batch.remove(key);
+            }
+            if !batch.ops.is_empty() {
+                self.db.apply_batch(&batch)?;
+            }
+
+            if key_list.list_end {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl api::Db for VersionDb {
+    fn apply_batch(&mut self, batch: &api::Batch) -> Result<bool> {
+        if batch.ops.iter().all(|m| is_reserved(m.ns.as_deref())) {
+            return self.db.apply_batch(batch);
+        }
+        self.enable()?;
+
+        // check version preconditions in order, each seeing the effect of
+        // earlier mutations; value preconditions are left to the driver
+        let mut seq = self.last_seq()?;
+        let mut pending: HashMap<(Option<&str>, &[u8]), Option<u64>> = HashMap::new();
+        for (op_idx, m) in batch.ops.iter().enumerate() {
+            let ns = m.ns.as_deref();
+            if is_reserved(ns) {
+                continue;
+            }
+
+            if let Some(cond @ api::Precondition::VersionEquals(_)) = &m.condition {
+                let cur = match pending.get(&(ns, &m.key[..])) {
+                    Some(cur) => *cur,
+                    None => self.version_ns(ns, &m.key)?,
+                };
+                cond.check_version(op_idx, &m.key, cur)?;
+            }
+
+            let version = match m.op {
+                api::MutationOp::Insert => {
+                    seq += 1;
+                    Some(seq)
+                }
+                api::MutationOp::Remove => None,
+            };
+            pending.insert((ns, &m.key), version);
+        }
+
+        // caller's mutations first, so value precondition failures report
+        // the caller's op index; then their version updates
+        let mut full = api::Batch::default();
+        full.ops = batch.ops.clone();
+        for m in &mut full.ops {
+            if let Some(api::Precondition::VersionEquals(_)) = m.condition {
+                m.condition = None;
+            }
+        }
+        full.set_ns(Some(VERSION_NS));
+        for ((ns, key), version) in &pending {
+            match version {
+                Some(version) => full.insert(&meta_key(*ns, key), &version.to_be_bytes()),
+                None => full.remove(&meta_key(*ns, key)),
+            }
+        }
+        full.insert(SEQ_KEY, &seq.to_be_bytes());
+
+        self.db.apply_batch(&full)
+    }
+
+    fn clear_ns(&mut self, ns: Option<&str>) -> Result<bool> {
+        let res = self.db.clear_ns(ns)?;
+        self.clear_versions(ns)?;
+
+        Ok(res)
+    }
+
+    fn del_ns(&mut self, ns: Option<&str>, key: &[u8]) -> Result<bool> {
+        if is_reserved(ns) {
+            return self.db.del_ns(ns, key);
+        }
+
+        let found = self.db.get_ns(ns, key)?.is_some();
+
+        let mut batch = api::Batch::default();
+        batch.set_ns(ns);
+        batch.remove(key);
+        self.apply_batch(&batch)?;
+
+        Ok(found)
+    }
+
+    fn get_ns(&self, ns: Option<&str>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db.get_ns(ns, key)
+    }
+
+    fn put_ns(&mut self, ns: Option<&str>, key: &[u8], val: &[u8]) -> Result<bool> {
+        let mut batch = api::Batch::default();
+        batch.set_ns(ns);
+        batch.insert(key, val);
+
+        self.apply_batch(&batch)
+    }
+
+    fn version_ns(&self, ns: Option<&str>, key: &[u8]) -> Result<Option<u64>> {
+        match self.db.get_ns(ns, key)? {
+            None => Ok(None),
+            Some(_val) => Ok(Some(self.stored_version(ns, key)?)),
+        }
+    }
+
+    fn scan(&self, opts: api::IterOptions) -> Result<api::KvList> {
+        self.db.scan(opts)
+    }
+
+    fn stat_ns(&self, ns: Option<&str>) -> Result<api::DbStat> {
+        self.db.stat_ns(ns)
+    }
+
+    fn storage_stat(&self) -> Result<api::StorageStat> {
+        self.db.storage_stat()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        self.db.compact()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.db.close()
+    }
+
+    fn open_ns(&mut self, ns: &str) -> Result<bool> {
+        self.db.open_ns(ns)
+    }
+
+    fn drop_ns(&mut self, ns: &str) -> Result<bool> {
+        let res = self.db.drop_ns(ns)?;
+        self.clear_versions(Some(ns))?;
+
+        Ok(res)
+    }
+
+    fn list_ns(&self) -> Result<Vec<String>> {
+        let names = self.db.list_ns()?;
+
+        Ok(names
+            .into_iter()
+            .filter(|name| name != VERSION_NS)
+            .collect())
+    }
+
+    fn compare_and_swap_ns(
+        &mut self,
+        ns: Option<&str>,
+        key: &[u8],
+        old_val: Option<&[u8]>,
+        new_val: Option<&[u8]>,
+    ) -> Result<bool> {
+        let cond = api::Precondition::from_expected(old_val);
+
+        let mut batch = api::Batch::default();
+        batch.set_ns(ns);
+        match new_val {
+            None => batch.remove_if(key, cond),
+            Some(val) => batch.insert_if(key, val, cond),
+        }
+
+        self.apply_batch(&batch)
+    }
+}
@@ -0,0 +1,210 @@
+use std::{fmt, io};
+
+use crate::pbapi::basic_response::ErrCode;
+
+/// Errors returned by database drivers and the operations built on them.
+#[derive(Debug)]
+pub enum Error {
+    NotFound,                // db, key or namespace does not exist
+    ReadOnly,                // write attempted on a read-only database
+    InvalidArgument(String), // malformed or unsupported request
+    TooLarge(String),        // key, value or request exceeds a backend limit
+    Full(String),            // backend or filesystem out of space
+    Corruption(String),      // on-disk data failed validation
+    ConditionFailed(String), // mutation precondition did not hold
+    Io(io::Error),           // filesystem/OS error
+    BackendSpecific(Box<dyn std::error::Error + Send + Sync>), // other backend error
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Machine-readable error code, as carried in BasicResponse.err_code
+    pub fn err_code(&self) -> ErrCode {
+        match self {
+            Error::NotFound => ErrCode::NOT_FOUND,
+            Error::ReadOnly => ErrCode::READ_ONLY,
+            Error::InvalidArgument(_) => ErrCode::INVALID_ARGUMENT,
+            Error::TooLarge(_) => ErrCode::TOO_LARGE,
+            Error::Full(_) => ErrCode::FULL,
+            Error::Corruption(_) => ErrCode::CORRUPTION,
+            Error::ConditionFailed(_) => ErrCode::CONDITION_FAILED,
+            Error::Io(e) => match e.raw_os_error() {
+                Some(ENOSPC) => ErrCode::FULL,
+                _ => ErrCode::IO,
+            },
+            Error::BackendSpecific(_) => ErrCode::BACKEND,
+        }
+    }
+
+    pub fn backend<E>(e: E) -> Error
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        Error::BackendSpecific(Box::new(e))
+    }
+}
+
+// errno for "No space left on device", identical across supported platforms
+const ENOSPC: i32 = 28;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound => write!(f, "not found"),
+            Error::ReadOnly => write!(f, "database is read-only"),
+            Error::InvalidArgument(msg) => write!(f, "invalid argument: {}", msg),
+            Error::TooLarge(msg) => write!(f, "too large: {}", msg),
+            Error::Full(msg) => write!(f, "storage full: {}", msg),
+            Error::Corruption(msg) => write!(f, "corruption: {}", msg),
+            Error::ConditionFailed(msg) => write!(f, "precondition failed: {}", msg),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::BackendSpecific(e) => write!(f, "backend error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::BackendSpecific(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(e: sled::Error) -> Error {
+        match e {
+            sled::Error::Io(e) => Error::Io(e),
+            sled::Error::CollectionNotFound(_) => Error::NotFound,
+            sled::Error::Corruption { at, .. } => Error::Corruption(format!("sled, at {:?}", at)),
+            e => Error::backend(e),
+        }
+    }
+}
+
+impl From<lmdb::Error> for Error {
+    fn from(e: lmdb::Error) -> Error {
+        match e {
+            lmdb::Error::NotFound => Error::NotFound,
+            lmdb::Error::MapFull | lmdb::Error::DbsFull | lmdb::Error::TxnFull => {
+                Error::Full(format!("lmdb: {}", e))
+            }
+            lmdb::Error::BadValSize => Error::TooLarge(format!("lmdb: {}", e)),
+            lmdb::Error::PageNotFound
+            | lmdb::Error::Corrupted
+            | lmdb::Error::Panic
+            | lmdb::Error::VersionMismatch
+            | lmdb::Error::Invalid => Error::Corruption(format!("lmdb: {}", e)),
+            lmdb::Error::Other(EACCES) => Error::ReadOnly,
+            lmdb::Error::Other(errno) => Error::Io(io::Error::from_raw_os_error(errno)),
+            e => Error::backend(e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        let code = match &e {
+            rusqlite::Error::SqliteFailure(err, _msg) => err.code,
+            _ => return Error::backend(e),
+        };
+        match code {
+            rusqlite::ErrorCode::DiskFull => Error::Full(format!("sqlite: {}", e)),
+            rusqlite::ErrorCode::TooBig => Error::TooLarge(format!("sqlite: {}", e)),
+            rusqlite::ErrorCode::DatabaseCorrupt | rusqlite::ErrorCode::NotADatabase => {
+                Error::Corruption(format!("sqlite: {}", e))
+            }
+            rusqlite::ErrorCode::ReadOnly => Error::ReadOnly,
+            _ => Error::backend(e),
+        }
+    }
+}
+
+impl From<redb::Error> for Error {
+    fn from(e: redb::Error) -> Error {
+        match e {
+            redb::Error::Io(e) => Error::Io(e),
+            redb::Error::TableDoesNotExist(_) => Error::NotFound,
+            redb::Error::ValueTooLarge(_) => Error::TooLarge(format!("redb: {}", e)),
+            redb::Error::Corrupted(_) => Error::Corruption(format!("redb: {}", e)),
+            e => Error::backend(e),
+        }
+    }
+}
+
+// redb's per-operation error types, each convertible to redb::Error
+impl From<redb::DatabaseError> for Error {
+    fn from(e: redb::DatabaseError) -> Error {
+        redb::Error::from(e).into()
+    }
+}
+
+impl From<redb::TransactionError> for Error {
+    fn from(e: redb::TransactionError) -> Error {
+        redb::Error::from(e).into()
+    }
+}
+
+impl From<redb::TableError> for Error {
+    fn from(e: redb::TableError) -> Error {
+        redb::Error::from(e).into()
+    }
+}
+
+impl From<redb::StorageError> for Error {
+    fn from(e: redb::StorageError) -> Error {
+        redb::Error::from(e).into()
+    }
+}
+
+impl From<redb::CommitError> for Error {
+    fn from(e: redb::CommitError) -> Error {
+        redb::Error::from(e).into()
+    }
+}
+
+impl From<redb::SetDurabilityError> for Error {
+    fn from(e: redb::SetDurabilityError) -> Error {
+        redb::Error::from(e).into()
+    }
+}
+
+// errno returned by lmdb for write transactions on a read-only environment
+const EACCES: i32 = 13;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_err_code() {
+        assert_eq!(Error::NotFound.err_code(), ErrCode::NOT_FOUND);
+        assert_eq!(Error::from(lmdb::Error::MapFull).err_code(), ErrCode::FULL);
+        assert_eq!(
+            Error::from(lmdb::Error::Other(EACCES)).err_code(),
+            ErrCode::READ_ONLY
+        );
+        assert_eq!(
+            Error::from(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_FULL),
+                None
+            ))
+            .err_code(),
+            ErrCode::FULL
+        );
+        assert_eq!(
+            Error::from(io::Error::from_raw_os_error(ENOSPC)).err_code(),
+            ErrCode::FULL
+        );
+        assert_eq!(Error::from(io::Error::other("eio")).err_code(), ErrCode::IO);
+    }
+}
@@ -0,0 +1,83 @@
+/*
+ * grpc: generated client and server of the KeyValueDb gRPC service
+ * declared in protos/pbapi.proto, with the tonic codec for
+ * rust-protobuf messages they share.
+ */
+
+use std::marker::PhantomData;
+
+use bytes::{Buf, BufMut};
+use protobuf::Message;
+use tonic::codec::{Codec, DecodeBuf, Decoder, EncodeBuf, Encoder};
+use tonic::Status;
+
+include!(concat!(env!("OUT_DIR"), "/kvdbd.KeyValueDb.rs"));
+
+// request metadata key naming the target database
+pub const GRPC_DB_KEY: &str = "kvdb-db";
+
+// request metadata key carrying "Bearer KEY" API key
+pub const GRPC_AUTH_KEY: &str = "authorization";
+
+// response metadata key carrying BasicResponse.ErrCode on failed calls
+pub const GRPC_ERR_CODE_KEY: &str = "kvdb-err-code";
+
+/// tonic codec for rust-protobuf generated messages
+pub struct ProtobufCodec<T, U>(PhantomData<(T, U)>);
+
+impl<T, U> Default for ProtobufCodec<T, U> {
+    fn default() -> Self {
+        ProtobufCodec(PhantomData)
+    }
+}
+
+impl<T, U> Codec for ProtobufCodec<T, U>
+where
+    T: Message + Send + 'static,
+    U: Message + Send + 'static,
+{
+    type Encode = T;
+    type Decode = U;
+    type Encoder = ProtobufEncoder<T>;
+    type Decoder = ProtobufDecoder<U>;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        ProtobufEncoder(PhantomData)
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        ProtobufDecoder(PhantomData)
+    }
+}
+
+pub struct ProtobufEncoder<T>(PhantomData<T>);
+
+impl<T: Message> Encoder for ProtobufEncoder<T> {
+    type Item = T;
+    type Error = Status;
+
+    fn encode(&mut self, item: T, buf: &mut EncodeBuf<'_>) -> Result<(), Status> {
+        match item.write_to_bytes() {
+            Ok(bytes) => {
+                buf.put_slice(&bytes);
+                Ok(())
+            }
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
+    }
+}
+
+pub struct ProtobufDecoder<U>(PhantomData<U>);
+
+impl<U: Message> Decoder for ProtobufDecoder<U> {
+    type Item = U;
+    type Error = Status;
+
+    fn decode(&mut self, buf: &mut DecodeBuf<'_>) -> Result<Option<U>, Status> {
+        let bytes = buf.copy_to_bytes(buf.remaining());
+        match U::parse_from_bytes(&bytes) {
+            Ok(msg) => Ok(Some(msg)),
+            Err(e) => Err(Status::invalid_argument(e.to_string())),
+        }
+    }
+}
@@ -3,3 +3,7 @@ include!(concat!(env!("OUT_DIR"), "/protos/mod.rs"));
 pub mod client;
 pub mod codec;
 pub mod db;
+pub mod error;
+pub mod grpc;
+
+pub use error::{Error, Result};
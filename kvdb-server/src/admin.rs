@@ -0,0 +1,335 @@
+/*
+ * admin: runtime database management API.
+ *
+ * Requests must carry the server's admin_token, as an
+ * "Authorization: Bearer TOKEN" header.  With no admin_token configured,
+ * every admin request is refused.
+ */
+
+use std::fs;
+use std::path::Path;
+
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde_derive::Deserialize;
+use serde_json::json;
+
+use kvdb_lib::pbapi::basic_response::ErrCode;
+use kvdb_lib::Error;
+
+use crate::{
+    auth, canonical_path, err_500, err_bad_req, err_db, err_not_found, err_resp, ok_json, reload,
+    DbConfig, ServerState,
+};
+
+// POST /admin/databases request body
+#[derive(Deserialize)]
+struct AttachRequest {
+    #[serde(flatten)]
+    cfg: DbConfig,
+    #[serde(default)]
+    create: bool, // true: create new db at path. false: path must exist
+}
+
+// POST /admin/databases/{name}/degraded request body
+#[derive(Deserialize)]
+struct DegradedRequest {
+    #[serde(default)]
+    reason: String, // reported by /readyz
+}
+
+// admin operation failure
+enum AdminError {
+    Conflict(String), // name or path already in use
+    Db(Error),
+}
+
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/admin/databases").route(web::post().to(req_attach)))
+        .service(web::resource("/admin/databases/{name}").route(web::delete().to(req_detach)))
+        .service(
+            web::resource("/admin/databases/{name}/degraded")
+                .route(web::post().to(req_degrade))
+                .route(web::delete().to(req_restore)),
+        )
+        .service(
+            web::resource("/admin/databases/{name}/compact").route(web::post().to(req_compact)),
+        )
+        .service(web::resource("/admin/reload").route(web::post().to(req_reload)));
+}
+
+/// Check request bearer token against admin_token.
+pub fn authorized(m_state: &ServerState, req: &HttpRequest) -> bool {
+    if m_state.admin_token.is_empty() {
+        return false;
+    }
+
+    let hdr = match req.headers().get(header::AUTHORIZATION) {
+        None => return false,
+        Some(hdr) => hdr,
+    };
+    match hdr.to_str() {
+        Err(_e) => false,
+        Ok(val) => match auth::bearer_token(val) {
+            None => false,
+            Some(token) => auth::token_eq(token.as_bytes(), m_state.admin_token.as_bytes()),
+        },
+    }
+}
+
+// helper function, 401 unauthorized
+fn err_unauthorized() -> HttpResponse {
+    err_resp(
+        StatusCode::UNAUTHORIZED,
+        ErrCode::UNAUTHORIZED,
+        "admin authorization required",
+    )
+}
+
+fn err_admin(e: &AdminError) -> HttpResponse {
+    match e {
+        AdminError::Conflict(msg) => err_resp(StatusCode::CONFLICT, ErrCode::INVALID_ARGUMENT, msg),
+        AdminError::Db(e) => err_db(e),
+    }
+}
+
+// database names are URI path elements
+fn valid_db_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-' || b == b'.')
+}
+
+// database path must not be that of an open database
+fn check_path_unused(m_state: &ServerState, path: &str) -> Result<(), AdminError> {
+    let path = fs::canonicalize(path).map_err(|e| AdminError::Db(e.into()))?;
+    for db_state in m_state.databases() {
+        if canonical_path(&db_state.cfg.path) == path {
+            return Err(AdminError::Conflict(format!(
+                "path in use by database {}",
+                db_state.cfg.name
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// open database and add it to the registry
+fn attach_db(m_state: &ServerState, attach: &AttachRequest) -> Result<(), AdminError> {
+    let db_cfg = &attach.cfg;
+    let _guard = m_state.config.lock().unwrap();
+
+    // name must be unused
+    if m_state.lookup(&db_cfg.name).is_some() {
+        return Err(AdminError::Conflict(format!(
+            "database {} exists",
+            db_cfg.name
+        )));
+    }
+
+    let path = Path::new(&db_cfg.path);
+    if attach.create {
+        if path.exists() {
+            return Err(AdminError::Conflict(format!("path {} exists", db_cfg.path)));
+        }
+        if let Err(e) = fs::create_dir_all(path) {
+            return Err(AdminError::Db(e.into()));
+        }
+    } else if !path.exists() {
+        return Err(AdminError::Db(Error::NotFound));
+    }
+
+    // path must be unused, however it is spelled
+    let res = match check_path_unused(m_state, &db_cfg.path) {
+        Ok(()) => m_state.open_db(db_cfg).map_err(AdminError::Db),
+        Err(e) => Err(e),
+    };
+    let db_state = match res {
+        Ok(db_state) => db_state,
+        Err(e) => {
+            // remove directory created above
+            if attach.create {
+                let _res = fs::remove_dir_all(path);
+            }
+            return Err(e);
+        }
+    };
+    m_state.registry_write().insert(db_state);
+
+    println!("admin: Database {} attached.", db_cfg.name);
+    Ok(())
+}
+
+// remove database from the registry, and close it; false if not open
+fn detach_db(m_state: &ServerState, name: &str) -> Result<bool, Error> {
+    let _guard = m_state.config.lock().unwrap();
+
+    if !m_state.close_db(name)? {
+        return Ok(false);
+    }
+
+    println!("admin: Database {} detached.", name);
+    Ok(true)
+}
+
+/// ATTACH (open) database, creating it if requested.  DbConfig JSON in
+/// HTTP payload.
+async fn req_attach(
+    m_state: web::Data<ServerState>,
+    (req, body): (HttpRequest, web::Bytes),
+) -> HttpResponse {
+    if !authorized(&m_state, &req) {
+        return err_unauthorized();
+    }
+
+    // decode JSON request
+    let attach: AttachRequest = match serde_json::from_slice(&body) {
+        Ok(attach) => attach,
+        Err(_e) => return err_bad_req(),
+    };
+    if !valid_db_name(&attach.cfg.name) {
+        return err_bad_req();
+    }
+
+    // open database, off the worker thread
+    let res = web::block(move || attach_db(&m_state, &attach)).await;
+    match res {
+        Ok(Ok(())) => ok_json(json!({"result": true})),
+        Ok(Err(e)) => err_admin(&e), // db: error, or conflict
+        Err(_e) => err_500(),        // thread pool: canceled
+    }
+}
+
+/// DETACH (close) database named in URL path.
+async fn req_detach(
+    m_state: web::Data<ServerState>,
+    (req, path): (HttpRequest, web::Path<(String,)>),
+) -> HttpResponse {
+    if !authorized(&m_state, &req) {
+        return err_unauthorized();
+    }
+
+    // close database, off the worker thread
+    let name = path.into_inner().0;
+    let res = web::block(move || detach_db(&m_state, &name)).await;
+    match res {
+        Ok(Ok(true)) => ok_json(json!({"result": true})),
+        Ok(Ok(false)) => err_not_found(), // db: not found
+        Ok(Err(e)) => err_db(&e),         // db: close failed
+        Err(_e) => err_500(),             // thread pool: canceled
+    }
+}
+
+// set or clear degraded mark of database; false if not open
+fn mark_degraded(m_state: &ServerState, name: &str, reason: Option<String>) -> bool {
+    let db_state = match m_state.lookup(name) {
+        None => return false,
+        Some(db_state) => db_state,
+    };
+
+    match &reason {
+        Some(reason) => println!("admin: Database {} marked degraded: {}", name, reason),
+        None => println!("admin: Database {} no longer degraded.", name),
+    }
+    *db_state.degraded.lock().unwrap() = reason;
+    true
+}
+
+/// DEGRADE database named in URL path: /readyz reports it, but does not
+/// fail on it.  Optional {"reason": "..."} JSON in HTTP payload.
+async fn req_degrade(
+    m_state: web::Data<ServerState>,
+    (req, path, body): (HttpRequest, web::Path<(String,)>, web::Bytes),
+) -> HttpResponse {
+    if !authorized(&m_state, &req) {
+        return err_unauthorized();
+    }
+
+    // decode JSON request; empty body: no reason
+    let degrade: DegradedRequest = if body.is_empty() {
+        DegradedRequest {
+            reason: String::new(),
+        }
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(degrade) => degrade,
+            Err(_e) => return err_bad_req(),
+        }
+    };
+    let reason = match degrade.reason.is_empty() {
+        true => String::from("marked by admin"),
+        false => degrade.reason,
+    };
+
+    let name = path.into_inner().0;
+    match mark_degraded(&m_state, &name, Some(reason)) {
+        true => ok_json(json!({"result": true})),
+        false => err_not_found(), // db: not found
+    }
+}
+
+/// RESTORE database named in URL path, clearing its degraded mark.
+async fn req_restore(
+    m_state: web::Data<ServerState>,
+    (req, path): (HttpRequest, web::Path<(String,)>),
+) -> HttpResponse {
+    if !authorized(&m_state, &req) {
+        return err_unauthorized();
+    }
+
+    let name = path.into_inner().0;
+    match mark_degraded(&m_state, &name, None) {
+        true => ok_json(json!({"result": true})),
+        false => err_not_found(), // db: not found
+    }
+}
+
+// compact database, reclaiming space; None if not open
+fn compact_db(m_state: &ServerState, name: &str) -> Option<Result<(), Error>> {
+    let db_state = m_state.lookup(name)?;
+
+    println!("admin: Compacting database {}.", name);
+    let res = db_state.write_db().compact();
+    match &res {
+        Ok(()) => println!("admin: Database {} compacted.", name),
+        Err(e) => println!("admin: Database {} compaction failed: {}", name, e),
+    }
+    Some(res)
+}
+
+/// COMPACT database named in URL path.  Writes wait until it finishes.
+async fn req_compact(
+    m_state: web::Data<ServerState>,
+    (req, path): (HttpRequest, web::Path<(String,)>),
+) -> HttpResponse {
+    if !authorized(&m_state, &req) {
+        return err_unauthorized();
+    }
+
+    // rewrite database files, off the worker thread
+    let name = path.into_inner().0;
+    let res = web::block(move || compact_db(&m_state, &name)).await;
+    match res {
+        Ok(Some(Ok(()))) => ok_json(json!({"result": true})),
+        Ok(Some(Err(e))) => err_db(&e), // db: unsupported, or error
+        Ok(None) => err_not_found(),    // db: not found
+        Err(_e) => err_500(),           // thread pool: canceled
+    }
+}
+
+/// RELOAD server configuration file.
+async fn req_reload(m_state: web::Data<ServerState>, req: HttpRequest) -> HttpResponse {
+    if !authorized(&m_state, &req) {
+        return err_unauthorized();
+    }
+
+    // reopen databases, off the worker thread
+    let res = web::block(move || reload::reload_config(&m_state)).await;
+    match res {
+        Ok(Ok(changes)) => ok_json(json!({"result": true, "changes": changes})),
+        Ok(Err(e)) => err_db(&e), // config: invalid, or db: error
+        Err(_e) => err_500(),     // thread pool: canceled
+    }
+}
@@ -0,0 +1,359 @@
+/*
+ * auth: API key and client certificate authentication, and per-database
+ * access control.
+ *
+ * With api_keys configured, requests may carry one of the keys, as an
+ * "Authorization: Bearer KEY" header (gRPC: "authorization" metadata).
+ * With client_certs configured, a verified TLS client certificate is
+ * matched by subject CN or subject alternative name.  Each matching key
+ * or certificate grants an access level per database.  With neither
+ * configured, access is unrestricted.
+ */
+
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::body::EitherBody;
+use actix_web::dev::{
+    forward_ready, Extensions, Service, ServiceRequest, ServiceResponse, Transform,
+};
+use actix_web::http::{header, StatusCode};
+use actix_web::rt::net::TcpStream;
+use actix_web::{web, HttpRequest, HttpResponse};
+use openssl::nid::Nid;
+use openssl::x509::X509Ref;
+use serde_derive::{Deserialize, Serialize};
+
+use kvdb_lib::pbapi::basic_response::ErrCode;
+use kvdb_lib::Error;
+
+use crate::{err_resp, metrics, ServerState};
+
+// database name matching all databases, in ApiKeyConfig.databases
+const ANY_DB: &str = "*";
+
+/// Access level granted to a key.  Each level includes those below it.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Read,  // get, iter, scan, stat, list namespaces
+    Write, // put, del, mutate, cas
+    Admin, // clear, create and drop namespaces
+}
+
+// api_keys entry of server configuration file
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct ApiKeyConfig {
+    pub name: String,                       // key owner, for logging
+    pub key: String,                        // bearer token
+    pub databases: HashMap<String, Access>, // db name, or "*", to access level
+}
+
+// client_certs entry of server configuration file; one of cn, san is set
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct ClientCertConfig {
+    pub name: String, // identity name, for logging
+    #[serde(default)]
+    pub cn: String, // match certificate subject common name
+    #[serde(default)]
+    pub san: String, // match certificate DNS, email or URI alt name
+    pub databases: HashMap<String, Access>, // db name, or "*", to access level
+}
+
+impl ClientCertConfig {
+    fn matches(&self, peer: &PeerIdentity) -> bool {
+        if !self.cn.is_empty() {
+            peer.cn.as_deref() == Some(self.cn.as_str())
+        } else {
+            peer.sans.contains(&self.san)
+        }
+    }
+}
+
+// all configured credentials
+#[derive(Default)]
+pub struct AccessRules {
+    pub api_keys: Vec<ApiKeyConfig>,
+    pub client_certs: Vec<ClientCertConfig>,
+}
+
+/// Names in a verified client certificate.
+#[derive(Clone)]
+pub struct PeerIdentity {
+    pub cn: Option<String>, // subject common name
+    pub sans: Vec<String>,  // DNS, email and URI subject alt names
+}
+
+impl PeerIdentity {
+    pub fn from_cert(cert: &X509Ref) -> PeerIdentity {
+        let cn = cert
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .and_then(|entry| entry.data().as_utf8().ok())
+            .map(|cn| cn.to_string());
+
+        let mut sans = Vec::new();
+        if let Some(names) = cert.subject_alt_names() {
+            for name in names {
+                let val = name.dnsname().or(name.email()).or(name.uri());
+                if let Some(val) = val {
+                    sans.push(val.to_string());
+                }
+            }
+        }
+
+        PeerIdentity { cn, sans }
+    }
+}
+
+/// Request credentials, either or both of which may be present.
+pub struct Credentials<'a> {
+    pub token: Option<&'a str>,         // API key
+    pub peer: Option<&'a PeerIdentity>, // verified client certificate
+}
+
+// reason for refusing a request
+pub enum Denied {
+    Unauthenticated, // missing or unknown key
+    Forbidden,       // key lacks access to database
+}
+
+/// Check configured keys for empty and duplicate entries, and
+/// certificate rules for a single name to match.
+pub fn validate_rules(
+    api_keys: &[ApiKeyConfig],
+    client_certs: &[ClientCertConfig],
+) -> kvdb_lib::Result<()> {
+    for client_cert in client_certs {
+        if client_cert.cn.is_empty() == client_cert.san.is_empty() {
+            return Err(Error::InvalidArgument(format!(
+                "client cert {}: set one of cn, san",
+                client_cert.name
+            )));
+        }
+    }
+
+    let mut keys = HashSet::new();
+    for api_key in api_keys {
+        if api_key.key.is_empty() {
+            return Err(Error::InvalidArgument(format!(
+                "api key {}: empty key",
+                api_key.name
+            )));
+        }
+        if !keys.insert(&api_key.key) {
+            return Err(Error::InvalidArgument(format!(
+                "api key {}: key configured twice",
+                api_key.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+// compare without early exit, to not leak token contents through timing
+pub fn token_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Extract token from an Authorization header value.
+pub fn bearer_token(hdr: &str) -> Option<&str> {
+    hdr.strip_prefix("Bearer ")
+}
+
+// access level granted to database by one credential
+fn granted(databases: &HashMap<String, Access>, db: &str) -> Option<Access> {
+    match databases.get(db) {
+        Some(access) => Some(*access),
+        None => databases.get(ANY_DB).copied(),
+    }
+}
+
+/// Check that credentials grant `need` access to database `db`, or, with
+/// no database, that some credential is valid.
+pub fn check_access(
+    m_state: &ServerState,
+    creds: &Credentials,
+    db: Option<&str>,
+    need: Access,
+) -> Result<(), Denied> {
+    let hist = m_state.metrics.lock_wait("access", "", "read");
+    let rules = metrics::timed(&hist, || m_state.access.read().unwrap());
+    if rules.api_keys.is_empty() && rules.client_certs.is_empty() {
+        return Ok(());
+    }
+
+    // per-database grants of each matching credential
+    let mut grants = Vec::new();
+    if let Some(token) = creds.token {
+        if let Some(api_key) = rules
+            .api_keys
+            .iter()
+            .find(|k| token_eq(k.key.as_bytes(), token.as_bytes()))
+        {
+            grants.push(&api_key.databases);
+        }
+    }
+    if let Some(peer) = creds.peer {
+        for client_cert in &rules.client_certs {
+            if client_cert.matches(peer) {
+                grants.push(&client_cert.databases);
+            }
+        }
+    }
+    if grants.is_empty() {
+        return Err(Denied::Unauthenticated);
+    }
+
+    let db = match db {
+        None => return Ok(()),
+        Some(db) => db,
+    };
+    match grants
+        .iter()
+        .any(|databases| matches!(granted(databases, db), Some(access) if access >= need))
+    {
+        true => Ok(()),
+        false => Err(Denied::Forbidden),
+    }
+}
+
+/// actix on_connect hook: record the identity of a verified TLS client
+/// certificate, for access checks of each request on the connection.
+pub fn on_connect(conn: &dyn Any, data: &mut Extensions) {
+    if let Some(tls) = conn.downcast_ref::<TlsStream<TcpStream>>() {
+        if let Some(cert) = tls.ssl().peer_certificate() {
+            data.insert(PeerIdentity::from_cert(&cert));
+        }
+    }
+}
+
+// access level required by a REST API operation, the last segment of the
+// routed resource pattern
+fn required_access(op: &str) -> Access {
+    match op {
+        "iter" | "mget" | "namespaces" | "scan" | "stat" | "stat.json" => Access::Read,
+        "cas" | "del" | "mutate" | "put" => Access::Write,
+        _ => Access::Admin, // clear, create, drop
+    }
+}
+
+// check REST API request, once routed: the database and operation are
+// those actix matched, as handlers see them.  The admin API has its own
+// authentication.
+fn check_request(m_state: &ServerState, req: &ServiceRequest) -> Result<(), HttpResponse> {
+    let (db, need) = match req.match_info().get("db") {
+        None => (None, Access::Read), // server index
+        Some(db) => {
+            // left percent-encoded by routing; handlers would decode it
+            if db.contains('%') {
+                return Err(err_resp(
+                    StatusCode::BAD_REQUEST,
+                    ErrCode::INVALID_ARGUMENT,
+                    "invalid database name",
+                ));
+            }
+            let pattern = req.match_pattern().unwrap_or_default();
+            let op = pattern.rsplit('/').next().unwrap();
+            (Some(db), required_access(op))
+        }
+    };
+
+    check_http(m_state, req.request(), db, need).map_err(err_denied)
+}
+
+/// Check the API key or client certificate of HTTP request `req`, as
+/// check_access does.
+pub fn check_http(
+    m_state: &ServerState,
+    req: &HttpRequest,
+    db: Option<&str>,
+    need: Access,
+) -> Result<(), Denied> {
+    let token = match req.headers().get(header::AUTHORIZATION) {
+        None => None,
+        Some(hdr) => match hdr.to_str() {
+            Ok(hdr) => bearer_token(hdr),
+            Err(_e) => None,
+        },
+    };
+    let peer = req.conn_data::<PeerIdentity>();
+    check_access(m_state, &Credentials { token, peer }, db, need)
+}
+
+// helper function, 401 or 403 for refused request
+pub fn err_denied(denied: Denied) -> HttpResponse {
+    match denied {
+        Denied::Unauthenticated => err_resp(
+            StatusCode::UNAUTHORIZED,
+            ErrCode::UNAUTHORIZED,
+            "valid API key or client certificate required",
+        ),
+        Denied::Forbidden => err_resp(
+            StatusCode::FORBIDDEN,
+            ErrCode::FORBIDDEN,
+            "credentials lack access to database",
+        ),
+    }
+}
+
+/// actix middleware enforcing API key and client certificate access,
+/// before handlers run.  Wraps each REST API resource, so that access is
+/// checked against the routed path.
+pub struct ApiAuth;
+
+impl<S, B> Transform<S, ServiceRequest> for ApiAuth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = ApiAuthMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ApiAuthMiddleware { service }))
+    }
+}
+
+pub struct ApiAuthMiddleware<S> {
+    service: S,
+}
+
+type LocalBoxFuture<T> = Pin<Box<dyn Future<Output = T>>>;
+
+impl<S, B> Service<ServiceRequest> for ApiAuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let res = match req.app_data::<web::Data<ServerState>>() {
+            None => Ok(()),
+            Some(m_state) => check_request(m_state, &req),
+        };
+
+        match res {
+            Ok(()) => {
+                let fut = self.service.call(req);
+                Box::pin(async move { Ok(fut.await?.map_into_left_body()) })
+            }
+            Err(resp) => {
+                let resp = req.into_response(resp).map_into_right_body();
+                Box::pin(async move { Ok(resp) })
+            }
+        }
+    }
+}
@@ -0,0 +1,633 @@
+/*
+ * grpc: native gRPC service for the KeyValueDb service declared in
+ * kvdb-lib/src/protos/pbapi.proto, sharing the open databases of the
+ * REST API server.
+ *
+ * The target database is selected per-call with the "kvdb-db" request
+ * metadata entry, and the namespace by the request message ns field.
+ * API keys, when configured, are sent as "authorization" metadata, and
+ * client certificates are verified as for the REST API.
+ * Request magic numbers are not required over gRPC.
+ */
+
+// tonic::Status is the error type of every generated service method
+#![allow(clippy::result_large_err)]
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{fmt, thread};
+
+use actix_web::web;
+use openssl::x509::X509;
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::pki_types::{CertificateDer, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{DigitallySignedStruct, DistinguishedName, RootCertStore, SignatureScheme};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+use tonic::metadata::MetadataValue;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::Server;
+use tonic::{Code, Request, Response, Status};
+
+use kvdb_lib::grpc::key_value_db_server::{KeyValueDb, KeyValueDbServer};
+use kvdb_lib::grpc::{GRPC_AUTH_KEY, GRPC_DB_KEY, GRPC_ERR_CODE_KEY};
+use kvdb_lib::pbapi::basic_response::ErrCode;
+use kvdb_lib::pbapi::{
+    BasicRequest, BasicResponse, CasRequest, DbStatResponse, GetRequest, GetResponse, IterRequest,
+    IterResponse, KeyRequest, MutationRequest, NsListResponse, NsRequest, ScanResponse,
+    UpdateRequest,
+};
+use kvdb_lib::{codec, db, Error};
+
+use crate::auth::{self, Access, Credentials, Denied, PeerIdentity};
+use crate::{exec_mget, exec_mutate, metrics, ClientVerify, DbState, ServerState, SslConfig};
+
+// max time for a client to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// TLS connections accepted, awaiting the server
+const ACCEPT_BACKLOG: usize = 64;
+
+// gRPC status, with machine-readable code in metadata
+fn status_from_code(code: Code, err_code: ErrCode, message: &str) -> Status {
+    let mut status = Status::new(code, message);
+    status
+        .metadata_mut()
+        .insert(GRPC_ERR_CODE_KEY, MetadataValue::from(err_code as i32));
+    status
+}
+
+// map db error to gRPC status, with machine-readable code in metadata
+fn status_from_err(e: &Error) -> Status {
+    let err_code = e.err_code();
+    let code = match err_code {
+        ErrCode::NOT_FOUND => Code::NotFound,
+        ErrCode::READ_ONLY => Code::PermissionDenied,
+        ErrCode::INVALID_ARGUMENT => Code::InvalidArgument,
+        ErrCode::TOO_LARGE => Code::OutOfRange,
+        ErrCode::FULL => Code::ResourceExhausted,
+        ErrCode::CORRUPTION => Code::DataLoss,
+        ErrCode::CONDITION_FAILED => Code::FailedPrecondition,
+        _ => Code::Internal,
+    };
+
+    status_from_code(code, err_code, &e.to_string())
+}
+
+// BasicResponse of a db call.  A failure is also noted in a response
+// extension, where metrics can see it, as for HTTP error responses.
+fn basic_resp(res: &kvdb_lib::Result<bool>) -> Response<BasicResponse> {
+    let mut resp = Response::new(codec::pbenc_basic_resp(res));
+    let err_code = match res {
+        Ok(true) => return resp,
+        Ok(false) => ErrCode::NOT_FOUND,
+        Err(e) => e.err_code(),
+    };
+    resp.extensions_mut().insert(err_code);
+
+    resp
+}
+
+// resolve namespace named in request message
+fn request_ns(msg_ns: &str) -> Result<Option<String>, Status> {
+    codec::request_ns(None, msg_ns).map_err(|e| status_from_err(&e))
+}
+
+// run a db operation on the blocking thread pool
+async fn blocking<F, R>(f: F) -> Result<R, Status>
+where
+    F: FnOnce() -> kvdb_lib::Result<R> + Send + 'static,
+    R: Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(Ok(r)) => Ok(r),
+        Ok(Err(e)) => Err(status_from_err(&e)),
+        Err(_e) => Err(Status::internal("db task canceled")),
+    }
+}
+
+pub struct KvdbService {
+    state: web::Data<ServerState>,
+}
+
+impl KvdbService {
+    // lookup database named in request metadata, checking the request's
+    // API key grants the needed access
+    fn lookup<T>(&self, request: &Request<T>, need: Access) -> Result<Arc<DbState>, Status> {
+        let name = match request.metadata().get(GRPC_DB_KEY) {
+            None => {
+                return Err(Status::invalid_argument(format!(
+                    "missing {} metadata",
+                    GRPC_DB_KEY
+                )))
+            }
+            Some(val) => match val.to_str() {
+                Ok(name) => name,
+                Err(_e) => return Err(Status::invalid_argument("invalid database name")),
+            },
+        };
+
+        let token = match request.metadata().get(GRPC_AUTH_KEY) {
+            None => None,
+            Some(val) => match val.to_str() {
+                Ok(val) => auth::bearer_token(val),
+                Err(_e) => None,
+            },
+        };
+        // verified client certificate, leaf first
+        let peer = request
+            .peer_certs()
+            .and_then(|certs| certs.first().and_then(|cert| X509::from_der(cert).ok()))
+            .map(|cert| PeerIdentity::from_cert(&cert));
+        let creds = Credentials {
+            token,
+            peer: peer.as_ref(),
+        };
+        match auth::check_access(&self.state, &creds, Some(name), need) {
+            Ok(()) => {}
+            Err(Denied::Unauthenticated) => {
+                return Err(status_from_code(
+                    Code::Unauthenticated,
+                    ErrCode::UNAUTHORIZED,
+                    "valid API key required",
+                ))
+            }
+            Err(Denied::Forbidden) => {
+                return Err(status_from_code(
+                    Code::PermissionDenied,
+                    ErrCode::FORBIDDEN,
+                    "API key lacks access to database",
+                ))
+            }
+        }
+
+        match self.state.lookup(name) {
+            None => Err(Status::not_found(format!("database {} not found", name))),
+            Some(db_state) => Ok(db_state),
+        }
+    }
+
+    // lookup database named in request metadata, for writing
+    fn lookup_writable<T>(
+        &self,
+        request: &Request<T>,
+        need: Access,
+    ) -> Result<Arc<DbState>, Status> {
+        let db_state = self.lookup(request, need)?;
+        match db_state.cfg.read_only {
+            true => Err(status_from_err(&Error::ReadOnly)),
+            false => Ok(db_state),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl KeyValueDb for KvdbService {
+    async fn clear(
+        &self,
+        request: Request<BasicRequest>,
+    ) -> Result<Response<BasicResponse>, Status> {
+        let db_state = self.lookup_writable(&request, Access::Admin)?;
+        let ns = request_ns(&request.get_ref().ns)?;
+
+        let res =
+            tokio::task::spawn_blocking(move || db_state.write_db().clear_ns(ns.as_deref())).await;
+        match res {
+            Ok(res) => Ok(basic_resp(&res)),
+            Err(_e) => Err(Status::internal("db task canceled")),
+        }
+    }
+
+    async fn cas(&self, request: Request<CasRequest>) -> Result<Response<BasicResponse>, Status> {
+        let db_state = self.lookup_writable(&request, Access::Write)?;
+        let in_msg = request.into_inner();
+        let ns = request_ns(&in_msg.ns)?;
+
+        let res = tokio::task::spawn_blocking(move || {
+            db_state.write_db().compare_and_swap_ns(
+                ns.as_deref(),
+                &in_msg.key,
+                in_msg.old_value.as_deref(),
+                in_msg.new_value.as_deref(),
+            )
+        })
+        .await;
+        match res {
+            Ok(res) => Ok(basic_resp(&res)),
+            Err(_e) => Err(Status::internal("db task canceled")),
+        }
+    }
+
+    async fn create_ns(
+        &self,
+        request: Request<NsRequest>,
+    ) -> Result<Response<BasicResponse>, Status> {
+        let db_state = self.lookup_writable(&request, Access::Admin)?;
+        let ns = match request_ns(&request.get_ref().ns)? {
+            None => return Err(Status::invalid_argument("missing namespace")),
+            Some(ns) => ns,
+        };
+
+        let res = tokio::task::spawn_blocking(move || db_state.write_db().open_ns(&ns)).await;
+        match res {
+            Ok(res) => Ok(basic_resp(&res)),
+            Err(_e) => Err(Status::internal("db task canceled")),
+        }
+    }
+
+    async fn db_stat(
+        &self,
+        request: Request<BasicRequest>,
+    ) -> Result<Response<DbStatResponse>, Status> {
+        let db_state = self.lookup(&request, Access::Read)?;
+        let ns = request_ns(&request.get_ref().ns)?;
+
+        let st = blocking(move || db_state.read_db().stat_ns(ns.as_deref())).await?;
+        Ok(Response::new(codec::db_stat_resp(st.n_records)))
+    }
+
+    async fn del(&self, request: Request<KeyRequest>) -> Result<Response<BasicResponse>, Status> {
+        let db_state = self.lookup_writable(&request, Access::Write)?;
+        let in_msg = request.into_inner();
+        let ns = request_ns(&in_msg.ns)?;
+
+        let res = tokio::task::spawn_blocking(move || {
+            db_state.write_db().del_ns(ns.as_deref(), &in_msg.key)
+        })
+        .await;
+        match res {
+            Ok(res) => Ok(basic_resp(&res)),
+            Err(_e) => Err(Status::internal("db task canceled")),
+        }
+    }
+
+    async fn drop_ns(
+        &self,
+        request: Request<NsRequest>,
+    ) -> Result<Response<BasicResponse>, Status> {
+        let db_state = self.lookup_writable(&request, Access::Admin)?;
+        let ns = match request_ns(&request.get_ref().ns)? {
+            None => return Err(Status::invalid_argument("missing namespace")),
+            Some(ns) => ns,
+        };
+
+        let res = tokio::task::spawn_blocking(move || db_state.write_db().drop_ns(&ns)).await;
+        match res {
+            Ok(res) => Ok(basic_resp(&res)),
+            Err(_e) => Err(Status::internal("db task canceled")),
+        }
+    }
+
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let db_state = self.lookup(&request, Access::Read)?;
+        let in_msg = request.into_inner();
+        let ns = request_ns(&in_msg.ns)?;
+
+        let out_msg =
+            blocking(move || exec_mget(&**db_state.read_db(), ns.as_deref(), &in_msg)).await?;
+        Ok(Response::new(out_msg))
+    }
+
+    async fn iter(&self, request: Request<IterRequest>) -> Result<Response<IterResponse>, Status> {
+        let db_state = self.lookup(&request, Access::Read)?;
+        let mut opts = codec::iter_opts_from_req(request.get_ref());
+        if let Some(ns) = request_ns(&request.get_ref().ns)? {
+            opts.ns(&ns);
+        }
+
+        let key_list = blocking(move || db_state.read_db().iter_keys(opts)).await?;
+        Ok(Response::new(codec::iter_resp(&key_list)))
+    }
+
+    async fn list_ns(
+        &self,
+        request: Request<BasicRequest>,
+    ) -> Result<Response<NsListResponse>, Status> {
+        let db_state = self.lookup(&request, Access::Read)?;
+
+        let names = blocking(move || db_state.read_db().list_ns()).await?;
+
+        // internal namespaces are not listed
+        let names: Vec<String> = names
+            .into_iter()
+            .filter(|name| !name.starts_with(db::api::RESERVED_NS_PREFIX))
+            .collect();
+        Ok(Response::new(codec::ns_list_resp(&names)))
+    }
+
+    async fn mutate(
+        &self,
+        request: Request<MutationRequest>,
+    ) -> Result<Response<BasicResponse>, Status> {
+        let db_state = self.lookup_writable(&request, Access::Write)?;
+        let batch = match codec::batch_from_mutate_req(request.get_ref(), None) {
+            Ok(batch) => batch,
+            Err(e) => return Err(status_from_err(&e)),
+        };
+
+        let durable = request.get_ref().durable;
+        let res =
+            tokio::task::spawn_blocking(move || exec_mutate(&db_state, &batch, durable)).await;
+        match res {
+            Ok(res) => Ok(basic_resp(&res)),
+            Err(_e) => Err(Status::internal("db task canceled")),
+        }
+    }
+
+    async fn put(
+        &self,
+        request: Request<UpdateRequest>,
+    ) -> Result<Response<BasicResponse>, Status> {
+        let db_state = self.lookup_writable(&request, Access::Write)?;
+        let in_msg = request.into_inner();
+        if !in_msg.is_insert {
+            return Err(Status::invalid_argument("put requires is_insert"));
+        }
+        let ns = request_ns(&in_msg.ns)?;
+
+        let res = tokio::task::spawn_blocking(move || {
+            db_state.write_db().put_expiring_ns(
+                ns.as_deref(),
+                &in_msg.key,
+                &in_msg.value,
+                codec::expiry_from_update(&in_msg),
+            )
+        })
+        .await;
+        match res {
+            Ok(res) => Ok(basic_resp(&res)),
+            Err(_e) => Err(Status::internal("db task canceled")),
+        }
+    }
+
+    async fn scan(&self, request: Request<IterRequest>) -> Result<Response<ScanResponse>, Status> {
+        let db_state = self.lookup(&request, Access::Read)?;
+        let mut opts = codec::iter_opts_from_req(request.get_ref());
+        if let Some(ns) = request_ns(&request.get_ref().ns)? {
+            opts.ns(&ns);
+        }
+
+        let kv_list = blocking(move || db_state.read_db().scan(opts)).await?;
+        Ok(Response::new(codec::scan_resp(&kv_list)))
+    }
+}
+
+/// gRPC TLS certificate and client certificate verifier, loaded from the
+/// same files as the HTTPS TLS context.
+pub struct GrpcTls {
+    cert: Arc<CertifiedKey>,
+    verifier: Arc<dyn ClientCertVerifier>,
+}
+
+// all certificates in a PEM file
+fn read_pem_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::certs(&mut reader).collect()
+}
+
+/// Load TLS key, certificate chain and client CA bundle, for gRPC
+pub fn grpc_tls(ssl_cfg: &SslConfig) -> io::Result<GrpcTls> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let certs = read_pem_certs(&ssl_cfg.cert_chain_path)?;
+    let mut reader = BufReader::new(File::open(&ssl_cfg.private_key_path)?);
+    let key = match rustls_pemfile::private_key(&mut reader)? {
+        None => {
+            return Err(io::Error::other(format!(
+                "{}: no private key",
+                ssl_cfg.private_key_path
+            )))
+        }
+        Some(key) => key,
+    };
+    let key = provider
+        .key_provider
+        .load_private_key(key)
+        .map_err(io::Error::other)?;
+    let cert = CertifiedKey::new(certs, key);
+    cert.keys_match().map_err(io::Error::other)?;
+
+    let verifier = match ssl_cfg.client_verify {
+        ClientVerify::None => WebPkiClientVerifier::no_client_auth(),
+        ClientVerify::Optional | ClientVerify::Required => {
+            let mut roots = RootCertStore::empty();
+            for ca_cert in read_pem_certs(&ssl_cfg.ca_file)? {
+                roots.add(ca_cert).map_err(io::Error::other)?;
+            }
+            let mut builder =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            if ssl_cfg.client_verify == ClientVerify::Optional {
+                builder = builder.allow_unauthenticated();
+            }
+            builder.build().map_err(io::Error::other)?
+        }
+    };
+
+    Ok(GrpcTls {
+        cert: Arc::new(cert),
+        verifier,
+    })
+}
+
+// gRPC TLS settings of the server's current TLS state.  Each handshake
+// reads them anew, so that reloaded certificates apply to new connections.
+struct SharedTls(web::Data<ServerState>);
+
+impl SharedTls {
+    fn verifier(&self) -> Arc<dyn ClientCertVerifier> {
+        let tls = self.0.tls.read().unwrap();
+        match tls.as_ref().and_then(|tls| tls.grpc.as_ref()) {
+            None => WebPkiClientVerifier::no_client_auth(),
+            Some(grpc) => grpc.verifier.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for SharedTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedTls")
+    }
+}
+
+impl ResolvesServerCert for SharedTls {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let tls = self.0.tls.read().unwrap();
+        tls.as_ref()
+            .and_then(|tls| tls.grpc.as_ref())
+            .map(|grpc| grpc.cert.clone())
+    }
+}
+
+impl ClientCertVerifier for SharedTls {
+    fn offer_client_auth(&self) -> bool {
+        self.verifier().offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        self.verifier().client_auth_mandatory()
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        // hints are optional; the verifier may be swapped at any time
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.verifier()
+            .verify_client_cert(end_entity, intermediates, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier().verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier().verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.verifier().supported_verify_schemes()
+    }
+}
+
+// rustls server configuration, reading certificates from shared TLS state
+fn server_tls_config(state: web::Data<ServerState>) -> io::Result<rustls::ServerConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let shared = Arc::new(SharedTls(state));
+
+    let mut tls_cfg = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_client_cert_verifier(shared.clone())
+        .with_cert_resolver(shared);
+    tls_cfg.alpn_protocols = vec![b"h2".to_vec()];
+
+    Ok(tls_cfg)
+}
+
+// TLS connections, from accepted TCP connections.  Handshakes run
+// concurrently; those failing or timing out are dropped.
+fn tls_incoming(
+    mut tcp: TcpIncoming,
+    acceptor: TlsAcceptor,
+) -> ReceiverStream<io::Result<TlsStream<TcpStream>>> {
+    let (tx, rx) = mpsc::channel(ACCEPT_BACKLOG);
+
+    tokio::spawn(async move {
+        while let Some(conn) = tcp.next().await {
+            let stream = match conn {
+                Ok(stream) => stream,
+                Err(_e) => continue, // connection reset before accept
+            };
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let res = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await;
+                match res {
+                    Ok(Ok(tls_stream)) => {
+                        let _ = tx.send(Ok(tls_stream)).await;
+                    }
+                    Ok(Err(e)) => log::debug!("gRPC TLS handshake failed: {}", e),
+                    Err(_e) => log::debug!("gRPC TLS handshake timed out"),
+                }
+            });
+        }
+    });
+
+    ReceiverStream::new(rx)
+}
+
+/// Running gRPC server.
+pub struct GrpcServer {
+    stop: oneshot::Sender<()>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl GrpcServer {
+    /// Stop accepting requests, and wait for those in flight.
+    pub fn shutdown(self) {
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+    }
+}
+
+/// Start the gRPC server on its own multi-threaded runtime, in a
+/// background thread.  The listening socket is bound before returning,
+/// so that bind errors reach the caller.  TLS is enabled by `use_tls`.
+pub fn spawn_server(
+    state: web::Data<ServerState>,
+    addr: SocketAddr,
+    use_tls: bool,
+) -> io::Result<GrpcServer> {
+    let tls_cfg = match use_tls {
+        true => Some(Arc::new(server_tls_config(state.clone())?)),
+        false => None,
+    };
+
+    // bind now, so that failure is reported to the caller
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+
+    let layer = metrics::GrpcMetrics::new(state.clone());
+    let svc = KeyValueDbServer::new(KvdbService { state });
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .thread_name("kvdbd-grpc")
+        .enable_all()
+        .build()?;
+
+    let (stop, stopped) = oneshot::channel::<()>();
+    let thread = thread::spawn(move || {
+        let server = Server::builder().layer(layer).add_service(svc);
+        let shutdown = async {
+            let _ = stopped.await;
+        };
+        let res: Result<(), Box<dyn std::error::Error + Send + Sync>> = rt.block_on(async move {
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            let incoming = TcpIncoming::from_listener(listener, true, None)?;
+            match tls_cfg {
+                None => {
+                    server
+                        .serve_with_incoming_shutdown(incoming, shutdown)
+                        .await?
+                }
+                Some(tls_cfg) => {
+                    let tls_incoming = tls_incoming(incoming, TlsAcceptor::from(tls_cfg));
+                    server
+                        .serve_with_incoming_shutdown(tls_incoming, shutdown)
+                        .await?
+                }
+            }
+            Ok(())
+        });
+        if let Err(e) = res {
+            log::error!("gRPC server failed: {}", e);
+        }
+    });
+
+    Ok(GrpcServer { stop, thread })
+}
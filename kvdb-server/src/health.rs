@@ -0,0 +1,145 @@
+/*
+ * health: liveness and readiness probes.
+ *
+ * /healthz answers whenever the HTTP server runs.  /readyz checks that
+ * each configured database is open and answers a point read; databases
+ * marked degraded, through the admin API, are reported but do not fail
+ * readiness, nor do databases busy with a long write.  Neither requires
+ * credentials.
+ */
+
+use std::sync::TryLockError;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse};
+use serde_derive::Serialize;
+use serde_json::json;
+
+use crate::{err_500, DbState, ServerState};
+
+// key read by the readiness probe; its absence is the expected answer
+const PROBE_KEY: &[u8] = b"\0kvdbd-readyz-probe";
+
+// longest wait for a database lock held by a writer, before the probe
+// reports the database busy; and poll interval
+const PROBE_LOCK_WAIT: Duration = Duration::from_millis(500);
+const PROBE_LOCK_POLL: Duration = Duration::from_millis(5);
+
+// readiness of one database
+#[derive(Serialize)]
+struct DbHealth {
+    name: String,
+    status: &'static str, // "ok", "degraded", "busy" or "failed"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>, // degraded, busy or failed: why
+}
+
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/healthz").route(web::get().to(req_healthz)))
+        .service(web::resource("/readyz").route(web::get().to(req_readyz)));
+}
+
+// probe one open database
+fn probe_db(db_state: &DbState) -> DbHealth {
+    let name = db_state.cfg.name.clone();
+    if let Some(reason) = db_state.degraded.lock().unwrap().clone() {
+        return DbHealth {
+            name,
+            status: "degraded",
+            reason: Some(reason),
+        };
+    }
+
+    // a probe waiting behind a writer would stall the response, and
+    // block readers queued behind it
+    let deadline = Instant::now() + PROBE_LOCK_WAIT;
+    let db = loop {
+        match db_state.db.try_read() {
+            Ok(db) => break db,
+            Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                thread::sleep(PROBE_LOCK_POLL)
+            }
+            Err(TryLockError::WouldBlock) => {
+                return DbHealth {
+                    name,
+                    status: "busy",
+                    reason: Some(String::from("write in progress")),
+                }
+            }
+            Err(TryLockError::Poisoned(_e)) => {
+                return DbHealth {
+                    name,
+                    status: "failed",
+                    reason: Some(String::from("lock poisoned")),
+                }
+            }
+        }
+    };
+
+    match db.get(PROBE_KEY) {
+        Ok(_val) => DbHealth {
+            name,
+            status: "ok",
+            reason: None,
+        },
+        Err(e) => DbHealth {
+            name,
+            status: "failed",
+            reason: Some(e.to_string()),
+        },
+    }
+}
+
+// probe all databases: each open one, then any configured but not open
+fn probe_all(m_state: &ServerState) -> Vec<DbHealth> {
+    let db_states = m_state.databases();
+    let mut res: Vec<DbHealth> = db_states
+        .iter()
+        .map(|db_state| probe_db(db_state))
+        .collect();
+
+    // config is held through admin changes and reloads; skip, not wait
+    if let Ok(config) = m_state.config.try_lock() {
+        for db_cfg in &config.databases {
+            if !db_states
+                .iter()
+                .any(|db_state| db_state.cfg.name == db_cfg.name)
+            {
+                res.push(DbHealth {
+                    name: db_cfg.name.clone(),
+                    status: "failed",
+                    reason: Some(String::from("not open")),
+                });
+            }
+        }
+    }
+
+    res
+}
+
+/// LIVENESS: the server process runs.
+async fn req_healthz() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(json!({"status": "ok"}).to_string())
+}
+
+/// READINESS: every database not marked degraded answers.  503 if not.
+async fn req_readyz(m_state: web::Data<ServerState>) -> HttpResponse {
+    let res = web::block(move || probe_all(&m_state)).await;
+    let databases = match res {
+        Ok(databases) => databases,
+        Err(_e) => return err_500(), // thread pool: canceled
+    };
+
+    let ready = databases.iter().all(|db| db.status != "failed");
+    let (status, status_str) = match ready {
+        true => (StatusCode::OK, "ready"),
+        false => (StatusCode::SERVICE_UNAVAILABLE, "not ready"),
+    };
+    HttpResponse::build(status)
+        .content_type("application/json")
+        .body(json!({"status": status_str, "databases": databases}).to_string())
+}
@@ -3,40 +3,117 @@ extern crate actix_web;
 extern crate clap;
 extern crate openssl;
 
-const APPNAME: &'static str = "kvdbd";
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
-const DEF_CFG_FN: &'static str = "cfg-kvdbd.json";
-const DEF_BIND_ADDR: &'static str = "127.0.0.1";
-const DEF_BIND_PORT: &'static str = "8080";
-
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use std::{env, fs, process};
+mod admin;
+mod auth;
+mod grpc;
+mod health;
+mod metrics;
+mod reload;
+
+const APPNAME: &str = "kvdbd";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const DEF_CFG_FN: &str = "cfg-kvdbd.json";
+const DEF_BIND_ADDR: &str = "127.0.0.1";
+const DEF_BIND_PORT: &str = "8080";
+
+// interval between purges of expired records
+const REAP_INTERVAL_SECS: u64 = 5;
+
+// max expired records purged per write-lock hold
+const REAP_BATCH: usize = 1000;
+
+// max wait for in-flight requests, when closing a database
+const FINISH_TIMEOUT_SECS: u64 = 30;
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+use std::{env, fs, process, thread};
 
 use actix_web::http::StatusCode;
-use actix_web::{middleware, web, App, HttpResponse, HttpServer};
-use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use actix_web::{middleware, web, App, HttpResponse, HttpServer, Route};
 use serde_derive::{Deserialize, Serialize};
 use serde_json::json;
 
 use protobuf::{EnumOrUnknown, Message};
 
-use kvdb_lib::pbapi::{get_op_result, get_response, update_request, GetOpResult, GetResponse};
-use kvdb_lib::{codec, db};
+use kvdb_lib::pbapi::basic_response::ErrCode;
+use kvdb_lib::pbapi::{
+    get_op_result, get_response, update_request, GetOpResult, GetRequest, GetResponse,
+};
+use kvdb_lib::{codec, db, Error};
 
 // struct used for both input (server config file) and output (server info)
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 struct DbConfig {
     name: String,
     path: String,
     driver: String,
+    #[serde(default)]
     read_only: bool,
+    #[serde(default)]
+    durability: db::api::Durability,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    options: BTreeMap<String, serde_json::Value>, // driver tuning options
 }
 
-#[derive(Serialize, Deserialize)]
+impl DbConfig {
+    // driver options, as strings; numbers and booleans are accepted
+    fn driver_options(&self) -> kvdb_lib::Result<db::api::Options> {
+        let mut options = db::api::Options::new();
+        for (key, val) in &self.options {
+            let val = match val {
+                serde_json::Value::String(val) => val.clone(),
+                serde_json::Value::Number(val) => val.to_string(),
+                serde_json::Value::Bool(val) => val.to_string(),
+                _ => {
+                    return Err(Error::InvalidArgument(format!(
+                        "database {}: option {}: expected string, number or boolean",
+                        self.name, key
+                    )))
+                }
+            };
+            options.insert(key.clone(), val);
+        }
+        Ok(options)
+    }
+
+    // backend driver configuration
+    fn driver_config(&self) -> kvdb_lib::Result<db::api::Config> {
+        if self.durability == db::api::Durability::Periodic(0) {
+            return Err(Error::InvalidArgument(format!(
+                "database {}: periodic durability interval must be positive",
+                self.name
+            )));
+        }
+        Ok(db::api::ConfigBuilder::new()
+            .path(self.path.clone())
+            .read_only(self.read_only)
+            .durability(self.durability)
+            .options(self.driver_options()?)
+            .build())
+    }
+}
+
+// TLS client certificate verification
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum ClientVerify {
+    #[default]
+    None, // no client certificate requested
+    Optional, // certificate verified, if client presents one
+    Required, // connection refused without verified certificate
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 struct SslConfig {
     private_key_path: String, // empty, if no SSL
     cert_chain_path: String,  // empty, if no SSL
+    #[serde(default)]
+    ca_file: String, // CA bundle verifying client certificates
+    #[serde(default)]
+    client_verify: ClientVerify,
 }
 
 impl SslConfig {
@@ -44,15 +121,32 @@ impl SslConfig {
         SslConfig {
             private_key_path: String::new(),
             cert_chain_path: String::new(),
+            ca_file: String::new(),
+            client_verify: ClientVerify::None,
         }
     }
+
+    // TLS is used when both key and cert are configured
+    fn enabled(&self) -> bool {
+        !self.private_key_path.is_empty() && !self.cert_chain_path.is_empty()
+    }
 }
 
 // top-level schema for server configuration file
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct ServerConfig {
     debug: bool,
     ssl: SslConfig,
+    #[serde(default)]
+    grpc_port: u16, // 0, if no gRPC service
+    #[serde(default)]
+    admin_token: String, // empty, if no admin API
+    #[serde(default)]
+    api_keys: Vec<auth::ApiKeyConfig>, // empty, if no key authentication
+    #[serde(default)]
+    client_certs: Vec<auth::ClientCertConfig>, // empty, if no cert authentication
+    #[serde(default)]
+    record_gauge_secs: u64, // 0, if record counts are not gauged
     databases: Vec<DbConfig>,
 }
 
@@ -70,18 +164,245 @@ struct DbStatResponseJson {
     n_records: String, // some JSON impl have trouble with big ints
 }
 
+// URL path of a db API request; ns is absent for the default keyspace
+#[derive(Deserialize)]
+struct NsPath {
+    db: String,
+    ns: Option<String>,
+}
+
+impl NsPath {
+    // resolve request namespace, from URL path and message field
+    fn ns(&self, msg_ns: &str) -> Result<Option<String>, HttpResponse> {
+        match codec::request_ns(self.ns.as_deref(), msg_ns) {
+            Ok(ns) => Ok(ns),
+            Err(e) => Err(err_db(&e)),
+        }
+    }
+}
+
 // per-db runtime state info
 struct DbState {
-    cfg: DbConfig,                   // imported db configuration
-    db: Box<dyn db::api::Db + Send>, // open db handle
+    cfg: DbConfig,                     // imported db configuration
+    db: RwLock<Box<dyn db::api::Db>>,  // open db handle; readers share, writers exclusive
+    read_wait: prometheus::Histogram,  // db lock waits, shared
+    write_wait: prometheus::Histogram, // db lock waits, exclusive
+    degraded: Mutex<Option<String>>,   // reason, if marked degraded by admin
+}
+
+impl DbState {
+    // shared db handle, timing the lock wait
+    fn read_db(&self) -> RwLockReadGuard<'_, Box<dyn db::api::Db>> {
+        metrics::timed(&self.read_wait, || self.db.read().unwrap())
+    }
+
+    // exclusive db handle, timing the lock wait
+    fn write_db(&self) -> RwLockWriteGuard<'_, Box<dyn db::api::Db>> {
+        metrics::timed(&self.write_wait, || self.db.write().unwrap())
+    }
+}
+
+// open databases, by name
+#[derive(Default)]
+struct DbRegistry {
+    name_idx: HashMap<String, usize>,
+    dbs: Vec<Arc<DbState>>, // in order opened
+}
+
+impl DbRegistry {
+    // add database; false if its name is taken
+    fn insert(&mut self, db_state: Arc<DbState>) -> bool {
+        if self.name_idx.contains_key(&db_state.cfg.name) {
+            return false;
+        }
+
+        self.name_idx
+            .insert(db_state.cfg.name.clone(), self.dbs.len());
+        self.dbs.push(db_state);
+        true
+    }
+
+    // remove database by name
+    fn remove(&mut self, name: &str) -> Option<Arc<DbState>> {
+        let idx = self.name_idx.remove(name)?;
+        let db_state = self.dbs.remove(idx);
+        for other_idx in self.name_idx.values_mut() {
+            if *other_idx > idx {
+                *other_idx -= 1;
+            }
+        }
+
+        Some(db_state)
+    }
 }
 
 // runtime server state info
 #[allow(dead_code)]
 struct ServerState {
     debug: bool,
-    name_idx: HashMap<String, usize>,
-    dbs: Vec<DbState>, // all open databases
+    admin_token: String,                   // empty, if no admin API
+    cfg_path: Option<String>,              // config file, if not zeroconf
+    config: Mutex<ServerConfig>,           // applied config; serializes registry changes
+    tls: RwLock<Option<reload::TlsState>>, // current TLS certificates, if TLS
+    access: RwLock<auth::AccessRules>,     // API keys and client cert rules
+    backends: BackendState,                // available db drivers
+    registry: RwLock<DbRegistry>,          // all open databases
+    metrics: metrics::Metrics,             // Prometheus metrics
+}
+
+impl ServerState {
+    // shared registry, timing the lock wait
+    fn registry_read(&self) -> RwLockReadGuard<'_, DbRegistry> {
+        let hist = self.metrics.lock_wait("registry", "", "read");
+        metrics::timed(&hist, || self.registry.read().unwrap())
+    }
+
+    // exclusive registry, timing the lock wait
+    fn registry_write(&self) -> RwLockWriteGuard<'_, DbRegistry> {
+        let hist = self.metrics.lock_wait("registry", "", "write");
+        metrics::timed(&hist, || self.registry.write().unwrap())
+    }
+
+    // lookup database by name, returning a handle usable off the worker thread
+    fn lookup(&self, name: &str) -> Option<Arc<DbState>> {
+        let registry = self.registry_read();
+        registry
+            .name_idx
+            .get(name)
+            .map(|idx| Arc::clone(&registry.dbs[*idx]))
+    }
+
+    // all open databases, in order opened
+    fn databases(&self) -> Vec<Arc<DbState>> {
+        self.registry_read().dbs.clone()
+    }
+
+    // open database through its backend driver
+    fn open_db(&self, db_cfg: &DbConfig) -> kvdb_lib::Result<Arc<DbState>> {
+        // verify this is a known backend
+        let backend = match self.backends.backends.get(&db_cfg.driver) {
+            None => {
+                return Err(Error::InvalidArgument(format!(
+                    "unsupported db driver {}",
+                    db_cfg.driver
+                )))
+            }
+            Some(backend) => backend,
+        };
+
+        let db = backend.driver.start_db(db_cfg.driver_config()?)?;
+        Ok(Arc::new(DbState {
+            cfg: db_cfg.clone(),
+            db: RwLock::new(db),
+            read_wait: self.metrics.lock_wait("db", &db_cfg.name, "read"),
+            write_wait: self.metrics.lock_wait("db", &db_cfg.name, "write"),
+            degraded: Mutex::new(None),
+        }))
+    }
+
+    // remove database from the registry, and close it; false if not open.
+    // On error, the database is no longer served, but may not be flushed.
+    fn close_db(&self, name: &str) -> kvdb_lib::Result<bool> {
+        let db_state = match self.registry_write().remove(name) {
+            None => return Ok(false),
+            Some(db_state) => db_state,
+        };
+
+        let res = finish_db(db_state);
+        self.metrics.forget_db(name);
+        if let Err(e) = res {
+            println!("db {}: close failed: {}", name, e);
+            return Err(e);
+        }
+
+        Ok(true)
+    }
+
+    // close all databases, at shutdown; false if any failed to flush
+    fn close_all(&self) -> bool {
+        let registry = std::mem::take(&mut *self.registry_write());
+
+        let mut ok = true;
+        for db_state in registry.dbs {
+            let name = db_state.cfg.name.clone();
+            match finish_db(db_state) {
+                Ok(()) => println!("shutdown: Database {} closed.", name),
+                Err(e) => {
+                    println!("shutdown: Database {} failed to close: {}", name, e);
+                    ok = false;
+                }
+            }
+        }
+        ok
+    }
+}
+
+// flush and close database removed from the registry, once in-flight
+// requests finish, so that it is closed on return.  If requests are still
+// running at the deadline, the database is left to close when the last
+// one finishes, and an error returned.
+fn finish_db(mut db_state: Arc<DbState>) -> kvdb_lib::Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(FINISH_TIMEOUT_SECS);
+    loop {
+        match Arc::try_unwrap(db_state) {
+            Ok(db_state) => {
+                let mut db = db_state.db.into_inner().unwrap();
+                return db.close();
+            }
+            Err(shared) => {
+                if Instant::now() >= deadline {
+                    return Err(Error::Io(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!(
+                            "requests still in flight after {}s; not flushed",
+                            FINISH_TIMEOUT_SECS
+                        ),
+                    )));
+                }
+                db_state = shared;
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+}
+
+// database path, resolved for comparison with others: symlinks, "." and
+// ".." are removed.  Paths not yet created are compared as given.
+fn canonical_path(path: &str) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_e| PathBuf::from(path))
+}
+
+// background thread: purge expired records from all databases
+fn spawn_reaper(state: web::Data<ServerState>) -> std::io::Result<()> {
+    thread::Builder::new()
+        .name(String::from("kvdbd-reaper"))
+        .spawn(move || loop {
+            thread::sleep(Duration::from_secs(REAP_INTERVAL_SECS));
+
+            for db_state in state.databases() {
+                if db_state.cfg.read_only {
+                    continue;
+                }
+
+                // release write lock between batches, to let requests in
+                loop {
+                    let res = db_state.write_db().purge_expired(REAP_BATCH);
+                    match res {
+                        Ok(n) if n == REAP_BATCH => {}
+                        Ok(_) => break,
+                        Err(e) => {
+                            println!(
+                                "db {}: purge of expired records failed: {}",
+                                db_state.cfg.name, e
+                            );
+                            break;
+                        }
+                    }
+                }
+            }
+        })?;
+
+    Ok(())
 }
 
 struct Backend {
@@ -102,7 +423,13 @@ fn build_backend(id: &str) -> Backend {
         cli_value_name: value_str,
         driver: match id {
             "sled" => db::sled::new_driver(),
+            #[cfg(feature = "gdbm")]
+            "gdbm" => db::gdbm::new_driver(),
             "lmdb" => db::lmdb::new_driver(),
+            "memory" => db::memory::new_driver(),
+            "redb" => db::redb::new_driver(),
+            "sqlite" => db::sqlite::new_driver(),
+            "bitcask" => db::bitcask::new_driver(),
             _ => panic!("unknown db driver"),
         },
     }
@@ -117,47 +444,80 @@ fn register_backends() -> BackendState {
         .insert(String::from("sled"), build_backend("sled"));
     bs.backends
         .insert(String::from("lmdb"), build_backend("lmdb"));
+    bs.backends
+        .insert(String::from("memory"), build_backend("memory"));
+    bs.backends
+        .insert(String::from("redb"), build_backend("redb"));
+    bs.backends
+        .insert(String::from("sqlite"), build_backend("sqlite"));
+    bs.backends
+        .insert(String::from("bitcask"), build_backend("bitcask"));
+    #[cfg(feature = "gdbm")]
+    bs.backends
+        .insert(String::from("gdbm"), build_backend("gdbm"));
 
-    return bs;
+    bs
 }
 
-// helper function, 404 not found
-fn err_not_found() -> HttpResponse {
-    HttpResponse::build(StatusCode::NOT_FOUND)
+// helper function, JSON error response with HTTP status and machine-readable code
+fn err_resp(status: StatusCode, err_code: ErrCode, message: &str) -> HttpResponse {
+    let mut resp = HttpResponse::build(status)
         .content_type("application/json")
         .body(
             json!({
           "error": {
-             "code" : -404,
-              "message": "not found"}})
+             "code" : -(status.as_u16() as i32),
+             "err_code": err_code as i32,
+              "message": message}})
             .to_string(),
-        )
+        );
+
+    // error type, for metrics
+    resp.extensions_mut().insert(err_code);
+    resp
+}
+
+// helper function, 404 not found
+fn err_not_found() -> HttpResponse {
+    err_resp(StatusCode::NOT_FOUND, ErrCode::NOT_FOUND, "not found")
 }
 
 // helper function, 400 bad request
 fn err_bad_req() -> HttpResponse {
-    HttpResponse::build(StatusCode::BAD_REQUEST)
-        .content_type("application/json")
-        .body(
-            json!({
-          "error": {
-             "code" : -400,
-              "message": "invalid/malformed request"}})
-            .to_string(),
-        )
+    err_resp(
+        StatusCode::BAD_REQUEST,
+        ErrCode::INVALID_ARGUMENT,
+        "invalid/malformed request",
+    )
+}
+
+// helper function, 403 write to read-only db
+fn err_read_only() -> HttpResponse {
+    err_db(&Error::ReadOnly)
 }
 
 // helper function, 500 server error
 fn err_500() -> HttpResponse {
-    HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
-        .content_type("application/json")
-        .body(
-            json!({
-          "error": {
-             "code" : -500,
-              "message": "internal server error"}})
-            .to_string(),
-        )
+    err_resp(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        ErrCode::BACKEND,
+        "internal server error",
+    )
+}
+
+// helper function, map db error to HTTP status and error code
+fn err_db(e: &Error) -> HttpResponse {
+    let err_code = e.err_code();
+    let status = match err_code {
+        ErrCode::NOT_FOUND => StatusCode::NOT_FOUND,
+        ErrCode::READ_ONLY => StatusCode::FORBIDDEN,
+        ErrCode::INVALID_ARGUMENT => StatusCode::BAD_REQUEST,
+        ErrCode::TOO_LARGE => StatusCode::PAYLOAD_TOO_LARGE,
+        ErrCode::FULL => StatusCode::INSUFFICIENT_STORAGE,
+        ErrCode::CONDITION_FAILED => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    err_resp(status, err_code, &e.to_string())
 }
 
 // helper function, success + binary response
@@ -175,8 +535,8 @@ fn ok_json(jval: serde_json::Value) -> HttpResponse {
 }
 
 /// simple root index handler, describes our service
-#[get("/")]
-async fn req_index(m_state: web::Data<Arc<Mutex<ServerState>>>) -> HttpResponse {
+#[get("/", wrap = "auth::ApiAuth")]
+async fn req_index(m_state: web::Data<ServerState>) -> HttpResponse {
     // fill basic server info struct used for output
     let mut srv_info = ServerInfo {
         name: String::from(APPNAME),
@@ -184,11 +544,8 @@ async fn req_index(m_state: web::Data<Arc<Mutex<ServerState>>>) -> HttpResponse
         databases: Vec::new(),
     };
 
-    // lock runtime-live state data
-    let state = m_state.lock().unwrap();
-
     // copy each db config into output struct
-    for db_state in &state.dbs {
+    for db_state in m_state.databases() {
         srv_info.databases.push(db_state.cfg.clone());
     }
 
@@ -200,48 +557,52 @@ async fn req_index(m_state: web::Data<Arc<Mutex<ServerState>>>) -> HttpResponse
 }
 
 /// CLEAR all data items.
-async fn req_clear(
-    m_state: web::Data<Arc<Mutex<ServerState>>>,
-    path: web::Path<(String,)>,
-) -> HttpResponse {
-    // lock runtime-live state data
-    let mut state = m_state.lock().unwrap();
-
-    // lookup database index by name (path elem 0)
-    let idx: usize;
-    match state.name_idx.get(&path.0) {
+async fn req_clear(m_state: web::Data<ServerState>, path: web::Path<NsPath>) -> HttpResponse {
+    // lookup database by name (path elem 0)
+    let db_state = match m_state.lookup(&path.db) {
         None => return err_not_found(),
-        Some(r_idx) => idx = *r_idx,
+        Some(db_state) => db_state,
+    };
+
+    // reject writes to read-only db, before they reach the driver
+    if db_state.cfg.read_only {
+        return err_read_only();
     }
 
-    // attempt to clear all records from db
-    match state.dbs[idx].db.clear() {
-        Ok(_optval) => ok_json(json!({"result": true})),
-        Err(_e) => err_500(), // db: error
+    let ns = match path.ns("") {
+        Ok(ns) => ns,
+        Err(resp) => return resp,
+    };
+
+    // attempt to clear all records from db, off the worker thread
+    let res = web::block(move || db_state.write_db().clear_ns(ns.as_deref())).await;
+    match res {
+        Ok(Ok(_optval)) => ok_json(json!({"result": true})),
+        Ok(Err(e)) => err_db(&e), // db: error
+        Err(_e) => err_500(),     // thread pool: canceled
     }
 }
 
 /// Return db stats as protobuf
-async fn req_stat(
-    m_state: web::Data<Arc<Mutex<ServerState>>>,
-    path: web::Path<(String,)>,
-) -> HttpResponse {
-    // lock runtime-live state data
-    let state = m_state.lock().unwrap();
-
-    // lookup database index by name (path elem 0)
-    let idx: usize;
-    match state.name_idx.get(&path.0) {
+async fn req_stat(m_state: web::Data<ServerState>, path: web::Path<NsPath>) -> HttpResponse {
+    // lookup database by name (path elem 0)
+    let db_state = match m_state.lookup(&path.db) {
         None => return err_not_found(),
-        Some(r_idx) => idx = *r_idx,
-    }
+        Some(db_state) => db_state,
+    };
 
-    // attempt to list keys, starting at supplied key (or at db-start, if none)
-    let res = state.dbs[idx].db.stat();
-    if res.is_err() {
-        return err_500();
-    }
-    let st = res.unwrap();
+    let ns = match path.ns("") {
+        Ok(ns) => ns,
+        Err(resp) => return resp,
+    };
+
+    // query db statistics, off the worker thread
+    let res = web::block(move || db_state.read_db().stat_ns(ns.as_deref())).await;
+    let st = match res {
+        Ok(Ok(st)) => st,
+        Ok(Err(e)) => return err_db(&e),
+        Err(_e) => return err_500(),
+    };
 
     // encode protobuf output to bytes
     let out_bytes = codec::pbenc_db_stat_resp(st.n_records);
@@ -250,26 +611,25 @@ async fn req_stat(
 }
 
 /// Return db stats as JSON
-async fn req_stat_json(
-    m_state: web::Data<Arc<Mutex<ServerState>>>,
-    path: web::Path<(String,)>,
-) -> HttpResponse {
-    // lock runtime-live state data
-    let state = m_state.lock().unwrap();
-
-    // lookup database index by name (path elem 0)
-    let idx: usize;
-    match state.name_idx.get(&path.0) {
+async fn req_stat_json(m_state: web::Data<ServerState>, path: web::Path<NsPath>) -> HttpResponse {
+    // lookup database by name (path elem 0)
+    let db_state = match m_state.lookup(&path.db) {
         None => return err_not_found(),
-        Some(r_idx) => idx = *r_idx,
-    }
+        Some(db_state) => db_state,
+    };
 
-    // attempt to list keys, starting at supplied key (or at db-start, if none)
-    let res = state.dbs[idx].db.stat();
-    if res.is_err() {
-        return err_500();
-    }
-    let st = res.unwrap();
+    let ns = match path.ns("") {
+        Ok(ns) => ns,
+        Err(resp) => return resp,
+    };
+
+    // query db statistics, off the worker thread
+    let res = web::block(move || db_state.read_db().stat_ns(ns.as_deref())).await;
+    let st = match res {
+        Ok(Ok(st)) => st,
+        Ok(Err(e)) => return err_db(&e),
+        Err(_e) => return err_500(),
+    };
 
     // fill for-JSON-output struct with return data
     let out_msg = DbStatResponseJson {
@@ -285,209 +645,471 @@ async fn req_stat_json(
 
 /// Sequential iteration through all KEYS in db. Start-key in HTTP payload.
 async fn req_iter(
-    m_state: web::Data<Arc<Mutex<ServerState>>>,
-    (path, body): (web::Path<(String,)>, web::Bytes),
+    m_state: web::Data<ServerState>,
+    (path, body): (web::Path<NsPath>, web::Bytes),
 ) -> HttpResponse {
     // decode protobuf msg containing key, into KeyRequest struct
-    let res = codec::pbdec_iter_req(&body);
-    if res.is_none() {
-        return err_bad_req();
-    }
-    let in_msg = res.unwrap();
-
-    // lock runtime-live state data
-    let state = m_state.lock().unwrap();
+    let in_msg = match codec::pbdec_iter_req(&body) {
+        None => return err_bad_req(),
+        Some(in_msg) => in_msg,
+    };
 
-    // lookup database index by name (path elem 0)
-    let idx: usize;
-    match state.name_idx.get(&path.0) {
+    // lookup database by name (path elem 0)
+    let db_state = match m_state.lookup(&path.db) {
         None => return err_not_found(),
-        Some(r_idx) => idx = *r_idx,
-    }
+        Some(db_state) => db_state,
+    };
 
     // attempt to list keys, starting at supplied key (or at db-start, if none)
-    let mut opts = db::api::IterOptions::new();
-    if !in_msg.start_key.is_empty() {
-        opts.start(&in_msg.start_key);
-    }
-    if !in_msg.prefix.is_empty() {
-        opts.prefix(&in_msg.prefix);
-    }
-    let res = state.dbs[idx].db.iter_keys(opts);
-    if res.is_err() {
-        return err_500();
+    let mut opts = codec::iter_opts_from_req(&in_msg);
+    match path.ns(&in_msg.ns) {
+        Ok(None) => {}
+        Ok(Some(ns)) => {
+            opts.ns(&ns);
+        }
+        Err(resp) => return resp,
     }
+    let res = web::block(move || db_state.read_db().iter_keys(opts)).await;
+    let key_list = match res {
+        Ok(Ok(key_list)) => key_list,
+        Ok(Err(e)) => return err_db(&e),
+        Err(_e) => return err_500(),
+    };
 
     // encode protobuf output to bytes
-    let key_list = res.unwrap();
     let out_bytes = codec::pbenc_iter_resp(&key_list);
 
     ok_binary(out_bytes)
 }
 
+/// Sequential scan through key/value records in db. Start-key in HTTP payload.
+async fn req_scan(
+    m_state: web::Data<ServerState>,
+    (path, body): (web::Path<NsPath>, web::Bytes),
+) -> HttpResponse {
+    // decode protobuf msg containing scan options, into IterRequest struct
+    let in_msg = match codec::pbdec_iter_req(&body) {
+        None => return err_bad_req(),
+        Some(in_msg) => in_msg,
+    };
+
+    // lookup database by name (path elem 0)
+    let db_state = match m_state.lookup(&path.db) {
+        None => return err_not_found(),
+        Some(db_state) => db_state,
+    };
+
+    // attempt to list records, starting at supplied key (or at db-start, if none)
+    let mut opts = codec::iter_opts_from_req(&in_msg);
+    match path.ns(&in_msg.ns) {
+        Ok(None) => {}
+        Ok(Some(ns)) => {
+            opts.ns(&ns);
+        }
+        Err(resp) => return resp,
+    }
+    let res = web::block(move || db_state.read_db().scan(opts)).await;
+    let kv_list = match res {
+        Ok(Ok(kv_list)) => kv_list,
+        Ok(Err(e)) => return err_db(&e),
+        Err(_e) => return err_500(),
+    };
+
+    // encode protobuf output to bytes
+    let out_bytes = codec::pbenc_scan_resp(&kv_list);
+
+    ok_binary(out_bytes)
+}
+
 /// DELETE data item. key in HTTP payload.  return ok as json response
 async fn req_del(
-    m_state: web::Data<Arc<Mutex<ServerState>>>,
-    (path, body): (web::Path<(String,)>, web::Bytes),
+    m_state: web::Data<ServerState>,
+    (path, body): (web::Path<NsPath>, web::Bytes),
 ) -> HttpResponse {
     // decode protobuf msg containing key, into KeyRequest struct
-    let res = codec::pbdec_key_req(&body);
-    if res.is_none() {
-        return err_bad_req();
-    }
-    let in_msg = res.unwrap();
-
-    // lock runtime-live state data
-    let mut state = m_state.lock().unwrap();
+    let in_msg = match codec::pbdec_key_req(&body) {
+        None => return err_bad_req(),
+        Some(in_msg) => in_msg,
+    };
 
-    // lookup database index by name (path elem 0)
-    let idx: usize;
-    match state.name_idx.get(&path.0) {
+    // lookup database by name (path elem 0)
+    let db_state = match m_state.lookup(&path.db) {
         None => return err_not_found(),
-        Some(r_idx) => idx = *r_idx,
+        Some(db_state) => db_state,
+    };
+
+    // reject writes to read-only db, before they reach the driver
+    if db_state.cfg.read_only {
+        return err_read_only();
     }
 
-    // attempt to remove record from db, based on key (path elem 1)
-    match state.dbs[idx].db.del(&in_msg.key) {
-        Ok(optval) => match optval {
+    let ns = match path.ns(&in_msg.ns) {
+        Ok(ns) => ns,
+        Err(resp) => return resp,
+    };
+
+    // attempt to remove record from db, based on key (http payload)
+    let res = web::block(move || db_state.write_db().del_ns(ns.as_deref(), &in_msg.key)).await;
+    match res {
+        Ok(Ok(optval)) => match optval {
             true => ok_json(json!({"result": true})),
             false => err_not_found(), // db: value not found
         },
-        Err(_e) => err_500(), // db: error
+        Ok(Err(e)) => err_db(&e), // db: error
+        Err(_e) => err_500(),     // thread pool: canceled
     }
 }
 
-/// Multiple-GET data item. key in HTTP payload, returns value in HTTP payload.
-async fn req_mget(
-    m_state: web::Data<Arc<Mutex<ServerState>>>,
-    (path, body): (web::Path<(String,)>, web::Bytes),
-) -> HttpResponse {
-    // decode protobuf msg containing key, into KeyRequest struct
-    let res = codec::pbdec_mget_req(&body);
-    if res.is_none() {
-        return err_bad_req();
+// apply batch of updates; if durable, flush before returning, with the
+// write lock released so that readers proceed
+fn exec_mutate(
+    db_state: &DbState,
+    batch: &db::api::Batch,
+    durable: bool,
+) -> kvdb_lib::Result<bool> {
+    let res = db_state.write_db().apply_batch(batch)?;
+    if durable {
+        db_state.read_db().flush()?;
     }
-    let in_msg = res.unwrap();
+    Ok(res)
+}
 
+// execute multiple-GET query against an open db namespace
+fn exec_mget(
+    db: &dyn db::api::Db,
+    ns: Option<&str>,
+    in_msg: &GetRequest,
+) -> kvdb_lib::Result<GetResponse> {
     let mut out_msg = GetResponse::new();
     out_msg.magic = EnumOrUnknown::new(get_response::MagicNum::MAGIC);
 
-    // lock runtime-live state data
-    let state = m_state.lock().unwrap();
-
-    // lookup database index by name (path elem 0)
-    let idx: usize;
-    match state.name_idx.get(&path.0) {
-        None => return err_not_found(),
-        Some(r_idx) => idx = *r_idx,
-    }
-
-    let ops = in_msg.ops.to_vec();
-    for op in &ops {
-        // attempt to read record from db, based on key (http payload)
-        match state.dbs[idx].db.get(&op.key) {
-            Ok(optval) => match optval {
-                Some(val) => {
-                    let mut out_res = GetOpResult::new();
-                    if !op.skip_val {
-                        out_res.val = val;
-                    }
-                    out_res.is_ok = true;
-                    out_res.err = EnumOrUnknown::new(get_op_result::GetErr::NONE);
-                    out_msg.res.push(out_res);
-                }
-                None => {
-                    let mut out_res = GetOpResult::new();
-                    out_res.is_ok = false;
-                    out_res.err = EnumOrUnknown::new(get_op_result::GetErr::KEY_NOT_FOUND);
-                    out_msg.res.push(out_res);
+    for op in &in_msg.ops {
+        // attempt to read record from db, based on key
+        let mut out_res = GetOpResult::new();
+        match db.get_ns(ns, &op.key)? {
+            Some(val) => {
+                out_res.version = db.version_ns(ns, &op.key)?.unwrap_or(0);
+                if !op.skip_val {
+                    out_res.val = val;
                 }
-            },
-            Err(_e) => return err_500(), // db: error
+                out_res.is_ok = true;
+                out_res.err = EnumOrUnknown::new(get_op_result::GetErr::NONE);
+            }
+            None => {
+                out_res.is_ok = false;
+                out_res.err = EnumOrUnknown::new(get_op_result::GetErr::KEY_NOT_FOUND);
+            }
         }
+        out_msg.res.push(out_res);
     }
 
-    ok_binary(out_msg.write_to_bytes().unwrap())
+    Ok(out_msg)
+}
+
+/// Multiple-GET data item. key in HTTP payload, returns value in HTTP payload.
+async fn req_mget(
+    m_state: web::Data<ServerState>,
+    (path, body): (web::Path<NsPath>, web::Bytes),
+) -> HttpResponse {
+    // decode protobuf msg containing key, into KeyRequest struct
+    let in_msg = match codec::pbdec_mget_req(&body) {
+        None => return err_bad_req(),
+        Some(in_msg) => in_msg,
+    };
+
+    // lookup database by name (path elem 0)
+    let db_state = match m_state.lookup(&path.db) {
+        None => return err_not_found(),
+        Some(db_state) => db_state,
+    };
+
+    let ns = match path.ns(&in_msg.ns) {
+        Ok(ns) => ns,
+        Err(resp) => return resp,
+    };
+
+    // read all records under a single shared lock, off the worker thread
+    let res = web::block(move || exec_mget(&**db_state.read_db(), ns.as_deref(), &in_msg)).await;
+
+    match res {
+        Ok(Ok(out_msg)) => ok_binary(out_msg.write_to_bytes().unwrap()),
+        Ok(Err(e)) => err_db(&e), // db: error
+        Err(_e) => err_500(),     // thread pool: canceled
+    }
 }
 
 /// atomic PUT of multiple data items. data items in HTTP payload. ret json ok.
 async fn req_mutate(
-    m_state: web::Data<Arc<Mutex<ServerState>>>,
-    (path, body): (web::Path<(String,)>, web::Bytes),
+    m_state: web::Data<ServerState>,
+    (path, body): (web::Path<NsPath>, web::Bytes),
 ) -> HttpResponse {
     // decode protobuf msg containing key/value pairs
-    let res = codec::pbdec_mutate_req(&body);
-    if res.is_none() {
-        return err_bad_req();
-    }
-    let in_msg = res.unwrap();
+    let in_msg = match codec::pbdec_mutate_req(&body) {
+        None => return err_bad_req(),
+        Some(in_msg) => in_msg,
+    };
 
     // build batch
-    let mut batch = db::api::Batch::default();
-    let updates = in_msg.reqs.to_vec();
-    for update in &updates {
+    for update in &in_msg.reqs {
         if update.magic != EnumOrUnknown::new(update_request::MagicNum::MAGIC) {
             return err_bad_req();
         }
-        if update.is_insert {
-            batch.insert(&update.key, &update.value);
-        } else {
-            batch.remove(&update.key);
-        }
     }
+    let batch = match codec::batch_from_mutate_req(&in_msg, path.ns.as_deref()) {
+        Ok(batch) => batch,
+        Err(e) => return err_db(&e),
+    };
 
-    // lock runtime-live state data
-    let mut state = m_state.lock().unwrap();
+    // lookup database by name (path elem 0)
+    let db_state = match m_state.lookup(&path.db) {
+        None => return err_not_found(),
+        Some(db_state) => db_state,
+    };
+
+    // reject writes to read-only db, before they reach the driver
+    if db_state.cfg.read_only {
+        return err_read_only();
+    }
 
-    // lookup database index by name (path elem 0)
-    let idx: usize;
-    match state.name_idx.get(&path.0) {
+    // attempt to apply batch of updates to db
+    let durable = in_msg.durable;
+    let res = web::block(move || exec_mutate(&db_state, &batch, durable)).await;
+    match res {
+        Ok(Ok(_optval)) => ok_json(json!({"result": true})),
+        Ok(Err(e)) => err_db(&e), // db: error
+        Err(_e) => err_500(),     // thread pool: canceled
+    }
+}
+
+/// Compare-and-swap data item. key, expected and new values in HTTP payload.
+async fn req_cas(
+    m_state: web::Data<ServerState>,
+    (path, body): (web::Path<NsPath>, web::Bytes),
+) -> HttpResponse {
+    // decode protobuf msg containing key and values, into CasRequest struct
+    let in_msg = match codec::pbdec_cas_req(&body) {
+        None => return err_bad_req(),
+        Some(in_msg) => in_msg,
+    };
+
+    // lookup database by name (path elem 0)
+    let db_state = match m_state.lookup(&path.db) {
         None => return err_not_found(),
-        Some(r_idx) => idx = *r_idx,
+        Some(db_state) => db_state,
+    };
+
+    // reject writes to read-only db, before they reach the driver
+    if db_state.cfg.read_only {
+        return err_read_only();
     }
 
-    // attempt to store record in db, based on key (path elem 1)
-    match state.dbs[idx].db.apply_batch(&batch) {
-        Ok(_optval) => ok_json(json!({"result": true})),
-        Err(_e) => err_500(), // db: error
+    let ns = match path.ns(&in_msg.ns) {
+        Ok(ns) => ns,
+        Err(resp) => return resp,
+    };
+
+    // attempt to swap record value, if current value is as expected
+    let res = web::block(move || {
+        db_state.write_db().compare_and_swap_ns(
+            ns.as_deref(),
+            &in_msg.key,
+            in_msg.old_value.as_deref(),
+            in_msg.new_value.as_deref(),
+        )
+    })
+    .await;
+    match res {
+        Ok(Ok(_optval)) => ok_json(json!({"result": true})),
+        Ok(Err(e)) => err_db(&e), // db: error, or precondition failed
+        Err(_e) => err_500(),     // thread pool: canceled
     }
 }
 
 /// PUT data item. key/value in HTTP payload.
 async fn req_put(
-    m_state: web::Data<Arc<Mutex<ServerState>>>,
-    (path, body): (web::Path<(String,)>, web::Bytes),
+    m_state: web::Data<ServerState>,
+    (path, body): (web::Path<NsPath>, web::Bytes),
 ) -> HttpResponse {
     // decode protobuf msg containing key, into KeyRequest struct
-    let res = codec::pbdec_update_req(&body);
-    if res.is_none() {
-        return err_bad_req();
-    }
-    let in_msg = res.unwrap();
+    let in_msg = match codec::pbdec_update_req(&body) {
+        None => return err_bad_req(),
+        Some(in_msg) => in_msg,
+    };
     if !in_msg.is_insert {
         return err_bad_req();
     }
 
-    // lock runtime-live state data
-    let mut state = m_state.lock().unwrap();
-
-    // lookup database index by name (path elem 0)
-    let idx: usize;
-    match state.name_idx.get(&path.0) {
+    // lookup database by name (path elem 0)
+    let db_state = match m_state.lookup(&path.db) {
         None => return err_not_found(),
-        Some(r_idx) => idx = *r_idx,
+        Some(db_state) => db_state,
+    };
+
+    // reject writes to read-only db, before they reach the driver
+    if db_state.cfg.read_only {
+        return err_read_only();
     }
 
+    let ns = match path.ns(&in_msg.ns) {
+        Ok(ns) => ns,
+        Err(resp) => return resp,
+    };
+
     // attempt to store record in db, based on key
-    match state.dbs[idx].db.put(&in_msg.key, &in_msg.value) {
-        Ok(_optval) => ok_json(json!({"result": true})),
-        Err(_e) => err_500(), // db: error
+    let res = web::block(move || {
+        db_state.write_db().put_expiring_ns(
+            ns.as_deref(),
+            &in_msg.key,
+            &in_msg.value,
+            codec::expiry_from_update(&in_msg),
+        )
+    })
+    .await;
+    match res {
+        Ok(Ok(_optval)) => ok_json(json!({"result": true})),
+        Ok(Err(e)) => err_db(&e), // db: error
+        Err(_e) => err_500(),     // thread pool: canceled
+    }
+}
+
+/// List NAMESPACES in db, as JSON
+async fn req_ns_list(m_state: web::Data<ServerState>, path: web::Path<NsPath>) -> HttpResponse {
+    // lookup database by name (path elem 0)
+    let db_state = match m_state.lookup(&path.db) {
+        None => return err_not_found(),
+        Some(db_state) => db_state,
+    };
+
+    // list namespaces, off the worker thread
+    let res = web::block(move || db_state.read_db().list_ns()).await;
+    let names = match res {
+        Ok(Ok(names)) => names,
+        Ok(Err(e)) => return err_db(&e),
+        Err(_e) => return err_500(),
+    };
+
+    // internal namespaces are not listed
+    let names: Vec<String> = names
+        .into_iter()
+        .filter(|name| !name.starts_with(db::api::RESERVED_NS_PREFIX))
+        .collect();
+
+    ok_json(json!({ "namespaces": names }))
+}
+
+/// CREATE namespace named in URL path, if it does not exist
+async fn req_ns_create(m_state: web::Data<ServerState>, path: web::Path<NsPath>) -> HttpResponse {
+    // lookup database by name (path elem 0)
+    let db_state = match m_state.lookup(&path.db) {
+        None => return err_not_found(),
+        Some(db_state) => db_state,
+    };
+
+    // reject writes to read-only db, before they reach the driver
+    if db_state.cfg.read_only {
+        return err_read_only();
+    }
+
+    let ns = match path.ns("") {
+        Ok(Some(ns)) => ns,
+        Ok(None) => return err_bad_req(),
+        Err(resp) => return resp,
+    };
+
+    // attempt to create namespace
+    let res = web::block(move || db_state.write_db().open_ns(&ns)).await;
+    match res {
+        Ok(Ok(_optval)) => ok_json(json!({"result": true})),
+        Ok(Err(e)) => err_db(&e), // db: error
+        Err(_e) => err_500(),     // thread pool: canceled
+    }
+}
+
+/// DROP namespace named in URL path, and all its records
+async fn req_ns_drop(m_state: web::Data<ServerState>, path: web::Path<NsPath>) -> HttpResponse {
+    // lookup database by name (path elem 0)
+    let db_state = match m_state.lookup(&path.db) {
+        None => return err_not_found(),
+        Some(db_state) => db_state,
+    };
+
+    // reject writes to read-only db, before they reach the driver
+    if db_state.cfg.read_only {
+        return err_read_only();
+    }
+
+    let ns = match path.ns("") {
+        Ok(Some(ns)) => ns,
+        Ok(None) => return err_bad_req(),
+        Err(resp) => return resp,
+    };
+
+    // attempt to remove namespace
+    let res = web::block(move || db_state.write_db().drop_ns(&ns)).await;
+    match res {
+        Ok(Ok(optval)) => match optval {
+            true => ok_json(json!({"result": true})),
+            false => err_not_found(), // db: namespace not found
+        },
+        Ok(Err(e)) => err_db(&e), // db: error
+        Err(_e) => err_500(),     // thread pool: canceled
+    }
+}
+
+// register a REST API route, checking API keys once routed, before the
+// handler runs
+fn api_route(cfg: &mut web::ServiceConfig, path: String, route: Route) {
+    cfg.service(web::resource(path).route(route).wrap(auth::ApiAuth));
+}
+
+// register namespace management routes
+fn ns_routes(cfg: &mut web::ServiceConfig) {
+    let routes = [
+        ("{db}/namespaces", web::get().to(req_ns_list)),
+        ("{db}/{ns}/create", web::post().to(req_ns_create)),
+        ("{db}/{ns}/drop", web::post().to(req_ns_drop)),
+    ];
+    for (path, route) in routes {
+        api_route(cfg, format!("/api/{}", path), route);
+    }
+}
+
+// register db API routes below prefix; "/api/{db}" or "/api/{db}/{ns}"
+fn db_routes(cfg: &mut web::ServiceConfig, prefix: &str) {
+    let routes = [
+        ("mutate", web::post().to(req_mutate)),
+        ("cas", web::post().to(req_cas)),
+        ("clear", web::post().to(req_clear)),
+        ("del", web::post().to(req_del)),
+        ("mget", web::post().to(req_mget)),
+        ("iter", web::post().to(req_iter)),
+        ("put", web::post().to(req_put)),
+        ("scan", web::post().to(req_scan)),
+        ("stat", web::get().to(req_stat)),
+        ("stat.json", web::get().to(req_stat_json)),
+    ];
+    for (op, route) in routes {
+        api_route(cfg, format!("{}/{}", prefix, op), route);
+    }
+}
+
+// stop gRPC service, then flush and close all databases; false if any
+// database failed to close
+fn shutdown(state: &ServerState, grpc_server: Option<grpc::GrpcServer>) -> bool {
+    println!("shutdown: HTTP server stopped.");
+    if let Some(grpc_server) = grpc_server {
+        grpc_server.shutdown();
+        println!("shutdown: gRPC server stopped.");
     }
+
+    // held until exit: no admin changes or reloads reopen databases
+    let _guard = state.config.lock().unwrap();
+    state.close_all()
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env::set_var("RUST_LOG", "actix_web=debug");
+    env::set_var("RUST_LOG", "actix_web=debug,kvdbd=info");
     env_logger::init();
 
     let backend_state = register_backends();
@@ -521,6 +1143,13 @@ async fn main() -> std::io::Result<()> {
                 .help("Custom server socket bind port")
                 .default_value(DEF_BIND_PORT)
                 .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("grpc-port")
+                .long("grpc-port")
+                .value_name("PORT")
+                .help("Serve gRPC on this port, at bind address (overrides config)")
+                .takes_value(true),
         );
 
     // CLI parser dynamic setup: add zeroconf database options
@@ -543,28 +1172,36 @@ async fn main() -> std::io::Result<()> {
     let bind_pair = format!("{}:{}", bind_addr, bind_port);
     let server_hdr = format!("{}/{}", APPNAME, VERSION);
 
-    // init server state
-    let mut name_idx = HashMap::new();
-    let mut dbs = Vec::new();
-
     // determine if zeroconf is requested
     let mut zeroconf = false;
     let mut server_cfg = ServerConfig {
         debug: false,
         ssl: SslConfig::new(),
+        grpc_port: 0,
+        admin_token: String::new(),
+        api_keys: vec![],
+        client_certs: vec![],
+        record_gauge_secs: 0,
         databases: vec![],
     };
-    for (be_name, _be_info) in &backend_state.backends {
+    for be_name in backend_state.backends.keys() {
         // if matched, build single-db static configuration
         if cli_matches.is_present(be_name) {
             server_cfg = ServerConfig {
                 debug: false,
                 ssl: SslConfig::new(),
+                grpc_port: 0,
+                admin_token: String::new(),
+                api_keys: vec![],
+                client_certs: vec![],
+                record_gauge_secs: 0,
                 databases: vec![DbConfig {
                     name: String::from("db"),
                     path: cli_matches.value_of(be_name).unwrap().to_string(),
                     driver: be_name.clone(),
                     read_only: false,
+                    durability: db::api::Durability::Default,
+                    options: BTreeMap::new(),
                 }],
             };
             zeroconf = true;
@@ -573,85 +1210,191 @@ async fn main() -> std::io::Result<()> {
     }
 
     // read JSON configuration file, unless already configured
+    let mut cfg_path = None;
     if !zeroconf {
         let cfg_fn = cli_matches.value_of("config").unwrap_or(DEF_CFG_FN);
         let cfg_text = fs::read_to_string(cfg_fn)?;
         server_cfg = serde_json::from_str(&cfg_text)?;
+        cfg_path = Some(cfg_fn.to_string());
+    }
+    if let Some(port) = cli_matches.value_of("grpc-port") {
+        server_cfg.grpc_port = match port.parse() {
+            Ok(port) => port,
+            Err(_e) => {
+                println!("Invalid gRPC port {}", port);
+                process::exit(1);
+            }
+        };
     }
 
+    if let Err(e) = auth::validate_rules(&server_cfg.api_keys, &server_cfg.client_certs) {
+        println!("config: {}", e);
+        process::exit(1);
+    }
+
+    // load TLS certificates, if configured
+    let use_tls = server_cfg.ssl.enabled();
+    let tls_ctx = match use_tls {
+        true => match reload::tls_state(&server_cfg.ssl, server_cfg.grpc_port != 0) {
+            Ok(tls) => Some(tls),
+            Err(e) => {
+                println!("config: TLS setup failed: {}", e);
+                process::exit(1);
+            }
+        },
+        false => None,
+    };
+
+    // init server state
+    let srv_state = web::Data::new(ServerState {
+        debug: server_cfg.debug,
+        admin_token: server_cfg.admin_token.clone(),
+        cfg_path,
+        config: Mutex::new(server_cfg.clone()),
+        tls: RwLock::new(tls_ctx),
+        access: RwLock::new(auth::AccessRules {
+            api_keys: server_cfg.api_keys.clone(),
+            client_certs: server_cfg.client_certs.clone(),
+        }),
+        backends: backend_state,
+        registry: RwLock::new(DbRegistry::default()),
+        metrics: metrics::Metrics::new(),
+    });
+
     // configure and open databases
     for db_cfg in &server_cfg.databases {
-        // setup backend config
-        let db_config = db::api::ConfigBuilder::new()
-            .path(db_cfg.path.clone())
-            .read_only(db_cfg.read_only)
-            .build();
+        let db_state = match srv_state.open_db(db_cfg) {
+            Ok(db_state) => db_state,
+            Err(e) => {
+                println!("config: Database {} failed to open: {}", db_cfg.name, e);
+                srv_state.close_all(); // those opened so far
+                process::exit(1);
+            }
+        };
 
-        // verify this is a known backend
-        if !backend_state.backends.contains_key(&db_cfg.driver) {
-            println!("config: Unsupported db driver {} specified.", db_cfg.driver);
+        // add db to server state
+        if !srv_state.registry_write().insert(db_state) {
+            println!("config: Database {} configured twice.", db_cfg.name);
+            srv_state.close_all();
             process::exit(1);
         }
-
-        let backend = &backend_state.backends[&db_cfg.driver];
-
-        // add db to server state
-        let next_idx = dbs.len();
-        name_idx.insert(db_cfg.name.clone(), next_idx);
-        dbs.push(DbState {
-            cfg: db_cfg.clone(),
-            db: backend.driver.start_db(db_config).unwrap(),
-        });
     }
 
-    let srv_state = Arc::new(Mutex::new(ServerState {
-        debug: server_cfg.debug,
-        name_idx: name_idx,
-        dbs: dbs,
-    }));
+    // start purging expired records
+    spawn_reaper(srv_state.clone())?;
+    metrics::spawn_refresher(srv_state.clone(), server_cfg.record_gauge_secs)?;
+
+    // re-read configuration file on SIGHUP
+    reload::spawn_sighup_handler(srv_state.clone())?;
+
+    // start gRPC service, if requested, sharing the open databases
+    let mut grpc_server = None;
+    if server_cfg.grpc_port != 0 {
+        let grpc_pair = format!("{}:{}", bind_addr, server_cfg.grpc_port);
+        let grpc_addr = match grpc_pair.parse() {
+            Ok(addr) => addr,
+            Err(_e) => {
+                println!("Invalid gRPC bind address {}", grpc_pair);
+                process::exit(1);
+            }
+        };
+        println!("Starting gRPC server: {}", grpc_pair);
+        grpc_server = Some(grpc::spawn_server(srv_state.clone(), grpc_addr, use_tls)?);
+    }
 
     // configure web server
+    let tls_state = srv_state.clone();
+    let shutdown_state = srv_state.clone();
     let app = move || {
         App::new()
             // pass application state to each handler
-            .app_data(web::Data::new(Arc::clone(&srv_state)))
+            .app_data(srv_state.clone())
             // apply default headers
             .wrap(middleware::DefaultHeaders::new().add(("Server", server_hdr.to_string())))
+            // count and time requests
+            .wrap(metrics::RequestMetrics)
             // enable logger - always register actix-web Logger middleware last
             .wrap(middleware::Logger::default())
             // register our routes
             .service(req_index)
-            .service(web::resource("/api/{db}/mutate").route(web::post().to(req_mutate)))
-            .service(web::resource("/api/{db}/clear").route(web::post().to(req_clear)))
-            .service(web::resource("/api/{db}/del").route(web::post().to(req_del)))
-            .service(web::resource("/api/{db}/mget").route(web::post().to(req_mget)))
-            .service(web::resource("/api/{db}/iter").route(web::post().to(req_iter)))
-            .service(web::resource("/api/{db}/put").route(web::post().to(req_put)))
-            .service(web::resource("/api/{db}/stat").route(web::get().to(req_stat)))
-            .service(web::resource("/api/{db}/stat.json").route(web::get().to(req_stat_json)))
+            .service(metrics::req_metrics)
+            .configure(admin::routes)
+            .configure(health::routes)
+            .configure(ns_routes)
+            .configure(|cfg| db_routes(cfg, "/api/{db}"))
+            .configure(|cfg| db_routes(cfg, "/api/{db}/{ns}"))
     };
 
     // if TLS key/cert present in config, run in TLS mode
-    if !server_cfg.ssl.private_key_path.is_empty() && !server_cfg.ssl.cert_chain_path.is_empty() {
-        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
-        builder
-            .set_private_key_file(server_cfg.ssl.private_key_path, SslFiletype::PEM)
-            .unwrap();
-        builder
-            .set_certificate_chain_file(server_cfg.ssl.cert_chain_path)
-            .unwrap();
+    let bound = if use_tls {
+        let builder = match reload::tls_acceptor(&server_cfg.ssl, tls_state) {
+            Ok(builder) => builder,
+            Err(e) => {
+                println!("config: TLS setup failed: {}", e);
+                process::exit(1);
+            }
+        };
         println!("Starting https server: {}", bind_pair);
         HttpServer::new(app)
-            .bind_openssl(bind_pair.to_string(), builder)?
-            .run()
-            .await
+            .on_connect(auth::on_connect)
+            .bind_openssl(bind_pair.to_string(), builder)
 
     // otherwise, plain ole HTTP
     } else {
         println!("Starting http server: {}", bind_pair);
-        HttpServer::new(app)
-            .bind(bind_pair.to_string())?
-            .run()
-            .await
+        HttpServer::new(app).bind(bind_pair.to_string())
+    };
+
+    // run until SIGTERM (in-flight requests drained first), SIGINT or SIGQUIT
+    let res = match bound {
+        Ok(server) => server.run().await,
+        Err(e) => Err(e),
+    };
+
+    if !shutdown(&shutdown_state, grpc_server) {
+        process::exit(1);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::api::Durability;
+
+    // memory database configuration, without snapshot file
+    fn db_cfg(durability: &str) -> DbConfig {
+        serde_json::from_str(&format!(
+            r#"{{"name": "db1", "path": "", "driver": "memory",
+                "durability": {}, "options": {{"snapshot": false}}}}"#,
+            durability
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_durability_config() {
+        for (json, durability) in [
+            (r#""default""#, Durability::Default),
+            (r#""sync""#, Durability::Sync),
+            (r#"{"periodic": 25}"#, Durability::Periodic(25)),
+            (r#""nosync""#, Durability::NoSync),
+        ] {
+            let db_cfg = db_cfg(json);
+            assert_eq!(db_cfg.durability, durability);
+            assert_eq!(db_cfg.driver_config().unwrap().durability, durability);
+        }
+        assert!(db_cfg(r#"{"periodic": 0}"#).driver_config().is_err());
+        assert!(serde_json::from_str::<Durability>(r#""periodic""#).is_err());
+
+        // the memory driver supports only default and nosync durability
+        let backends = register_backends();
+        let driver = &backends.backends["memory"].driver;
+        let start = |json| driver.start_db(db_cfg(json).driver_config().unwrap());
+        assert!(start(r#""nosync""#).is_ok());
+        assert!(matches!(
+            start(r#"{"periodic": 25}"#),
+            Err(Error::InvalidArgument(_))
+        ));
     }
 }
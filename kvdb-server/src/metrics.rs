@@ -0,0 +1,551 @@
+/*
+ * metrics: Prometheus metrics, served in text format at /metrics.
+ *
+ * The RequestMetrics middleware counts and times each HTTP request,
+ * labelled by route pattern and, for database routes, database name;
+ * the GrpcMetrics layer does likewise for gRPC calls, by method.
+ * Waits for server state locks are timed where the locks are taken.
+ * Database gauges are refreshed by a background thread, every
+ * GAUGE_REFRESH_SECS, so that scrapes take no database locks.  Record
+ * counts take time proportional to database size with most drivers, so
+ * are only refreshed if configured, at their own interval.
+ *
+ * With api_keys or client_certs configured, scrapes need a valid key or
+ * certificate, or the admin token, as metric labels name each database.
+ */
+
+use std::future::{ready, Future, Ready};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use protobuf::Enum;
+
+use tonic::codegen::http;
+use tonic::Code;
+use tower_layer::Layer;
+
+use kvdb_lib::grpc::{GRPC_DB_KEY, GRPC_ERR_CODE_KEY};
+use kvdb_lib::pbapi::basic_response::ErrCode;
+
+use crate::{admin, auth, err_500, ServerState};
+
+// lock wait histogram buckets, in seconds
+const LOCK_WAIT_BUCKETS: &[f64] = &[0.000_01, 0.000_1, 0.001, 0.01, 0.1, 1.0, 10.0];
+
+// interval between refreshes of database gauges
+const GAUGE_REFRESH_SECS: u64 = 15;
+
+// route label of requests matching no route
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,      // by db, route, status
+    duration: HistogramVec,       // by db, route
+    errors: IntCounterVec,        // by db, route, error type
+    bytes_in: IntCounterVec,      // request bodies, by db, route
+    bytes_out: IntCounterVec,     // response bodies, by db, route
+    grpc_requests: IntCounterVec, // by db, method, status code
+    grpc_errors: IntCounterVec,   // by db, method, error type
+    grpc_duration: HistogramVec,  // by db, method
+    lock_wait: HistogramVec,      // by lock, db, mode
+    records: IntGaugeVec,         // by db, namespace
+    disk_bytes: IntGaugeVec,      // by db
+    map_bytes: IntGaugeVec,       // by db
+}
+
+// register collector, returning it for use
+fn register<C: Collector + Clone + 'static>(registry: &Registry, collector: C) -> C {
+    registry.register(Box::new(collector.clone())).unwrap();
+    collector
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let registry = Registry::new();
+        let db_route = &["db", "route"];
+
+        Metrics {
+            requests: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("kvdb_http_requests_total", "HTTP requests handled"),
+                    &["db", "route", "status"],
+                )
+                .unwrap(),
+            ),
+            duration: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "kvdb_http_request_duration_seconds",
+                        "HTTP request latency, until the response is ready",
+                    ),
+                    db_route,
+                )
+                .unwrap(),
+            ),
+            errors: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("kvdb_http_errors_total", "HTTP requests failed, by error"),
+                    &["db", "route", "type"],
+                )
+                .unwrap(),
+            ),
+            bytes_in: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "kvdb_http_request_bytes_total",
+                        "HTTP request body bytes, by Content-Length",
+                    ),
+                    db_route,
+                )
+                .unwrap(),
+            ),
+            bytes_out: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("kvdb_http_response_bytes_total", "HTTP response body bytes"),
+                    db_route,
+                )
+                .unwrap(),
+            ),
+            grpc_requests: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("kvdb_grpc_requests_total", "gRPC calls handled"),
+                    &["db", "method", "code"],
+                )
+                .unwrap(),
+            ),
+            grpc_errors: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("kvdb_grpc_errors_total", "gRPC calls failed, by error"),
+                    &["db", "method", "type"],
+                )
+                .unwrap(),
+            ),
+            grpc_duration: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "kvdb_grpc_request_duration_seconds",
+                        "gRPC call latency, until the response headers are ready",
+                    ),
+                    &["db", "method"],
+                )
+                .unwrap(),
+            ),
+            lock_wait: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "kvdb_lock_wait_seconds",
+                        "Time waiting to acquire server state locks",
+                    )
+                    .buckets(LOCK_WAIT_BUCKETS.to_vec()),
+                    &["lock", "db", "mode"],
+                )
+                .unwrap(),
+            ),
+            records: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("kvdb_db_records", "Records stored, by namespace"),
+                    &["db", "ns"],
+                )
+                .unwrap(),
+            ),
+            disk_bytes: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("kvdb_db_disk_bytes", "Space used by database files"),
+                    &["db"],
+                )
+                .unwrap(),
+            ),
+            map_bytes: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("kvdb_db_map_bytes", "Database size limit (LMDB map size)"),
+                    &["db"],
+                )
+                .unwrap(),
+            ),
+            registry,
+        }
+    }
+
+    /// Histogram of waits for lock `lock` ("db", "registry", "access"),
+    /// of database `db` if per-database, in `mode` ("read", "write").
+    pub fn lock_wait(&self, lock: &str, db: &str, mode: &str) -> Histogram {
+        self.lock_wait.with_label_values(&[lock, db, mode])
+    }
+
+    /// Drop lock wait histograms of closed database `db`.
+    pub fn forget_db(&self, db: &str) {
+        for mode in ["read", "write"] {
+            let _ = self.lock_wait.remove_label_values(&["db", db, mode]);
+        }
+    }
+
+    // record one HTTP request
+    fn observe<B>(&self, m_state: &ServerState, res: &ServiceResponse<B>, start: Instant, n_in: u64)
+    where
+        B: MessageBody,
+    {
+        let req = res.request();
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| String::from(UNMATCHED_ROUTE));
+
+        // label open databases only, so clients cannot add label values
+        let db = match req.match_info().get("db") {
+            Some(db) if m_state.lookup(db).is_some() => db,
+            _ => "",
+        };
+
+        let status = res.status();
+        self.requests
+            .with_label_values(&[db, &route, status.as_str()])
+            .inc();
+        self.duration
+            .with_label_values(&[db, &route])
+            .observe(start.elapsed().as_secs_f64());
+
+        // error type: ErrCode set by err_resp, or another HTTP error
+        let err_type = match res.response().extensions().get::<ErrCode>() {
+            Some(err_code) => Some(format!("{:?}", err_code)),
+            None if status.is_client_error() || status.is_server_error() => {
+                Some(String::from("OTHER"))
+            }
+            None => None,
+        };
+        if let Some(err_type) = err_type {
+            self.errors
+                .with_label_values(&[db, &route, &err_type])
+                .inc();
+        }
+
+        self.bytes_in.with_label_values(&[db, &route]).inc_by(n_in);
+        if let BodySize::Sized(n_out) = res.response().body().size() {
+            self.bytes_out
+                .with_label_values(&[db, &route])
+                .inc_by(n_out);
+        }
+    }
+
+    // record one gRPC call, of `method` on database `db`, failed with
+    // `err_type` if any
+    fn observe_grpc(
+        &self,
+        m_state: &ServerState,
+        db: Option<&str>,
+        method: &str,
+        code: Code,
+        err_type: Option<String>,
+        start: Instant,
+    ) {
+        // label open databases and served methods only, as for HTTP
+        let db = match db {
+            Some(db) if m_state.lookup(db).is_some() => db,
+            _ => "",
+        };
+        let method = match code {
+            Code::Unimplemented => UNMATCHED_ROUTE,
+            _ => method,
+        };
+
+        self.grpc_requests
+            .with_label_values(&[db, method, &format!("{:?}", code)])
+            .inc();
+        self.grpc_duration
+            .with_label_values(&[db, method])
+            .observe(start.elapsed().as_secs_f64());
+        if let Some(err_type) = err_type {
+            self.grpc_errors
+                .with_label_values(&[db, method, &err_type])
+                .inc();
+        }
+    }
+
+    // refresh database gauges, and record counts if `count_records`.
+    // Values are read first, so that scrapes see the previous values
+    // until replaced.
+    fn refresh_gauges(&self, m_state: &ServerState, count_records: bool) {
+        let mut records = Vec::new();
+        let mut disk_bytes = Vec::new();
+        let mut map_bytes = Vec::new();
+
+        for db_state in m_state.databases() {
+            let name = db_state.cfg.name.clone();
+            let db = db_state.read_db();
+
+            // default keyspace, then each namespace
+            if count_records {
+                let mut nss = vec![None];
+                let names = db.list_ns().unwrap_or_default();
+                nss.extend(names.iter().map(|ns| Some(ns.as_str())));
+                for ns in nss {
+                    if let Ok(st) = db.stat_ns(ns) {
+                        let ns = ns.unwrap_or("").to_string();
+                        records.push((name.clone(), ns, st.n_records as i64));
+                    }
+                }
+            }
+
+            if let Ok(st) = db.storage_stat() {
+                if let Some(n_bytes) = st.disk_bytes {
+                    disk_bytes.push((name.clone(), n_bytes as i64));
+                }
+                if let Some(n_bytes) = st.map_bytes {
+                    map_bytes.push((name.clone(), n_bytes as i64));
+                }
+            }
+        }
+
+        // detached databases drop out
+        if count_records {
+            self.records.reset();
+            for (name, ns, n_records) in records {
+                self.records.with_label_values(&[&name, &ns]).set(n_records);
+            }
+        }
+        self.disk_bytes.reset();
+        for (name, n_bytes) in disk_bytes {
+            self.disk_bytes.with_label_values(&[&name]).set(n_bytes);
+        }
+        self.map_bytes.reset();
+        for (name, n_bytes) in map_bytes {
+            self.map_bytes.with_label_values(&[&name]).set(n_bytes);
+        }
+    }
+
+    // encode all metrics
+    fn render(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .unwrap();
+        buf
+    }
+}
+
+/// Start the background thread refreshing database gauges.  Record
+/// counts are refreshed every `record_secs`, rounded up to a whole
+/// number of gauge refreshes; never, if 0.
+pub fn spawn_refresher(state: web::Data<ServerState>, record_secs: u64) -> io::Result<()> {
+    let record_interval = Duration::from_secs(record_secs);
+    thread::Builder::new()
+        .name(String::from("kvdbd-metrics"))
+        .spawn(move || {
+            let mut last_count: Option<Instant> = None;
+            loop {
+                let count_records = record_secs != 0
+                    && last_count.is_none_or(|when| when.elapsed() >= record_interval);
+                if count_records {
+                    last_count = Some(Instant::now());
+                }
+                state.metrics.refresh_gauges(&state, count_records);
+                thread::sleep(Duration::from_secs(GAUGE_REFRESH_SECS));
+            }
+        })?;
+    Ok(())
+}
+
+/// Acquire a lock through `acquire`, recording the wait in `hist`.
+pub fn timed<T>(hist: &Histogram, acquire: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let guard = acquire();
+    hist.observe(start.elapsed().as_secs_f64());
+    guard
+}
+
+/// Prometheus metrics, in text exposition format.
+#[get("/metrics")]
+pub async fn req_metrics(m_state: web::Data<ServerState>, req: HttpRequest) -> HttpResponse {
+    if !admin::authorized(&m_state, &req) {
+        if let Err(denied) = auth::check_http(&m_state, &req, None, auth::Access::Read) {
+            return auth::err_denied(denied);
+        }
+    }
+
+    let res = web::block(move || m_state.metrics.render()).await;
+    match res {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(prometheus::TEXT_FORMAT)
+            .body(body),
+        Err(_e) => err_500(),
+    }
+}
+
+/// actix middleware recording request metrics.
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RequestMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestMetricsMiddleware { service }))
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: S,
+}
+
+type LocalBoxFuture<T> = Pin<Box<dyn Future<Output = T>>>;
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let n_in = match req.headers().get(header::CONTENT_LENGTH) {
+            None => 0,
+            Some(hdr) => hdr
+                .to_str()
+                .ok()
+                .and_then(|len| len.parse().ok())
+                .unwrap_or(0),
+        };
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let res = fut.await?;
+            if let Some(m_state) = res.request().app_data::<web::Data<ServerState>>() {
+                m_state.metrics.observe(m_state, &res, start, n_in);
+            }
+            Ok(res)
+        })
+    }
+}
+
+/// tower layer recording gRPC call metrics, for the tonic server.
+#[derive(Clone)]
+pub struct GrpcMetrics {
+    state: web::Data<ServerState>,
+}
+
+impl GrpcMetrics {
+    pub fn new(state: web::Data<ServerState>) -> GrpcMetrics {
+        GrpcMetrics { state }
+    }
+}
+
+impl<S> Layer<S> for GrpcMetrics {
+    type Service = GrpcMetricsService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        GrpcMetricsService {
+            service,
+            state: self.state.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct GrpcMetricsService<S> {
+    service: S,
+    state: web::Data<ServerState>,
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+impl<S, ReqBody, ResBody> tower_service::Service<http::Request<ReqBody>> for GrpcMetricsService<S>
+where
+    S: tower_service::Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<ReqBody>) -> Self::Future {
+        let start = Instant::now();
+        let method = req
+            .uri()
+            .path()
+            .rsplit('/')
+            .next()
+            .unwrap_or("")
+            .to_string();
+        let db = req
+            .headers()
+            .get(GRPC_DB_KEY)
+            .and_then(|val| val.to_str().ok())
+            .map(|db| db.to_string());
+        let state = self.state.clone();
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let res = fut.await;
+            if let Ok(resp) = &res {
+                // failed calls carry grpc-status in headers; successful
+                // unary calls in trailers, after the response body
+                let code = resp
+                    .headers()
+                    .get("grpc-status")
+                    .and_then(|val| val.to_str().ok())
+                    .and_then(|val| val.parse().ok())
+                    .map_or(Code::Ok, Code::from_i32);
+
+                // error type: ErrCode of a BasicResponse, or of a failed
+                // call's status, or another gRPC error
+                let err_code = match resp.extensions().get::<ErrCode>() {
+                    Some(err_code) => Some(*err_code),
+                    None => resp
+                        .headers()
+                        .get(GRPC_ERR_CODE_KEY)
+                        .and_then(|val| val.to_str().ok())
+                        .and_then(|val| val.parse().ok())
+                        .and_then(ErrCode::from_i32),
+                };
+                let err_type = match err_code {
+                    Some(err_code) => Some(format!("{:?}", err_code)),
+                    None if code != Code::Ok => Some(String::from("OTHER")),
+                    None => None,
+                };
+                state
+                    .metrics
+                    .observe_grpc(&state, db.as_deref(), &method, code, err_type, start);
+            }
+            res
+        })
+    }
+}
@@ -0,0 +1,525 @@
+/*
+ * reload: apply changes to the server configuration file, without restart.
+ *
+ * Triggered by SIGHUP, or the admin API.  The new configuration is fully
+ * validated before any running state is touched.  Databases are diffed by
+ * name: new entries are opened, removed entries closed, and changed
+ * entries (path, driver or read_only) reopened; if any fails to open,
+ * the running databases are left as they were.  TLS certificates are
+ * swapped in for new HTTPS and gRPC connections, while the listeners keep
+ * running.
+ * API keys and client certificate rules are replaced.
+ */
+
+use std::collections::HashSet;
+use std::fs;
+use std::sync::Arc;
+
+use actix_web::web;
+use openssl::error::ErrorStack;
+use openssl::ssl::{
+    AlpnError, SniError, SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype, SslMethod,
+    SslVerifyMode,
+};
+use openssl::x509::X509Name;
+use tokio::signal::unix::{signal, SignalKind};
+
+use kvdb_lib::{db, Error};
+
+use crate::{
+    auth, canonical_path, finish_db, grpc, ClientVerify, DbConfig, DbState, ServerConfig,
+    ServerState, SslConfig,
+};
+
+// session cache scope; required for resumption when verifying clients
+const SESSION_ID_CONTEXT: &[u8] = b"kvdbd";
+
+// request and verify client certificates, as configured
+fn set_client_verify(
+    builder: &mut SslAcceptorBuilder,
+    ssl_cfg: &SslConfig,
+) -> Result<(), ErrorStack> {
+    builder.set_session_id_context(SESSION_ID_CONTEXT)?;
+
+    let mode = match ssl_cfg.client_verify {
+        ClientVerify::None => return Ok(()),
+        ClientVerify::Optional => SslVerifyMode::PEER,
+        ClientVerify::Required => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+    };
+    builder.set_ca_file(&ssl_cfg.ca_file)?;
+    builder.set_client_ca_list(X509Name::load_client_ca_file(&ssl_cfg.ca_file)?);
+    builder.set_verify(mode);
+    Ok(())
+}
+
+/// TLS certificates in use for new connections: the HTTPS context, and
+/// gRPC's, when gRPC is served.  Swapped as a whole by reload.
+pub struct TlsState {
+    pub ctx: SslContext,
+    pub grpc: Option<grpc::GrpcTls>,
+}
+
+/// Load TLS key, certificate chain and client CA bundle, for HTTPS, and
+/// for gRPC if with_grpc.
+pub fn tls_state(ssl_cfg: &SslConfig, with_grpc: bool) -> Result<TlsState, String> {
+    let ctx = tls_context(ssl_cfg).map_err(|e| e.to_string())?;
+    let grpc = match with_grpc {
+        true => match grpc::grpc_tls(ssl_cfg) {
+            Ok(grpc) => Some(grpc),
+            Err(e) => return Err(format!("gRPC: {}", e)),
+        },
+        false => None,
+    };
+
+    Ok(TlsState { ctx, grpc })
+}
+
+// load TLS key, certificate chain and client CA bundle into a context,
+// usable for new connections
+fn tls_context(ssl_cfg: &SslConfig) -> Result<SslContext, ErrorStack> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_private_key_file(&ssl_cfg.private_key_path, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(&ssl_cfg.cert_chain_path)?;
+    builder.check_private_key()?;
+    set_client_verify(&mut builder, ssl_cfg)?;
+
+    // the context replaces the listener's during handshake, ALPN included;
+    // negotiate as actix-web does
+    builder.set_alpn_select_callback(|_, protocols| {
+        const H2: &[u8] = b"\x02h2";
+        const H11: &[u8] = b"\x08http/1.1";
+
+        if protocols.windows(3).any(|window| window == H2) {
+            Ok(b"h2")
+        } else if protocols.windows(9).any(|window| window == H11) {
+            Ok(b"http/1.1")
+        } else {
+            Err(AlpnError::NOACK)
+        }
+    });
+
+    Ok(builder.build().into_context())
+}
+
+/// Build the TLS listener configuration.  Each handshake uses the server's
+/// current TLS context, so that reloaded certificates apply to new
+/// connections.
+pub fn tls_acceptor(
+    ssl_cfg: &SslConfig,
+    state: web::Data<ServerState>,
+) -> Result<SslAcceptorBuilder, ErrorStack> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_private_key_file(&ssl_cfg.private_key_path, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(&ssl_cfg.cert_chain_path)?;
+    set_client_verify(&mut builder, ssl_cfg)?;
+
+    // called for every ClientHello, with or without SNI.  The verify mode
+    // is not taken from the swapped-in context, so is set explicitly.
+    builder.set_servername_callback(move |ssl, _alert| {
+        let tls = state.tls.read().unwrap();
+        match &*tls {
+            None => Ok(()),
+            Some(tls) => match ssl.set_ssl_context(&tls.ctx) {
+                Ok(()) => {
+                    ssl.set_verify(tls.ctx.verify_mode());
+                    Ok(())
+                }
+                Err(_e) => Err(SniError::ALERT_FATAL),
+            },
+        }
+    });
+
+    Ok(builder)
+}
+
+/// Re-read configuration file upon SIGHUP.
+pub fn spawn_sighup_handler(state: web::Data<ServerState>) -> std::io::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    actix_web::rt::spawn(async move {
+        while hangup.recv().await.is_some() {
+            println!("reload: SIGHUP received.");
+
+            // outcome is logged by reload_config
+            let state = state.clone();
+            let _res = web::block(move || reload_config(&state)).await;
+        }
+    });
+
+    Ok(())
+}
+
+// describe durability setting of a database entry
+fn durability_desc(db_cfg: &DbConfig) -> String {
+    match db_cfg.durability {
+        db::api::Durability::Periodic(ms) => format!("periodic {}ms", ms),
+        durability => format!("{:?}", durability).to_lowercase(),
+    }
+}
+
+// describe changes to a database entry
+fn db_changes(old: &DbConfig, new: &DbConfig) -> String {
+    let mut diffs = Vec::new();
+    if old.path != new.path {
+        diffs.push(format!("path {} -> {}", old.path, new.path));
+    }
+    if old.driver != new.driver {
+        diffs.push(format!("driver {} -> {}", old.driver, new.driver));
+    }
+    if old.read_only != new.read_only {
+        diffs.push(format!("read_only {} -> {}", old.read_only, new.read_only));
+    }
+    if old.options != new.options {
+        diffs.push(String::from("driver options changed"));
+    }
+    if old.durability != new.durability {
+        diffs.push(format!(
+            "durability {} -> {}",
+            durability_desc(old),
+            durability_desc(new)
+        ));
+    }
+    diffs.join(", ")
+}
+
+// check new database list against drivers and running databases
+fn validate_databases(
+    m_state: &ServerState,
+    cur_cfg: &ServerConfig,
+    new_cfg: &ServerConfig,
+) -> kvdb_lib::Result<()> {
+    let mut names = HashSet::new();
+    for db_cfg in &new_cfg.databases {
+        if !names.insert(&db_cfg.name) {
+            return Err(Error::InvalidArgument(format!(
+                "database {} configured twice",
+                db_cfg.name
+            )));
+        }
+        let backend = match m_state.backends.backends.get(&db_cfg.driver) {
+            None => {
+                return Err(Error::InvalidArgument(format!(
+                    "database {}: unsupported db driver {}",
+                    db_cfg.name, db_cfg.driver
+                )))
+            }
+            Some(backend) => backend,
+        };
+        db_cfg.driver_config()?;
+        match backend.driver.check_options(&db_cfg.driver_options()?) {
+            Ok(()) => {}
+            Err(Error::InvalidArgument(msg)) => {
+                return Err(Error::InvalidArgument(format!(
+                    "database {}: {}",
+                    db_cfg.name, msg
+                )))
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    // running databases not closed by this reload
+    let staying: Vec<_> = m_state
+        .databases()
+        .into_iter()
+        .filter(|db_state| {
+            match cur_cfg
+                .databases
+                .iter()
+                .find(|d| d.name == db_state.cfg.name)
+            {
+                None => true, // attached through admin API
+                Some(old) => new_cfg.databases.contains(old),
+            }
+        })
+        .collect();
+
+    // databases to open must not collide with those
+    for db_cfg in &new_cfg.databases {
+        for db_state in &staying {
+            if db_state.cfg == *db_cfg {
+                continue;
+            }
+            if db_state.cfg.name == db_cfg.name {
+                return Err(Error::InvalidArgument(format!(
+                    "database {} already attached, with another configuration",
+                    db_cfg.name
+                )));
+            }
+            if canonical_path(&db_state.cfg.path) == canonical_path(&db_cfg.path) {
+                return Err(Error::InvalidArgument(format!(
+                    "database {}: path in use by database {}",
+                    db_cfg.name, db_state.cfg.name
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// close database no longer served, logging failure
+fn finish_or_log(db_state: Arc<DbState>, changes: &mut Vec<String>) {
+    let name = db_state.cfg.name.clone();
+    if let Err(e) = finish_db(db_state) {
+        changes.push(format!("Database {} close failed: {}", name, e));
+    }
+}
+
+// open added and changed databases, then swap them in: all or none.
+// Databases moving to another path are opened alongside the running
+// ones.  Those reopened at the same path must be closed first, and are
+// reopened with their previous configuration if anything fails.  On
+// failure, applied.databases reverts to the running set.
+fn apply_databases(
+    m_state: &ServerState,
+    cur_cfg: &ServerConfig,
+    applied: &mut ServerConfig,
+    changes: &mut Vec<String>,
+) -> Option<Error> {
+    // added and changed databases; changed ones at the same path last
+    let mut to_open = Vec::new();
+    for db_cfg in &applied.databases {
+        let old = cur_cfg.databases.iter().find(|d| d.name == db_cfg.name);
+        if old == Some(db_cfg) {
+            continue;
+        }
+        // already attached through admin API
+        if old.is_none() && m_state.lookup(&db_cfg.name).is_some() {
+            continue;
+        }
+        let same_path = match old {
+            None => false,
+            Some(old) => canonical_path(&old.path) == canonical_path(&db_cfg.path),
+        };
+        to_open.push((db_cfg, old, same_path));
+    }
+    to_open.sort_by_key(|(_, _, same_path)| *same_path);
+
+    let mut opened = Vec::new();
+    let mut reopened = Vec::new(); // same path: previous one closed; new, if opened
+    let mut failure = None;
+    for (db_cfg, old, same_path) in to_open {
+        if same_path {
+            reopened.push((None, old.unwrap()));
+            if let Err(e) = m_state.close_db(&db_cfg.name) {
+                failure = Some((db_cfg, e));
+                break;
+            }
+        }
+        match m_state.open_db(db_cfg) {
+            Ok(db_state) => match same_path {
+                true => reopened.last_mut().unwrap().0 = Some(db_state),
+                false => opened.push((db_state, old)),
+            },
+            Err(e) => {
+                failure = Some((db_cfg, e));
+                break;
+            }
+        }
+    }
+
+    // roll back: close what was opened, reopen what was closed
+    if let Some((db_cfg, e)) = failure {
+        changes.push(format!("Database {} failed to open: {}", db_cfg.name, e));
+        for (db_state, _old) in opened {
+            finish_or_log(db_state, changes);
+        }
+        let mut lost = Vec::new();
+        for (db_state, old) in reopened {
+            if let Some(db_state) = db_state {
+                finish_or_log(db_state, changes);
+            }
+            match m_state.open_db(old) {
+                Ok(db_state) => {
+                    m_state.registry_write().insert(db_state);
+                }
+                Err(e) => {
+                    changes.push(format!("Database {} failed to reopen: {}", old.name, e));
+                    lost.push(old.name.clone());
+                }
+            }
+        }
+        changes.push(String::from("Databases unchanged."));
+
+        // running set, as before
+        applied.databases = cur_cfg
+            .databases
+            .iter()
+            .filter(|d| !lost.contains(&d.name))
+            .cloned()
+            .collect();
+        return Some(e);
+    }
+
+    // swap in databases opened alongside running ones
+    for (db_state, old) in opened {
+        let name = db_state.cfg.name.clone();
+        let prev = {
+            let mut registry = m_state.registry_write();
+            let prev = registry.remove(&name);
+            registry.insert(db_state);
+            prev
+        };
+        match old {
+            None => changes.push(format!("Database {} opened.", name)),
+            Some(old) => changes.push(format!(
+                "Database {} reopened: {}.",
+                name,
+                db_changes(
+                    old,
+                    applied.databases.iter().find(|d| d.name == name).unwrap()
+                )
+            )),
+        }
+        if let Some(prev) = prev {
+            finish_or_log(prev, changes);
+        }
+    }
+    for (db_state, old) in reopened {
+        let db_state = db_state.unwrap();
+        changes.push(format!(
+            "Database {} reopened: {}.",
+            old.name,
+            db_changes(old, &db_state.cfg)
+        ));
+        m_state.registry_write().insert(db_state);
+    }
+
+    // close removed databases
+    for old in &cur_cfg.databases {
+        if applied.databases.iter().any(|d| d.name == old.name) {
+            continue;
+        }
+        match m_state.close_db(&old.name) {
+            Ok(true) => changes.push(format!("Database {} closed.", old.name)),
+            Ok(false) => {}
+            Err(e) => changes.push(format!("Database {} close failed: {}", old.name, e)),
+        }
+    }
+
+    None
+}
+
+// install new TLS certificates
+fn apply_tls(
+    m_state: &ServerState,
+    cur_cfg: &ServerConfig,
+    applied: &mut ServerConfig,
+    new_tls: Option<TlsState>,
+    changes: &mut Vec<String>,
+) {
+    let mut tls = m_state.tls.write().unwrap();
+    match (&*tls, new_tls) {
+        (Some(cur_tls), Some(new_tls)) => {
+            let cert_der = |ctx: &SslContext| ctx.certificate().and_then(|c| c.to_der().ok());
+            let changed =
+                cert_der(&cur_tls.ctx) != cert_der(&new_tls.ctx) || applied.ssl != cur_cfg.ssl;
+
+            // swap even if unchanged, picking up any new CA bundle contents
+            *tls = Some(new_tls);
+            if !changed {
+                return;
+            }
+            changes.push(String::from("TLS settings reloaded."));
+        }
+        (None, None) => {}
+        _ => {
+            changes.push(String::from(
+                "Enabling or disabling TLS requires restart; ignored.",
+            ));
+            applied.ssl = cur_cfg.ssl.clone();
+        }
+    }
+}
+
+/// Re-read the configuration file, and apply changes to databases and TLS
+/// certificates.  Returns a description of each change made.  An invalid
+/// configuration is rejected with no change to running state.
+pub fn reload_config(m_state: &ServerState) -> kvdb_lib::Result<Vec<String>> {
+    let (changes, apply_err) = match try_reload(m_state) {
+        Ok(res) => res,
+        Err(e) => {
+            println!("reload: Configuration rejected: {}", e);
+            return Err(e);
+        }
+    };
+
+    if changes.is_empty() {
+        println!("reload: No changes.");
+    }
+    for change in &changes {
+        println!("reload: {}", change);
+    }
+    match apply_err {
+        None => Ok(changes),
+        Some(e) => Err(e),
+    }
+}
+
+// returns changes made, and the first failure applying them
+fn try_reload(m_state: &ServerState) -> kvdb_lib::Result<(Vec<String>, Option<Error>)> {
+    let cfg_path = match &m_state.cfg_path {
+        None => {
+            return Err(Error::InvalidArgument(String::from(
+                "no configuration file",
+            )))
+        }
+        Some(cfg_path) => cfg_path,
+    };
+    let mut cur_cfg = m_state.config.lock().unwrap();
+
+    // read and validate new configuration, before changing anything
+    let cfg_text = fs::read_to_string(cfg_path)?;
+    let new_cfg: ServerConfig = match serde_json::from_str(&cfg_text) {
+        Ok(cfg) => cfg,
+        Err(e) => return Err(Error::InvalidArgument(format!("{}: {}", cfg_path, e))),
+    };
+    validate_databases(m_state, &cur_cfg, &new_cfg)?;
+    auth::validate_rules(&new_cfg.api_keys, &new_cfg.client_certs)?;
+    let new_tls = match new_cfg.ssl.enabled() {
+        true => match tls_state(&new_cfg.ssl, cur_cfg.grpc_port != 0) {
+            Ok(tls) => Some(tls),
+            Err(e) => return Err(Error::InvalidArgument(format!("TLS setup failed: {}", e))),
+        },
+        false => None,
+    };
+
+    // settings only read at startup
+    let mut changes = Vec::new();
+    let mut applied = new_cfg;
+    if applied.debug != cur_cfg.debug {
+        changes.push(String::from("Changing debug requires restart; ignored."));
+        applied.debug = cur_cfg.debug;
+    }
+    if applied.admin_token != cur_cfg.admin_token {
+        changes.push(String::from(
+            "Changing admin_token requires restart; ignored.",
+        ));
+        applied.admin_token = cur_cfg.admin_token.clone();
+    }
+    if applied.record_gauge_secs != cur_cfg.record_gauge_secs {
+        changes.push(String::from(
+            "Changing record_gauge_secs requires restart; ignored.",
+        ));
+        applied.record_gauge_secs = cur_cfg.record_gauge_secs;
+    }
+    applied.grpc_port = cur_cfg.grpc_port; // may be set from command line
+
+    apply_tls(m_state, &cur_cfg, &mut applied, new_tls, &mut changes);
+    if applied.api_keys != cur_cfg.api_keys || applied.client_certs != cur_cfg.client_certs {
+        *m_state.access.write().unwrap() = auth::AccessRules {
+            api_keys: applied.api_keys.clone(),
+            client_certs: applied.client_certs.clone(),
+        };
+        changes.push(format!(
+            "Access rules reloaded: {} API keys, {} client certs.",
+            applied.api_keys.len(),
+            applied.client_certs.len()
+        ));
+    }
+    let apply_err = apply_databases(m_state, &cur_cfg, &mut applied, &mut changes);
+
+    *cur_cfg = applied;
+    Ok((changes, apply_err))
+}
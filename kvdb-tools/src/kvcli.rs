@@ -1,12 +1,12 @@
 extern crate clap;
 
-const APPNAME: &'static str = "kvcli";
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const APPNAME: &str = "kvcli";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-const T_ENDPOINT: &'static str = "https://127.0.0.1:8080";
+const T_ENDPOINT: &str = "https://127.0.0.1:8080";
 
-use std::fs::File;
-use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Error, Write};
 use std::{env, io};
 
 use kvdb_lib::{client, pbapi};
@@ -130,11 +130,46 @@ fn encode_batch(batch_path: String) -> io::Result<()> {
     stdout_bytes(&out_bytes)
 }
 
-async fn cmd_serverinfo(endpoint: &str) -> io::Result<()> {
-    let mut kvdb_client = client::KvdbClient::new(endpoint.to_string(), "unused_dbid".to_string());
+// server connection options
+struct ClientOpts {
+    endpoint: String,
+    api_key: Option<String>,
+    client_cert: Option<(Vec<u8>, Vec<u8>)>, // cert chain, private key PEM
+    ca_file: Option<String>,                 // trusted CA certificates PEM
+    system_roots: bool,                      // trust system root CAs
+    pin_sha256: Option<String>,              // server certificate fingerprint
+    insecure: bool,                          // accept any server certificate
+}
+
+// connect to server, with credentials and TLS trust settings if given
+fn connect(opts: &ClientOpts, db_id: &str) -> io::Result<client::KvdbClient> {
+    let mut builder = client::KvdbClient::builder(opts.endpoint.clone(), db_id.to_string())
+        .system_roots(opts.system_roots)
+        .danger_accept_invalid_certs(opts.insecure);
+    if let Some(api_key) = &opts.api_key {
+        builder = builder.api_key(api_key);
+    }
+    if let Some((cert_pem, key_pem)) = &opts.client_cert {
+        builder = builder.client_cert(cert_pem, key_pem);
+    }
+    if let Some(ca_file) = &opts.ca_file {
+        builder = builder.ca_file(ca_file);
+    }
+    if let Some(pin) = &opts.pin_sha256 {
+        builder = builder.pin_sha256(pin);
+    }
+
+    match builder.build() {
+        Ok(kvdb_client) => Ok(kvdb_client),
+        Err(e) => Err(Error::other(e.to_string())),
+    }
+}
+
+async fn cmd_serverinfo(opts: &ClientOpts) -> io::Result<()> {
+    let mut kvdb_client = connect(opts, "unused_dbid")?;
     let res = kvdb_client.serverinfo().await;
     match res {
-        None => Err(Error::new(ErrorKind::Other, "ServerInfo Failed")),
+        None => Err(Error::other("ServerInfo Failed")),
         Some(resp) => {
             let s = std::str::from_utf8(&resp).unwrap();
             println!("{}", s);
@@ -143,11 +178,11 @@ async fn cmd_serverinfo(endpoint: &str) -> io::Result<()> {
     }
 }
 
-async fn cmd_stat(endpoint: &str, db_id: &str) -> io::Result<()> {
-    let mut kvdb_client = client::KvdbClient::new(endpoint.to_string(), db_id.to_string());
+async fn cmd_stat(opts: &ClientOpts, db_id: &str) -> io::Result<()> {
+    let mut kvdb_client = connect(opts, db_id)?;
     let res = kvdb_client.stat().await;
     match res {
-        None => Err(Error::new(ErrorKind::Other, "Database Stat Error")),
+        None => Err(Error::other("Database Stat Error")),
         Some(resp) => {
             println!("{:?}", resp);
             Ok(())
@@ -155,38 +190,29 @@ async fn cmd_stat(endpoint: &str, db_id: &str) -> io::Result<()> {
     }
 }
 
-async fn cmd_get(endpoint: &str, db_id: &str, key: &str) -> io::Result<()> {
-    let mut kvdb_client = client::KvdbClient::new(endpoint.to_string(), db_id.to_string());
+async fn cmd_get(opts: &ClientOpts, db_id: &str, key: &str) -> io::Result<()> {
+    let mut kvdb_client = connect(opts, db_id)?;
     let res = kvdb_client.get1(key.to_string()).await;
     match res {
-        None => Err(Error::new(
-            ErrorKind::Other,
-            "Error: Key not found in database.",
-        )),
+        None => Err(Error::other("Error: Key not found in database.")),
         Some(val) => stdout_bytes(&val),
     }
 }
 
-async fn cmd_del(endpoint: &str, db_id: &str, key: &str) -> io::Result<()> {
-    let mut kvdb_client = client::KvdbClient::new(endpoint.to_string(), db_id.to_string());
+async fn cmd_del(opts: &ClientOpts, db_id: &str, key: &str) -> io::Result<()> {
+    let mut kvdb_client = connect(opts, db_id)?;
     let res = kvdb_client.del1(key.to_string()).await;
     match res {
-        false => Err(Error::new(
-            ErrorKind::Other,
-            "Error: Database delete failed.",
-        )),
+        false => Err(Error::other("Error: Database delete failed.")),
         true => Ok(()),
     }
 }
 
-async fn cmd_put(endpoint: &str, db_id: &str, key: &str, value: &str) -> io::Result<()> {
-    let mut kvdb_client = client::KvdbClient::new(endpoint.to_string(), db_id.to_string());
+async fn cmd_put(opts: &ClientOpts, db_id: &str, key: &str, value: &str) -> io::Result<()> {
+    let mut kvdb_client = connect(opts, db_id)?;
     let res = kvdb_client.put1(key.to_string(), value.to_string()).await;
     match res {
-        false => Err(Error::new(
-            ErrorKind::Other,
-            "Error: Database store failed.",
-        )),
+        false => Err(Error::other("Error: Database store failed.")),
         true => Ok(()),
     }
 }
@@ -240,7 +266,7 @@ async fn main() -> io::Result<()> {
                 .long("encode")
                 .value_name("OP")
                 .help("Command: ENCODE CLI args to protobuf output")
-                .possible_values(&op_vals)
+                .possible_values(op_vals)
                 .takes_value(true),
         )
         .arg(
@@ -251,6 +277,53 @@ async fn main() -> io::Result<()> {
                 .default_value(T_ENDPOINT)
                 .takes_value(true),
         )
+        .arg(
+            clap::Arg::with_name("api-key")
+                .long("api-key")
+                .value_name("KEY")
+                .help("API key sent to server (default: $KVDB_API_KEY)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("client-cert")
+                .long("client-cert")
+                .value_name("PEM-FILE")
+                .help("TLS client certificate chain presented to server")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("client-key")
+                .long("client-key")
+                .value_name("PEM-FILE")
+                .help("Private key of --client-cert")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("ca-file")
+                .long("ca-file")
+                .value_name("PEM-FILE")
+                .help("Trust CA certificates in this file, for the server certificate")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("no-system-roots")
+                .long("no-system-roots")
+                .help("Do not trust system root CAs, for the server certificate")
+                .takes_value(false),
+        )
+        .arg(
+            clap::Arg::with_name("pin-sha256")
+                .long("pin-sha256")
+                .value_name("FINGERPRINT")
+                .help("Accept only the server certificate with this SHA-256 fingerprint (hex)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("insecure")
+                .long("insecure")
+                .help("Accept any server certificate.  Unsafe: exposes traffic and credentials")
+                .takes_value(false),
+        )
         .arg(
             clap::Arg::with_name("dbid")
                 .long("dbid")
@@ -281,34 +354,53 @@ async fn main() -> io::Result<()> {
         )
         .get_matches();
 
+    let api_key = match cli_matches.value_of("api-key") {
+        Some(api_key) => Some(api_key.to_string()),
+        None => env::var("KVDB_API_KEY").ok(),
+    };
+    let client_cert = match (
+        cli_matches.value_of("client-cert"),
+        cli_matches.value_of("client-key"),
+    ) {
+        (None, None) => None,
+        (Some(cert_path), Some(key_path)) => Some((fs::read(cert_path)?, fs::read(key_path)?)),
+        _ => return Err(Error::other("--client-cert and --client-key go together")),
+    };
+    let opts = ClientOpts {
+        endpoint: cli_matches.value_of("endpoint").unwrap().to_string(),
+        api_key,
+        client_cert,
+        ca_file: cli_matches.value_of("ca-file").map(|s| s.to_string()),
+        system_roots: !cli_matches.is_present("no-system-roots"),
+        pin_sha256: cli_matches.value_of("pin-sha256").map(|s| s.to_string()),
+        insecure: cli_matches.is_present("insecure"),
+    };
+
     if cli_matches.is_present("decode") {
-        Err(Error::new(
-            ErrorKind::Other,
-            "TODO: Decode not implemented yet",
-        ))
+        Err(Error::other("TODO: Decode not implemented yet"))
     } else if cli_matches.is_present("encode") {
         let op = cli_matches.value_of("encode").unwrap();
         match op {
             "get" | "del" => {
                 if !cli_matches.is_present("key") {
-                    return Err(Error::new(ErrorKind::Other, "Missing --key"));
+                    return Err(Error::other("Missing --key"));
                 }
                 let key = cli_matches.value_of("key").unwrap();
                 encode_get(key.to_string())
             }
             "mget" => {
                 if !cli_matches.is_present("metadata") {
-                    return Err(Error::new(ErrorKind::Other, "Missing --metadata"));
+                    return Err(Error::other("Missing --metadata"));
                 }
                 let batch_path = cli_matches.value_of("metadata").unwrap();
                 encode_mget(batch_path.to_string())
             }
             "put" => {
                 if !cli_matches.is_present("key") {
-                    return Err(Error::new(ErrorKind::Other, "Missing --key"));
+                    return Err(Error::other("Missing --key"));
                 }
                 if !cli_matches.is_present("value") {
-                    return Err(Error::new(ErrorKind::Other, "Missing --value"));
+                    return Err(Error::other("Missing --value"));
                 }
                 let key = cli_matches.value_of("key").unwrap();
                 let val = cli_matches.value_of("value").unwrap();
@@ -316,7 +408,7 @@ async fn main() -> io::Result<()> {
             }
             "mutate" => {
                 if !cli_matches.is_present("metadata") {
-                    return Err(Error::new(ErrorKind::Other, "Missing --metadata"));
+                    return Err(Error::other("Missing --metadata"));
                 }
                 let batch_path = cli_matches.value_of("metadata").unwrap();
                 encode_batch(batch_path.to_string())
@@ -330,54 +422,42 @@ async fn main() -> io::Result<()> {
             || !cli_matches.is_present("value")
             || !cli_matches.is_present("dbid")
         {
-            return Err(Error::new(
-                ErrorKind::Other,
-                "Missing --key, --value or --dbid",
-            ));
+            return Err(Error::other("Missing --key, --value or --dbid"));
         }
 
-        let endpoint = cli_matches.value_of("endpoint").unwrap();
         let dbid = cli_matches.value_of("dbid").unwrap();
         let key = cli_matches.value_of("key").unwrap();
         let value = cli_matches.value_of("value").unwrap();
 
-        cmd_put(endpoint, dbid, key, value).await
+        cmd_put(&opts, dbid, key, value).await
     } else if cli_matches.is_present("get") {
         if !cli_matches.is_present("key") || !cli_matches.is_present("dbid") {
-            return Err(Error::new(ErrorKind::Other, "Missing --key or --dbid"));
+            return Err(Error::other("Missing --key or --dbid"));
         }
 
-        let endpoint = cli_matches.value_of("endpoint").unwrap();
         let dbid = cli_matches.value_of("dbid").unwrap();
         let key = cli_matches.value_of("key").unwrap();
 
-        cmd_get(endpoint, dbid, key).await
+        cmd_get(&opts, dbid, key).await
     } else if cli_matches.is_present("del") {
         if !cli_matches.is_present("key") || !cli_matches.is_present("dbid") {
-            return Err(Error::new(ErrorKind::Other, "Missing --key or --dbid"));
+            return Err(Error::other("Missing --key or --dbid"));
         }
 
-        let endpoint = cli_matches.value_of("endpoint").unwrap();
         let dbid = cli_matches.value_of("dbid").unwrap();
         let key = cli_matches.value_of("key").unwrap();
 
-        cmd_del(endpoint, dbid, key).await
+        cmd_del(&opts, dbid, key).await
     } else if cli_matches.is_present("stat") {
         if !cli_matches.is_present("dbid") {
-            return Err(Error::new(ErrorKind::Other, "Missing --dbid"));
+            return Err(Error::other("Missing --dbid"));
         }
-        let endpoint = cli_matches.value_of("endpoint").unwrap();
         let dbid = cli_matches.value_of("dbid").unwrap();
 
-        cmd_stat(endpoint, dbid).await
+        cmd_stat(&opts, dbid).await
     } else if cli_matches.is_present("serverinfo") {
-        let endpoint = cli_matches.value_of("endpoint").unwrap();
-
-        cmd_serverinfo(endpoint).await
+        cmd_serverinfo(&opts).await
     } else {
-        Err(Error::new(
-            ErrorKind::Other,
-            "Error: No command operation specified.",
-        ))
+        Err(Error::other("Error: No command operation specified."))
     }
 }
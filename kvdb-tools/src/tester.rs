@@ -11,21 +11,25 @@ extern crate reqwest;
 
 use kvdb_lib::{client, codec, pbapi};
 
-const T_ENDPOINT: &'static str = "https://127.0.0.1:8080";
-const T_BASEURI: &'static str = "/api";
+const T_ENDPOINT: &str = "https://127.0.0.1:8080";
+const T_BASEURI: &str = "/api";
 
-const APPNAME: &'static str = "kvdbd-tester";
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const APPNAME: &str = "kvdbd-tester";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 use reqwest::{Client, StatusCode};
 
 use protobuf::{EnumOrUnknown, Message};
 
-use client::KvdbClient;
+use client::{KvdbClient, KvdbClientBuilder};
+use kvdb_lib::grpc::key_value_db_client::KeyValueDbClient;
+use kvdb_lib::grpc::{GRPC_AUTH_KEY, GRPC_DB_KEY, GRPC_ERR_CODE_KEY};
 use pbapi::{
-    get_op_result, get_response, iter_request, mutation_request, update_request, GetResponse,
-    IterRequest, IterResponse, MutationRequest, UpdateRequest,
+    get_op_result, get_request, get_response, iter_request, key_request, mutation_request,
+    update_request, GetOp, GetRequest, GetResponse, IterRequest, IterResponse, KeyRequest,
+    MutationRequest, UpdateRequest,
 };
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
 
 struct KeyList {
     keys: Vec<Vec<u8>>,
@@ -43,7 +47,7 @@ fn pbenc_iter_req(start_key: Option<Vec<u8>>, prefix: Option<Vec<u8>>) -> Vec<u8
         None => out_msg.prefix = Vec::new(),
         Some(s) => out_msg.prefix = s,
     }
-    return out_msg.write_to_bytes().unwrap();
+    out_msg.write_to_bytes().unwrap()
 }
 
 fn pbenc_update_ins(key: &[u8], val: &[u8]) -> UpdateRequest {
@@ -67,7 +71,7 @@ fn pbenc_update_del(key: &[u8]) -> UpdateRequest {
 
 fn pbenc_update_req(key: &[u8], val: &[u8]) -> Vec<u8> {
     let out_msg = pbenc_update_ins(key, val);
-    return out_msg.write_to_bytes().unwrap();
+    out_msg.write_to_bytes().unwrap()
 }
 
 async fn t_iter(client: &Client, db_id: String, start_key: Option<Vec<u8>>) -> KeyList {
@@ -78,34 +82,12 @@ async fn t_iter(client: &Client, db_id: String, start_key: Option<Vec<u8>>) -> K
     let out_bytes = pbenc_iter_req(start_key, None);
 
     // exec keys request; check for successful response
-    let resp_res = client.post(&iter_url).body(out_bytes).send().await;
-    if resp_res.is_err() {
-        assert!(false);
-    }
-    let resp = resp_res.unwrap();
+    let resp = client.post(&iter_url).body(out_bytes).send().await.unwrap();
     assert_eq!(resp.status(), StatusCode::OK);
 
     // decode protobuf list-of-keys response
-    let bytes_res = resp.bytes().await;
-    let bytes;
-    match bytes_res {
-        Ok(bytes_) => bytes = bytes_,
-        Err(_e) => {
-            assert!(false);
-            panic!("silence E0381 warning");
-        }
-    }
-
-    let in_msg;
-    match IterResponse::parse_from_bytes(&bytes) {
-        Err(_e) => {
-            assert!(false);
-            panic!("silence E0381 warning");
-        }
-        Ok(req) => {
-            in_msg = req;
-        }
-    }
+    let bytes = resp.bytes().await.unwrap();
+    let in_msg = IterResponse::parse_from_bytes(&bytes).unwrap();
 
     // copy from pb struct to normal struct for returning data
     let mut key_list: Vec<Vec<u8>> = Vec::new();
@@ -134,7 +116,7 @@ async fn t_get_gone(client: &Client, db_id: String, key: String) {
 
             match resp.bytes().await {
                 Ok(bytes) => match GetResponse::parse_from_bytes(&bytes) {
-                    Err(_e) => assert!(false),
+                    Err(e) => panic!("{}", e),
                     Ok(in_resp) => {
                         assert_eq!(
                             in_resp.magic,
@@ -142,17 +124,17 @@ async fn t_get_gone(client: &Client, db_id: String, key: String) {
                         );
                         assert_eq!(in_resp.res.len(), 1);
 
-                        assert_eq!(in_resp.res[0].is_ok, false);
+                        assert!(!in_resp.res[0].is_ok);
                         assert_eq!(
                             in_resp.res[0].err,
                             EnumOrUnknown::new(get_op_result::GetErr::KEY_NOT_FOUND)
                         );
                     }
                 },
-                Err(_e) => assert!(false),
+                Err(e) => panic!("{}", e),
             }
         }
-        Err(_e) => assert!(false),
+        Err(e) => panic!("{}", e),
     }
 }
 
@@ -171,7 +153,7 @@ async fn t_get_ok(client: &Client, db_id: String, key: String, value: String) {
 
             match resp.bytes().await {
                 Ok(bytes) => match GetResponse::parse_from_bytes(&bytes) {
-                    Err(_e) => assert!(false),
+                    Err(e) => panic!("{}", e),
                     Ok(in_resp) => {
                         assert_eq!(
                             in_resp.magic,
@@ -179,14 +161,14 @@ async fn t_get_ok(client: &Client, db_id: String, key: String, value: String) {
                         );
                         assert_eq!(in_resp.res.len(), 1);
 
-                        assert_eq!(in_resp.res[0].is_ok, true);
+                        assert!(in_resp.res[0].is_ok);
                         assert_eq!(in_resp.res[0].val, value.as_bytes());
                     }
                 },
-                Err(_e) => assert!(false),
+                Err(e) => panic!("{}", e),
             }
         }
-        Err(_e) => assert!(false),
+        Err(e) => panic!("{}", e),
     }
 }
 
@@ -205,10 +187,10 @@ async fn t_put_bytes(client: &Client, db_id: String, key: &[u8], value: &[u8]) {
 
             match resp.text().await {
                 Ok(_body) => {}
-                Err(_e) => assert!(false),
+                Err(e) => panic!("{}", e),
             }
         }
-        Err(_e) => assert!(false),
+        Err(e) => panic!("{}", e),
     }
 }
 
@@ -227,13 +209,10 @@ async fn t_put(client: &Client, db_id: String, key: String, value: String) {
 
             match resp.text().await {
                 Ok(_body) => {}
-                Err(_e) => assert!(false),
+                Err(e) => panic!("{}", e),
             }
         }
-        Err(_e) => {
-            println!("PUT-err {}", _e);
-            assert!(false)
-        }
+        Err(e) => panic!("PUT-err {}", e),
     }
 }
 
@@ -252,10 +231,10 @@ async fn t_del(client: &Client, db_id: String, key: String) {
 
             match resp.text().await {
                 Ok(_body) => {}
-                Err(_e) => assert!(false),
+                Err(e) => panic!("{}", e),
             }
         }
-        Err(_e) => assert!(false),
+        Err(e) => panic!("{}", e),
     }
 }
 
@@ -274,10 +253,26 @@ async fn t_del_gone(client: &Client, db_id: String, key: String) {
 
             match resp.text().await {
                 Ok(_body) => {}
-                Err(_e) => assert!(false),
+                Err(e) => panic!("{}", e),
             }
         }
-        Err(_e) => assert!(false),
+        Err(e) => panic!("{}", e),
+    }
+}
+
+// POST request body; assert response status
+async fn t_post_status(client: &Client, url: String, body: Vec<u8>, status: StatusCode) {
+    let resp_res = client.post(&url).body(body).send().await;
+    match resp_res {
+        Ok(resp) => {
+            assert_eq!(resp.status(), status, "POST {}", url);
+
+            match resp.text().await {
+                Ok(_body) => {}
+                Err(e) => panic!("{}", e),
+            }
+        }
+        Err(e) => panic!("{}", e),
     }
 }
 
@@ -304,7 +299,7 @@ async fn op_batch(kvdb_client: &mut KvdbClient, client: &Client, db_id: String)
 
     // exec batch request
     let res = kvdb_client.mutate(&out_msg).await;
-    assert_eq!(res, true);
+    assert!(res);
 
     t_get_gone(client, db_id.clone(), test_key.clone()).await;
     t_get_ok(
@@ -323,7 +318,927 @@ async fn op_batch(kvdb_client: &mut KvdbClient, client: &Client, db_id: String)
     .await;
 
     t_del(client, db_id.clone(), String::from("op_batch_key2")).await;
-    t_del(client, db_id, String::from("op_batch_key3")).await;
+    t_del(client, db_id.clone(), String::from("op_batch_key3")).await;
+
+    // durable batch: response follows flush to disk
+    let mut out_msg = MutationRequest::new();
+    out_msg.magic = EnumOrUnknown::new(mutation_request::MagicNum::MAGIC);
+    out_msg.durable = true;
+    let req = pbenc_update_ins("op_batch_key4".as_bytes(), "op_batch_value4".as_bytes());
+    out_msg.reqs.push(req);
+    assert!(kvdb_client.mutate(&out_msg).await);
+    t_get_ok(
+        client,
+        db_id.clone(),
+        String::from("op_batch_key4"),
+        String::from("op_batch_value4"),
+    )
+    .await;
+    t_del(client, db_id, String::from("op_batch_key4")).await;
+}
+
+async fn op_cas(kvdb_client: &mut KvdbClient, client: &Client, db_id: String) {
+    let test_key = String::from("op_cas_key1");
+    let v1 = Some(String::from("op_cas_value1"));
+    let v2 = Some(String::from("op_cas_value2"));
+
+    // create-if-absent, then swap
+    assert!(kvdb_client.cas1(test_key.clone(), None, v1.clone()).await);
+    assert!(!kvdb_client.cas1(test_key.clone(), None, v2.clone()).await);
+    assert!(
+        kvdb_client
+            .cas1(test_key.clone(), v1.clone(), v2.clone())
+            .await
+    );
+    t_get_ok(client, db_id.clone(), test_key.clone(), v2.clone().unwrap()).await;
+
+    // failed precondition aborts the whole mutation
+    let mut out_msg = MutationRequest::new();
+    out_msg.magic = EnumOrUnknown::new(mutation_request::MagicNum::MAGIC);
+
+    let req = pbenc_update_ins(b"op_cas_key2", b"op_cas_value2");
+    out_msg.reqs.push(req);
+
+    let mut req = pbenc_update_del(test_key.as_bytes());
+    req.condition = EnumOrUnknown::new(update_request::Condition::VALUE_EQUALS);
+    req.expected_value = v1.clone().unwrap().into_bytes();
+    out_msg.reqs.push(req);
+
+    assert!(!kvdb_client.mutate(&out_msg).await);
+    t_get_gone(client, db_id.clone(), String::from("op_cas_key2")).await;
+
+    // compare-and-delete
+    assert!(kvdb_client.cas1(test_key.clone(), v2, None).await);
+    t_get_gone(client, db_id, test_key).await;
+}
+
+async fn op_ns(kvdb_client: &mut KvdbClient, client: &Client, db_id: String) {
+    let ns_id = format!("{}/op_ns_users", db_id);
+    let test_key = String::from("op_ns_key1");
+    let test_value = format!("helloworld op_ns {}", db_id);
+
+    // namespace must exist before use
+    assert!(!kvdb_client.create_ns("__op_ns_reserved").await);
+    assert!(kvdb_client.create_ns("op_ns_users").await);
+
+    // same key, separate keyspaces
+    t_put(client, ns_id.clone(), test_key.clone(), test_value.clone()).await;
+    t_get_gone(client, db_id.clone(), test_key.clone()).await;
+    t_get_ok(client, ns_id.clone(), test_key.clone(), test_value.clone()).await;
+
+    // one mutation spans namespaces
+    let mut out_msg = MutationRequest::new();
+    out_msg.magic = EnumOrUnknown::new(mutation_request::MagicNum::MAGIC);
+
+    let mut req = pbenc_update_del(test_key.as_bytes());
+    req.ns = String::from("op_ns_users");
+    out_msg.reqs.push(req);
+
+    let req = pbenc_update_ins(test_key.as_bytes(), test_value.as_bytes());
+    out_msg.reqs.push(req);
+
+    assert!(kvdb_client.mutate(&out_msg).await);
+    t_get_gone(client, ns_id.clone(), test_key.clone()).await;
+    t_get_ok(client, db_id.clone(), test_key.clone(), test_value).await;
+    t_del(client, db_id, test_key).await;
+
+    assert!(kvdb_client.drop_ns("op_ns_users").await);
+    assert!(!kvdb_client.drop_ns("op_ns_users").await);
+}
+
+async fn op_ttl(kvdb_client: &mut KvdbClient, client: &Client, db_id: String) {
+    let short_key = String::from("op_ttl_key1");
+    let long_key = String::from("op_ttl_key2");
+    let test_value = format!("helloworld op_ttl {}", db_id);
+
+    let mut out_msg = MutationRequest::new();
+    out_msg.magic = EnumOrUnknown::new(mutation_request::MagicNum::MAGIC);
+
+    let mut req = pbenc_update_ins(short_key.as_bytes(), test_value.as_bytes());
+    req.ttl_ms = 1;
+    out_msg.reqs.push(req);
+
+    let mut req = pbenc_update_ins(long_key.as_bytes(), test_value.as_bytes());
+    req.ttl_ms = 3_600_000;
+    out_msg.reqs.push(req);
+
+    assert!(kvdb_client.mutate(&out_msg).await);
+
+    // expired records vanish at once, without waiting for the reaper
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    t_get_gone(client, db_id.clone(), short_key).await;
+    t_get_ok(client, db_id.clone(), long_key.clone(), test_value).await;
+    t_del(client, db_id, long_key).await;
+}
+
+// server certificate trust settings
+struct TlsOpts {
+    ca_file: Option<String>, // trusted CA certificates PEM
+    insecure: bool,          // accept any server certificate
+}
+
+// client builder, trusting the server certificate as configured
+fn client_builder(tls: &TlsOpts, db_id: String) -> KvdbClientBuilder {
+    let mut builder = KvdbClient::builder(T_ENDPOINT.to_string(), db_id)
+        .danger_accept_invalid_certs(tls.insecure);
+    if let Some(ca_file) = &tls.ca_file {
+        builder = builder.ca_file(ca_file);
+    }
+    builder
+}
+
+// http client, sending API key if given
+fn http_client(tls: &TlsOpts, api_key: Option<&str>) -> Client {
+    let mut builder = client_builder(tls, String::new());
+    if let Some(api_key) = api_key {
+        builder = builder.api_key(api_key);
+    }
+    builder.build_http_client().unwrap()
+}
+
+// kvdb client, sending API key if given
+fn kvdb_client(tls: &TlsOpts, api_key: Option<&str>, db_id: String) -> KvdbClient {
+    let mut builder = client_builder(tls, db_id);
+    if let Some(api_key) = api_key {
+        builder = builder.api_key(api_key);
+    }
+    builder.build().unwrap()
+}
+
+async fn t_get_status(client: &Client, url: String, status: StatusCode) {
+    let resp = client.get(&url).send().await.unwrap();
+    assert_eq!(resp.status(), status, "GET {}", url);
+}
+
+async fn op_auth(tls: &TlsOpts, api_key: &str, read_key: Option<&str>) {
+    let db_url = format!("{}{}/db1", T_ENDPOINT, T_BASEURI);
+
+    // no key, or unknown key: refused
+    for client in [
+        http_client(tls, None),
+        http_client(tls, Some("op_auth_bad_key")),
+    ] {
+        t_get_status(
+            &client,
+            format!("{}/", T_ENDPOINT),
+            StatusCode::UNAUTHORIZED,
+        )
+        .await;
+        t_get_status(
+            &client,
+            format!("{}/stat", db_url),
+            StatusCode::UNAUTHORIZED,
+        )
+        .await;
+        t_post_status(
+            &client,
+            format!("{}/put", db_url),
+            pbenc_update_req(b"op_auth_key1", b"value"),
+            StatusCode::UNAUTHORIZED,
+        )
+        .await;
+    }
+
+    let client = http_client(tls, Some(api_key));
+    t_put(
+        &client,
+        String::from("db1"),
+        String::from("op_auth_key1"),
+        String::from("value"),
+    )
+    .await;
+
+    // read-only key: reads allowed, writes forbidden
+    if let Some(read_key) = read_key {
+        let client = http_client(tls, Some(read_key));
+        t_get_status(&client, format!("{}/", T_ENDPOINT), StatusCode::OK).await;
+        t_get_status(&client, format!("{}/stat", db_url), StatusCode::OK).await;
+        t_post_status(
+            &client,
+            format!("{}/put", db_url),
+            pbenc_update_req(b"op_auth_key1", b"value2"),
+            StatusCode::FORBIDDEN,
+        )
+        .await;
+        t_post_status(
+            &client,
+            format!("{}/clear", db_url),
+            Vec::new(),
+            StatusCode::FORBIDDEN,
+        )
+        .await;
+
+        // percent-encoded paths get db1's grant, as routed, never that of
+        // "*"; a database name still encoded after routing is refused
+        for url in [
+            format!("{}{}/%64b1/put", T_ENDPOINT, T_BASEURI),
+            format!("{}/%70ut", db_url),
+        ] {
+            t_post_status(
+                &client,
+                url,
+                pbenc_update_req(b"op_auth_key1", b"value2"),
+                StatusCode::FORBIDDEN,
+            )
+            .await;
+        }
+        t_get_status(
+            &client,
+            format!("{}{}/db%251/stat", T_ENDPOINT, T_BASEURI),
+            StatusCode::BAD_REQUEST,
+        )
+        .await;
+
+        let mut kvdb_client = kvdb_client(tls, Some(read_key), String::from("db1"));
+        assert_eq!(
+            kvdb_client.get1(String::from("op_auth_key1")).await,
+            Some(b"value".to_vec())
+        );
+        assert!(!kvdb_client.del1(String::from("op_auth_key1")).await);
+    }
+
+    let mut kvdb_client = kvdb_client(tls, Some(api_key), String::from("db1"));
+    assert!(kvdb_client.del1(String::from("op_auth_key1")).await);
+}
+
+async fn op_client_cert(tls: &TlsOpts, cert_path: &str, key_path: &str) {
+    let cert_pem = std::fs::read(cert_path).unwrap();
+    let key_pem = std::fs::read(key_path).unwrap();
+    let builder = client_builder(tls, String::from("db1")).client_cert(&cert_pem, &key_pem);
+    let client = builder.build_http_client().unwrap();
+
+    // certificate identity alone grants read access to db1
+    let db_url = format!("{}{}/db1", T_ENDPOINT, T_BASEURI);
+    t_get_status(&client, format!("{}/stat", db_url), StatusCode::OK).await;
+    t_post_status(
+        &client,
+        format!("{}/put", db_url),
+        pbenc_update_req(b"op_client_cert_key1", b"value"),
+        StatusCode::FORBIDDEN,
+    )
+    .await;
+    t_get_status(
+        &client,
+        format!("{}{}/db2/stat", T_ENDPOINT, T_BASEURI),
+        StatusCode::FORBIDDEN,
+    )
+    .await;
+
+    let mut kvdb_client = builder.build().unwrap();
+    assert!(kvdb_client.stat().await.is_some());
+    assert!(
+        !kvdb_client
+            .put1(String::from("op_client_cert_key1"), String::from("value"))
+            .await
+    );
+}
+
+async fn op_read_only(kvdb_client: &mut KvdbClient, client: &Client, db_id: String) {
+    let basepath = format!("{}{}/{}/", T_ENDPOINT, T_BASEURI, db_id);
+    let key = b"op_read_only_key1";
+
+    // every write is rejected by the server
+    let mut mut_req = MutationRequest::new();
+    mut_req.magic = EnumOrUnknown::new(mutation_request::MagicNum::MAGIC);
+    mut_req.reqs.push(pbenc_update_ins(key, b"value"));
+
+    let writes = [
+        ("put", pbenc_update_req(key, b"value")),
+        ("mutate", mut_req.write_to_bytes().unwrap()),
+        ("del", codec::pbenc_key_req(key)),
+        ("cas", codec::pbenc_cas_req(key, None, Some(b"value"))),
+        ("clear", Vec::new()),
+        ("op_read_only_ns/create", Vec::new()),
+    ];
+    for (op, body) in writes {
+        let url = format!("{}{}", basepath, op);
+        t_post_status(client, url, body, StatusCode::FORBIDDEN).await;
+    }
+
+    // reads still succeed
+    t_get_gone(client, db_id.clone(), String::from("op_read_only_key1")).await;
+    assert!(kvdb_client.stat().await.is_some());
+    t_iter(client, db_id, None).await;
+}
+
+// gRPC request for database db_id, sending API key if given
+fn grpc_request<T>(msg: T, db_id: Option<&str>, api_key: Option<&str>) -> tonic::Request<T> {
+    let mut request = tonic::Request::new(msg);
+    if let Some(db_id) = db_id {
+        request
+            .metadata_mut()
+            .insert(GRPC_DB_KEY, db_id.parse().unwrap());
+    }
+    if let Some(api_key) = api_key {
+        request.metadata_mut().insert(
+            GRPC_AUTH_KEY,
+            format!("Bearer {}", api_key).parse().unwrap(),
+        );
+    }
+    request
+}
+
+// assert failed gRPC call, with expected status and kvdb error code
+fn t_grpc_err<T: std::fmt::Debug>(
+    res: Result<tonic::Response<T>, tonic::Status>,
+    code: tonic::Code,
+    err_code: Option<pbapi::basic_response::ErrCode>,
+) {
+    let status = res.unwrap_err();
+    assert_eq!(status.code(), code, "{:?}", status);
+    if let Some(err_code) = err_code {
+        let meta = status.metadata().get(GRPC_ERR_CODE_KEY).unwrap();
+        assert_eq!(meta.to_str().unwrap(), (err_code as i32).to_string());
+    }
+}
+
+fn grpc_update_req(key: &[u8], value: &[u8], is_insert: bool) -> UpdateRequest {
+    let mut out_msg = UpdateRequest::new();
+    out_msg.magic = EnumOrUnknown::new(update_request::MagicNum::MAGIC);
+    out_msg.key = key.to_vec();
+    out_msg.value = value.to_vec();
+    out_msg.is_insert = is_insert;
+    out_msg
+}
+
+fn grpc_key_req(key: &[u8]) -> KeyRequest {
+    let mut out_msg = KeyRequest::new();
+    out_msg.magic = EnumOrUnknown::new(key_request::MagicNum::MAGIC);
+    out_msg.key = key.to_vec();
+    out_msg
+}
+
+fn grpc_get_req(key: &[u8]) -> GetRequest {
+    let mut out_msg = GetRequest::new();
+    out_msg.magic = EnumOrUnknown::new(get_request::MagicNum::MAGIC);
+    let mut out_op = GetOp::new();
+    out_op.key = key.to_vec();
+    out_msg.ops.push(out_op);
+    out_msg
+}
+
+// gRPC client, trusting the server certificate as configured
+async fn grpc_client(endpoint: &str, tls: &TlsOpts) -> KeyValueDbClient<Channel> {
+    let mut endpoint = Endpoint::from_shared(endpoint.to_string()).unwrap();
+    if let Some(ca_file) = &tls.ca_file {
+        let ca = std::fs::read(ca_file).unwrap();
+        let tls_cfg = ClientTlsConfig::new().ca_certificate(Certificate::from_pem(ca));
+        endpoint = endpoint.tls_config(tls_cfg).unwrap();
+    }
+    KeyValueDbClient::new(endpoint.connect().await.unwrap())
+}
+
+async fn op_grpc_client_cert(endpoint: &str, tls: &TlsOpts, cert_path: &str, key_path: &str) {
+    let cert_pem = std::fs::read(cert_path).unwrap();
+    let key_pem = std::fs::read(key_path).unwrap();
+    let ca = std::fs::read(tls.ca_file.as_ref().unwrap()).unwrap();
+    let tls_cfg = ClientTlsConfig::new()
+        .ca_certificate(Certificate::from_pem(ca))
+        .identity(Identity::from_pem(cert_pem, key_pem));
+    let endpoint = Endpoint::from_shared(endpoint.to_string())
+        .unwrap()
+        .tls_config(tls_cfg)
+        .unwrap();
+    let mut grpc = KeyValueDbClient::new(endpoint.connect().await.unwrap());
+
+    // certificate identity alone grants read access to db1
+    let resp = grpc
+        .get(grpc_request(
+            grpc_get_req(b"op_client_cert_key1"),
+            Some("db1"),
+            None,
+        ))
+        .await
+        .unwrap();
+    assert!(!resp.get_ref().res[0].is_ok);
+    let res = grpc
+        .put(grpc_request(
+            grpc_update_req(b"op_client_cert_key1", b"value", true),
+            Some("db1"),
+            None,
+        ))
+        .await;
+    t_grpc_err(res, tonic::Code::PermissionDenied, None);
+    let res = grpc
+        .get(grpc_request(
+            grpc_get_req(b"op_client_cert_key1"),
+            Some("db2"),
+            None,
+        ))
+        .await;
+    t_grpc_err(res, tonic::Code::PermissionDenied, None);
+}
+
+async fn op_grpc(endpoint: &str, tls: &TlsOpts, api_key: Option<&str>, db_id: &str) {
+    let mut grpc = grpc_client(endpoint, tls).await;
+    let db = Some(db_id);
+    let key = b"op_grpc_key1";
+    let value = format!("helloworld op_grpc {}", db_id);
+
+    // put, get, del
+    let resp = grpc
+        .put(grpc_request(
+            grpc_update_req(key, value.as_bytes(), true),
+            db,
+            api_key,
+        ))
+        .await
+        .unwrap();
+    assert!(resp.get_ref().is_ok);
+
+    let resp = grpc
+        .get(grpc_request(grpc_get_req(key), db, api_key))
+        .await
+        .unwrap();
+    let res = &resp.get_ref().res[0];
+    assert!(res.is_ok);
+    assert_eq!(res.val, value.as_bytes());
+
+    let resp = grpc
+        .del(grpc_request(grpc_key_req(key), db, api_key))
+        .await
+        .unwrap();
+    assert!(resp.get_ref().is_ok);
+
+    let resp = grpc
+        .get(grpc_request(grpc_get_req(key), db, api_key))
+        .await
+        .unwrap();
+    let res = &resp.get_ref().res[0];
+    assert!(!res.is_ok);
+    assert_eq!(
+        res.err.enum_value_or_default(),
+        get_op_result::GetErr::KEY_NOT_FOUND
+    );
+
+    // batch insert, then scan it back in pages
+    let mut batch = MutationRequest::new();
+    batch.magic = EnumOrUnknown::new(mutation_request::MagicNum::MAGIC);
+    for n in 0..5 {
+        let key = format!("op_grpc_batch{}", n);
+        batch
+            .reqs
+            .push(grpc_update_req(key.as_bytes(), key.as_bytes(), true));
+    }
+    let resp = grpc.mutate(grpc_request(batch, db, api_key)).await.unwrap();
+    assert!(resp.get_ref().is_ok);
+
+    let mut n_found = 0;
+    let mut scan_req = codec::pbenc_scan_req(b"", false);
+    scan_req.prefix = b"op_grpc_batch".to_vec();
+    scan_req.limit = 2;
+    loop {
+        let resp = grpc
+            .scan(grpc_request(scan_req.clone(), db, api_key))
+            .await
+            .unwrap();
+        let in_msg = resp.into_inner();
+        assert!(in_msg.pairs.len() <= 2);
+        for kv in &in_msg.pairs {
+            assert!(kv.key.starts_with(b"op_grpc_batch"));
+            assert_eq!(kv.key, kv.value);
+            n_found += 1;
+        }
+        if in_msg.list_end {
+            break;
+        }
+        // continue after the last key of this page
+        let mut start_key = in_msg.pairs.last().unwrap().key.clone();
+        start_key.push(0);
+        scan_req.start_key = start_key;
+    }
+    assert_eq!(n_found, 5);
+
+    // failed precondition aborts the whole batch, reported in-band
+    let mut batch = MutationRequest::new();
+    batch.magic = EnumOrUnknown::new(mutation_request::MagicNum::MAGIC);
+    let mut upd = grpc_update_req(b"op_grpc_batch0", b"changed", true);
+    upd.condition = EnumOrUnknown::new(update_request::Condition::NOT_EXISTS);
+    batch.reqs.push(upd);
+    batch
+        .reqs
+        .push(grpc_update_req(b"op_grpc_batch1", b"", false));
+    let resp = grpc.mutate(grpc_request(batch, db, api_key)).await.unwrap();
+    assert!(!resp.get_ref().is_ok);
+    assert_eq!(
+        resp.get_ref().err_code,
+        pbapi::basic_response::ErrCode::CONDITION_FAILED as i32
+    );
+    let resp = grpc
+        .get(grpc_request(grpc_get_req(b"op_grpc_batch1"), db, api_key))
+        .await
+        .unwrap();
+    assert!(resp.get_ref().res[0].is_ok);
+
+    // clean up, by batch removal
+    let mut batch = MutationRequest::new();
+    batch.magic = EnumOrUnknown::new(mutation_request::MagicNum::MAGIC);
+    for n in 0..5 {
+        let key = format!("op_grpc_batch{}", n);
+        batch.reqs.push(grpc_update_req(key.as_bytes(), b"", false));
+    }
+    let resp = grpc.mutate(grpc_request(batch, db, api_key)).await.unwrap();
+    assert!(resp.get_ref().is_ok);
+
+    // put is insert-only
+    t_grpc_err(
+        grpc.put(grpc_request(grpc_update_req(key, b"", false), db, api_key))
+            .await,
+        tonic::Code::InvalidArgument,
+        None,
+    );
+
+    // missing or unknown database
+    t_grpc_err(
+        grpc.get(grpc_request(grpc_get_req(key), None, api_key))
+            .await,
+        tonic::Code::InvalidArgument,
+        None,
+    );
+    t_grpc_err(
+        grpc.get(grpc_request(
+            grpc_get_req(key),
+            Some("op_grpc_no_such_db"),
+            api_key,
+        ))
+        .await,
+        tonic::Code::NotFound,
+        None,
+    );
+
+    // no key, or unknown key: refused
+    if api_key.is_some() {
+        for bad_key in [None, Some("op_grpc_bad_key")] {
+            t_grpc_err(
+                grpc.get(grpc_request(grpc_get_req(key), db, bad_key)).await,
+                tonic::Code::Unauthenticated,
+                Some(pbapi::basic_response::ErrCode::UNAUTHORIZED),
+            );
+            t_grpc_err(
+                grpc.del(grpc_request(grpc_key_req(key), db, bad_key)).await,
+                tonic::Code::Unauthenticated,
+                Some(pbapi::basic_response::ErrCode::UNAUTHORIZED),
+            );
+        }
+    }
+}
+
+// writes to a read-only database fail with a gRPC status
+async fn op_grpc_read_only(endpoint: &str, tls: &TlsOpts, api_key: Option<&str>, db_id: &str) {
+    let mut grpc = grpc_client(endpoint, tls).await;
+    let db = Some(db_id);
+
+    t_grpc_err(
+        grpc.put(grpc_request(
+            grpc_update_req(b"op_grpc_ro_key", b"value", true),
+            db,
+            api_key,
+        ))
+        .await,
+        tonic::Code::PermissionDenied,
+        Some(pbapi::basic_response::ErrCode::READ_ONLY),
+    );
+    t_grpc_err(
+        grpc.del(grpc_request(grpc_key_req(b"op_grpc_ro_key"), db, api_key))
+            .await,
+        tonic::Code::PermissionDenied,
+        Some(pbapi::basic_response::ErrCode::READ_ONLY),
+    );
+
+    let resp = grpc
+        .get(grpc_request(grpc_get_req(b"op_grpc_ro_key"), db, api_key))
+        .await
+        .unwrap();
+    assert!(!resp.get_ref().res[0].is_ok);
+}
+
+async fn op_metrics(
+    tls: &TlsOpts,
+    api_key: Option<&str>,
+    admin_token: Option<&str>,
+    client: &Client,
+    grpc: bool,
+) {
+    // labels name databases: credentials required, when configured
+    if api_key.is_some() {
+        t_get_status(
+            &http_client(tls, None),
+            format!("{}/metrics", T_ENDPOINT),
+            StatusCode::UNAUTHORIZED,
+        )
+        .await;
+    }
+    if let Some(token) = admin_token {
+        t_get_status(
+            &http_client(tls, Some(token)),
+            format!("{}/metrics", T_ENDPOINT),
+            StatusCode::OK,
+        )
+        .await;
+    }
+
+    let resp = client
+        .get(format!("{}/metrics", T_ENDPOINT))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = resp.text().await.unwrap();
+
+    // requests made above, and gauges of each database tested
+    assert!(
+        body.contains(r#"kvdb_http_requests_total{db="db1",route="/api/{db}/put",status="200"}"#)
+    );
+    for db_id in ["db1", "db2"] {
+        assert!(body.contains(&format!(r#"kvdb_db_records{{db="{}",ns=""}}"#, db_id)));
+        assert!(body.contains(&format!(r#"kvdb_db_disk_bytes{{db="{}"}}"#, db_id)));
+    }
+    if grpc {
+        assert!(body.contains(r#"kvdb_grpc_requests_total{code="Ok",db="db1",method="Put"}"#));
+        // failed precondition, reported in a BasicResponse
+        assert!(body.contains(
+            r#"kvdb_grpc_errors_total{db="db1",method="Mutate",type="CONDITION_FAILED"}"#
+        ));
+    }
+    if grpc && api_key.is_some() {
+        assert!(body
+            .contains(r#"kvdb_grpc_requests_total{code="Unauthenticated",db="db1",method="Del"}"#));
+    }
+}
+
+// readiness of each database, by name
+async fn t_readyz(client: &Client, status: StatusCode) -> serde_json::Value {
+    let resp = client
+        .get(format!("{}/readyz", T_ENDPOINT))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), status);
+    let body: serde_json::Value = serde_json::from_slice(&resp.bytes().await.unwrap()).unwrap();
+
+    let mut databases = serde_json::Map::new();
+    for db_health in body["databases"].as_array().unwrap() {
+        let name = db_health["name"].as_str().unwrap().to_string();
+        databases.insert(name, db_health.clone());
+    }
+    serde_json::Value::Object(databases)
+}
+
+async fn op_health(tls: &TlsOpts) {
+    // probes need no credentials
+    let client = http_client(tls, None);
+
+    let resp = client
+        .get(format!("{}/healthz", T_ENDPOINT))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let databases = t_readyz(&client, StatusCode::OK).await;
+    for db_id in ["db1", "db2"] {
+        assert_eq!(databases[db_id]["status"], "ok");
+    }
+}
+
+async fn op_admin(client: &Client, token: &str) {
+    let admin_url = format!("{}/admin/databases", T_ENDPOINT);
+    let db_id = String::from("op_admin_db");
+    let db_path = std::env::temp_dir().join(format!("kvdb-tester-{}.kv", std::process::id()));
+    let attach_req = format!(
+        "{{\"name\":\"{}\",\"path\":{:?},\"driver\":\"sled\",\"create\":true}}",
+        db_id, db_path
+    );
+
+    // admin token is required
+    let resp = client
+        .post(&admin_url)
+        .body(attach_req.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // create and use new database
+    let resp = client
+        .post(&admin_url)
+        .bearer_auth(token)
+        .body(attach_req.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = client
+        .post(&admin_url)
+        .bearer_auth(token)
+        .body(attach_req)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+    // same path, spelled another way
+    let alias_req = format!(
+        "{{\"name\":\"op_admin_alias\",\"path\":{:?},\"driver\":\"sled\"}}",
+        db_path.join(".")
+    );
+    let resp = client
+        .post(&admin_url)
+        .bearer_auth(token)
+        .body(alias_req)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+    // failed open removes the directory it created
+    let bad_path = std::env::temp_dir().join(format!("kvdb-tester-bad-{}.kv", std::process::id()));
+    let bad_req = format!(
+        "{{\"name\":\"op_admin_bad\",\"path\":{:?},\"driver\":\"no_such_driver\",\"create\":true}}",
+        bad_path
+    );
+    let resp = client
+        .post(&admin_url)
+        .bearer_auth(token)
+        .body(bad_req)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(!bad_path.exists());
+
+    let info = client.get(format!("{}/", T_ENDPOINT)).send().await.unwrap();
+    assert!(info.text().await.unwrap().contains(&db_id));
+    op_put(client, db_id.clone()).await;
+
+    // reload of unchanged config keeps all databases, attached ones included
+    let resp = client
+        .post(format!("{}/admin/reload", T_ENDPOINT))
+        .bearer_auth(token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let info = client.get(format!("{}/", T_ENDPOINT)).send().await.unwrap();
+    assert!(info.text().await.unwrap().contains(&db_id));
+    op_put(client, db_id.clone()).await;
+
+    // degraded database is reported, but server stays ready
+    let degraded_url = format!("{}/{}/degraded", admin_url, db_id);
+    let resp = client
+        .post(&degraded_url)
+        .body(r#"{"reason":"tester"}"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let resp = client
+        .post(&degraded_url)
+        .bearer_auth(token)
+        .body(r#"{"reason":"tester"}"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let databases = t_readyz(client, StatusCode::OK).await;
+    assert_eq!(databases[&db_id]["status"], "degraded");
+    assert_eq!(databases[&db_id]["reason"], "tester");
+    let resp = client
+        .delete(&degraded_url)
+        .bearer_auth(token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let databases = t_readyz(client, StatusCode::OK).await;
+    assert_eq!(databases[&db_id]["status"], "ok");
+
+    // sled has no compaction
+    let compact_url = format!("{}/{}/compact", admin_url, db_id);
+    let resp = client.post(&compact_url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let resp = client
+        .post(&compact_url)
+        .bearer_auth(token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = client
+        .post(format!("{}/no_such_db/compact", admin_url))
+        .bearer_auth(token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    // detach; database is gone
+    let db_url = format!("{}/{}", admin_url, db_id);
+    let resp = client
+        .delete(&db_url)
+        .bearer_auth(token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = client
+        .delete(&db_url)
+        .bearer_auth(token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    t_post_status(
+        client,
+        format!("{}{}/{}/put", T_ENDPOINT, T_BASEURI, db_id),
+        pbenc_update_req(b"op_admin_key1", b"value"),
+        StatusCode::NOT_FOUND,
+    )
+    .await;
+
+    std::fs::remove_dir_all(db_path).unwrap();
+}
+
+// create a database of each driver, then reattach it read-only and test that
+async fn op_admin_read_only(tls: &TlsOpts, api_key: Option<&str>, client: &Client, token: &str) {
+    let admin_url = format!("{}/admin/databases", T_ENDPOINT);
+
+    for driver in ["lmdb", "bitcask"] {
+        let db_id = format!("op_read_only_{}", driver);
+        let db_path =
+            std::env::temp_dir().join(format!("kvdb-tester-{}-{}.kv", driver, std::process::id()));
+
+        for (read_only, create) in [(false, true), (true, false)] {
+            let attach_req = format!(
+                "{{\"name\":\"{}\",\"path\":{:?},\"driver\":\"{}\",\"read_only\":{},\"create\":{}}}",
+                db_id, db_path, driver, read_only, create
+            );
+            let resp = client
+                .post(&admin_url)
+                .bearer_auth(token)
+                .body(attach_req)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK, "attach {}", db_id);
+
+            match read_only {
+                false => {
+                    // records written before, visible after reattach
+                    t_put(
+                        client,
+                        db_id.clone(),
+                        String::from("op_admin_read_only_key1"),
+                        String::from("value"),
+                    )
+                    .await
+                }
+                true => {
+                    t_get_ok(
+                        client,
+                        db_id.clone(),
+                        String::from("op_admin_read_only_key1"),
+                        String::from("value"),
+                    )
+                    .await;
+                    let mut kvdb_client = kvdb_client(tls, api_key, db_id.clone());
+                    op_read_only(&mut kvdb_client, client, db_id.clone()).await;
+                }
+            }
+
+            let resp = client
+                .delete(format!("{}/{}", admin_url, db_id))
+                .bearer_auth(token)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK, "detach {}", db_id);
+        }
+
+        std::fs::remove_dir_all(db_path).unwrap();
+    }
+
+    // sled has no read-only mode
+    let db_path = std::env::temp_dir().join(format!("kvdb-tester-sled-{}.kv", std::process::id()));
+    std::fs::create_dir(&db_path).unwrap();
+    let attach_req = format!(
+        "{{\"name\":\"op_read_only_sled\",\"path\":{:?},\"driver\":\"sled\",\"read_only\":true}}",
+        db_path
+    );
+    let resp = client
+        .post(&admin_url)
+        .bearer_auth(token)
+        .body(attach_req)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        resp.status(),
+        StatusCode::BAD_REQUEST,
+        "attach sled read-only"
+    );
+
+    std::fs::remove_dir_all(db_path).unwrap();
 }
 
 async fn op_del(client: &Client, db_id: String) {
@@ -340,14 +1255,10 @@ async fn op_stat(kvdb_client: &mut KvdbClient) {
     let test_value = format!("hllworld op_stat {}", kvdb_client.db_id.clone());
 
     let res = kvdb_client.put1(test_key.clone(), test_value.clone()).await;
-    assert_eq!(res, true);
+    assert!(res);
 
     // exec db-stat request
-    let resp_res = kvdb_client.stat().await;
-    if resp_res.is_none() {
-        assert!(false);
-    }
-    let in_msg = resp_res.unwrap();
+    let in_msg = kvdb_client.stat().await.unwrap();
 
     assert_eq!(in_msg.n_records, 1);
 }
@@ -364,7 +1275,7 @@ async fn op_iter(client: &Client, db_id: String) {
     }
 
     for s in &vdata {
-        t_put_bytes(client, db_id.clone(), &s, &s).await;
+        t_put_bytes(client, db_id.clone(), s, s).await;
     }
 
     let mut check_data: Vec<Vec<u8>> = Vec::new();
@@ -392,6 +1303,35 @@ async fn op_iter(client: &Client, db_id: String) {
     }
 }
 
+async fn op_scan(kvdb_client: &mut KvdbClient) {
+    // op_iter stored "datum N" records, with key==value
+    for keys_only in [false, true] {
+        let mut n_datum = 0;
+        let mut last_key: Vec<u8> = Vec::new();
+        let mut list_end = false;
+        while !list_end {
+            let scan_req = codec::pbenc_scan_req(&last_key, keys_only);
+            let in_msg = kvdb_client.scan(&scan_req).await.unwrap();
+
+            for kv in in_msg.pairs {
+                assert!(kv.key > last_key);
+                if kv.key.starts_with(b"datum ") {
+                    match keys_only {
+                        true => assert!(kv.value.is_empty()),
+                        false => assert_eq!(kv.key, kv.value),
+                    }
+                    n_datum += 1;
+                }
+                last_key = kv.key;
+            }
+
+            list_end = in_msg.list_end;
+        }
+
+        assert_eq!(n_datum, 2001);
+    }
+}
+
 async fn op_get(kvdb_client: &mut KvdbClient) {
     let test_key = String::from("op_key1");
     let test_value = format!("helloworld op_get {}", kvdb_client.db_id);
@@ -400,7 +1340,7 @@ async fn op_get(kvdb_client: &mut KvdbClient) {
     assert_eq!(res, None);
 
     let res = kvdb_client.put1(test_key.clone(), test_value.clone()).await;
-    assert_eq!(res, true);
+    assert!(res);
 
     let res = kvdb_client.get1(test_key.clone()).await;
     assert_ne!(res, None);
@@ -408,7 +1348,7 @@ async fn op_get(kvdb_client: &mut KvdbClient) {
     assert_eq!(test_value.as_bytes(), res_value);
 
     let res = kvdb_client.del1(test_key.clone()).await;
-    assert_eq!(res, true);
+    assert!(res);
 
     let res = kvdb_client.get1(test_key.clone()).await;
     assert_eq!(res, None);
@@ -428,7 +1368,7 @@ async fn op_clear(client: &Client, db_id: String) {
     let resp_res = client.post(&clear_url).send().await;
     match resp_res {
         Ok(resp) => assert_eq!(resp.status(), StatusCode::OK),
-        Err(_e) => assert!(false),
+        Err(e) => panic!("{}", e),
     }
 
     t_get_gone(client, db_id, test_key).await;
@@ -451,15 +1391,15 @@ async fn op_put(client: &Client, db_id: String) {
 
             match resp.text().await {
                 Ok(_body) => {}
-                Err(_e) => assert!(false),
+                Err(e) => panic!("{}", e),
             }
         }
-        Err(_e) => assert!(false),
+        Err(e) => panic!("{}", e),
     }
 
     // encode verification get request
-    t_get_ok(&client, db_id.clone(), test_key.clone(), test_value).await;
-    t_del(&client, db_id.clone(), test_key).await;
+    t_get_ok(client, db_id.clone(), test_key.clone(), test_value).await;
+    t_del(client, db_id.clone(), test_key).await;
 }
 
 #[tokio::main]
@@ -468,31 +1408,141 @@ async fn main() -> Result<(), reqwest::Error> {
     let cli_app = clap::App::new(APPNAME)
         .version(VERSION)
         .author("Jeff Garzik <jgarzik@pobox.com>")
-        .about("Integration tester for kvdbd");
+        .about("Integration tester for kvdbd")
+        .arg(
+            clap::Arg::with_name("admin-token")
+                .long("admin-token")
+                .value_name("TOKEN")
+                .help("Also test admin API, using this token.  kvdbd must run on this host.")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("api-key")
+                .long("api-key")
+                .value_name("KEY")
+                .help("API key, with admin access to all databases")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("read-key")
+                .long("read-key")
+                .value_name("KEY")
+                .help("Also test access control, using this API key with read access to db1")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("client-cert")
+                .long("client-cert")
+                .value_name("PEM-FILE")
+                .help("Also test client certificates, using this cert with read access to db1")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("client-key")
+                .long("client-key")
+                .value_name("PEM-FILE")
+                .help("Private key of --client-cert")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("ca-file")
+                .long("ca-file")
+                .value_name("PEM-FILE")
+                .help("Trust CA certificates in this file, for the server certificate")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("grpc-endpoint")
+                .long("grpc-endpoint")
+                .value_name("URL")
+                .help("Also test gRPC API at this URL, e.g. https://127.0.0.1:8081")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("insecure")
+                .long("insecure")
+                .help("Accept any server certificate")
+                .takes_value(false),
+        );
 
     // parse command line
-    let _cli_matches = cli_app.get_matches();
+    let cli_matches = cli_app.get_matches();
 
     // create http client
-    let client = reqwest::Client::builder()
-        .danger_accept_invalid_certs(true)
-        .build()
-        .unwrap();
+    let tls = TlsOpts {
+        ca_file: cli_matches.value_of("ca-file").map(|s| s.to_string()),
+        insecure: cli_matches.is_present("insecure"),
+    };
+    let api_key = cli_matches.value_of("api-key");
+    let client = http_client(&tls, api_key);
 
     // test, for each database
     for n in 1..3 {
         let db_id = format!("db{}", n);
 
-        let mut kvdb_client = client::KvdbClient::new(T_ENDPOINT.to_string(), db_id.clone());
+        let mut kvdb_client = kvdb_client(&tls, api_key, db_id.clone());
 
         op_batch(&mut kvdb_client, &client, db_id.clone()).await;
         op_del(&client, db_id.clone()).await;
         op_get(&mut kvdb_client).await;
         op_put(&client, db_id.clone()).await;
+        op_cas(&mut kvdb_client, &client, db_id.clone()).await;
+        op_ns(&mut kvdb_client, &client, db_id.clone()).await;
+        op_ttl(&mut kvdb_client, &client, db_id.clone()).await;
         op_clear(&client, db_id.clone()).await;
         op_stat(&mut kvdb_client).await;
         op_iter(&client, db_id.clone()).await;
+        op_scan(&mut kvdb_client).await;
+    }
+
+    // test each read-only database, if any are configured
+    let mut kvdb_client = kvdb_client(&tls, api_key, String::new());
+    let info_bytes = kvdb_client.serverinfo().await.unwrap();
+    let info: serde_json::Value = serde_json::from_slice(&info_bytes).unwrap();
+    for db_info in info["databases"].as_array().unwrap() {
+        if db_info["read_only"] == serde_json::Value::Bool(true) {
+            let db_id = db_info["name"].as_str().unwrap().to_string();
+            kvdb_client.db_id = db_id.clone();
+            op_read_only(&mut kvdb_client, &client, db_id.clone()).await;
+            if let Some(endpoint) = cli_matches.value_of("grpc-endpoint") {
+                op_grpc_read_only(endpoint, &tls, api_key, &db_id).await;
+            }
+        }
+    }
+
+    if let Some(api_key) = api_key {
+        op_auth(&tls, api_key, cli_matches.value_of("read-key")).await;
+    }
+
+    if let (Some(cert_path), Some(key_path)) = (
+        cli_matches.value_of("client-cert"),
+        cli_matches.value_of("client-key"),
+    ) {
+        op_client_cert(&tls, cert_path, key_path).await;
+    }
+
+    if let Some(endpoint) = cli_matches.value_of("grpc-endpoint") {
+        for n in 1..3 {
+            op_grpc(endpoint, &tls, api_key, &format!("db{}", n)).await;
+        }
+        if let (Some(cert_path), Some(key_path)) = (
+            cli_matches.value_of("client-cert"),
+            cli_matches.value_of("client-key"),
+        ) {
+            op_grpc_client_cert(endpoint, &tls, cert_path, key_path).await;
+        }
     }
+
+    let grpc = cli_matches.is_present("grpc-endpoint");
+    let admin_token = cli_matches.value_of("admin-token");
+    op_metrics(&tls, api_key, admin_token, &client, grpc).await;
+    op_health(&tls).await;
+
+    if let Some(token) = cli_matches.value_of("admin-token") {
+        op_admin(&client, token).await;
+        op_admin_read_only(&tls, api_key, &client, token).await;
+    }
+
     println!("Integration testing successful.");
     Ok(())
 }